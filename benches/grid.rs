@@ -0,0 +1,69 @@
+// Read-path benchmarks that compare two ways of getting the same answer.
+//
+//     cargo bench --bench grid
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_lib_turbosheet::api::simple::{ContentKind, FilterCondition, FilterPredicate, GridChunkOptions, SheetSession};
+use std::hint::black_box;
+
+// A session over `text`, written to a temporary file first
+fn open(name: &str, text: &str) -> SheetSession {
+    let path = std::env::temp_dir().join(format!("ts_bench_{}_{}.csv", name, std::process::id()));
+    std::fs::write(&path, text).unwrap();
+    let sheet = SheetSession::new_from_file(path.to_string_lossy().into_owned()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    sheet
+}
+
+// Link detection should cost little over the plain grid: well under a
+// microsecond per cell, so a 1,000-cell viewport pays under a millisecond
+fn link_detection(c: &mut Criterion) {
+    // Three link cells per row: a URL, an email and a bare www. host
+    let row = |i| format!("{0},https://example.com/item/{0},user{0}@example.org,www.site{0}.net,text {0}", i);
+    let rows: Vec<String> = (0..10_000).map(row).collect();
+    let sheet = open("links", &rows.join("\n"));
+    let chunks = |detect_links: bool| {
+        let mut links = 0;
+        for chunk in 0..100 {
+            let opts = GridChunkOptions { detect_links, ..Default::default() };
+            let rows = sheet.get_grid_chunk_with_options(chunk * 100, 100, 0, 5, opts);
+            let cells = rows.iter().flat_map(|row| &row.cells);
+            links += cells.filter(|cell| cell.content_kind != ContentKind::Plain).count();
+        }
+        links
+    };
+    assert_eq!((chunks(false), chunks(true)), (0, 100 * 100 * 3));
+
+    let mut group = c.benchmark_group("100 chunks of 100x5 cells");
+    group.bench_function("plain", |b| b.iter(|| black_box(chunks(false))));
+    group.bench_function("detect_links", |b| b.iter(|| black_box(chunks(true))));
+    group.finish();
+}
+
+// One AnyColumnContains pass against OR-ing a Contains filter per column
+fn quick_filter(c: &mut Criterion) {
+    let mut text = String::from("id,city,country,note\n");
+    for i in 0..500_000 {
+        text.push_str(&format!("{},City {},Country {},note {}\n", i, i % 997, i % 89, i % 10_007));
+    }
+    let sheet = open("quick_filter", &text);
+    let needle = "ty 42";
+    let quick = || sheet.filter_rows(vec![FilterCondition::AnyColumnContains(needle.to_string())]).unwrap();
+    let per_column = || {
+        let mut rows = std::collections::BTreeSet::new();
+        for col in 0..sheet.total_cols {
+            let predicate = FilterPredicate::Contains(needle.to_string());
+            rows.extend(sheet.filter_rows(vec![FilterCondition::Column { col, predicate }]).unwrap());
+        }
+        rows.into_iter().collect::<Vec<_>>()
+    };
+    assert_eq!(quick(), per_column());
+
+    let mut group = c.benchmark_group("filter 500k rows x 4 columns");
+    group.sample_size(10);
+    group.bench_function("AnyColumnContains", |b| b.iter(|| black_box(quick())));
+    group.bench_function("per-column OR", |b| b.iter(|| black_box(per_column())));
+    group.finish();
+}
+
+criterion_group!(benches, link_detection, quick_filter);
+criterion_main!(benches);
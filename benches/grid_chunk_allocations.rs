@@ -0,0 +1,47 @@
+// Heap allocations of one 10,000 x 100 get_grid_chunk, counted by dhat. Its
+// own binary so the counting allocator stays out of every other target.
+//
+//     cargo bench --bench grid_chunk_allocations
+use rust_lib_turbosheet::api::simple::{CellData, ContentKind, SheetSession};
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// What `f` returns, and how many allocations (reallocations included) it made
+fn allocations<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    let before = dhat::HeapStats::get().total_blocks;
+    let result = f();
+    (result, dhat::HeapStats::get().total_blocks - before)
+}
+
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+    let (rows, cols) = (10_000, 100);
+    let line: Vec<String> = (0..cols).map(|c| format!("cell{}", c)).collect();
+    let path = std::env::temp_dir().join(format!("ts_bench_allocations_{}.csv", std::process::id()));
+    std::fs::write(&path, vec![line.join(","); rows].join("\n")).unwrap();
+    let sheet = SheetSession::new_from_file(path.to_string_lossy().into_owned()).unwrap();
+
+    let chunk = |cols: usize| allocations(|| sheet.get_grid_chunk(0, rows as i32, 0, cols as i32));
+    let (wide, total) = chunk(cols);
+    let (_, narrow) = chunk(5);
+    let (_, growing) = allocations(|| {
+        let mut cells = Vec::new();
+        for _ in 0..cols {
+            let content_kind = ContentKind::Plain;
+            cells.push(CellData { content: String::new(), is_null: false, is_padding: false, content_kind });
+        }
+        std::hint::black_box(cells);
+    });
+    let strings = (rows * cols) as u64;
+    println!(
+        "{}x{} chunk: {} allocations ({} cell strings); growing each row's cells would add {}",
+        rows, cols, total, strings, (growing - 1) * rows as u64
+    );
+    // With the cells pre-sized, what a row costs beyond its strings doesn't
+    // depend on how many cells it has (growing them would add more per row
+    // the wider the chunk)
+    assert_eq!(wide.len(), rows);
+    dhat::assert_eq!(total - strings, narrow - 5 * rows as u64);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,308 @@
+// ------------------------------------
+// C API (feature "c-ffi")
+// ------------------------------------
+// Plain extern "C" entry points for non-Flutter hosts (Tauri, Python via
+// ctypes, ...). Every function forwards to the same SheetSession methods the
+// Dart side calls, so both front ends behave the same; see turbo_sheet.h.
+//
+// Sessions live in a registry and are addressed by u64 handles (0 is never
+// issued). Each session has its own lock, so a long export on one handle
+// doesn't hold up calls on the others. Functions return TS_OK or a negative
+// TS_ERR_* code; the message of the last failure on the calling thread is
+// available from ts_last_error. Panics never cross into the host: they fail
+// the call with TS_ERR_OPERATION_FAILED, and a session whose operation
+// panicked refuses further calls until it is closed.
+//
+// Safety: pointer arguments must be valid for the stated length (strings
+// NUL-terminated UTF-8) for the duration of the call. Nothing is retained.
+#![cfg(feature = "c-ffi")]
+#![allow(clippy::missing_safety_doc)]
+
+use crate::api::simple::{write_csv_row, FilterCondition, SheetSession};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+
+pub const TS_OK: i32 = 0;
+pub const TS_ERR_INVALID_HANDLE: i32 = -1;
+pub const TS_ERR_INVALID_ARGUMENT: i32 = -2;
+pub const TS_ERR_OPERATION_FAILED: i32 = -3; // Details in ts_last_error
+pub const TS_ERR_BUFFER_TOO_SMALL: i32 = -4; // *out_len holds the size needed
+
+static SESSIONS: OnceLock<Mutex<HashMap<u64, Arc<Mutex<SheetSession>>>>> = OnceLock::new();
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// Helper: remember the message and return the code
+fn fail(code: i32, message: impl Into<String>) -> i32 {
+    LAST_ERROR.with(|e| *e.borrow_mut() = message.into());
+    code
+}
+
+// Helper: the handle registry. Only inserts and removes happen under this
+// lock, which leave the map whole even if a panic poisoned it.
+fn sessions() -> MutexGuard<'static, HashMap<u64, Arc<Mutex<SheetSession>>>> {
+    SESSIONS.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Helper: run an entry point, turning a panic into TS_ERR_OPERATION_FAILED
+fn guarded(f: impl FnOnce() -> i32) -> i32 {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let detail = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        fail(TS_ERR_OPERATION_FAILED, format!("Internal error: {}", detail))
+    })
+}
+
+// Helper: run `f` on the session behind `handle`, holding only its lock
+fn with_session(handle: u64, f: impl FnOnce(&mut SheetSession) -> i32) -> i32 {
+    let Some(session) = sessions().get(&handle).cloned() else {
+        return fail(TS_ERR_INVALID_HANDLE, format!("No session with handle {}", handle));
+    };
+    let Ok(mut session) = session.lock() else {
+        return fail(TS_ERR_OPERATION_FAILED, "An earlier call on this session failed internally; close it");
+    };
+    f(&mut session)
+}
+
+// Helper: borrowed C string -> &str
+unsafe fn read_str<'a>(ptr: *const c_char) -> Result<&'a str, i32> {
+    if ptr.is_null() {
+        return Err(fail(TS_ERR_INVALID_ARGUMENT, "Null string argument"));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| fail(TS_ERR_INVALID_ARGUMENT, "String argument is not valid UTF-8"))
+}
+
+// Helper: copy `bytes` into a caller buffer, reporting the size either way
+unsafe fn copy_out(bytes: &[u8], buf: *mut u8, buf_len: usize, out_len: *mut usize) -> i32 {
+    if !out_len.is_null() {
+        *out_len = bytes.len();
+    }
+    if bytes.len() > buf_len || (buf.is_null() && !bytes.is_empty()) {
+        return fail(TS_ERR_BUFFER_TOO_SMALL, format!("Needs a {} byte buffer", bytes.len()));
+    }
+    if !bytes.is_empty() {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len());
+    }
+    TS_OK
+}
+
+// Open (memory-map and index) a CSV file. On success *out_handle is set.
+#[no_mangle]
+pub unsafe extern "C" fn ts_open(path: *const c_char, out_handle: *mut u64) -> i32 {
+    guarded(|| {
+        let path = match read_str(path) {
+            Ok(path) => path.to_string(),
+            Err(code) => return code,
+        };
+        if out_handle.is_null() {
+            return fail(TS_ERR_INVALID_ARGUMENT, "out_handle is null");
+        }
+        match SheetSession::new_from_file(path) {
+            Ok(session) => {
+                let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+                sessions().insert(handle, Arc::new(Mutex::new(session)));
+                *out_handle = handle;
+                TS_OK
+            }
+            Err(e) => fail(TS_ERR_OPERATION_FAILED, e),
+        }
+    })
+}
+
+// Drop a session; the handle is invalid afterwards. A call still running on
+// it finishes first (the session is freed after it).
+#[no_mangle]
+pub extern "C" fn ts_close(handle: u64) -> i32 {
+    guarded(|| match sessions().remove(&handle) {
+        Some(_) => TS_OK,
+        None => fail(TS_ERR_INVALID_HANDLE, format!("No session with handle {}", handle)),
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ts_totals(handle: u64, out_rows: *mut i64, out_cols: *mut i64) -> i32 {
+    if out_rows.is_null() || out_cols.is_null() {
+        return fail(TS_ERR_INVALID_ARGUMENT, "Null output pointer");
+    }
+    guarded(|| {
+        with_session(handle, |session| {
+            *out_rows = session.total_rows;
+            *out_cols = session.total_cols;
+            TS_OK
+        })
+    })
+}
+
+// A grid chunk serialized as CSV (RFC 4180 quoting, one \n-terminated record
+// per row), the cells get_grid_chunk returns but never cut short by the
+// response size budget (the caller's buffer is the limit here). Call with a
+// null buffer to learn the size.
+#[no_mangle]
+pub unsafe extern "C" fn ts_get_grid_chunk(
+    handle: u64,
+    row_start: i64,
+    row_count: i32,
+    col_start: i64,
+    col_count: i32,
+    buf: *mut u8,
+    buf_len: usize,
+    out_len: *mut usize,
+) -> i32 {
+    guarded(|| {
+        with_session(handle, |session| {
+            let mut out = Vec::new();
+            for row in session.get_grid_chunk_unbounded(row_start, row_count, col_start, col_count) {
+                let cells: Vec<String> = row.cells.into_iter().map(|c| c.content).collect();
+                if let Err(e) = write_csv_row(&mut out, &cells) {
+                    return fail(TS_ERR_OPERATION_FAILED, e);
+                }
+                out.push(b'\n');
+            }
+            copy_out(&out, buf, buf_len, out_len)
+        })
+    })
+}
+
+// Rows where any cell contains `needle` (session trim/case options apply).
+// Writes up to max_rows row indices; *out_count is the full match count.
+#[no_mangle]
+pub unsafe extern "C" fn ts_search(
+    handle: u64,
+    needle: *const c_char,
+    out_rows: *mut i64,
+    max_rows: usize,
+    out_count: *mut usize,
+) -> i32 {
+    let needle = match read_str(needle) {
+        Ok(needle) => needle.to_string(),
+        Err(code) => return code,
+    };
+    if out_count.is_null() || (out_rows.is_null() && max_rows > 0) {
+        return fail(TS_ERR_INVALID_ARGUMENT, "Null output pointer");
+    }
+    guarded(|| {
+        with_session(handle, |session| {
+            match session.filter_rows(vec![FilterCondition::AnyColumnContains(needle)]) {
+                Ok(rows) => {
+                    let shown = rows.len().min(max_rows);
+                    if shown > 0 {
+                        std::ptr::copy_nonoverlapping(rows.as_ptr(), out_rows, shown);
+                    }
+                    *out_count = rows.len();
+                    TS_OK
+                }
+                Err(e) => fail(TS_ERR_OPERATION_FAILED, e),
+            }
+        })
+    })
+}
+
+// Same as save_to_file
+#[no_mangle]
+pub unsafe extern "C" fn ts_export_csv(handle: u64, path: *const c_char) -> i32 {
+    let path = match read_str(path) {
+        Ok(path) => path.to_string(),
+        Err(code) => return code,
+    };
+    guarded(|| {
+        with_session(handle, |session| match session.save_to_file(path) {
+            Ok(()) => TS_OK,
+            Err(e) => fail(TS_ERR_OPERATION_FAILED, e),
+        })
+    })
+}
+
+// Message for the last failure on this thread (UTF-8, not NUL-terminated).
+// A size query here leaves the message in place.
+#[no_mangle]
+pub unsafe extern "C" fn ts_last_error(buf: *mut u8, buf_len: usize, out_len: *mut usize) -> i32 {
+    let message = LAST_ERROR.with(|e| e.borrow().clone());
+    let code = copy_out(message.as_bytes(), buf, buf_len, out_len);
+    if code != TS_OK {
+        LAST_ERROR.with(|e| *e.borrow_mut() = message);
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    // Helper: the last error as a String, through the C API
+    fn last_error() -> String {
+        let mut len = 0usize;
+        unsafe { ts_last_error(std::ptr::null_mut(), 0, &mut len) };
+        let mut buf = vec![0u8; len];
+        assert_eq!(unsafe { ts_last_error(buf.as_mut_ptr(), buf.len(), &mut len) }, TS_OK);
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn round_trip_through_the_c_api() {
+        let dir = std::env::temp_dir().join(format!("ts_ffi_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("in.csv");
+        std::fs::write(&input, "name,city\nAnn,Oslo\n\"Bo, Jr\",Rome\n").unwrap();
+        let path = CString::new(input.to_str().unwrap()).unwrap();
+
+        let mut handle = 0u64;
+        assert_eq!(unsafe { ts_open(path.as_ptr(), &mut handle) }, TS_OK);
+        assert_ne!(handle, 0);
+
+        let (mut rows, mut cols) = (0i64, 0i64);
+        assert_eq!(unsafe { ts_totals(handle, &mut rows, &mut cols) }, TS_OK);
+        assert_eq!((rows, cols), (4, 2)); // Trailing newline gives an empty last row
+
+        // Size query, too-small buffer, then the real read
+        let mut len = 0usize;
+        let code = unsafe { ts_get_grid_chunk(handle, 1, 2, 0, 2, std::ptr::null_mut(), 0, &mut len) };
+        assert_eq!(code, TS_ERR_BUFFER_TOO_SMALL);
+        let mut small = vec![0u8; len - 1];
+        let code = unsafe { ts_get_grid_chunk(handle, 1, 2, 0, 2, small.as_mut_ptr(), small.len(), &mut len) };
+        assert_eq!(code, TS_ERR_BUFFER_TOO_SMALL);
+        let mut buf = vec![0u8; len];
+        let code = unsafe { ts_get_grid_chunk(handle, 1, 2, 0, 2, buf.as_mut_ptr(), buf.len(), &mut len) };
+        assert_eq!(code, TS_OK);
+        assert_eq!(std::str::from_utf8(&buf[..len]).unwrap(), "Ann,Oslo\n\"Bo, Jr\",Rome\n");
+
+        let needle = CString::new("Rome").unwrap();
+        let (mut found, mut count) = ([0i64; 4], 0usize);
+        let code = unsafe { ts_search(handle, needle.as_ptr(), found.as_mut_ptr(), found.len(), &mut count) };
+        assert_eq!((code, count, found[0]), (TS_OK, 1, 2));
+
+        let output = dir.join("out.csv");
+        let out_path = CString::new(output.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { ts_export_csv(handle, out_path.as_ptr()) }, TS_OK);
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+
+        assert_eq!(ts_close(handle), TS_OK);
+        assert_eq!(unsafe { ts_totals(handle, &mut rows, &mut cols) }, TS_ERR_INVALID_HANDLE);
+        assert!(last_error().contains("No session with handle"));
+        assert_eq!(ts_close(handle), TS_ERR_INVALID_HANDLE);
+
+        let missing = CString::new(dir.join("missing.csv").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { ts_open(missing.as_ptr(), &mut handle) }, TS_ERR_OPERATION_FAILED);
+        assert!(last_error().starts_with("Failed to open file"));
+        assert_eq!(unsafe { ts_open(std::ptr::null(), &mut handle) }, TS_ERR_INVALID_ARGUMENT);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn panics_fail_the_call_instead_of_unwinding_into_the_host() {
+        assert_eq!(guarded(|| panic!("boom")), TS_ERR_OPERATION_FAILED);
+        assert_eq!(last_error(), "Internal error: boom");
+    }
+}
@@ -0,0 +1,11 @@
+// Crate root. flutter_rust_bridge scans crate::api (simple.rs, which sits
+// next to this file); frb_generated.rs is written by
+// `flutter_rust_bridge_codegen generate` and isn't checked in, so it is
+// only compiled with the "frb" feature (the Flutter build turns it on).
+#[path = "."]
+pub mod api {
+    pub mod simple;
+}
+#[cfg(feature = "frb")]
+mod frb_generated;
+mod ffi;
@@ -1,8 +1,27 @@
+#[cfg(feature = "frb")]
+use crate::frb_generated::StreamSink;
 use flutter_rust_bridge::frb;
+use std::borrow::Cow;
 use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
 use memmap2::Mmap;
-use std::sync::Arc;
+use regex::Regex;
+use sha2::Digest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::str;
+use std::time::{Duration, Instant};
+
+// tracing::event! at TRACE level with the "tracing" feature; nothing otherwise.
+// get_grid_chunk is traced this way for per-chunk profiling on devices.
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::TRACE, $($arg)*);
+    };
+}
 
 // ------------------------------------
 // Data Objects (Sent to Dart)
@@ -10,17 +29,788 @@ use std::str;
 
 pub struct CellData {
     pub content: String,
+    pub is_null: bool, // content is one of the column's null tokens (see set_null_tokens)
+    // With set_strict_padding on: the row has no such field and the empty
+    // content was made up to fill the grid. Always false otherwise.
+    pub is_padding: bool,
+    // Link-like content, with GridChunkOptions.detect_links. Always Plain otherwise.
+    pub content_kind: ContentKind,
+}
+
+// What a cell holds, for rendering it as a link (see content_kind_of)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ContentKind {
+    Plain,
+    Url,
+    Email,
 }
 
 pub struct RowData {
     pub index: i64,
+    pub source_row: i64, // Row in the file (differs from index once rows are reordered)
     pub cells: Vec<CellData>,
+    pub decoded_with_fallback: bool, // Line isn't valid UTF-8 and was read as Windows-1252
+    // Set on the last row when the response hit the size budget (see
+    // set_response_size_budget): fetch again from here for the rest
+    pub continuation: Option<ChunkContinuation>,
+    pub row_number: Option<i64>, // 1-based view position, when asked for (GridChunkOptions)
+}
+
+// Where a size-capped chunk stopped: the first cell that wasn't sent
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChunkContinuation {
+    pub next_row: i64,
+    pub next_col: i64,
+}
+
+// Where a scrollbar fraction lands (see row_at_fraction). is_exact is false
+// when the row was estimated rather than read from the row index.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RowLocation {
+    pub row: i64,
+    pub is_exact: bool,
+}
+
+// Options for opening a file. Everything here is optional; the defaults
+// match what new_from_file has always done.
+#[derive(Default)]
+pub struct OpenOptions {
+    // Pin the column count instead of detecting it from the first row.
+    pub total_cols_override: Option<i64>,
+    // Safety limit for the detected column count (defaults to DEFAULT_MAX_COLS).
+    pub max_cols: Option<i64>,
+    // Heap budget in bytes for the whole session (see set_memory_budget).
+    pub memory_budget: Option<i64>,
+    // Record terminator; None means Auto.
+    pub line_terminator: Option<LineTerminator>,
+    // Treat a run of commas outside quotes as one separator (a,,,,b is two
+    // fields), as spreadsheet import wizards offer for padded conversions.
+    // SessionInfo.merge_delimiter_rows shows whether it would matter.
+    pub merge_consecutive_delimiters: bool,
+    // How the file is held (see StorageBackend); None means Auto.
+    pub backend: Option<StorageBackend>,
+    // Retries for temporary read errors; None means IoRetryPolicy::default().
+    pub io_retry: Option<IoRetryPolicy>,
+    // The first row holds column names: they replace the letters (repeats
+    // made unique in get_header_metadata), the rows start after it, and
+    // exports write it back as their header line.
+    pub first_row_header: bool,
+}
+
+// Mapped reads the file through the page cache (fast, nothing copied), but a
+// page fault on a share that has gone away can kill the app; Buffered reads
+// it into memory once. Auto maps local files and buffers files on a network
+// filesystem. SessionInfo.backend/backend_reason show what was picked.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StorageBackend {
+    Auto,
+    Mapped,
+    Buffered,
+}
+
+// Opening and reading retry errors that tend to clear up by themselves (EIO,
+// timeouts, a dropped SMB/NFS connection) up to `attempts` times in all,
+// waiting `backoff_ms` before the first retry and doubling it each time.
+// Errors that won't (not found, permission denied, ...) fail at once.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct IoRetryPolicy {
+    pub attempts: u32,
+    pub backoff_ms: u64,
+}
+
+impl Default for IoRetryPolicy {
+    fn default() -> Self {
+        IoRetryPolicy { attempts: 3, backoff_ms: 100 }
+    }
+}
+
+// Error from the calls that open or reload a file (new_from_file*,
+// open_with_index, reopen, save_in_place). kind tells the UI whether
+// offering "try again" makes sense (or, for the memory budget, what didn't
+// fit); message is what the String errors say.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SheetError {
+    pub kind: SheetErrorKind,
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum SheetErrorKind {
+    Transient, // A temporary I/O error outlasted the retries (e.g. an unreachable share)
+    Permanent, // Anything else: not found, access denied, not valid CSV, ...
+    // The file can't be opened under the memory budget even after degrading
+    // (e.g. "buffer" when a buffered copy alone is over it)
+    MemoryBudgetExceeded { component: String },
+}
+
+impl std::fmt::Display for SheetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for SheetError {
+    fn from(message: String) -> Self {
+        SheetError { kind: SheetErrorKind::Permanent, message }
+    }
+}
+
+impl From<SheetError> for String {
+    fn from(e: SheetError) -> Self {
+        e.message
+    }
+}
+
+// Decimal mark of the numbers in the file (set_decimal_separator), so the
+// formula guard can tell "-1,5" (a number in many locales) from a formula
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum DecimalSeparator {
+    #[default]
+    Point, // -1.5
+    Comma, // -1,5
+}
+
+// What file exports do, with set_strict_padding on, when a row would be
+// written with padding cells (fields the row doesn't have, e.g. a short row
+// re-encoded because of edits or column reordering, written as empty values)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PaddingExport {
+    Write, // As before
+    Warn,  // Write, counted in SessionInfo.padded_cells_exported
+    Fail,  // The export fails (partial output is left as with other errors)
+}
+
+// Extras for get_grid_chunk_with_options. The defaults give plain get_grid_chunk.
+#[derive(Default)]
+pub struct GridChunkOptions {
+    // Fill RowData.row_number with the 1-based view position, so the UI needs
+    // no row number column of its own.
+    pub include_row_number: bool,
+    // Set CellData.content_kind on every cell. A few byte checks per cell,
+    // cheap enough to leave on.
+    pub detect_links: bool,
+}
+
+// How records end. Auto picks from the file: \r\n if the first line ends
+// that way, else \n; bare \r only when the file has no \n at all (old Mac
+// exports). In \n-based files a stray \r stays part of the cell.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineTerminator {
+    Auto,
+    Lf,
+    Crlf,
+    Cr,
+}
+
+// How cell values are compared by the matching/search style APIs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NormalizeOptions {
+    pub trim_whitespace: bool,
+    pub case_sensitive: bool,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        NormalizeOptions { trim_whitespace: false, case_sensitive: true }
+    }
+}
+
+pub enum CompareMode {
+    Differs, // Any selected column differs from the reference row
+    Matches, // Every selected column equals the reference row
+}
+
+// Rolling aggregate for window_aggregate
+pub enum AggOp {
+    Sum,
+    Mean,
+    Min,
+    Max,
+}
+
+// How one sort key orders a column. "Null" means an empty (or blank) cell;
+// the value comparators always put nulls last, whatever the direction.
+pub enum SortComparator {
+    NumericAscending, // Cells that aren't numbers sort with the nulls
+    NumericDescending,
+    LexicographicAscending, // Honors the session's trim/case options
+    LexicographicDescending,
+    LengthAscending, // In characters
+    LengthDescending,
+    NullsFirst, // Only splits nulls from the rest; later keys order within each group
+    NullsLast,
+}
+
+pub enum SortKey {
+    // Numbers numerically (before any text), otherwise text; nulls last
+    Column { col: i64, descending: bool },
+    Custom { col: i64, comparator: SortComparator },
+}
+
+// One column test for the row filter. Values are compared after the session's
+// trim/case normalization.
+pub enum FilterPredicate {
+    Equals(String),
+    NotEquals(String),
+    Contains(String),
+    IsEmpty,
+    IsNotEmpty,
+    IsNull,    // Empty, or a null token (set_null_tokens)
+    IsNotNull,
+}
+
+// Row filter conditions; apply_filter keeps the rows matching all of them.
+pub enum FilterCondition {
+    Column { col: i64, predicate: FilterPredicate },
+    AnyColumnContains(String), // Quick-filter box: some cell contains the text
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ColumnType {
+    Empty, // No non-empty cells in the sample
+    Integer,
+    Float,
+    Boolean,
+    Text,
+}
+
+// One cell with its own type (see classify_cell). Zero-padded or signed
+// numbers ("007", "+5") stay Text so IDs round-trip.
+#[derive(Clone, PartialEq, Debug)]
+pub enum CellValue {
+    Empty,
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Text(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColumnAlignment {
+    Left,
+    Center,
+    Right,
+}
+
+// Advisory per-column rendering info so Dart doesn't re-infer types per frame.
+#[derive(Clone)]
+pub struct RenderHint {
+    pub col: i64,
+    pub alignment: ColumnAlignment,
+    pub type_tag: ColumnType,
+    pub is_monospace_suggested: bool, // ID-like columns (fixed-length digit strings)
+}
+
+// A whole-column edit for map_column. Results go into the edit overlay.
+pub enum ColumnTransform {
+    Uppercase,
+    Lowercase,
+    TrimWhitespace,
+    TrimPrefix(String),
+    TrimSuffix(String),
+    Replace { from: String, to: String },
+    RegexReplace { pattern: String, replacement: String },
+}
+
+// A cleanup step for apply_column_edit
+pub enum EditOp {
+    Trim,
+    Upper,
+    Lower,
+    RegexReplace { pattern: String, replacement: String },
+    SetIfEmpty(String), // Blank (or whitespace-only) cells get the value
+}
+
+// What apply_lookup_table does with a cell that isn't in the table
+pub enum UnmappedPolicy {
+    LeaveUnchanged,
+    ReplaceWithEmpty,
+    ReplaceWith(String),
+}
+
+// What detect_and_fix_encoding does with bytes that aren't valid UTF-8
+pub enum EncodingFixStrategy {
+    Remove,          // Drop them
+    Replace(String), // One replacement per invalid sequence
+    Latin1Decode,    // Read each invalid byte as a Latin-1 character
+}
+
+// Digest for generate_row_hash_column (hex in the cell)
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+    Md5,
+    Xxhash64, // Seed 0
+}
+
+// One piece of a concatenated derived column
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ConcatPart {
+    Column(i64), // A real column's cell (empty if the row is short)
+    Literal(String),
+}
+
+// One column's declared type. Usually comes from inference, but the user
+// may override inferred_type before applying it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ColumnSchema {
+    pub col: i64,
+    pub name: String,
+    pub inferred_type: ColumnType,
+    pub nullable: bool,
+}
+
+// The export_schema_to_json file. Stable across versions: new fields are
+// only ever added with serde defaults, and anything older readers would
+// misread bumps schema_version (readers refuse newer versions).
+#[derive(Serialize, Deserialize)]
+struct SchemaFile {
+    schema_version: u32,
+    columns: Vec<ColumnSchema>,
+}
+
+const SCHEMA_FILE_VERSION: u32 = 1;
+
+pub struct ApplySchemaReport {
+    pub cells_coerced: u64,
+    pub coercion_errors: Vec<(i64, i64, String)>, // (row, col, message)
+}
+
+// export_to_parquet_partitioned result. Keys are the partition cells as
+// they appear in the sheet (HIVE_DEFAULT_PARTITION for empty ones).
+pub struct PartitionedExportReport {
+    pub partitions_written: usize,
+    pub rows_per_partition: HashMap<String, u64>,
+}
+
+// validate_against_schema result. Each list holds at most
+// MAX_REPORTED_ERRORS entries; truncated says some were left out.
+pub struct ValidationReport {
+    pub type_errors: Vec<TypeValidationError>,
+    pub null_errors: Vec<(i64, i64)>, // (row, col) of empty cells in non-nullable columns
+    pub truncated: bool,
+}
+
+// validate_date_column result (view rows). Each list holds at most
+// MAX_REPORTED_ERRORS entries; truncated says some were left out.
+pub struct DateValidationReport {
+    pub invalid_format_rows: Vec<i64>, // Non-empty cells that don't parse with the format
+    pub out_of_order_rows: Vec<i64>,   // Dates earlier than the previous valid one (require_ascending)
+    pub null_rows: Vec<i64>,           // Blank and null-token cells
+    pub truncated: bool,
+}
+
+pub struct TypeValidationError {
+    pub row: i64,
+    pub col: i64,
+    pub expected_type: ColumnType,
+    pub actual_value: String,
+}
+
+// One rectangle of a multi-range (ctrl-click) selection
+#[derive(Clone, Copy, Debug)]
+pub struct CellRange {
+    pub row_start: i64,
+    pub row_count: i64,
+    pub col_start: i64,
+    pub col_count: i64,
+}
+
+// Text layout for export_ranges (TSV is what spreadsheets paste best)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RangeTextFormat {
+    Csv,
+    Tsv,
+}
+
+// compute_cell_lengths result, in characters (Unicode code points). All
+// zero for an empty view or an out-of-range column.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LengthStats {
+    pub min_len: usize,
+    pub max_len: usize,
+    pub mean_len: f64,
+    pub p50_len: usize,
+    pub p95_len: usize,
+    pub p99_len: usize,
+}
+
+// Status-bar style aggregates for a selected rectangle.
+pub struct RangeStats {
+    pub count: i64,         // Non-empty cells (text included)
+    pub numeric_count: i64, // Cells that parsed as numbers
+    pub sum: f64,
+    pub mean: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    // Set (with everything else zero) when the selection is too big for the
+    // synchronous call; use range_stats_full instead.
+    pub exceeds_sync_limit: bool,
+    pub is_partial: bool,  // The deadline hit first; only rows_scanned rows were read
+    pub rows_scanned: i64, // From row_start on
+}
+
+// What count_matches found. With is_partial, count is a lower bound taken
+// over the first rows_scanned view rows.
+pub struct MatchCount {
+    pub count: i64,
+    pub rows_scanned: i64,
+    pub total_rows: i64,
+    pub is_partial: bool,
+}
+
+// analyze_encoding result. Confidence is the share of non-ASCII lines an
+// encoding explains (1.0 for both when every line is ASCII).
+pub struct EncodingReport {
+    pub scores: Vec<EncodingScore>, // Most likely first
+    pub fallback_lines: i64,        // Lines read as Windows-1252, among those scanned
+    pub lines_scanned: i64,
+    pub is_exact: bool, // Every row was scanned
+}
+
+// Where resolve_anchor found an anchored row. row is None when the row is
+// filtered out or gone from the file; nearest is then the view row closest
+// to where it was (None only for an empty view).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AnchorPosition {
+    pub row: Option<i64>,
+    pub nearest: Option<i64>,
+}
+
+// compare_summary result: is `other` basically the same dataset as self?
+pub struct CompareSummary {
+    pub rows_self: i64,
+    pub rows_other: i64,
+    pub row_count_delta: i64, // rows_other - rows_self
+    pub sampled_rows_self: i64,
+    pub sampled_rows_other: i64,
+    pub matched_by_name: bool, // false: by position (a side has no header names)
+    pub only_in_self: Vec<String>,
+    pub only_in_other: Vec<String>,
+    pub moved: Vec<String>, // Matched by name at a different position
+    pub columns: Vec<ColumnComparison>, // Matched columns, in self's order
+    // Matched columns below COMPARE_DIFFERENT_BELOW similarity
+    pub different_distributions: Vec<String>,
+}
+
+pub struct ColumnComparison {
+    pub name: String, // Self's canonical name
+    pub col_self: i64,
+    pub col_other: i64,
+    // 0..1 overlap of the sampled value distributions: binned histograms
+    // when both sides are all numbers, value frequencies otherwise
+    pub similarity: f64,
+    pub numeric: bool,
+    pub null_ratio_self: f64,
+    pub null_ratio_other: f64,
+}
+
+pub struct EncodingScore {
+    pub encoding: String,
+    pub confidence: f64,
+}
+
+// column_unique_values result; partial results cover the first rows_scanned rows
+pub struct UniqueValues {
+    pub values: Vec<(String, i64)>, // Most frequent first, at most max_values
+    pub distinct_count: i64,
+    pub rows_scanned: i64,
+    pub total_rows: i64,
+    pub is_partial: bool,
+}
+
+// What apply_view hands back: the UI-side layout to restore, plus anything
+// that no longer fits the current file.
+pub struct ViewRestoreReport {
+    pub scroll_anchor_row: i64,
+    pub col_start: i64,
+    pub visible_columns: Vec<i64>,
+    pub problems: Vec<String>,
+}
+
+// How the parser read one row (the "inspect row" popover)
+pub struct RowDiagnostics {
+    pub row: i64,
+    pub source_row: i64,
+    pub raw_byte_len: i64, // Without the terminator
+    pub field_count: i64,
+    pub field_byte_ranges: Vec<(i64, i64)>, // [start, end) in the raw line, per field
+    pub had_quotes: bool,
+    pub had_cr: bool, // Line ended in \r\n; outside CRLF mode the last cell keeps the \r
+    pub had_invalid_utf8: bool,
+    pub is_edited: bool,
+    pub edited_cols: Vec<i64>,
+    pub warnings: Vec<String>,
+}
+
+// What count_rows found, without opening a session
+pub struct RowCount {
+    pub total_rows: i64,      // Same number a session opened with the same options reports
+    pub max_line_length: i64, // Longest row in bytes, terminator excluded
+    pub byte_size: i64,
+}
+
+// One file found by scan_directory, from the first SCAN_SNIFF_BYTES only.
+// With error set the other fields may be empty.
+pub struct FileSummary {
+    pub path: String,        // For display (lossy if the name isn't UTF-8)
+    pub path_bytes: Vec<u8>, // Exact name, as in SessionInfo
+    pub size: i64,
+    pub modified: Option<i64>,   // Milliseconds since the Unix epoch
+    pub quick_row_estimate: i64, // Exact when the whole file fit in the sniff
+    pub detected_delimiter: Option<String>, // None: one column (or nothing to go on)
+    pub first_headers: Vec<String>,
+    pub error: Option<String>,
+}
+
+// Something about the session that changed without the UI asking (see
+// get_changes_since)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChangeKind {
+    ColumnCountRefined, // actual_total_cols grew or shrank; refresh the scroll range
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChangeEvent {
+    pub generation: i64, // 1 for the first change, counting up
+    pub kind: ChangeKind,
+}
+
+// Snapshot of the current long-running operation (exports etc.).
+// Dart polls this while the operation's own future is pending.
+pub struct TaskProgress {
+    pub processed_rows: i64,
+    pub total_rows: i64,
+    pub is_running: bool,
+}
+
+// One strip of the structure minimap (see structure_profile)
+#[derive(Clone)]
+pub struct StructureBucket {
+    pub row_start: i64, // View rows row_start..row_start + row_count
+    pub row_count: i64,
+    pub min_cols: i64,  // Fields per raw line, quote-aware (an empty line has 0)
+    pub max_cols: i64,
+    pub max_line_bytes: i64,
+}
+
+// A suggested fix for a row with the wrong number of fields
+pub struct RepairProposal {
+    pub description: String, // e.g. "Merge fields 2-3" or "Insert 1 empty field at 4"
+    pub cells: Vec<String>,  // The row after the fix, expected_cols cells
+    pub score: i64,          // Cells that fit their column's type; higher is likelier
+}
+
+pub struct RowRepair {
+    pub row: i64,
+    pub source_row: i64,
+    pub original: Vec<String>,
+    pub proposals: Vec<RepairProposal>, // Best first
+}
+
+// A column (or pair of columns) that may identify rows (see detect_key_columns)
+pub struct KeyCandidate {
+    pub col: i64,
+    pub paired_with: Option<i64>, // Second column of a two-column key
+    pub distinct_ratio: f64,      // Distinct non-null values / rows looked at
+    pub null_count: i64,          // Empty or null-token cells (either column, for pairs)
+    pub is_unique_in_sample: bool,
+}
+
+// How find_text matches. WholeToken looks for cells containing every query
+// token (runs of letters and digits, lowercase) and can use the search index.
+pub enum FindMode {
+    WholeToken,
+    Substring, // Case-insensitive
+    Regex,
+}
+
+pub struct SearchIndexInfo {
+    pub cols: Vec<i64>,
+    pub token_count: i64,
+    pub bytes: i64,
+}
+
+// write_column_index_stats output (also written to the file as JSON).
+// "Values" are the index's lowercase tokens, not whole cells.
+#[derive(Serialize)]
+pub struct ColumnIndexStats {
+    pub col: i64,
+    pub distinct_value_count: usize,
+    pub total_indexed_rows: i64, // Rows with at least one token
+    pub index_memory_bytes: usize,
+    pub top_20_values: Vec<(String, usize)>, // By row count, most first
+}
+
+// How build_inverted_index splits cells into tokens
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenizerMode {
+    WhitespaceSplit,
+    CharacterNgram(usize), // Every run of n chars; shorter cells aren't indexed
+}
+
+// One list_query_history entry
+pub struct QueryHistoryEntry {
+    pub id: i64,
+    pub timestamp_ms: i64,  // Unix time the filter was applied
+    pub description: String, // e.g. `status = "open" AND any column contains "x"`
+}
+
+// Null tokens for one column, replacing the sheet-wide list there
+pub struct ColumnNullTokens {
+    pub col: i64,
+    pub tokens: Vec<String>,
+}
+
+// Which rows export_sample_to_file takes
+pub enum SampleStrategy {
+    EveryKth(i64),        // Rows 0, k, 2k, ...
+    Random { seed: u64 }, // Same seed, same rows
+}
+
+// Columns export_sample_to_file scrambles: numbers are jittered by up to
+// +-10%, anything else becomes hex of the same length. Equal inputs give
+// equal outputs (per seed), so duplicates and joins still line up.
+pub struct AnonymizeSpec {
+    pub cols: Vec<i64>,
+    pub seed: u64,
+}
+
+// A column's header as shown (raw) and its unique form (canonical): the
+// first "amount" stays "amount", later ones become "amount_2", "amount_3"...
+// skipping suffixes another column already uses
+pub struct HeaderName {
+    pub raw: String,
+    pub canonical: String,
+}
+
+// Why resolve_column found no single column
+#[derive(Debug, PartialEq)]
+pub enum ColumnLookupError {
+    NotFound,
+    Ambiguous { candidates: Vec<i64> }, // Several columns match ignoring case/whitespace
+}
+
+// Which columns save_to_file_with_columns writes, and in what order
+pub enum ExportColumnMode {
+    VisibleOrder,       // As the grid shows them
+    SourceOrder,        // As the file has them (undoes reorder_columns); derived columns last
+    Explicit(Vec<i64>), // Just these session columns, in this order
+}
+
+// Options for export_to_html
+pub struct HtmlTableStyle {
+    pub include_header: bool, // Column names as a <th> row
+    pub zebra_striping: bool, // class="odd"/"even" on rows (colored when inline_css is set)
+    pub inline_css: bool,     // Embed a small <style> block
+    pub row_numbers: Option<String>, // Name of a leading source-row-number column ("#"), if wanted
+}
+
+// Approximate heap use of one part of a session
+pub struct MemoryUsage {
+    pub component: String,
+    pub bytes: i64,
+}
+
+pub struct SessionInfo {
+    pub path: String, // For display (lossy if the name isn't UTF-8); empty for in-memory sessions
+    pub path_bytes: Vec<u8>, // Exact name: raw bytes on Unix, UTF-16LE on Windows
+    pub total_rows: i64,
+    pub total_cols: i64,
+    pub line_terminator: LineTerminator, // As resolved at open (never Auto)
+    pub memory_budget: Option<i64>,
+    pub memory_used: i64,
+    pub memory: Vec<MemoryUsage>, // Per component; mapped file pages aren't counted
+    pub responses_truncated: i64, // Chunk responses cut short by the size budget
+    pub padded_cells_exported: i64, // Padding cells written under PaddingExport::Warn
+    // Of the first WIDTH_SCAN_ROWS rows, those whose field count
+    // merge_consecutive_delimiters changes (whether or not it is on)
+    pub merge_delimiter_rows: i64,
+    pub backend: StorageBackend, // Mapped or Buffered, as in use now
+    pub backend_reason: String,  // Why, e.g. "on a network filesystem (nfs4)"
+    // Set when save_in_place couldn't load the file back: the session shows
+    // no rows until reopen succeeds
+    pub detached: Option<String>,
+    // actual_total_cols, and whether it is exact (refine_total_cols has run)
+    // rather than estimated from the first WIDTH_SCAN_ROWS rows
+    pub cols_estimate: i64,
+    pub cols_exact: bool,
+    pub generation: i64, // Of the latest ChangeEvent; 0 before any
+}
+
+// One traced operation (see enable_tracing). args holds sizes, counts and
+// paths only, never cell values or search text, so traces can go into bug
+// reports as they are.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    pub operation: String,
+    pub args: String,
+    pub started_at_ms: i64, // Unix time
+    pub duration_micros: i64,
+    pub rows_touched: i64,
+    pub bytes_read: i64, // Raw row bytes the operation read
+    pub cache_hits: i64, // Indexes or cached results used instead of a scan
+}
+
+// Everything the grid needs for one frame, in one call.
+pub struct Viewport {
+    pub headers: Vec<String>,
+    pub rows: Vec<RowData>,
+    pub render_hints: Vec<RenderHint>,
+    pub continuation: Option<ChunkContinuation>, // Same as on the last row
+}
+
+// Quick dataset overview ("File Info" dialog), from one random row sample
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SummaryReport {
+    pub total_rows: i64,
+    pub sampled_rows: i64,
+    pub columns: Vec<ColumnSummary>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ColumnSummary {
+    pub col: i64,
+    pub name: String,
+    pub type_counts: Vec<(ColumnType, i64)>, // Non-empty cells per type
+    pub null_ratio: f64,                     // Empty (or blank) and null-token cells / sampled rows
+    pub null_like: i64,                      // Cells that were null tokens
+    pub unique_count: i64,
+    pub unique_count_capped: bool, // Stopped counting at SUMMARY_UNIQUE_CAP
+    // Only for columns whose non-empty cells are all numbers
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    pub std_dev: Option<f64>, // Sample standard deviation (n - 1)
+    pub top_values: Vec<(String, i64)>, // Most frequent first
+    // Non-empty cells that are URLs / email addresses (content_kind_of), over
+    // all non-empty cells; near 1.0 means the column can render as links
+    pub url_ratio: f64,
+    pub email_ratio: f64,
 }
 
 // ------------------------------------
 // Session Logic (Stays in Rust)
 // ------------------------------------
 
+// The bytes behind a session: a memory-mapped file, or an in-memory buffer
+// for derived sessions (joins, set operations, ...) and repaired encodings.
+enum FileContent {
+    Mapped(Mmap),
+    Buffered(Vec<u8>),
+}
+
+impl std::ops::Deref for FileContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileContent::Mapped(mmap) => mmap,
+            FileContent::Buffered(bytes) => bytes,
+        }
+    }
+}
+
 // This struct holds the file handle and the index.
 // Dart only holds a reference to this.
 pub struct SheetSession {
@@ -28,154 +818,9261 @@ pub struct SheetSession {
     pub total_cols: i64,
     
     // Internal fields hidden from Dart
-    mmap: Arc<Mmap>,
-    row_offsets: Vec<usize>, // The "Cheat Sheet" for where rows start
-}
+    path: std::path::PathBuf,  // Empty for sessions built in memory (joins etc.)
+    content: Arc<FileContent>,
+    row_offsets: Arc<RowIndex>, // The "Cheat Sheet" for where rows start (shared with sub views)
+    // Source rows row_base..row_end make up the session in file order
+    // (row_end moves on truncate; sub views narrow both)
+    row_base: usize,
+    row_end: usize,
+    col_window: Option<(usize, usize)>, // Sub views: (first file column, count)
+    col_order: Option<ColumnOrder>,     // reorder_columns_*: session order of the real columns
+    terminator: LineTerminator,          // Never Auto; writers use it too
 
-impl SheetSession {
-    // 1. OPEN FILE & INDEX IT
-    // This scans the file for newlines (\n) to build an index.
-    pub fn new_from_file(path: String) -> Result<SheetSession, String> {
-        // Try to open the file
-        let file = File::open(&path).map_err(|e| format!("Failed to open file: {}", e))?;
-        
-        // Memory Map the file (treat disk like RAM)
-        // UNSAFE: Standard requirement for mmap. We promise not to modify the file underneath.
-        let mmap = unsafe { 
-            Mmap::map(&file).map_err(|e| format!("Failed to map file: {}", e))? 
-        };
-        let mmap_arc = Arc::new(mmap);
+    // Column count bookkeeping: what the first row says, what the user pinned,
+    // and the ceiling we refuse to render past.
+    detected_cols: i64,
+    base_cols: i64, // Real columns; total_cols adds the derived ones
+    cols_override: Option<i64>,
+    max_cols: i64,
+    warnings: Vec<String>,
+    memory_budget: Option<u64>, // Bytes; None means unlimited
 
-        // Build Line Index
-        // We scan for byte 10 (\n) to mark the start of every row.
-        let mut row_offsets = Vec::new();
-        row_offsets.push(0); // Row 0 starts at the beginning
-        
-        for (i, &byte) in mmap_arc.iter().enumerate() {
-            if byte == b'\n' {
-                row_offsets.push(i + 1);
-            }
-        }
+    // Column names shown instead of letters (real columns only), and where
+    // they came from: write_csv_header (synthetic_header) or the file's first
+    // row (first_row_header; row_base then skips it)
+    header_names: Vec<String>,
+    synthetic_header: bool,
+    first_row_header: bool,
 
-        // Calculations
-        let total_rows = row_offsets.len() as i64;
-        
-        // Estimate Columns from the first row
-        // We look at the first line and count commas.
-        let first_line_end = *row_offsets.get(1).unwrap_or(&mmap_arc.len());
-        let first_line_slice = &mmap_arc[0..first_line_end];
-        
-        // If the file is empty or weird, default to 1 col
-        let total_cols = if total_rows > 0 {
-             byte_count_char(first_line_slice, b',') + 1
-        } else {
-             0
-        };
+    normalize: NormalizeOptions,
 
-        Ok(SheetSession {
-            total_rows,
-            total_cols,
-            mmap: mmap_arc,
-            row_offsets,
-        })
-    }
+    // Logical row -> source row. None means natural file order.
+    row_order: Option<Vec<i64>>,
+    reverse_order: bool, // Newest-first: view row 0 is the last row (on top of row_order)
+    filter_base: Option<Option<Vec<i64>>>, // Row order from before apply_filter (Some while filtered)
 
-    // 2. READ DATA CHUNK
-    // Reads only the specific bytes needed for the requested rows.
-    pub fn get_grid_chunk(
-        &self, 
-        row_start: i64, 
-        row_count: i32, 
-        col_start: i64, 
-        col_count: i32
-    ) -> Vec<RowData> {
-        let mut results = Vec::new();
-        
-        for r in 0..row_count {
-            let current_row_idx = row_start + (r as i64);
-            
-            // Stop if we go past the end of the file
-            if current_row_idx >= self.total_rows { break; }
-            
-            // --- CORE LOGIC: SLICE THE FILE ---
-            let start_byte = self.row_offsets[current_row_idx as usize];
-            
-            // The end byte is the start of the NEXT row, minus 1 (for the \n)
-            let end_byte = if (current_row_idx as usize) + 1 < self.row_offsets.len() {
-                self.row_offsets[(current_row_idx as usize) + 1].saturating_sub(1)
-            } else {
-                self.mmap.len()
-            };
+    // Edit overlay: source row -> (col -> new value). The mmap is never written.
+    edits: HashMap<usize, HashMap<usize, String>>,
 
-            // Safety check for empty lines or bad offsets
-            if start_byte >= end_byte { 
-                 results.push(RowData { index: current_row_idx, cells: vec![] });
-                 continue; 
-            }
+    // Part of the overlay: field count of rows fixed by apply_repair (extra
+    // raw fields past it are dropped, missing ones are edits)
+    repaired_widths: HashMap<usize, usize>,
 
-            // Get the bytes directly from memory map
-            let line_bytes = &self.mmap[start_byte..end_byte];
-            // Convert to string (lossy handles invalid characters without crashing)
-            let line_str = String::from_utf8_lossy(line_bytes);
+    // expected_cols of the last suggest_row_repairs, which apply_repair replays
+    repair_cols: Option<usize>,
 
-            // Split by comma
-            let all_cols: Vec<&str> = line_str.split(',').collect();
+    // Lazily computed per-column analysis (types, render hints)
+    analysis: Mutex<AnalysisCache>,
 
-            // Extract only the visible columns
-            let mut cells = Vec::new();
-            for c in 0..col_count {
-                let target_col = (col_start + (c as i64)) as usize;
-                
-                let content = if target_col < all_cols.len() {
-                    all_cols[target_col].to_string()
-                } else {
-                    String::new() // Padding for short rows
-                };
-                
-                cells.push(CellData { content });
-            }
+    // Progress of the running long operation, readable while it runs
+    task: TaskState,
 
-            results.push(RowData {
-                index: current_row_idx,
-                cells,
-            });
-        }
-        results
-    }
+    // MD5 of the mapped bytes, computed on first request
+    fingerprint: OnceLock<String>,
 
-    // 3. GENERATE HEADERS (A, B, C... AA, AB...)
-    pub fn get_header_chunk(&self, col_start: i64, col_count: i32) -> Vec<String> {
-        let mut headers = Vec::new();
-        for i in 0..col_count {
-            let actual_idx = col_start + (i as i64);
-            if actual_idx >= self.total_cols { break; }
-            headers.push(Self::number_to_col_name(actual_idx));
-        }
-        headers
-    }
+    // Widest of the first WIDTH_SCAN_ROWS raw rows (see actual_total_cols)
+    widest_row: OnceLock<i64>,
 
-    // Helper: 0 -> A, 26 -> AA
-    fn number_to_col_name(mut n: i64) -> String {
-        let mut result = String::new();
-        loop {
-            let remainder = (n % 26) as u8;
-            result.insert(0, (b'A' + remainder) as char);
-            n = n / 26 - 1;
-            if n < 0 { break; }
-        }
-        result
-    }
-}
+    // refine_total_cols: widest row of the whole file once scanned, and
+    // whether a scan is running (get_header_chunk names columns up to
+    // max_cols meanwhile)
+    exact_cols: OnceLock<i64>,
+    refining_cols: AtomicBool,
 
-// ------------------------------------
-// Setup & Utils
-// ------------------------------------
+    // Changes the UI is told about through get_changes_since, oldest first
+    changes: Mutex<Vec<ChangeEvent>>,
 
-fn byte_count_char(slice: &[u8], target: u8) -> i64 {
-    slice.iter().filter(|&&b| b == target).count() as i64
-}
+    // OpenOptions.merge_consecutive_delimiters, and whether exports write the
+    // merged fields (see set_write_merged_delimiters)
+    merge_delimiters: bool,
+    write_merged_delimiters: bool,
 
-#[frb(init)]
-pub fn init_app() {
-    flutter_rust_bridge::setup_default_user_utils();
+    // SessionInfo.merge_delimiter_rows, counted on first request
+    merge_delimiter_rows: OnceLock<i64>,
+
+    // OpenOptions.backend/io_retry (kept for reopen), and why the content is
+    // mapped or buffered (SessionInfo.backend_reason)
+    backend_choice: Option<StorageBackend>,
+    io_retry: Option<IoRetryPolicy>,
+    backend_reason: String,
+
+    // Why the session lost its file (SessionInfo.detached); see detach
+    detached: Option<String>,
+
+    // How many chunk responses the size budget cut short (get_session_info)
+    responses_truncated: AtomicU64,
+
+    // set_strict_padding / set_padding_export, and the Warn count
+    strict_padding: bool,
+    padding_export: PaddingExport,
+    padded_cells_exported: AtomicU64,
+
+    // Named views, persisted by the app through export_state/import_state
+    views: BTreeMap<String, SavedView>,
+
+    // Virtual columns after the real ones, computed per row on read
+    derived: Vec<DerivedColumn>,
+
+    // Cell values that mean "missing" ("\\N", "NULL", ...), see set_null_tokens
+    null_tokens: NullTokens,
+
+    // Recent apply_filter calls, oldest first (capped at QUERY_HISTORY_CAP)
+    query_history: VecDeque<QueryRecord>,
+
+    // build_inverted_index result; dropped when cells or normalization change
+    inverted_index: Option<InvertedIndex>,
+
+    // build_search_index result (whole-token find_text); dropped on edits
+    search_index: Option<SearchIndex>,
+
+    // File exporters defuse spreadsheet formulas (see set_sanitize_formulas)
+    sanitize_formulas: bool,
+    decimal_separator: DecimalSeparator,
+
+    // create_anchor results by id; next_anchor is the next id to hand out
+    anchors: HashMap<i64, Anchor>,
+    next_anchor: i64,
+
+    // tag_row: tag -> tagged source rows, sorted, each once; and a hash of
+    // each tagged row's bytes, so reopen can find it again (as with anchors)
+    row_tags: HashMap<String, Vec<i64>>,
+    tag_digests: HashMap<i64, [u8; 16]>,
+}
+
+// A permutation of the real columns: to_file[session col] = column in the
+// raw line, to_session is its inverse
+#[derive(Clone)]
+struct ColumnOrder {
+    to_file: Vec<usize>,
+    to_session: Vec<usize>,
+}
+
+// Where every row starts. Dense keeps each row's byte offset; sparse (the
+// fallback when that wouldn't fit the memory budget) keeps every stride-th
+// one, and rows in between are found by scanning forward from it.
+struct RowIndex {
+    offsets: Vec<usize>,
+    stride: usize, // 1 when dense
+    rows: usize,
+    byte: u8, // The terminator's index byte
+}
+
+impl RowIndex {
+    // Scan `data` for `byte`, keeping every `stride`-th row start. `rows`,
+    // when the caller already knows it, sizes the vector up front.
+    fn build(data: &[u8], byte: u8, rows: Option<usize>, stride: usize) -> Self {
+        let mut offsets = Vec::with_capacity(rows.map_or(1, |rows| rows.div_ceil(stride)));
+        offsets.push(0); // Row 0 starts at the beginning
+        let mut count = 1;
+        for (i, &b) in data.iter().enumerate() {
+            if b == byte {
+                if count % stride == 0 {
+                    offsets.push(i + 1);
+                }
+                count += 1;
+            }
+        }
+        RowIndex { offsets, stride, rows: count, byte }
+    }
+
+    // A checked prebuilt index (open_with_index), thinned out when sparse
+    fn from_offsets(offsets: Vec<usize>, byte: u8, stride: usize) -> Self {
+        let rows = offsets.len();
+        let offsets = if stride == 1 { offsets } else { offsets.into_iter().step_by(stride).collect() };
+        RowIndex { offsets, stride, rows, byte }
+    }
+
+    fn empty(byte: u8) -> Self {
+        RowIndex { offsets: Vec::new(), stride: 1, rows: 0, byte }
+    }
+
+    fn len(&self) -> usize {
+        self.rows
+    }
+
+    fn bytes(&self) -> usize {
+        self.offsets.capacity() * std::mem::size_of::<usize>()
+    }
+
+    // First byte of `row` (< len)
+    fn start(&self, data: &[u8], row: usize) -> usize {
+        let mut at = self.offsets[row / self.stride];
+        for _ in 0..row % self.stride {
+            at += data[at..].iter().position(|&b| b == self.byte).map_or(data.len() - at, |i| i + 1);
+        }
+        at
+    }
+
+    // Byte range of `row` up to its index byte
+    fn span(&self, data: &[u8], row: usize) -> (usize, usize) {
+        let start = self.start(data, row);
+        let end = if row + 1 >= self.rows {
+            data.len()
+        } else if self.stride == 1 {
+            self.offsets[row + 1].saturating_sub(1)
+        } else {
+            data[start..].iter().position(|&b| b == self.byte).map_or(data.len(), |i| start + i)
+        };
+        (start, end)
+    }
+
+    // Start of every row in order, dense or not (build_index_file)
+    fn starts<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let next = |&at: &usize| data[at..].iter().position(|&b| b == self.byte).map(|i| at + i + 1);
+        std::iter::successors(Some(0), next).take(self.rows)
+    }
+}
+
+// Token -> (source row, col) of every cell containing it, each cell once
+struct InvertedIndex {
+    mode: TokenizerMode,
+    postings: HashMap<String, Vec<(i64, i64)>>,
+    bytes: usize, // Estimate, for the memory budget
+}
+
+// Lowercase token -> source rows with that token in one of `cols`
+struct SearchIndex {
+    cols: Vec<usize>, // Sorted
+    postings: HashMap<String, RowSet>,
+    bytes: usize, // Estimate, for the memory budget
+}
+
+// Sorted row list, or a bitmap once that is smaller (rows / 32 entries)
+enum RowSet {
+    Sparse(Vec<u32>),
+    Dense(Vec<u64>),
+}
+
+impl RowSet {
+    fn compact(rows: Vec<u32>, total_rows: usize) -> Self {
+        if rows.len() <= total_rows / 32 {
+            return RowSet::Sparse(rows);
+        }
+        let mut bits = vec![0u64; total_rows.div_ceil(64)];
+        for row in rows {
+            bits[row as usize / 64] |= 1 << (row % 64);
+        }
+        RowSet::Dense(bits)
+    }
+
+    fn contains(&self, row: usize) -> bool {
+        match self {
+            RowSet::Sparse(rows) => u32::try_from(row).is_ok_and(|row| rows.binary_search(&row).is_ok()),
+            RowSet::Dense(bits) => bits.get(row / 64).is_some_and(|word| word & (1 << (row % 64)) != 0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            RowSet::Sparse(rows) => rows.len(),
+            RowSet::Dense(bits) => bits.iter().map(|word| word.count_ones() as usize).sum(),
+        }
+    }
+
+    // Set this set's rows in `bits` (one bit per source row)
+    fn mark(&self, bits: &mut [u64]) {
+        match self {
+            RowSet::Sparse(rows) => rows.iter().for_each(|&row| bits[row as usize / 64] |= 1 << (row % 64)),
+            RowSet::Dense(words) => bits.iter_mut().zip(words).for_each(|(bit, word)| *bit |= word),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            RowSet::Sparse(rows) => rows.capacity() * std::mem::size_of::<u32>(),
+            RowSet::Dense(bits) => bits.capacity() * std::mem::size_of::<u64>(),
+        }
+    }
+}
+
+// Null tokens, matched exactly against the trimmed cell
+#[derive(Clone, Default)]
+struct NullTokens {
+    default: HashSet<String>,
+    per_col: HashMap<usize, HashSet<String>>, // Replaces `default` for that column
+    export_as: Option<String>,                // What exporters write instead, if set
+}
+
+// A derived column and how to compute it (the serializable part)
+#[derive(Clone, Serialize, Deserialize)]
+struct DerivedColumnState {
+    name: String,
+    spec: DerivedSpec,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+enum DerivedSpec {
+    // Capture group `group_index` of `pattern` applied to the source cell
+    RegexExtract { source_col: i64, pattern: String, group_index: i64 },
+    // Column cells and literal text glued together, e.g. first + " " + last
+    Concat { parts: Vec<ConcatPart> },
+    // Hex digest of the cells joined with NUL bytes (row dedup keys)
+    RowHash { cols: Vec<i64>, algorithm: HashAlgorithm },
+}
+
+#[derive(Clone)]
+struct DerivedColumn {
+    state: DerivedColumnState,
+    regex: Option<Regex>, // Compiled once when the column is added
+}
+
+impl DerivedColumn {
+    fn new(state: DerivedColumnState) -> Result<DerivedColumn, String> {
+        let regex = match &state.spec {
+            DerivedSpec::RegexExtract { pattern, group_index, .. } => {
+                let regex = Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))?;
+                if *group_index < 0 || *group_index as usize >= regex.captures_len() {
+                    return Err(format!("Pattern has no capture group {}", group_index));
+                }
+                Some(regex)
+            }
+            DerivedSpec::Concat { parts } => {
+                if parts.is_empty() {
+                    return Err("Concatenation needs at least one part".to_string());
+                }
+                None
+            }
+            DerivedSpec::RowHash { cols, .. } => {
+                if cols.is_empty() {
+                    return Err("A row hash needs at least one column".to_string());
+                }
+                None
+            }
+        };
+        Ok(DerivedColumn { state, regex })
+    }
+
+    fn source_cols(&self) -> Vec<i64> {
+        match &self.state.spec {
+            DerivedSpec::RegexExtract { source_col, .. } => vec![*source_col],
+            DerivedSpec::Concat { parts } => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ConcatPart::Column(col) => Some(*col),
+                    ConcatPart::Literal(_) => None,
+                })
+                .collect(),
+            DerivedSpec::RowHash { cols, .. } => cols.clone(),
+        }
+    }
+
+    // `fields` are the row's real cells (edits applied). Non-matches give "".
+    fn evaluate(&self, fields: &[String]) -> String {
+        match &self.state.spec {
+            DerivedSpec::RegexExtract { source_col, group_index, .. } => {
+                let source = field_or_empty(fields, *source_col as usize);
+                self.regex
+                    .as_ref()
+                    .and_then(|regex| regex.captures(source))
+                    .and_then(|caps| caps.get(*group_index as usize))
+                    .map(|m| m.as_str().to_string())
+                    .unwrap_or_default()
+            }
+            DerivedSpec::Concat { parts } => parts
+                .iter()
+                .map(|part| match part {
+                    ConcatPart::Column(col) => field_or_empty(fields, *col as usize),
+                    ConcatPart::Literal(text) => text.as_str(),
+                })
+                .collect(),
+            DerivedSpec::RowHash { cols, algorithm } => {
+                let values: Vec<&str> = cols.iter().map(|&col| field_or_empty(fields, col as usize)).collect();
+                let bytes = values.join("\0");
+                match algorithm {
+                    HashAlgorithm::Sha256 => format!("{:x}", sha2::Sha256::digest(bytes.as_bytes())),
+                    HashAlgorithm::Md5 => format!("{:x}", md5::compute(bytes.as_bytes())),
+                    HashAlgorithm::Xxhash64 => format!("{:016x}", xxhash_rust::xxh64::xxh64(bytes.as_bytes(), 0)),
+                }
+            }
+        }
+    }
+}
+
+// Everything a named view restores. Row order stands in for sort/shuffle.
+#[derive(Clone, Serialize, Deserialize)]
+struct SavedView {
+    scroll_anchor_row: i64,
+    col_start: i64,
+    visible_columns: Vec<i64>,
+    row_order: Option<Vec<i64>>,
+    #[serde(default)]
+    reverse_order: bool,
+    normalize: NormalizeOptions,
+}
+
+// export_manifest output
+#[derive(Serialize)]
+struct Manifest {
+    path: Option<String>, // None for in-memory sessions
+    size_bytes: u64,
+    fingerprint: String,
+    delimiter: &'static str,
+    line_terminator: &'static str,
+    encoding: &'static str,
+    row_count: i64,
+    column_count: i64,
+    rows_scanned: i64,
+    types_exact: bool,  // false when inference only saw a sample
+    counts_exact: bool, // false when completeness/sample values only saw a sample
+    columns: Vec<ManifestColumn>,
+}
+
+#[derive(Serialize)]
+struct ManifestColumn {
+    index: i64,
+    name: String,
+    inferred_type: ColumnType,
+    non_empty: i64,
+    empty: i64,                 // Blank or whitespace-only
+    null_like: i64,             // Null tokens (set_null_tokens); not in non_empty
+    sample_values: Vec<String>, // First distinct non-empty values seen
+}
+
+// Versioned so older app builds can refuse state they don't understand
+#[derive(Serialize, Deserialize)]
+struct SessionState {
+    version: u32,
+    views: BTreeMap<String, SavedView>,
+    #[serde(default)]
+    derived_columns: Vec<DerivedColumnState>,
+    #[serde(default)]
+    query_history: Vec<QueryRecord>,
+    #[serde(default)]
+    row_tags: BTreeMap<String, Vec<i64>>,
+}
+
+// A create_anchor row: its row in the file and the MD5 of its raw bytes, so
+// resolve_anchor can tell whether that row still holds it
+struct Anchor {
+    source_row: usize,
+    digest: [u8; 16],
+}
+
+// One apply_filter call, kept for replay_query
+#[derive(Clone, Serialize, Deserialize)]
+struct QueryRecord {
+    id: i64,
+    timestamp_ms: i64,
+    conditions: Vec<RecordedCondition>,
+}
+
+// FilterCondition in storable form. col_name is set when the column had a
+// real name (added header or derived column) and wins on replay.
+#[derive(Clone, Serialize, Deserialize)]
+enum RecordedCondition {
+    Column { col: i64, col_name: Option<String>, predicate: RecordedPredicate },
+    AnyColumnContains(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+enum RecordedPredicate {
+    Equals(String),
+    NotEquals(String),
+    Contains(String),
+    IsEmpty,
+    IsNotEmpty,
+    IsNull,
+    IsNotNull,
+}
+
+const SESSION_STATE_VERSION: u32 = 1;
+
+#[derive(Default)]
+struct TaskState {
+    processed: AtomicI64,
+    total: AtomicI64,
+    running: AtomicBool,
+    cancel_requested: AtomicBool,
+}
+
+// Marks the task finished when dropped, including on early error returns
+struct TaskGuard<'a>(&'a TaskState);
+
+impl TaskGuard<'_> {
+    fn report(&self, processed: i64) {
+        self.0.processed.store(processed, Ordering::Relaxed);
+    }
+
+    // Set by cancel_task; operations that support cancelling poll this
+    fn is_cancelled(&self) -> bool {
+        self.0.cancel_requested.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for TaskGuard<'_> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::Relaxed);
+    }
+}
+
+// A filter with its values normalized, plus the raw-byte needles used to
+// pre-screen AnyColumnContains before any row is split
+struct CompiledFilter {
+    conditions: Vec<FilterCondition>,
+    raw_needles: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct AnalysisCache {
+    profiles: HashMap<usize, ColumnProfile>,
+    read_order: Option<(Vec<i64>, Arc<Vec<usize>>)>, // get_rows_by_index sort permutation
+}
+
+impl SheetSession {
+    // 1. OPEN FILE & INDEX IT
+    // This scans the file for newlines (\n) to build an index.
+    pub fn new_from_file(path: String) -> Result<SheetSession, SheetError> {
+        Self::new_from_file_with_options(path, OpenOptions::default())
+    }
+
+    pub fn new_from_file_with_options(path: String, options: OpenOptions) -> Result<SheetSession, SheetError> {
+        Self::open_path(path.into(), options)
+    }
+
+    // 1b. OPEN BY RAW PATH
+    // A String can't carry every file name: Unix names are arbitrary bytes
+    // (Latin-1 names on old servers) and Windows names are UTF-16 that may
+    // hold unpaired surrogates. These take the name exactly as the OS
+    // reports it; SessionInfo.path_bytes hands it back in the same form.
+    #[cfg(unix)]
+    pub fn new_from_file_bytes(path_bytes: Vec<u8>) -> Result<SheetSession, SheetError> {
+        use std::os::unix::ffi::OsStringExt;
+        Self::open_path(std::ffi::OsString::from_vec(path_bytes).into(), OpenOptions::default())
+    }
+
+    #[cfg(windows)]
+    pub fn new_from_file_wide(path_units: Vec<u16>) -> Result<SheetSession, SheetError> {
+        use std::os::windows::ffi::OsStringExt;
+        Self::open_path(std::ffi::OsString::from_wide(&path_units).into(), OpenOptions::default())
+    }
+
+    // 1c. PREBUILT ROW INDEX
+    // For servers that ship the row index next to the CSV so clients skip the
+    // newline scan. build_index_file writes the index of csv_path (the format
+    // is described at INDEX_MAGIC) with the terminator and
+    // merge_consecutive_delimiters from `options` baked in; the other options
+    // only apply to the build. open_with_index opens the CSV with it after
+    // checking that it fits the file (size, hash of both ends, settings,
+    // offsets); an index that is missing, corrupt, from another format
+    // version or for other bytes is ignored with a warning (get_warnings) and
+    // the file is scanned as new_from_file would.
+    pub fn build_index_file(csv_path: String, idx_path: String, options: OpenOptions) -> Result<(), String> {
+        let session = Self::open_path(csv_path.into(), options)?;
+        let bytes =
+            encode_row_index(&session.content, &session.row_offsets, session.terminator, session.merge_delimiters);
+        std::fs::write(&idx_path, bytes).map_err(|e| format!("Failed to write index file: {}", e))
+    }
+
+    pub fn open_with_index(csv_path: String, idx_path: String) -> Result<SheetSession, SheetError> {
+        let path = std::path::PathBuf::from(csv_path);
+        let (content, reason) = load_file(&path, StorageBackend::Auto, IoRetryPolicy::default())?;
+        let index = std::fs::read(&idx_path)
+            .map_err(|e| format!("can't read it: {}", e))
+            .and_then(|bytes| decode_row_index(&bytes, &content));
+        match index {
+            Ok(index) => {
+                let options = OpenOptions {
+                    line_terminator: Some(index.terminator),
+                    merge_consecutive_delimiters: index.merge,
+                    ..Default::default()
+                };
+                let mut session = Self::from_content(path, content, options, Some(index.row_offsets))?;
+                session.backend_reason = reason;
+                Ok(session)
+            }
+            Err(why) => {
+                let mut session = Self::from_content(path, content, OpenOptions::default(), None)?;
+                session.warnings.push(format!("Index file not used ({}); the file was scanned instead", why));
+                session.backend_reason = reason;
+                Ok(session)
+            }
+        }
+    }
+
+    fn open_path(path: std::path::PathBuf, options: OpenOptions) -> Result<SheetSession, SheetError> {
+        let (backend, io_retry) = (options.backend, options.io_retry);
+        let (content, reason) =
+            load_file(&path, backend.unwrap_or(StorageBackend::Auto), io_retry.unwrap_or_default())?;
+        let mut session = Self::from_content(path, content, options, None)?;
+        session.backend_choice = backend;
+        session.io_retry = io_retry;
+        session.backend_reason = reason;
+        Ok(session)
+    }
+
+    // In-memory session over bytes we built ourselves (join results etc.)
+    fn from_buffer(bytes: Vec<u8>) -> Result<SheetSession, String> {
+        Self::from_content(std::path::PathBuf::new(), FileContent::Buffered(bytes), OpenOptions::default(), None)
+            .map_err(String::from)
+    }
+
+    // In-memory session over \n-terminated records (see append_records). The
+    // last terminator is dropped so we don't index a phantom empty row.
+    fn from_records(mut records: Vec<u8>) -> Result<SheetSession, String> {
+        if records.last() == Some(&b'\n') {
+            records.pop();
+        }
+        Self::from_buffer(records)
+    }
+
+    // `row_offsets`, when given, is a checked prebuilt index (open_with_index)
+    fn from_content(
+        path: std::path::PathBuf,
+        content: FileContent,
+        options: OpenOptions,
+        row_offsets: Option<Vec<usize>>,
+    ) -> Result<SheetSession, SheetError> {
+        let mut trace = OpTrace::start("open", || format!("{}, {} bytes", path.display(), content.len()));
+        if row_offsets.is_some() {
+            trace.cache_hit();
+        }
+        let max_cols = options.max_cols.unwrap_or(DEFAULT_MAX_COLS);
+        if max_cols < 1 {
+            return Err(format!("max_cols must be at least 1, got {}", max_cols).into());
+        }
+        if let Some(cols) = options.total_cols_override {
+            if cols < 0 {
+                return Err(format!("Column override must not be negative, got {}", cols).into());
+            }
+        }
+        let memory_budget = options.memory_budget.map(check_memory_budget).transpose()?;
+
+        let content = Arc::new(content);
+        let mut warnings = Vec::new();
+        let terminator = match options.line_terminator.unwrap_or(LineTerminator::Auto) {
+            LineTerminator::Auto => detect_line_terminator(&content, &mut warnings),
+            explicit => explicit,
+        };
+
+        // Decide before allocating the index rather than after (and, having
+        // counted anyway, allocate it at its final size): a dense index over
+        // the budget becomes a sparse one; what can't shrink is refused
+        let mut counted_rows = row_offsets.as_ref().map(Vec::len);
+        let mut stride = 1;
+        if let Some(budget) = memory_budget {
+            let rows = counted_rows
+                .unwrap_or_else(|| 1 + content.iter().filter(|&&b| b == terminator.index_byte()).count());
+            counted_rows = Some(rows);
+            let buffered = match &*content {
+                FileContent::Buffered(bytes) => bytes.len(),
+                FileContent::Mapped(_) => 0,
+            };
+            let index_bytes = |stride: usize| (rows.div_ceil(stride) * std::mem::size_of::<usize>()) as u64;
+            let refuse = |component: &str, needed: u64| SheetError {
+                kind: SheetErrorKind::MemoryBudgetExceeded { component: component.to_string() },
+                message: format!(
+                    "Memory budget exceeded: {} needs {} bytes for {} rows, budget is {}",
+                    component, needed, rows, budget
+                ),
+            };
+            if buffered as u64 > budget {
+                return Err(refuse("buffer", buffered as u64));
+            }
+            if index_bytes(1) + buffered as u64 > budget {
+                stride = SPARSE_INDEX_STRIDE;
+                if index_bytes(stride) + buffered as u64 > budget {
+                    return Err(refuse("row_index", index_bytes(stride) + buffered as u64));
+                }
+                warnings.push(format!(
+                    "A full row index ({} bytes) would exceed the memory budget; every {}th row is indexed \
+                     instead, which makes reading rows slower",
+                    index_bytes(1), stride
+                ));
+            }
+        }
+
+        let row_offsets = match row_offsets {
+            Some(offsets) => RowIndex::from_offsets(offsets, terminator.index_byte(), stride),
+            None => RowIndex::build(&content, terminator.index_byte(), counted_rows, stride),
+        };
+
+        // Calculations
+        let total_rows = row_offsets.len() as i64;
+        let merge = options.merge_consecutive_delimiters;
+        let detected_cols = detect_total_cols(&content, &row_offsets, merge);
+        let backend_reason = match &*content {
+            FileContent::Mapped(_) => "memory-mapped",
+            FileContent::Buffered(_) => "in-memory session",
+        };
+
+        let mut session = SheetSession {
+            total_rows,
+            total_cols: 0,
+            path,
+            content,
+            row_end: row_offsets.len(),
+            row_offsets: Arc::new(row_offsets),
+            row_base: 0,
+            col_window: None,
+            col_order: None,
+            terminator,
+            detected_cols,
+            base_cols: 0,
+            cols_override: options.total_cols_override,
+            max_cols,
+            warnings,
+            memory_budget,
+            header_names: Vec::new(),
+            synthetic_header: false,
+            first_row_header: false,
+            normalize: NormalizeOptions::default(),
+            row_order: None,
+            reverse_order: false,
+            filter_base: None,
+            edits: HashMap::new(),
+            repaired_widths: HashMap::new(),
+            repair_cols: None,
+            analysis: Mutex::new(AnalysisCache::default()),
+            task: TaskState::default(),
+            fingerprint: OnceLock::new(),
+            widest_row: OnceLock::new(),
+            exact_cols: OnceLock::new(),
+            refining_cols: AtomicBool::new(false),
+            changes: Mutex::new(Vec::new()),
+            merge_delimiters: merge,
+            write_merged_delimiters: true,
+            merge_delimiter_rows: OnceLock::new(),
+            backend_choice: options.backend,
+            io_retry: options.io_retry,
+            backend_reason: backend_reason.to_string(),
+            detached: None,
+            responses_truncated: AtomicU64::new(0),
+            strict_padding: false,
+            padding_export: PaddingExport::Write,
+            padded_cells_exported: AtomicU64::new(0),
+            views: BTreeMap::new(),
+            derived: Vec::new(),
+            null_tokens: NullTokens::default(),
+            query_history: VecDeque::new(),
+            inverted_index: None,
+            search_index: None,
+            sanitize_formulas: false,
+            decimal_separator: DecimalSeparator::Point,
+            anchors: HashMap::new(),
+            next_anchor: 1,
+            row_tags: HashMap::new(),
+            tag_digests: HashMap::new(),
+        };
+        session.resolve_total_cols();
+        if options.first_row_header {
+            session.take_header_row();
+        }
+        trace.touched(session.total_rows, || session.content.len() as i64);
+        Ok(session)
+    }
+
+    // Helper: OpenOptions.first_row_header. Row 0's cells name the real
+    // columns (blank or missing ones keep their letter) and the rows start
+    // after it.
+    fn take_header_row(&mut self) {
+        let fields = self.source_fields(0);
+        self.header_names = (0..self.base_cols)
+            .map(|col| match fields.get(col as usize) {
+                Some(name) if !name.trim().is_empty() => name.clone(),
+                _ => Self::number_to_col_name(col),
+            })
+            .collect();
+        self.first_row_header = true;
+        self.row_base = 1.min(self.row_end);
+        self.total_rows = self.natural_rows() as i64;
+    }
+
+    // Re-map the file from disk (e.g. after it changed) and rebuild the index.
+    // Column settings, normalization, views, derived columns, null tokens,
+    // query history and row tags carry over (tags move with their rows, see
+    // ROW TAGS); row order, edits and cached results (fingerprint included)
+    // are dropped since rows may have moved.
+    pub fn reopen(&mut self) -> Result<(), SheetError> {
+        if self.path.as_os_str().is_empty() {
+            return Err("In-memory session has no file to reopen".to_string().into());
+        }
+        if self.col_window.is_some() {
+            return Err("A sub view can't be reopened; reopen its parent and take a new sub view".to_string().into());
+        }
+        let options = OpenOptions {
+            total_cols_override: self.cols_override,
+            max_cols: Some(self.max_cols),
+            memory_budget: self.memory_budget.map(|b| b as i64),
+            line_terminator: Some(self.terminator),
+            merge_consecutive_delimiters: self.merge_delimiters,
+            backend: self.backend_choice,
+            io_retry: self.io_retry,
+            first_row_header: self.first_row_header,
+        };
+        let mut fresh = Self::open_path(self.path.clone(), options)?;
+        if self.col_order.is_some() && fresh.base_cols != self.base_cols {
+            return Err("The column count changed; call reset_column_order first".to_string().into());
+        }
+        // A header read from row 0 is re-read with the file (in file order,
+        // so the column order is applied to it); added names carry over
+        if fresh.first_row_header {
+            if let Some(order) = &self.col_order {
+                let names = &fresh.header_names;
+                fresh.header_names = order.to_file.iter().filter_map(|&file| names.get(file).cloned()).collect();
+            }
+        } else {
+            fresh.header_names = std::mem::take(&mut self.header_names);
+        }
+        fresh.col_order = self.col_order.take();
+        fresh.synthetic_header = self.synthetic_header;
+        fresh.normalize = std::mem::take(&mut self.normalize);
+        fresh.reverse_order = self.reverse_order;
+        fresh.views = std::mem::take(&mut self.views);
+        fresh.derived = std::mem::take(&mut self.derived);
+        fresh.null_tokens = std::mem::take(&mut self.null_tokens);
+        fresh.query_history = std::mem::take(&mut self.query_history);
+        fresh.sanitize_formulas = self.sanitize_formulas;
+        fresh.decimal_separator = self.decimal_separator;
+        fresh.write_merged_delimiters = self.write_merged_delimiters;
+        fresh.strict_padding = self.strict_padding;
+        fresh.padding_export = self.padding_export;
+        fresh.anchors = std::mem::take(&mut self.anchors);
+        self.reanchor_tags(&mut fresh);
+        fresh.changes = Mutex::new(std::mem::take(&mut self.changes.lock().unwrap()));
+        fresh.next_anchor = self.next_anchor;
+        fresh.derived_columns_changed();
+        *self = fresh;
+        Ok(())
+    }
+
+    // Pin the column count manually (e.g. when row 0 is corrupt).
+    // Passing None clears the override and re-runs detection.
+    pub fn set_total_cols_override(&mut self, cols: Option<i64>) -> Result<(), String> {
+        match cols {
+            Some(c) if c < 0 => {
+                return Err(format!("Column override must not be negative, got {}", c));
+            }
+            Some(_) => {}
+            None => self.detected_cols = self.detect_cols(),
+        }
+        self.cols_override = cols;
+        self.resolve_total_cols();
+        Ok(())
+    }
+
+    // Anything odd we noticed while opening/indexing (e.g. clamped columns).
+    pub fn get_warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+
+    // The override always wins; otherwise use the detected count, clamped to
+    // max_cols so a bad first line can't make the UI render 10,000 columns.
+    fn resolve_total_cols(&mut self) {
+        self.base_cols = match self.cols_override {
+            Some(cols) => cols,
+            None if self.detected_cols > self.max_cols => {
+                // Said once, however often this runs (e.g. an override cleared)
+                let warning = format!(
+                    "Detected {} columns in the first row; clamped to max_cols ({})",
+                    self.detected_cols, self.max_cols
+                );
+                if !self.warnings.contains(&warning) {
+                    self.warnings.push(warning);
+                }
+                self.max_cols
+            }
+            None => self.detected_cols,
+        };
+        self.total_cols = self.base_cols + self.derived.len() as i64;
+    }
+
+    // 2. READ DATA CHUNK
+    // Reads only the specific bytes needed for the requested rows. Responses
+    // are capped at the response size budget (set_response_size_budget):
+    // past it the chunk ends early, and the last row's continuation says
+    // which cell to fetch from next. The first cell is always sent, so
+    // every call makes progress.
+    pub fn get_grid_chunk(
+        &self, 
+        row_start: i64, 
+        row_count: i32, 
+        col_start: i64, 
+        col_count: i32
+    ) -> Vec<RowData> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::TRACE, "get_grid_chunk", row_start, row_count, col_start, col_count)
+            .entered();
+        let mut trace = OpTrace::start("get_grid_chunk", || {
+            format!("rows {}+{}, cols {}+{}", row_start, row_count, col_start, col_count)
+        });
+        let rows = self.grid_rows(row_start, row_count, col_start, col_count, &mut ResponseBudget::current());
+        trace.touched(rows.len() as i64, || {
+            rows.iter().map(|row| self.row_bytes(row.source_row as usize).len() as i64).sum()
+        });
+        rows
+    }
+
+    // get_grid_chunk with the extras in `opts`
+    pub fn get_grid_chunk_with_options(
+        &self,
+        row_start: i64,
+        row_count: i32,
+        col_start: i64,
+        col_count: i32,
+        opts: GridChunkOptions,
+    ) -> Vec<RowData> {
+        let mut rows = self.get_grid_chunk(row_start, row_count, col_start, col_count);
+        if opts.include_row_number {
+            for row in &mut rows {
+                row.row_number = Some(row.index + 1);
+            }
+        }
+        if opts.detect_links {
+            for cell in rows.iter_mut().flat_map(|row| row.cells.iter_mut()) {
+                cell.content_kind = content_kind_of(&cell.content);
+            }
+        }
+        rows
+    }
+
+    // get_grid_chunk without the size budget, for callers that bound the
+    // output themselves (the C API's caller buffer)
+    #[cfg(feature = "c-ffi")]
+    pub(crate) fn get_grid_chunk_unbounded(
+        &self,
+        row_start: i64,
+        row_count: i32,
+        col_start: i64,
+        col_count: i32,
+    ) -> Vec<RowData> {
+        self.grid_rows(row_start, row_count, col_start, col_count, &mut ResponseBudget::unlimited())
+    }
+
+    fn grid_rows(
+        &self,
+        row_start: i64,
+        row_count: i32,
+        col_start: i64,
+        col_count: i32,
+        budget: &mut ResponseBudget,
+    ) -> Vec<RowData> {
+        let rows_left = (self.total_rows - row_start.max(0)).max(0);
+        let mut results = Vec::with_capacity((row_count.max(0) as i64).min(rows_left) as usize);
+        
+        for r in 0..row_count {
+            let current_row_idx = row_start + (r as i64);
+            
+            // Stop if we go past the end of the file
+            if current_row_idx >= self.total_rows { break; }
+            
+            let Some(row) = self.build_row_within(current_row_idx, col_start, col_count, budget) else {
+                self.mark_truncated(&mut results, current_row_idx, col_start);
+                break;
+            };
+            let cut_short = row.continuation.is_some();
+            results.push(row);
+            trace_event!(row = current_row_idx, "row appended");
+            if cut_short {
+                self.responses_truncated.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+        }
+        results
+    }
+
+    // Helper: the budget ran out before `row` started; point the last row
+    // sent at it
+    fn mark_truncated(&self, results: &mut [RowData], row: i64, col_start: i64) {
+        if let Some(last) = results.last_mut() {
+            last.continuation = Some(ChunkContinuation { next_row: row, next_col: col_start.max(0) });
+            self.responses_truncated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // 2b. READ AN ARBITRARY SET OF ROWS, IN THE ORDER GIVEN
+    // Rows are read in file order (friendlier to the mmap / page cache) and then
+    // handed back in the requested order. Out-of-range indices are skipped.
+    // Size-capped like get_grid_chunk: the rows come back as a prefix of the
+    // (valid) indices, and the continuation's next_row is the index to
+    // resume from.
+    pub fn get_rows_by_index(&self, indices: Vec<i64>, col_start: i64, col_count: i32) -> Vec<RowData> {
+        let indices: Vec<i64> = indices
+            .into_iter()
+            .filter(|&i| i >= 0 && i < self.total_rows)
+            .collect();
+        if indices.is_empty() {
+            return Vec::new();
+        }
+
+        // The first requested row goes first so it always fits
+        let mut budget = ResponseBudget::current();
+        let mut slots: Vec<Option<RowData>> = (0..indices.len()).map(|_| None).collect();
+        slots[0] = self.build_row_within(indices[0], col_start, col_count, &mut budget);
+        let read_order = self.read_order_for(&indices);
+        for &pos in read_order.iter().filter(|&&pos| pos != 0) {
+            if budget.is_spent() {
+                break;
+            }
+            slots[pos] = self.build_row_within(indices[pos], col_start, col_count, &mut budget);
+        }
+
+        // Everything up to the first row that's missing or cut short
+        let mut results = Vec::with_capacity(indices.len());
+        for (slot, &index) in slots.into_iter().zip(&indices) {
+            let Some(row) = slot else {
+                self.mark_truncated(&mut results, index, col_start);
+                break;
+            };
+            let cut_short = row.continuation.is_some();
+            results.push(row);
+            if cut_short {
+                self.responses_truncated.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+        }
+        results
+    }
+
+    // 2c. SPARSE READ: rows row_start..row_end (exclusive) that have at least
+    // one non-empty cell in the column window. Cells are checked after
+    // decoding, so with trim_whitespace on, whitespace-only cells count as
+    // empty too. RowData::index says where each row sits in the view.
+    pub fn get_sparse_rows(&self, row_start: i64, row_end: i64, col_start: i64, col_count: i32) -> Vec<RowData> {
+        (row_start.max(0)..row_end.min(self.total_rows))
+            .map(|row| self.build_row(row, col_start, col_count))
+            .filter(|row| row.cells.iter().any(|cell| !self.normalize_cell(&cell.content).is_empty()))
+            .collect()
+    }
+
+    // 2d. CONTEXT AROUND A MATCH (search result preview)
+    // The rows row-row_radius..=row+row_radius and the same span of columns
+    // around col, clamped to the session. None if (row, col) is out of range.
+    pub fn get_cell_context(&self, row: i64, col: i64, row_radius: usize, col_radius: usize) -> Option<Vec<RowData>> {
+        if !(0..self.total_rows).contains(&row) || !(0..self.total_cols).contains(&col) {
+            return None;
+        }
+        let span = |center: i64, radius: usize, total: i64| {
+            let radius = i64::try_from(radius).unwrap_or(i64::MAX);
+            (center.saturating_sub(radius).max(0), center.saturating_add(radius).min(total - 1))
+        };
+        let (first_row, last_row) = span(row, row_radius, self.total_rows);
+        let (first_col, last_col) = span(col, col_radius, self.total_cols);
+        let col_count = (last_col - first_col + 1).min(i32::MAX as i64) as i32;
+        Some((first_row..=last_row).map(|r| self.build_row(r, first_col, col_count)).collect())
+    }
+
+    // Helper: decode one logical row into the visible column window
+    fn build_row(&self, row: i64, col_start: i64, col_count: i32) -> RowData {
+        let row_data = self.build_row_within(row, col_start, col_count, &mut ResponseBudget::unlimited());
+        row_data.expect("an unlimited budget always fits")
+    }
+
+    // Helper: build_row charging `budget` per cell. Stops at the first cell
+    // that doesn't fit (continuation set); None if not even the first did.
+    fn build_row_within(
+        &self,
+        row: i64,
+        col_start: i64,
+        col_count: i32,
+        budget: &mut ResponseBudget,
+    ) -> Option<RowData> {
+        // --- CORE LOGIC: SLICE THE FILE ---
+        // Get the bytes directly from memory map
+        let source_row = self.source_row(row);
+        let line_bytes = self.row_bytes(source_row);
+        let row_edits = self.edits.get(&source_row);
+        let width = self.repaired_widths.get(&source_row);
+        trace_event!(source_row, bytes = line_bytes.len(), "row sliced");
+
+        // Safety check for empty lines or bad offsets
+        if line_bytes.is_empty() && row_edits.is_none() { 
+             return budget.take(ROW_WIRE_OVERHEAD).then(|| RowData {
+                 index: row,
+                 source_row: source_row as i64,
+                 cells: vec![],
+                 decoded_with_fallback: false,
+                 continuation: None,
+                 row_number: None,
+             });
+        }
+
+        // Convert to string (UTF-8, or Windows-1252 for lines that aren't)
+        let line_str = decode_line(line_bytes);
+        let decoded_with_fallback = matches!(line_str, Cow::Owned(_));
+        trace_event!(source_row, fallback = decoded_with_fallback, "row decoded");
+
+        // Split by comma (quote-aware)
+        let all_cols = self.split_line(&line_str);
+
+        // Extract only the visible columns (sized up front: one allocation per row)
+        let mut cells = Vec::with_capacity(self.row_capacity(col_start, col_count));
+        let mut derived_input: Option<Vec<String>> = None;
+        let mut continuation = None;
+        for c in 0..col_count {
+            let target_col = col_start + (c as i64);
+            // Pad/trim to total_cols (which follows the override), not to the raw line
+            if target_col >= self.total_cols { break; }
+            let target_col = target_col as usize;
+            
+            let mut is_padding = false;
+            let content: Cow<str> = if target_col >= self.base_cols as usize {
+                // Derived column: only computed when it's actually on screen
+                let fields = derived_input.get_or_insert_with(|| self.source_fields(source_row));
+                Cow::Owned(self.derived[target_col - self.base_cols as usize].evaluate(fields))
+            } else if let Some(edited) = row_edits.and_then(|e| e.get(&target_col)) {
+                Cow::Borrowed(edited)
+            } else if let Some(cell) = self
+                .file_col(target_col)
+                .filter(|_| width.is_none_or(|&w| target_col < w))
+                .and_then(|c| all_cols.get(c))
+            {
+                Cow::Borrowed(cell)
+            } else {
+                is_padding = self.strict_padding;
+                Cow::Borrowed("") // Padding for short rows
+            };
+
+            // Charged before the copy, so an oversized cell is never duplicated
+            let row_cost = if cells.is_empty() { ROW_WIRE_OVERHEAD } else { 0 };
+            if !budget.take(row_cost + CELL_WIRE_OVERHEAD + content.len()) {
+                if cells.is_empty() {
+                    return None;
+                }
+                continuation = Some(ChunkContinuation { next_row: row, next_col: target_col as i64 });
+                break;
+            }
+            
+            let is_null = self.is_null_token(target_col, &content);
+            let content = content.into_owned();
+            cells.push(CellData { content, is_null, is_padding, content_kind: ContentKind::Plain });
+        }
+        if cells.is_empty() && !budget.take(ROW_WIRE_OVERHEAD) {
+            return None;
+        }
+
+        Some(RowData {
+            index: row,
+            source_row: source_row as i64,
+            cells,
+            decoded_with_fallback,
+            continuation,
+            row_number: None,
+        })
+    }
+
+    // Helper: how many cells build_row will produce for this column window
+    fn row_capacity(&self, col_start: i64, col_count: i32) -> usize {
+        let cols_left = (self.total_cols - col_start.max(0)).max(0);
+        (col_count.max(0) as i64).min(cols_left) as usize
+    }
+
+    // Helper: positions of `indices` sorted by source row. The last one is
+    // cached since the grid tends to re-request the same index set while scrolling.
+    fn read_order_for(&self, indices: &[i64]) -> Arc<Vec<usize>> {
+        let mut cache = self.analysis.lock().unwrap();
+        if let Some((key, order)) = &cache.read_order {
+            if key.as_slice() == indices {
+                return order.clone();
+            }
+        }
+
+        let mut order: Vec<usize> = (0..indices.len()).collect();
+        order.sort_by_key(|&pos| self.source_row(indices[pos]));
+        let order = Arc::new(order);
+
+        // Over budget: drop the old entry and just don't cache this one
+        cache.read_order = None;
+        let entry_bytes = (indices.len() * 2 * std::mem::size_of::<usize>()) as u64;
+        if self.budget_left(&cache).is_none_or(|left| entry_bytes <= left) {
+            cache.read_order = Some((indices.to_vec(), order.clone()));
+        }
+        order
+    }
+
+    // 2e. SCROLLBAR POSITIONS
+    // 0.0 is the first row of the view and 1.0 the last; NaN counts as 0.0.
+    // The row index is always complete once the session exists (it's built
+    // when the file is opened), so this is plain arithmetic and is_exact is
+    // always true. fraction_for_row is the inverse, for restoring positions.
+    pub fn row_at_fraction(&self, fraction: f64) -> RowLocation {
+        let last = (self.total_rows - 1).max(0);
+        let fraction = if fraction.is_nan() { 0.0 } else { fraction.clamp(0.0, 1.0) };
+        RowLocation { row: (fraction * last as f64).round() as i64, is_exact: true }
+    }
+
+    pub fn fraction_for_row(&self, row: i64) -> f64 {
+        let last = self.total_rows - 1;
+        if last <= 0 {
+            return 0.0;
+        }
+        row.clamp(0, last) as f64 / last as f64
+    }
+
+    // 3. GENERATE HEADERS (A, B, C... AA, AB...)
+    // Goes up to actual_total_cols, so ragged files get a header for every
+    // column their early rows use even though total_cols follows row 0.
+    // While refine_total_cols runs, columns up to max_cols get generated
+    // names, so a UI that widens early isn't cut off. A negative col_start
+    // or a col_count <= 0 is an empty range, and a sheet without columns has
+    // no headers: all return an empty vec.
+    pub fn get_header_chunk(&self, col_start: i64, col_count: i32) -> Vec<String> {
+        if col_start < 0 || col_count <= 0 || self.total_cols == 0 {
+            return vec![];
+        }
+        let width = match self.refining_cols.load(Ordering::Relaxed) {
+            true => self.actual_total_cols().max(self.max_cols),
+            false => self.actual_total_cols(),
+        };
+        let mut headers = Vec::new();
+        for i in 0..col_count {
+            let actual_idx = col_start + (i as i64);
+            if actual_idx >= width { break; }
+            if (self.base_cols..self.total_cols).contains(&actual_idx) {
+                headers.push(self.derived[(actual_idx - self.base_cols) as usize].state.name.clone());
+            } else if let Some(name) = self.header_names.get(actual_idx as usize) {
+                headers.push(name.clone());
+            } else {
+                headers.push(Self::number_to_col_name(actual_idx));
+            }
+        }
+        headers
+    }
+
+    // total_cols, or the field count of the widest of the first
+    // WIDTH_SCAN_ROWS rows in the file if that is more (the whole file once
+    // refine_total_cols has run). Scanned once and cached;
+    // set_total_cols_override widens the grid to match. With derived
+    // columns (which sit right after total_cols) or in a sub view this is
+    // just total_cols.
+    pub fn actual_total_cols(&self) -> i64 {
+        if !self.derived.is_empty() || self.col_window.is_some() {
+            return self.total_cols;
+        }
+        let widest = *self.widest_row.get_or_init(|| {
+            let rows = self.row_base..self.row_end.min(self.row_base + WIDTH_SCAN_ROWS);
+            rows.map(|row| count_fields(self.row_bytes(row), self.merge_delimiters)).max().unwrap_or(0)
+        });
+        self.total_cols.max(widest).max(self.exact_cols.get().copied().unwrap_or(0))
+    }
+
+    // Scans every row for the widest one (capped at max_cols) so
+    // actual_total_cols is exact rather than estimated. Meant to run in the
+    // background right after opening: progress shows in get_task_progress,
+    // cancel_task stops it, and reads go on meanwhile. If the width changes,
+    // a ColumnCountRefined change is recorded. Returns the new
+    // actual_total_cols; later calls return at once.
+    pub fn refine_total_cols(&self) -> Result<i64, String> {
+        if self.exact_cols.get().is_some() || !self.derived.is_empty() || self.col_window.is_some() {
+            return Ok(self.actual_total_cols());
+        }
+        let estimate = self.actual_total_cols();
+        self.refining_cols.store(true, Ordering::Relaxed);
+        let task = self.begin_task((self.row_end - self.row_base) as i64);
+        let mut widest = 0;
+        for (done, row) in (self.row_base..self.row_end).enumerate() {
+            if task.is_cancelled() {
+                self.refining_cols.store(false, Ordering::Relaxed);
+                return Err("Column count refinement was cancelled".to_string());
+            }
+            widest = widest.max(count_fields(self.row_bytes(row), self.merge_delimiters));
+            task.report(done as i64 + 1);
+        }
+        let _ = self.exact_cols.set(widest.min(self.max_cols));
+        self.refining_cols.store(false, Ordering::Relaxed);
+
+        let refined = self.actual_total_cols();
+        if refined != estimate {
+            let mut changes = self.changes.lock().unwrap();
+            let generation = changes.len() as i64 + 1;
+            changes.push(ChangeEvent { generation, kind: ChangeKind::ColumnCountRefined });
+        }
+        Ok(refined)
+    }
+
+    // Changes recorded after `generation` (0 for all), oldest first
+    pub fn get_changes_since(&self, generation: i64) -> Vec<ChangeEvent> {
+        let changes = self.changes.lock().unwrap();
+        changes.iter().filter(|change| change.generation > generation).copied().collect()
+    }
+
+    // 3b. BATCHED FRAME: headers + rows + render hints in one round trip
+    pub fn get_viewport(
+        &self,
+        row_start: i64,
+        row_count: i32,
+        col_start: i64,
+        col_count: i32
+    ) -> Viewport {
+        let mut viewport = Viewport {
+            headers: self.get_header_chunk(col_start, col_count),
+            rows: self.get_grid_chunk(row_start, row_count, col_start, col_count),
+            render_hints: self.get_column_render_hints(col_start, col_count),
+            continuation: None,
+        };
+        viewport.continuation = viewport.rows.last().and_then(|row| row.continuation);
+        viewport
+    }
+
+    // 4. TRUNCATE TO THE FIRST N ROWS
+    // Logical only: the mmap is untouched, we just forget the tail of the index.
+    // Handy for "take a sample of the first 10,000 rows".
+    pub fn truncate_to_rows(&mut self, n: i64) -> Result<(), String> {
+        if n < 0 {
+            return Err(format!("Row count must not be negative, got {}", n));
+        }
+        if n > self.total_rows {
+            return Err(format!(
+                "Cannot truncate to {} rows: session only has {}", n, self.total_rows
+            ));
+        }
+
+        let n = n as usize;
+        if let Some(order) = &mut self.row_order {
+            // Reordered (e.g. shuffled): keep the first n rows of the current order
+            order.truncate(n);
+        } else {
+            self.row_end = self.row_base + n;
+        }
+        self.total_rows = n as i64;
+        self.inverted_index = None;
+        self.search_index = None;
+        self.invalidate_analysis();
+        Ok(())
+    }
+
+    // 5. SAVE (rows currently in the session, in session order, raw bytes, \n separated)
+    pub fn save_to_file(&self, path: String) -> Result<(), String> {
+        self.write_csv_file(&path, None)
+    }
+
+    // save_to_file with a first column holding each row's 1-based line number
+    // in the source file (not its view position, so it survives sort and
+    // filter). An added header row names that column column_name.
+    pub fn save_to_file_with_row_numbers(&self, path: String, column_name: String) -> Result<(), String> {
+        self.write_csv_file(&path, Some(&column_name))
+    }
+
+    // save_to_file with a chosen column order (see ExportColumnMode). The
+    // header row, when include_header is set, lists the column names (renames
+    // included) in the same order; cells go through the same null token and
+    // formula handling as save_to_file. Explicit columns are checked before
+    // the file is created.
+    pub fn save_to_file_with_columns(
+        &self,
+        path: String,
+        mode: ExportColumnMode,
+        include_header: bool,
+    ) -> Result<(), String> {
+        let mut trace = OpTrace::start("save_to_file_with_columns", || path.clone());
+        let cols = self.export_cols(mode)?;
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        let file = File::create(&path).map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+        let pick = |fields: &[String]| -> Vec<String> {
+            cols.iter().map(|&c| fields.get(c).cloned().unwrap_or_default()).collect()
+        };
+
+        let terminator = self.terminator.as_bytes();
+        if include_header {
+            let all_cols = self.total_cols.min(i32::MAX as i64) as i32;
+            write_csv_row(&mut writer, &pick(&self.get_header_chunk(0, all_cols)))?;
+            if self.total_rows > 0 {
+                writer.write_all(terminator).map_err(write_err)?;
+            }
+        }
+        for (row, mut fields) in self.view_rows(0..self.total_rows) {
+            if row > 0 {
+                writer.write_all(terminator).map_err(write_err)?;
+            }
+            if fields.is_empty() {
+                continue; // Blank line stays blank
+            }
+            self.check_padding_export(row, cols.iter().copied())?;
+            self.export_nulls(&mut fields);
+            if self.sanitize_formulas {
+                sanitize_formulas(&mut fields, self.decimal_separator);
+            }
+            write_csv_row(&mut writer, &pick(&fields))?;
+        }
+        writer.flush().map_err(write_err)
+    }
+
+    // Helper: session columns to export, in order, for one ExportColumnMode
+    fn export_cols(&self, mode: ExportColumnMode) -> Result<Vec<usize>, String> {
+        let all = self.total_cols.max(0) as usize;
+        match mode {
+            ExportColumnMode::VisibleOrder => Ok((0..all).collect()),
+            ExportColumnMode::SourceOrder => {
+                let mut cols: Vec<usize> = match &self.col_order {
+                    Some(order) => order.to_session.clone(),
+                    None => (0..self.base_cols.max(0) as usize).collect(),
+                };
+                cols.extend(self.base_cols.max(0) as usize..all);
+                Ok(cols)
+            }
+            ExportColumnMode::Explicit(cols) => cols
+                .into_iter()
+                .map(|c| {
+                    if c < 0 || c >= self.total_cols {
+                        Err(format!("Column {} out of range (0..{})", c, self.total_cols))
+                    } else {
+                        Ok(c as usize)
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    // "Save" (not "Save As"): write the edits back to the session's own file.
+    // Every indexed row is written in file order, real columns only, so sort,
+    // filter, truncation and saved views still line up afterwards and are
+    // kept; the edit overlay is cleared. The data goes to a temp file next to
+    // the original, our mapping is dropped and the temp file is renamed over
+    // the original (or, where that fails, the original is moved aside first
+    // and put back on error), so the path always holds the old or the new
+    // file in full. Then the file is mapped and indexed again.
+    pub fn save_in_place(&mut self) -> Result<(), SheetError> {
+        if self.path.as_os_str().is_empty() {
+            return Err("In-memory session has no file to save over".to_string().into());
+        }
+        if self.col_window.is_some() {
+            return Err("A sub view can't be saved in place; save its parent".to_string().into());
+        }
+        if self.synthetic_header {
+            return Err("The added header row would shift every row; use save_to_file instead".to_string().into());
+        }
+        if let Some(reason) = &self.detached {
+            return Err(format!("The session is detached from its file ({}); reopen it first", reason).into());
+        }
+
+        let path = self.path.clone();
+        let temp = temp_path(&path);
+        if let Err(e) = self.write_file_rows(&temp) {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e.into());
+        }
+
+        // Windows refuses to replace a file that is still mapped. Elsewhere the
+        // mapping stays (it keeps the old file's pages) until reopen swaps it.
+        if cfg!(windows) {
+            self.content = Arc::new(FileContent::Buffered(Vec::new()));
+        }
+        if let Err(e) = replace_file(&temp, &path) {
+            let _ = std::fs::remove_file(&temp);
+            if !cfg!(windows) {
+                return Err(e.into());
+            }
+            // The original is untouched: load it again and carry on as before
+            let backend = self.backend_choice.unwrap_or(StorageBackend::Auto);
+            match load_file(&path, backend, self.io_retry.unwrap_or_default()) {
+                Ok((content, reason)) => {
+                    self.content = Arc::new(content);
+                    self.backend_reason = reason;
+                }
+                Err(reload) => {
+                    self.detach(format!("loading it back after a failed save failed: {}", reload));
+                    let message = format!("{}; loading the original back also failed: {}", e, reload);
+                    return Err(SheetError { kind: reload.kind, message });
+                }
+            }
+            return Err(e.into());
+        }
+
+        self.col_order = None; // The file is in the session's column order now
+        let (row_order, filter_base, row_end) = (self.row_order.take(), self.filter_base.take(), self.row_end);
+        // Every row is where it was, but edited ones have new bytes
+        let row_tags = std::mem::take(&mut self.row_tags);
+        self.tag_digests.clear();
+        // reopen loads through load_file, so temporary errors (a scanner still
+        // holding the new file, a flaky share) are retried per io_retry
+        if let Err(e) = self.reopen() {
+            self.detach(format!("saved, but loading the new file failed: {}", e));
+            let message = format!("Saved, but reopening the file failed: {}; call reopen to try again", e);
+            return Err(SheetError { kind: e.kind, message });
+        }
+        self.row_end = row_end.min(self.row_offsets.len());
+        self.total_rows = row_order.as_ref().map_or(self.natural_rows(), Vec::len) as i64;
+        self.row_order = row_order;
+        self.filter_base = filter_base;
+        self.row_tags = row_tags;
+        self.refresh_tag_digests();
+        Ok(())
+    }
+
+    // Helper: drop every row when the file can't be loaded back (save_in_place),
+    // so nothing reads through an index that no longer matches the content.
+    // Settings stay (edits and repairs go with the rows); reopen attaches the
+    // file again.
+    fn detach(&mut self, reason: String) {
+        self.content = Arc::new(FileContent::Buffered(Vec::new()));
+        self.row_offsets = Arc::new(RowIndex::empty(self.terminator.index_byte()));
+        self.edits.clear();
+        (self.row_base, self.row_end, self.total_rows) = (0, 0, 0);
+        self.row_order = None;
+        self.filter_base = None;
+        self.repaired_widths.clear();
+        self.inverted_index = None;
+        self.search_index = None;
+        self.invalidate_analysis();
+        self.warnings.push(format!("Detached from the file: {}", reason));
+        self.detached = Some(reason);
+    }
+
+    // Helper: every indexed row in file order with edits applied (save_in_place)
+    fn write_file_rows(&self, path: &std::path::Path) -> Result<(), String> {
+        let mut trace = OpTrace::start("save_in_place", || path.display().to_string());
+        trace.touched(self.row_offsets.len() as i64, || self.content.len() as i64);
+        let file = File::create(path).map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+
+        let rows = self.row_offsets.len();
+        let task = self.begin_task(rows as i64);
+        for row in 0..rows {
+            if row > 0 {
+                writer.write_all(self.terminator.as_bytes()).map_err(write_err)?;
+            }
+            if row == 0 && self.first_row_header && self.header_names.len() > self.source_fields(0).len() {
+                // Columns added since opening (materialise_column) get their names
+                let mut names = self.source_fields(0);
+                names.extend(self.header_names[names.len()..].iter().cloned());
+                write_csv_row(&mut writer, &names)?;
+            } else if self.edits.contains_key(&row) || self.col_order.is_some() || self.rewrites_merged_rows() {
+                write_csv_row(&mut writer, &self.source_fields(row))?;
+            } else {
+                writer.write_all(self.row_bytes(row)).map_err(write_err)?;
+            }
+            task.report(row as i64 + 1);
+        }
+        writer.flush().map_err(write_err)?;
+        writer.get_ref().sync_all().map_err(write_err)
+    }
+
+    // Helper: save_to_file, optionally with the row-number column
+    fn write_csv_file(&self, path: &str, row_numbers: Option<&str>) -> Result<(), String> {
+        let mut trace = OpTrace::start("save_to_file", || path.to_string());
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        let file = File::create(path).map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+
+        let terminator = self.terminator.as_bytes();
+        if self.has_header_row() {
+            let all_cols = self.total_cols.min(i32::MAX as i64) as i32;
+            let mut names = self.get_header_chunk(0, all_cols);
+            if let Some(name) = row_numbers {
+                names.insert(0, name.to_string());
+            }
+            write_csv_row(&mut writer, &names)?;
+            if self.total_rows > 0 {
+                writer.write_all(terminator).map_err(write_err)?;
+            }
+        }
+        for row in 0..self.total_rows {
+            if row > 0 {
+                writer.write_all(terminator).map_err(write_err)?;
+            }
+            if row_numbers.is_some() {
+                write!(writer, "{},", self.file_line_number(row)).map_err(write_err)?;
+            }
+            self.write_record(&mut writer, row, self.sanitize_formulas)?;
+        }
+        writer.flush().map_err(write_err)
+    }
+
+    // The export_manifest JSON, written next to the data for downstream tools
+    pub fn export_manifest_to_file(&self, path: String, sample_values: i64, full_scan: bool) -> Result<(), String> {
+        let json = self.export_manifest(sample_values, full_scan)?;
+        std::fs::write(&path, json).map_err(|e| format!("Failed to write manifest: {}", e))
+    }
+
+    // Standalone HTML page with one <table>, written row by row. Every cell is
+    // escaped; max_rows caps the data rows (the header doesn't count).
+    // style.row_numbers adds a first column of source line numbers (see
+    // save_to_file_with_row_numbers) under that name.
+    pub fn export_to_html(&self, path: String, max_rows: Option<usize>, style: HtmlTableStyle) -> Result<(), String> {
+        let rows = max_rows.map_or(self.total_rows, |max| self.total_rows.min(max as i64));
+        let mut trace = OpTrace::start("export_to_html", || path.clone());
+        trace.touched(rows, || self.view_bytes(0..rows));
+        let file = File::create(&path).map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+
+        writer.write_all(b"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n").map_err(write_err)?;
+        if style.inline_css {
+            writer.write_all(HTML_TABLE_CSS.as_bytes()).map_err(write_err)?;
+        }
+        writer.write_all(b"</head>\n<body>\n<table>\n").map_err(write_err)?;
+
+        let all_cols = self.total_cols.min(i32::MAX as i64) as i32;
+        if style.include_header {
+            writer.write_all(b"<thead><tr>").map_err(write_err)?;
+            for name in style.row_numbers.iter().cloned().chain(self.get_header_chunk(0, all_cols)) {
+                write!(writer, "<th>{}</th>", html_escape(&name)).map_err(write_err)?;
+            }
+            writer.write_all(b"</tr></thead>\n").map_err(write_err)?;
+        }
+
+        let task = self.begin_task(rows);
+        writer.write_all(b"<tbody>\n").map_err(write_err)?;
+        for (row, mut fields) in self.view_rows(0..rows) {
+            self.export_nulls(&mut fields);
+            if style.zebra_striping {
+                let class = if row % 2 == 0 { "odd" } else { "even" }; // 1-based like CSS nth-child
+                write!(writer, "<tr class=\"{}\">", class).map_err(write_err)?;
+            } else {
+                writer.write_all(b"<tr>").map_err(write_err)?;
+            }
+            if style.row_numbers.is_some() {
+                write!(writer, "<td>{}</td>", self.file_line_number(row)).map_err(write_err)?;
+            }
+            for col in 0..self.total_cols as usize {
+                write!(writer, "<td>{}</td>", html_escape(field_or_empty(&fields, col))).map_err(write_err)?;
+            }
+            writer.write_all(b"</tr>\n").map_err(write_err)?;
+            task.report(row + 1);
+        }
+        writer.write_all(b"</tbody>\n</table>\n</body>\n</html>\n").map_err(write_err)?;
+        writer.flush().map_err(write_err)
+    }
+
+    // GitHub-flavored Markdown table with padded, aligned columns. Markdown
+    // tables need a header line, so without include_header it's left blank.
+    // alignment is per column (missing entries are unaligned); max_rows
+    // defaults to MARKDOWN_MAX_ROWS since the whole table is buffered for
+    // the column widths. row_numbers adds a right-aligned first column of
+    // source line numbers (see save_to_file_with_row_numbers) under that name;
+    // alignment still lists the sheet's own columns.
+    pub fn export_to_markdown(
+        &self,
+        path: String,
+        include_header: bool,
+        mut alignment: Vec<ColumnAlignment>,
+        max_rows: Option<usize>,
+        row_numbers: Option<String>,
+    ) -> Result<(), String> {
+        let numbered = row_numbers.is_some();
+        let cols = self.total_cols as usize + numbered as usize;
+        let all_cols = self.total_cols.min(i32::MAX as i64) as i32;
+        let header: Vec<String> = if include_header {
+            row_numbers.into_iter().chain(self.get_header_chunk(0, all_cols)).map(|h| markdown_escape(&h)).collect()
+        } else {
+            vec![String::new(); cols]
+        };
+        if numbered {
+            alignment.insert(0, ColumnAlignment::Right);
+        }
+
+        let rows = self.total_rows.min(max_rows.unwrap_or(MARKDOWN_MAX_ROWS) as i64);
+        let body: Vec<Vec<String>> = self
+            .view_rows(0..rows)
+            .map(|(row, mut fields)| {
+                self.export_nulls(&mut fields);
+                let number = numbered.then(|| self.file_line_number(row).to_string());
+                let cells = (0..self.total_cols as usize).map(|col| markdown_escape(field_or_empty(&fields, col)));
+                number.into_iter().chain(cells).collect()
+            })
+            .collect();
+
+        // Width in chars; 3 is the shortest separator GitHub accepts
+        let mut widths = vec![3; cols];
+        for line in std::iter::once(&header).chain(&body) {
+            for (width, cell) in widths.iter_mut().zip(line) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let file = File::create(&path).map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+        let write_line = |writer: &mut BufWriter<File>, cells: &[String]| -> Result<(), String> {
+            let mut line = String::from("|");
+            for (col, cell) in cells.iter().enumerate() {
+                let pad = widths[col] - cell.chars().count();
+                let (before, after) = match alignment.get(col) {
+                    Some(ColumnAlignment::Right) => (pad, 0),
+                    Some(ColumnAlignment::Center) => (pad / 2, pad - pad / 2),
+                    _ => (0, pad),
+                };
+                line.push(' ');
+                line.extend(std::iter::repeat_n(' ', before));
+                line.push_str(cell);
+                line.extend(std::iter::repeat_n(' ', after));
+                line.push_str(" |");
+            }
+            line.push('\n');
+            writer.write_all(line.as_bytes()).map_err(write_err)
+        };
+
+        write_line(&mut writer, &header)?;
+        let separator: Vec<String> = (0..cols)
+            .map(|col| {
+                let dashes = |n: usize| "-".repeat(n);
+                match alignment.get(col) {
+                    Some(ColumnAlignment::Left) => format!(":{}", dashes(widths[col] - 1)),
+                    Some(ColumnAlignment::Center) => format!(":{}:", dashes(widths[col] - 2)),
+                    Some(ColumnAlignment::Right) => format!("{}:", dashes(widths[col] - 1)),
+                    None => dashes(widths[col]),
+                }
+            })
+            .collect();
+        write_line(&mut writer, &separator)?;
+        for line in &body {
+            write_line(&mut writer, line)?;
+        }
+        writer.flush().map_err(write_err)
+    }
+
+    // A grid range as CSV text for the clipboard (\n between records, cells as
+    // the grid shows them, null export applied). sanitize_formulas defaults
+    // to on (None): pasted into a spreadsheet, "=..." cells would otherwise
+    // be evaluated. Pass Some(false) only when the user asked for raw values.
+    pub fn copy_range_as_csv(
+        &self,
+        row_start: i64,
+        row_count: i32,
+        col_start: i64,
+        col_count: i32,
+        sanitize_formulas: Option<bool>,
+    ) -> Result<String, String> {
+        let sanitize_formulas = sanitize_formulas.unwrap_or(true);
+        let mut out = Vec::new();
+        for (i, row) in self.get_grid_chunk(row_start, row_count, col_start, col_count).into_iter().enumerate() {
+            if i > 0 {
+                out.push(b'\n');
+            }
+            let cells = self.clipboard_cells(row, col_start.max(0) as usize, sanitize_formulas);
+            write_csv_row(&mut out, &cells)?;
+        }
+        String::from_utf8(out).map_err(|e| format!("Failed to build CSV: {}", e))
+    }
+
+    // The file's own bytes for view rows row_start.. (row_count of them),
+    // each with its line terminator as stored (the file's last line may have
+    // none): no decoding, re-quoting or edits. Fails if they aren't UTF-8;
+    // concatenate_rows_to_bytes takes any bytes.
+    pub fn concatenate_rows_to_string(&self, row_start: i64, row_count: i32) -> Result<String, String> {
+        String::from_utf8(self.concatenate_rows_to_bytes(row_start, row_count))
+            .map_err(|_| "The rows aren't valid UTF-8; use concatenate_rows_to_bytes".to_string())
+    }
+
+    pub fn concatenate_rows_to_bytes(&self, row_start: i64, row_count: i32) -> Vec<u8> {
+        let start = row_start.clamp(0, self.total_rows);
+        let end = start.saturating_add(row_count.max(0) as i64).min(self.total_rows);
+        let mut out = Vec::new();
+        for row in start..end {
+            let source_row = self.source_row(row);
+            out.extend_from_slice(self.raw_line(source_row));
+            if source_row + 1 < self.row_offsets.len() {
+                out.push(self.terminator.index_byte());
+            }
+        }
+        out
+    }
+
+    // Multi-range copy. Ranges are clamped to the sheet and written as
+    // blocks (records \n-separated, a blank line between blocks) sorted
+    // top-to-bottom, then left-to-right; inside a block cells go row by row.
+    // A cell inside several ranges is written once, in the first block that
+    // covers it, and left empty in later ones. Like range_stats, fails above
+    // RANGE_STATS_SYNC_LIMIT distinct cells. Otherwise as copy_range_as_csv.
+    pub fn export_ranges(
+        &self,
+        ranges: Vec<CellRange>,
+        format: RangeTextFormat,
+        sanitize_formulas: Option<bool>,
+    ) -> Result<String, String> {
+        let sanitize_formulas = sanitize_formulas.unwrap_or(true);
+        let rects = self.clamp_ranges(&ranges);
+        let cells = union_cell_count(&range_union(&rects));
+        if cells > RANGE_STATS_SYNC_LIMIT {
+            return Err(format!(
+                "The selection has {} cells; at most {} can be copied", cells, RANGE_STATS_SYNC_LIMIT
+            ));
+        }
+
+        let mut out = Vec::new();
+        for (i, &(row_start, row_end, col_start, col_end)) in rects.iter().enumerate() {
+            if i > 0 {
+                out.extend_from_slice(b"\n\n");
+            }
+            let col_count = (col_end - col_start) as i32;
+            let rows = self.get_grid_chunk(row_start, (row_end - row_start) as i32, col_start, col_count);
+            for (offset, row) in rows.into_iter().enumerate() {
+                if offset > 0 {
+                    out.push(b'\n');
+                }
+                let row_index = row.index;
+                let mut cells = self.clipboard_cells(row, col_start as usize, sanitize_formulas);
+                for (c, cell) in cells.iter_mut().enumerate() {
+                    let col = col_start + c as i64;
+                    let earlier = rects[..i].iter().any(|&(r0, r1, c0, c1)| {
+                        (r0..r1).contains(&row_index) && (c0..c1).contains(&col)
+                    });
+                    if earlier {
+                        cell.clear();
+                    }
+                }
+                match format {
+                    RangeTextFormat::Csv => write_csv_row(&mut out, &cells)?,
+                    RangeTextFormat::Tsv => {
+                        let fields: Vec<Cow<str>> = cells.iter().map(|cell| quote_tsv_field(cell)).collect();
+                        out.extend_from_slice(fields.join("\t").as_bytes());
+                    }
+                }
+            }
+        }
+        String::from_utf8(out).map_err(|e| format!("Failed to build text: {}", e))
+    }
+
+    // Helper: one grid row as clipboard text (null export, optional formula guard)
+    fn clipboard_cells(&self, row: RowData, first_col: usize, sanitize: bool) -> Vec<String> {
+        let mut cells: Vec<String> = row.cells.into_iter().map(|cell| cell.content).collect();
+        if let Some(replacement) = &self.null_tokens.export_as {
+            for (offset, cell) in cells.iter_mut().enumerate() {
+                if self.is_null_token(first_col + offset, cell) {
+                    cell.clone_from(replacement);
+                }
+            }
+        }
+        if sanitize {
+            sanitize_formulas(&mut cells, self.decimal_separator);
+        }
+        cells
+    }
+
+    // Split the rows into shard_count files by key, e.g. for spreading work
+    // over machines. A row goes to shard FNV-1a-64(key cell's UTF-8 bytes) %
+    // shard_count, so the same key always lands in the same shard across
+    // runs and platforms. Files are out_dir/part-00000.csv etc.; unedited rows
+    // are copied byte for byte. include_header starts every shard with the
+    // header line (the file's with first_row_header, write_csv_header
+    // names, or letters without either). Cancellable via
+    // cancel_task. Shards are written under temporary names and moved into
+    // place at the end, so on cancel or error files already in out_dir are
+    // left alone. Returns the row count per shard.
+    pub fn partition_by_hash(
+        &self,
+        col: i64,
+        shard_count: i64,
+        out_dir: String,
+        include_header: bool,
+    ) -> Result<Vec<i64>, String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        if !(1..=MAX_SHARDS).contains(&shard_count) {
+            return Err(format!("Shard count must be between 1 and {}, got {}", MAX_SHARDS, shard_count));
+        }
+
+        let mut trace = OpTrace::start("partition_by_hash", || format!("{}, {} shards", out_dir, shard_count));
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        let paths: Vec<std::path::PathBuf> = (0..shard_count)
+            .map(|i| std::path::Path::new(&out_dir).join(format!("part-{:05}.csv", i)))
+            .collect();
+        write_staged(&paths, |staged| self.write_shards(col as usize, staged, include_header))
+    }
+
+    // Helper: the streaming part of partition_by_hash (see write_staged)
+    fn write_shards(&self, col: usize, paths: &[std::path::PathBuf], include_header: bool) -> Result<Vec<i64>, String> {
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+        let mut shards = Vec::with_capacity(paths.len());
+        for path in paths {
+            let file = File::create(path).map_err(|e| format!("Failed to create file: {}", e))?;
+            shards.push(BufWriter::new(file));
+        }
+
+        let header = include_header.then(|| {
+            let mut line = Vec::new();
+            let names = self.get_header_chunk(0, self.total_cols.min(i32::MAX as i64) as i32);
+            write_csv_row(&mut line, &names).map(|_| line)
+        });
+        let header = header.transpose()?;
+        let mut counts = vec![0i64; paths.len()];
+
+        let task = self.begin_task(self.total_rows);
+        for (row, fields) in self.view_rows(0..self.total_rows) {
+            if task.is_cancelled() {
+                return Err("Partitioning was cancelled; partial shards were removed".to_string());
+            }
+            let shard = (fnv1a_64(field_or_empty(&fields, col).as_bytes()) % paths.len() as u64) as usize;
+            let writer = &mut shards[shard];
+
+            if counts[shard] > 0 || header.is_some() {
+                if counts[shard] == 0 {
+                    writer.write_all(header.as_deref().unwrap_or_default()).map_err(write_err)?;
+                }
+                writer.write_all(self.terminator.as_bytes()).map_err(write_err)?;
+            }
+            self.write_record(writer, row, self.sanitize_formulas)?;
+            counts[shard] += 1;
+            task.report(row + 1);
+        }
+
+        for (i, writer) in shards.iter_mut().enumerate() {
+            // Shards that got no rows still get their header
+            if counts[i] == 0 {
+                if let Some(header) = &header {
+                    writer.write_all(header).map_err(write_err)?;
+                }
+            }
+            writer.flush().map_err(write_err)?;
+        }
+        Ok(counts)
+    }
+
+    // Split the rows, in view order, into files of rows_per_file rows each
+    // (the last one may be shorter), e.g. to process a huge file in parallel.
+    // Files are output_dir/part-001.csv, part-002.csv, ... (more digits past
+    // 999 parts, so names still sort); rows are written as save_to_file
+    // writes them. include_header starts every file with the header row when
+    // the session has one (the file's own with first_row_header, or
+    // write_csv_header names); without one it adds nothing. Cancellable via
+    // cancel_task; as with partition_by_hash, files already in output_dir
+    // only change once every part is complete. Returns the paths.
+    pub fn split_into_files(
+        &self,
+        output_dir: String,
+        rows_per_file: usize,
+        include_header: bool,
+    ) -> Result<Vec<String>, String> {
+        if rows_per_file == 0 {
+            return Err("rows_per_file must be at least 1".to_string());
+        }
+        let mut trace =
+            OpTrace::start("split_into_files", || format!("{}, {} rows per file", output_dir, rows_per_file));
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        let parts = (self.total_rows.max(0) as usize).div_ceil(rows_per_file);
+        let width = parts.to_string().len().max(3);
+        let paths: Vec<std::path::PathBuf> = (1..=parts)
+            .map(|i| std::path::Path::new(&output_dir).join(format!("part-{:0width$}.csv", i, width = width)))
+            .collect();
+        let header = include_header && self.has_header_row();
+        write_staged(&paths, |staged| self.write_parts(staged, rows_per_file, header))?;
+        Ok(paths.iter().map(|path| path.to_string_lossy().into_owned()).collect())
+    }
+
+    // Helper: the writing part of split_into_files (see write_staged)
+    fn write_parts(&self, paths: &[std::path::PathBuf], rows_per_file: usize, header: bool) -> Result<(), String> {
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+        let terminator = self.terminator.as_bytes();
+        let names = self.get_header_chunk(0, self.total_cols.min(i32::MAX as i64) as i32);
+
+        let task = self.begin_task(self.total_rows);
+        let mut row = 0;
+        for path in paths {
+            let file = File::create(path).map_err(|e| format!("Failed to create file: {}", e))?;
+            let mut writer = BufWriter::new(file);
+            if header {
+                write_csv_row(&mut writer, &names)?;
+                writer.write_all(terminator).map_err(write_err)?;
+            }
+            let end = (row + rows_per_file as i64).min(self.total_rows);
+            for part_row in row..end {
+                if task.is_cancelled() {
+                    return Err("Splitting was cancelled; partial files were removed".to_string());
+                }
+                if part_row > row {
+                    writer.write_all(terminator).map_err(write_err)?;
+                }
+                self.write_record(&mut writer, part_row, self.sanitize_formulas)?;
+                task.report(part_row + 1);
+            }
+            writer.flush().map_err(write_err)?;
+            row = end;
+        }
+        Ok(())
+    }
+
+    // Data lake export: one Parquet file per distinct value of partition_col,
+    // at output_dir/<column>=<value>/part-0.parquet (Hive layout; names and
+    // values %-escaped like Hive does, empty and null-token cells under
+    // HIVE_DEFAULT_PARTITION). The partition column is left out of the files,
+    // as Hive expects; every other column is an optional UTF-8 string named
+    // by its canonical header, with empty and null-token cells as nulls.
+    // Rows keep the view order, PARQUET_ROW_GROUP_ROWS per row group. At most
+    // MAX_PARTITIONS values. Cancellable via cancel_task. Files are written
+    // under temporary names and moved into place at the end (write_staged),
+    // so on cancel or error part files already there are left alone and
+    // only the partition directories this export created are removed.
+    pub fn export_to_parquet_partitioned(
+        &self,
+        output_dir: String,
+        partition_col: i64,
+    ) -> Result<PartitionedExportReport, String> {
+        if partition_col < 0 || partition_col >= self.total_cols {
+            return Err(format!("Column {} is out of range", partition_col));
+        }
+        let mut trace = OpTrace::start("export_to_parquet_partitioned", || output_dir.clone());
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        let col = partition_col as usize;
+        self.check_budget("partition_rows", self.total_rows as usize * std::mem::size_of::<i64>(), "filter first")?;
+
+        // BTreeMap: partitions are written in value order
+        let mut partitions: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+        for (row, value) in self.column_cells(col, 0..self.total_rows) {
+            let key = if value.trim().is_empty() || self.is_null_token(col, &value) {
+                HIVE_DEFAULT_PARTITION.to_string()
+            } else {
+                value
+            };
+            if !partitions.contains_key(&key) && partitions.len() >= MAX_PARTITIONS {
+                return Err(format!("More than {} distinct partition values", MAX_PARTITIONS));
+            }
+            partitions.entry(key).or_default().push(row);
+        }
+
+        let names = self.canonical_names();
+        let data_cols: Vec<usize> = (0..self.total_cols as usize).filter(|&c| c != col).collect();
+        let dirs: Vec<std::path::PathBuf> = partitions
+            .keys()
+            .map(|value| {
+                let value = if value == HIVE_DEFAULT_PARTITION { value.clone() } else { hive_escape(value) };
+                std::path::Path::new(&output_dir).join(format!("{}={}", hive_escape(&names[col]), value))
+            })
+            .collect();
+        let paths: Vec<std::path::PathBuf> = dirs.iter().map(|dir| dir.join("part-0.parquet")).collect();
+        let mut created = Vec::new();
+        let result = dirs
+            .iter()
+            .try_for_each(|dir| {
+                if !dir.is_dir() {
+                    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create directory: {}", e))?;
+                    created.push(dir);
+                }
+                Ok(())
+            })
+            .and_then(|()| {
+                write_staged(&paths, |staged| self.write_partitions(&names, &data_cols, &partitions, staged))
+            });
+        if result.is_err() {
+            for dir in created {
+                let _ = std::fs::remove_dir(dir); // Only if still empty
+            }
+        }
+        result?;
+
+        Ok(PartitionedExportReport {
+            partitions_written: partitions.len(),
+            rows_per_partition: partitions.into_iter().map(|(key, rows)| (key, rows.len() as u64)).collect(),
+        })
+    }
+
+    // Helper: the writing part of export_to_parquet_partitioned, one file
+    // per partition (see write_staged)
+    fn write_partitions(
+        &self,
+        names: &[String],
+        data_cols: &[usize],
+        partitions: &BTreeMap<String, Vec<i64>>,
+        paths: &[std::path::PathBuf],
+    ) -> Result<(), String> {
+        let task = self.begin_task(self.total_rows);
+        let mut done = 0i64;
+        for (rows, path) in partitions.values().zip(paths) {
+            let file = File::create(path).map_err(|e| format!("Failed to create file: {}", e))?;
+
+            let mut writer = self.parquet_writer(file, names, data_cols)?;
+            for group in rows.chunks(PARQUET_ROW_GROUP_ROWS) {
+                if task.is_cancelled() {
+                    return Err("Export was cancelled; partial files were removed".to_string());
+                }
+                let fields: Vec<Vec<String>> = self.view_rows(group.iter().copied()).map(|(_, f)| f).collect();
+                let mut row_group = writer.next_row_group().map_err(parquet_err)?;
+                for &c in data_cols {
+                    let mut values = Vec::new();
+                    let mut def_levels = Vec::with_capacity(group.len());
+                    for row in &fields {
+                        let value = field_or_empty(row, c);
+                        if value.trim().is_empty() || self.is_null_token(c, value) {
+                            def_levels.push(0);
+                        } else {
+                            def_levels.push(1);
+                            values.push(parquet::data_type::ByteArray::from(value));
+                        }
+                    }
+                    let mut column = row_group
+                        .next_column()
+                        .map_err(parquet_err)?
+                        .ok_or("Parquet schema has fewer columns than the sheet")?;
+                    column
+                        .typed::<parquet::data_type::ByteArrayType>()
+                        .write_batch(&values, Some(&def_levels), None)
+                        .map_err(parquet_err)?;
+                    column.close().map_err(parquet_err)?;
+                }
+                row_group.close().map_err(parquet_err)?;
+                done += group.len() as i64;
+                task.report(done);
+            }
+            writer.close().map_err(parquet_err)?;
+        }
+        Ok(())
+    }
+
+    // Helper: Parquet writer whose schema has one optional UTF-8 column per data column
+    fn parquet_writer(
+        &self,
+        file: File,
+        names: &[String],
+        data_cols: &[usize],
+    ) -> Result<parquet::file::writer::SerializedFileWriter<File>, String> {
+        use parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+        use parquet::schema::types::Type;
+        let fields = data_cols
+            .iter()
+            .map(|&c| {
+                Type::primitive_type_builder(&names[c], PhysicalType::BYTE_ARRAY)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_converted_type(ConvertedType::UTF8)
+                    .build()
+                    .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(parquet_err)?;
+        let schema = Type::group_type_builder("schema").with_fields(fields).build().map_err(parquet_err)?;
+        let properties = parquet::file::properties::WriterProperties::builder().build();
+        parquet::file::writer::SerializedFileWriter::new(file, Arc::new(schema), Arc::new(properties))
+            .map_err(parquet_err)
+    }
+
+    // Small repro file from a big (possibly confidential) one: the added
+    // header, if any, then up to `rows` sampled rows as their raw file bytes
+    // (quoting, junk after quotes, invalid UTF-8 and the line ending all
+    // kept, edits not applied) so parser bugs still reproduce. Scrambled
+    // cells are rewritten in place, inside their quotes if they had them.
+    // Returns the view rows included, ascending.
+    pub fn export_sample_to_file(
+        &self,
+        out_path: String,
+        rows: i64,
+        strategy: SampleStrategy,
+        anonymize: Option<AnonymizeSpec>,
+    ) -> Result<Vec<i64>, String> {
+        if self.col_order.is_some() {
+            return Err("Samples are copied from the file as is; call reset_column_order first".to_string());
+        }
+        let scramble_cols: HashSet<usize> = match &anonymize {
+            Some(spec) => spec
+                .cols
+                .iter()
+                .map(|&col| self.check_editable_col(col))
+                .collect::<Result<_, _>>()?,
+            None => HashSet::new(),
+        };
+        let seed = anonymize.as_ref().map_or(0, |spec| spec.seed);
+        let rows = rows.max(0);
+        let sample = match strategy {
+            SampleStrategy::EveryKth(k) if k < 1 => return Err(format!("Sampling step must be at least 1, got {}", k)),
+            SampleStrategy::EveryKth(k) => (0..self.total_rows).step_by(k as usize).take(rows as usize).collect(),
+            SampleStrategy::Random { seed } => self.sample_rows_seeded(rows as usize, seed),
+        };
+
+        let file = File::create(&out_path).map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+        if self.has_header_row() {
+            write_csv_row(&mut writer, &self.get_header_chunk(0, self.base_cols.min(i32::MAX as i64) as i32))?;
+            if !sample.is_empty() {
+                writer.write_all(self.terminator.as_bytes()).map_err(write_err)?;
+            }
+        }
+
+        let task = self.begin_task(sample.len() as i64);
+        for (done, &row) in sample.iter().enumerate() {
+            if done > 0 {
+                writer.write_all(self.terminator.as_bytes()).map_err(write_err)?;
+            }
+            let raw = self.row_bytes(self.source_row(row));
+            if scramble_cols.is_empty() || raw.is_empty() {
+                writer.write_all(raw).map_err(write_err)?;
+            } else {
+                let (fields, ranges) = fields_with_raw_ranges(raw, self.merge_delimiters);
+                let mut pos = 0;
+                for (file_col, (value, &(start, end))) in fields.iter().zip(&ranges).enumerate() {
+                    let Some(col) = self.session_col(file_col) else { continue };
+                    if !scramble_cols.contains(&col) || value.is_empty() { continue; }
+                    writer.write_all(&raw[pos..start]).map_err(write_err)?;
+                    let scrambled = scramble_cell(value, seed);
+                    if raw[start] == b'"' {
+                        write!(writer, "\"{}\"", scrambled).map_err(write_err)?;
+                    } else {
+                        writer.write_all(scrambled.as_bytes()).map_err(write_err)?;
+                    }
+                    pos = end;
+                }
+                writer.write_all(&raw[pos..]).map_err(write_err)?;
+            }
+            task.report(done as i64 + 1);
+        }
+        writer.flush().map_err(write_err)?;
+        Ok(sample)
+    }
+
+    // Helper: write one logical row (no terminator). Edited rows (and every row
+    // once derived columns exist) are re-encoded; the rest are copied byte for byte.
+    // `sanitize` applies the formula guard (file exports pass self.sanitize_formulas).
+    fn write_record<W: Write>(&self, writer: &mut W, row: i64, sanitize: bool) -> Result<(), String> {
+        let source_row = self.source_row(row);
+        if self.edits.contains_key(&source_row)
+            || !self.derived.is_empty()
+            || self.col_window.is_some()
+            || self.col_order.is_some()
+            || self.null_tokens.export_as.is_some()
+            || self.rewrites_merged_rows()
+            || sanitize
+        {
+            let mut fields = self.row_fields(source_row);
+            self.check_padding_export(row, 0..fields.len())?;
+            self.export_nulls(&mut fields);
+            if sanitize {
+                sanitize_formulas(&mut fields, self.decimal_separator);
+            }
+            write_csv_row(writer, &fields)
+        } else {
+            writer
+                .write_all(self.row_bytes(source_row))
+                .map_err(|e| format!("Failed to write file: {}", e))
+        }
+    }
+
+    // 6. COMPARE AGAINST A "GOLDEN" ROW
+    // Returns rows (excluding the reference itself) whose selected cells differ
+    // from / match the reference row, after normalization. Stops at max_hits.
+    pub fn compare_to_row(
+        &self,
+        reference_row: i64,
+        cols: Vec<i64>,
+        mode: CompareMode,
+        max_hits: i32,
+    ) -> Result<Vec<i64>, String> {
+        if reference_row < 0 || reference_row >= self.total_rows {
+            return Err(format!("Reference row {} is out of range", reference_row));
+        }
+        if cols.is_empty() {
+            return Err("No columns selected for comparison".to_string());
+        }
+        if let Some(&bad) = cols.iter().find(|&&c| c < 0 || c >= self.total_cols) {
+            return Err(format!("Column {} is out of range", bad));
+        }
+
+        let cols: Vec<usize> = cols.iter().map(|&c| c as usize).collect();
+        let reference_fields = self.row_fields(self.source_row(reference_row));
+        let reference: Vec<String> = cols
+            .iter()
+            .map(|&c| self.normalize_cell(field_or_empty(&reference_fields, c)).into_owned())
+            .collect();
+
+        let mut hits = Vec::new();
+        if max_hits <= 0 { return Ok(hits); }
+
+        for (row, fields) in self.view_rows((0..self.total_rows).filter(|&row| row != reference_row)) {
+            let all_match = cols
+                .iter()
+                .zip(&reference)
+                .all(|(&c, expected)| self.normalize_cell(field_or_empty(&fields, c)) == expected.as_str());
+
+            let is_hit = match mode {
+                CompareMode::Matches => all_match,
+                CompareMode::Differs => !all_match,
+            };
+            if is_hit {
+                hits.push(row);
+                if hits.len() >= max_hits as usize { break; }
+            }
+        }
+        Ok(hits)
+    }
+
+    // 7. SHUFFLE (reproducible for a given seed)
+    // Stores a random permutation of the current rows as the session's row order
+    // and returns it (as source rows). Combine with truncate_to_rows for sampling.
+    pub fn shuffle_rows(&mut self, seed: u64) -> Result<Vec<i64>, String> {
+        let n = self.total_rows as usize;
+        self.check_budget("row_order", n * std::mem::size_of::<i64>(), "truncate_to_rows to shuffle a sample")?;
+        let mut order = Vec::new();
+        order
+            .try_reserve_exact(n)
+            .map_err(|e| format!("Not enough memory to shuffle {} rows: {}", n, e))?;
+        order.extend((0..self.total_rows).map(|row| self.source_row(row) as i64));
+
+        // Fisher-Yates
+        let mut rng = SplitMix64(seed);
+        for i in (1..n).rev() {
+            let j = rng.next_below(i as u64 + 1) as usize;
+            order.swap(i, j);
+        }
+
+        self.row_order = Some(order.clone());
+        Ok(order)
+    }
+
+    // Bottom-up reading for log-like files: view row 0 becomes the last row.
+    // Pure index arithmetic on top of the current row order, so it costs nothing;
+    // RowData.source_row still reports the row in the file. truncate_to_rows
+    // ignores the flag (it keeps the first rows in file/shuffle order). A
+    // first_row_header row isn't one of the rows, so it never moves.
+    pub fn set_reverse_order(&mut self, reverse: bool) {
+        self.reverse_order = reverse;
+    }
+
+    // Back to file order. Rows dropped by truncate_to_rows while shuffled stay
+    // dropped; the remaining rows are simply put back in file order.
+    pub fn reset_row_order(&mut self) {
+        if let Some(mut order) = self.row_order.take() {
+            if order.len() < self.natural_rows() {
+                order.sort_unstable();
+                self.row_order = Some(order);
+            }
+        }
+    }
+
+    // 8. COLUMN TYPES (inferred from the first TYPE_SAMPLE_ROWS rows, cached)
+    pub fn infer_column_types(&self, col_start: i64, col_count: i32) -> Vec<ColumnType> {
+        let cols = self.visible_cols(col_start, col_count);
+        self.column_profiles(&cols).iter().map(|p| p.column_type).collect()
+    }
+
+    // Advisory rendering hints: numbers right-aligned, booleans centered,
+    // fixed-length digit strings (IDs, zip codes) left-aligned in monospace.
+    pub fn get_column_render_hints(&self, col_start: i64, col_count: i32) -> Vec<RenderHint> {
+        let cols = self.visible_cols(col_start, col_count);
+        let profiles = self.column_profiles(&cols);
+
+        cols.iter()
+            .zip(profiles)
+            .map(|(&col, profile)| {
+                let alignment = match profile.column_type {
+                    _ if profile.looks_like_id => ColumnAlignment::Left,
+                    ColumnType::Integer | ColumnType::Float => ColumnAlignment::Right,
+                    ColumnType::Boolean => ColumnAlignment::Center,
+                    ColumnType::Empty | ColumnType::Text => ColumnAlignment::Left,
+                };
+                RenderHint {
+                    col: col as i64,
+                    alignment,
+                    type_tag: profile.column_type,
+                    is_monospace_suggested: profile.looks_like_id,
+                }
+            })
+            .collect()
+    }
+
+    // 9. EXPLODE A MULTI-VALUE COLUMN ("red;blue" -> one row per value)
+    // Writes a copy of the file where each row is repeated once per sub-value of
+    // `col`. Empty cells give one row with an empty value. Returns rows written.
+    pub fn explode_column_to_file(
+        &self,
+        col: i64,
+        secondary_delimiter: String,
+        out_path: String,
+    ) -> Result<i64, String> {
+        self.check_explode_args(col, &secondary_delimiter)?;
+
+        let file = File::create(&out_path).map_err(|e| format!("Failed to create file: {}", e))?;
+        let mut writer = BufWriter::new(file);
+        let task = self.begin_task(self.total_rows);
+
+        let mut written = 0i64;
+        for row in 0..self.total_rows {
+            for mut fields in self.explode_row(row, col as usize, &secondary_delimiter) {
+                self.export_nulls(&mut fields);
+                if self.sanitize_formulas {
+                    sanitize_formulas(&mut fields, self.decimal_separator);
+                }
+                if written > 0 {
+                    writer.write_all(self.terminator.as_bytes()).map_err(|e| format!("Failed to write file: {}", e))?;
+                }
+                write_csv_row(&mut writer, &fields)?;
+                written += 1;
+            }
+            task.report(row + 1);
+        }
+        writer.flush().map_err(|e| format!("Failed to write file: {}", e))?;
+        Ok(written)
+    }
+
+    // Same as explode_column_to_file, but only returns the first max_rows
+    // exploded rows so the user can check the result first.
+    pub fn preview_explode_column(
+        &self,
+        col: i64,
+        secondary_delimiter: String,
+        max_rows: i32,
+    ) -> Result<Vec<RowData>, String> {
+        self.check_explode_args(col, &secondary_delimiter)?;
+
+        let mut results = Vec::new();
+        for row in 0..self.total_rows {
+            for fields in self.explode_row(row, col as usize, &secondary_delimiter) {
+                if results.len() >= max_rows.max(0) as usize { return Ok(results); }
+                results.push(RowData {
+                    index: row,
+                    source_row: self.source_row(row) as i64,
+                    cells: fields
+                        .into_iter()
+                        .enumerate()
+                        .map(|(col, content)| CellData {
+                            is_null: self.is_null_token(col, &content),
+                            content,
+                            is_padding: false,
+                            content_kind: ContentKind::Plain,
+                        })
+                        .collect(),
+                    decoded_with_fallback: str::from_utf8(self.row_bytes(self.source_row(row))).is_err(),
+                    continuation: None,
+                    row_number: None,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    // 10. EDITS
+    // Edits live in an overlay keyed by source row, so they follow rows through
+    // shuffles and are written out by save_to_file. Every cell read goes through
+    // build_row (the grid) or view_rows / column_cells (stats, unique values,
+    // summaries, exports), which apply the overlay, so analysis sees edited
+    // values; only byte-level diagnostics (structure_profile,
+    // analyze_encoding, export_sample_to_file) describe the file as stored.
+    pub fn set_cell(&mut self, row: i64, col: i64, value: String) -> Result<(), String> {
+        if row < 0 || row >= self.total_rows {
+            return Err(format!("Row {} is out of range", row));
+        }
+        let col = self.check_editable_col(col)?;
+        self.check_budget("edit_overlay", edit_cost(&value), "save_to_file and reopen the result")?;
+        let source_row = self.source_row(row);
+        self.edits.entry(source_row).or_default().insert(col, value);
+        self.inverted_index = None;
+        self.search_index = None;
+        self.invalidate_analysis();
+        Ok(())
+    }
+
+    pub fn discard_edits(&mut self) {
+        self.edits.clear();
+        self.repaired_widths.clear();
+        self.inverted_index = None;
+        self.search_index = None;
+        self.invalidate_analysis();
+    }
+
+    // Apply a transform to every cell of a column. Only cells whose value
+    // actually changes are written to the overlay; returns that count.
+    pub fn map_column(&mut self, col: i64, transform: ColumnTransform) -> Result<u64, String> {
+        let col = self.check_editable_col(col)?;
+        // Compile once, up front, so a bad pattern fails before anything changes
+        let regex = match &transform {
+            ColumnTransform::RegexReplace { pattern, .. } => {
+                Some(Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))?)
+            }
+            _ => None,
+        };
+
+        let changes = self.column_changes(col, 0..self.total_rows, true, |fields| {
+            let current = field_or_empty(fields, col);
+            let updated: Cow<str> = match &transform {
+                ColumnTransform::Uppercase => Cow::Owned(current.to_uppercase()),
+                ColumnTransform::Lowercase => Cow::Owned(current.to_lowercase()),
+                ColumnTransform::TrimWhitespace => Cow::Borrowed(current.trim()),
+                ColumnTransform::TrimPrefix(prefix) => {
+                    Cow::Borrowed(current.strip_prefix(prefix.as_str()).unwrap_or(current))
+                }
+                ColumnTransform::TrimSuffix(suffix) => {
+                    Cow::Borrowed(current.strip_suffix(suffix.as_str()).unwrap_or(current))
+                }
+                ColumnTransform::Replace { from, to } if !from.is_empty() => {
+                    Cow::Owned(current.replace(from.as_str(), to))
+                }
+                ColumnTransform::Replace { .. } => Cow::Borrowed(current),
+                ColumnTransform::RegexReplace { replacement, .. } => {
+                    regex.as_ref().unwrap().replace_all(current, replacement.as_str())
+                }
+            };
+            (updated != current).then(|| updated.into_owned())
+        })?;
+        self.commit_edits(changes)
+    }
+
+    // Bulk cleanup of one column ("clean column" actions), all in one call.
+    // Covers rows row_start.. (row_count of them, None: to the end) of the
+    // current view, or of the file's rows in file order when
+    // current_view_only is false (filtered-out rows included). Only changed
+    // cells are kept, budget-checked as they accumulate, and go into the
+    // overlay in one update. Returns the changed count.
+    pub fn apply_column_edit(
+        &mut self,
+        col: i64,
+        op: EditOp,
+        row_start: i64,
+        row_count: Option<i64>,
+        current_view_only: bool,
+    ) -> Result<u64, String> {
+        let col = self.check_editable_col(col)?;
+        let regex = match &op {
+            EditOp::RegexReplace { pattern, .. } => {
+                Some(Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))?)
+            }
+            _ => None,
+        };
+        let total = if current_view_only { self.total_rows } else { self.natural_rows() as i64 };
+        let start = row_start.clamp(0, total);
+        let end = row_count.map_or(total, |count| start.saturating_add(count.max(0)).min(total));
+
+        let changes = self.column_changes(col, start..end, current_view_only, |fields| {
+            // Like fill_with_default, blank rows are left blank
+            if fields.iter().all(|field| field.trim().is_empty()) {
+                return None;
+            }
+            let current = field_or_empty(fields, col);
+            let updated: Cow<str> = match &op {
+                EditOp::Trim => Cow::Borrowed(current.trim()),
+                EditOp::Upper => Cow::Owned(current.to_uppercase()),
+                EditOp::Lower => Cow::Owned(current.to_lowercase()),
+                EditOp::RegexReplace { replacement, .. } => {
+                    regex.as_ref().unwrap().replace_all(current, replacement.as_str())
+                }
+                EditOp::SetIfEmpty(value) if current.trim().is_empty() => Cow::Borrowed(value.as_str()),
+                EditOp::SetIfEmpty(_) => Cow::Borrowed(current),
+            };
+            (updated != current).then(|| updated.into_owned())
+        })?;
+        self.commit_edits(changes)
+    }
+
+    // VLOOKUP-style mapping: cells of `col` found in `table` (exact match on
+    // the cell text) become the mapped value; the rest follow
+    // unmapped_policy. Empty cells are only looked up, never replaced by
+    // the policy, and blank rows are skipped. Changed cells go into the
+    // overlay in one update; returns how many changed.
+    pub fn apply_lookup_table(
+        &mut self,
+        col: i64,
+        table: HashMap<String, String>,
+        unmapped_policy: UnmappedPolicy,
+    ) -> Result<u64, String> {
+        let col = self.check_editable_col(col)?;
+        let changes = self.column_changes(col, 0..self.total_rows, true, |fields| {
+            if fields.iter().all(|field| field.trim().is_empty()) {
+                return None;
+            }
+            let current = field_or_empty(fields, col);
+            let updated = match (table.get(current), &unmapped_policy) {
+                (Some(mapped), _) => mapped.as_str(),
+                (None, _) if current.is_empty() => current,
+                (None, UnmappedPolicy::LeaveUnchanged) => current,
+                (None, UnmappedPolicy::ReplaceWithEmpty) => "",
+                (None, UnmappedPolicy::ReplaceWith(value)) => value.as_str(),
+            };
+            (updated != current).then(|| updated.to_string())
+        })?;
+        self.commit_edits(changes)
+    }
+
+    // Helper: run `edit` over the fields of `rows` (view positions, or file
+    // rows past row_base when !in_view) and collect the `col` values it changes
+    fn column_changes(
+        &self,
+        col: usize,
+        rows: std::ops::Range<i64>,
+        in_view: bool,
+        edit: impl Fn(&[String]) -> Option<String>,
+    ) -> Result<Vec<(usize, usize, String)>, String> {
+        let mut changes = Vec::new();
+        let (mut bytes, mut checked) = (0usize, 0usize);
+        for row in rows {
+            let source_row = if in_view { self.source_row(row) } else { self.row_base + row as usize };
+            let fields = self.source_fields(source_row);
+            let Some(updated) = edit(&fields) else { continue };
+            bytes += edit_cost(&updated);
+            if bytes >= checked + INDEX_BUDGET_STEP {
+                checked = bytes;
+                self.check_budget("edit_overlay", bytes, "edit fewer rows at a time, or save and reopen")?;
+            }
+            changes.push((source_row, col, updated));
+        }
+        Ok(changes)
+    }
+
+    // Find & Replace (Ctrl+H): every occurrence of `find` in the column (None:
+    // all non-derived columns) becomes `replace`, where "$0" stands for the
+    // matched text like in RegexReplace; any other "$" is literal. Changed
+    // cells go into the overlay in one update. Returns the occurrences replaced.
+    pub fn search_and_replace(
+        &mut self,
+        col: Option<i64>,
+        find: String,
+        replace: String,
+        case_sensitive: bool,
+    ) -> Result<u64, String> {
+        if find.is_empty() {
+            return Err("Nothing to find".to_string());
+        }
+        let cols = match col {
+            Some(col) => vec![self.check_editable_col(col)?],
+            None => (0..self.base_cols as usize).collect(),
+        };
+        let flags = if case_sensitive { "" } else { "(?i)" };
+        let regex = Regex::new(&format!("{}{}", flags, regex::escape(&find)))
+            .map_err(|e| format!("Invalid search text: {}", e))?;
+        // Only $0 is special in our syntax; everything else must reach the regex crate as literal text
+        let replacement = replace.split("$0").map(|part| part.replace('$', "$$")).collect::<Vec<_>>().join("${0}");
+
+        let mut changes = Vec::new();
+        let mut replaced = 0u64;
+        for row in 0..self.total_rows {
+            let source_row = self.source_row(row);
+            let fields = self.source_fields(source_row);
+            for &col in &cols {
+                let current = field_or_empty(&fields, col);
+                let updated = regex.replace_all(current, replacement.as_str());
+                if updated != current {
+                    replaced += regex.find_iter(current).count() as u64;
+                    changes.push((source_row, col, updated.into_owned()));
+                }
+            }
+        }
+        self.commit_edits(changes)?;
+        Ok(replaced)
+    }
+
+    // Replace a whole column: values[i] goes to view row i, so there must be
+    // exactly total_rows of them. Like map_column, only cells that differ
+    // from what's shown now are written, in one overlay update; returns that
+    // count. Nothing changes if the count or budget check fails.
+    pub fn set_column_data(&mut self, col: i64, values: Vec<String>) -> Result<u64, String> {
+        let col = self.check_editable_col(col)?;
+        if values.len() as i64 != self.total_rows {
+            return Err(format!("Expected {} values (one per row), got {}", self.total_rows, values.len()));
+        }
+
+        let mut changes = Vec::new();
+        for (row, value) in values.into_iter().enumerate() {
+            let source_row = self.source_row(row as i64);
+            if self.cell_text(source_row, col) != value {
+                changes.push((source_row, col, value));
+            }
+        }
+        self.commit_edits(changes)
+    }
+
+    // Write default_value into every empty cell of col (with trim_whitespace
+    // on, whitespace-only cells count as empty). Blank rows, the ones
+    // get_sparse_rows leaves out, are not filled. Returns the cells written.
+    pub fn fill_with_default(&mut self, col: i64, default_value: String) -> Result<u64, String> {
+        let col = self.check_editable_col(col)?;
+        self.fill_empty(col..col + 1, &default_value)
+    }
+
+    // Same as fill_with_default for every (non-derived) column
+    pub fn fill_all_empty_with(&mut self, default_value: String) -> Result<u64, String> {
+        self.fill_empty(0..self.base_cols as usize, &default_value)
+    }
+
+    // Helper: fill empty cells of `cols` in non-blank rows, in one overlay update
+    fn fill_empty(&mut self, cols: std::ops::Range<usize>, default_value: &str) -> Result<u64, String> {
+        if default_value.is_empty() {
+            return Ok(0);
+        }
+        let mut changes = Vec::new();
+        for row in 0..self.total_rows {
+            let source_row = self.source_row(row);
+            let fields = self.row_fields(source_row);
+            let is_empty = |col: usize| self.normalize_cell(field_or_empty(&fields, col)).is_empty();
+            if (0..self.base_cols as usize).all(is_empty) {
+                continue;
+            }
+            let empty_cols = cols.clone().filter(|&col| is_empty(col));
+            changes.extend(empty_cols.map(|col| (source_row, col, default_value.to_string())));
+        }
+        self.commit_edits(changes)
+    }
+
+    // A schema for every real column from the first sample_size view rows:
+    // the type is what infer_column_types would pick on that sample, and a
+    // column is nullable if the sample has a blank or null-token cell in it.
+    // Names are the canonical ones (see get_header_metadata).
+    pub fn infer_schema(&self, sample_size: usize) -> Vec<ColumnSchema> {
+        let cols = self.base_cols.max(0) as usize;
+        let mut builders = vec![ProfileBuilder::default(); cols];
+        let mut nullable = vec![false; cols];
+        for (_, fields) in self.view_rows(0..self.total_rows.min(sample_size as i64)) {
+            for (col, builder) in builders.iter_mut().enumerate() {
+                let value = field_or_empty(&fields, col);
+                if value.trim().is_empty() || self.is_null_token(col, value) {
+                    nullable[col] = true;
+                } else {
+                    builder.observe(value);
+                }
+            }
+        }
+        let names = self.canonical_names();
+        builders
+            .into_iter()
+            .zip(nullable)
+            .enumerate()
+            .map(|(col, (builder, nullable))| ColumnSchema {
+                col: col as i64,
+                name: names[col].clone(),
+                inferred_type: builder.finish().column_type,
+                nullable,
+            })
+            .collect()
+    }
+
+    // infer_schema(sample_size) as JSON at `path`, for new_from_file_with_schema
+    // (format: SchemaFile)
+    pub fn export_schema_to_json(&self, path: String, sample_size: usize) -> Result<(), String> {
+        let file = SchemaFile { schema_version: SCHEMA_FILE_VERSION, columns: self.infer_schema(sample_size) };
+        let json = serde_json::to_string_pretty(&file).map_err(|e| format!("Failed to encode schema: {}", e))?;
+        std::fs::write(&path, json).map_err(|e| format!("Failed to write schema: {}", e))
+    }
+
+    // Open a CSV and apply_schema a stored schema, skipping inference. Fails
+    // on schema files from a newer format version or naming columns the file
+    // doesn't have. A column whose name no longer matches, and cells that
+    // couldn't be coerced, are reported in get_warnings.
+    pub fn new_from_file_with_schema(csv_path: String, schema_path: String) -> Result<SheetSession, String> {
+        let json = std::fs::read_to_string(&schema_path).map_err(|e| format!("Failed to read schema: {}", e))?;
+        let file: SchemaFile = serde_json::from_str(&json).map_err(|e| format!("Invalid schema file: {}", e))?;
+        if file.schema_version > SCHEMA_FILE_VERSION {
+            return Err(format!(
+                "Schema file version {} is newer than the supported version {}",
+                file.schema_version, SCHEMA_FILE_VERSION
+            ));
+        }
+
+        let mut session = Self::new_from_file(csv_path)?;
+        let names = session.canonical_names();
+        for column in &file.columns {
+            let current = names.get(column.col.max(0) as usize);
+            if column.col >= 0 && current.is_some_and(|name| *name != column.name) {
+                session.warnings.push(format!(
+                    "Schema column {} is named \"{}\" but the file calls it \"{}\"",
+                    column.col, column.name, current.unwrap()
+                ));
+            }
+        }
+        let report = session.apply_schema(file.columns)?;
+        if !report.coercion_errors.is_empty() {
+            session.warnings.push(format!(
+                "{} cells don't match the schema and were left as they are",
+                report.coercion_errors.len()
+            ));
+        }
+        Ok(session)
+    }
+
+    // Coerce every cell to its column's declared type (inferred or
+    // user-overridden) and write the canonical form into the edit overlay.
+    // Cells that can't be coerced are left alone and reported.
+    pub fn apply_schema(&mut self, schema: Vec<ColumnSchema>) -> Result<ApplySchemaReport, String> {
+        for column in &schema {
+            self.check_editable_col(column.col)?;
+        }
+
+        let mut changes = Vec::new();
+        let mut coercion_errors = Vec::new();
+        for row in 0..self.total_rows {
+            let source_row = self.source_row(row);
+            let fields = self.row_fields(source_row);
+            for column in &schema {
+                let col = column.col as usize;
+                let current = field_or_empty(&fields, col);
+                match coerce_cell(current, column.inferred_type) {
+                    Ok(Some(coerced)) if coerced != current => changes.push((source_row, col, coerced)),
+                    Ok(_) => {}
+                    Err(message) if coercion_errors.len() < MAX_REPORTED_ERRORS => {
+                        coercion_errors.push((row, column.col, message));
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+
+        let cells_coerced = self.commit_edits(changes)?;
+        Ok(ApplySchemaReport { cells_coerced, coercion_errors })
+    }
+
+    // Read-only check of every cell against the schema: values apply_schema
+    // couldn't coerce are type errors, and blank or null-token cells are
+    // errors in columns with nullable: false. Columns outside the session
+    // are skipped.
+    pub fn validate_against_schema(&self, schema: Vec<ColumnSchema>) -> ValidationReport {
+        let schema: Vec<&ColumnSchema> = schema.iter().filter(|c| (0..self.total_cols).contains(&c.col)).collect();
+        let mut report = ValidationReport { type_errors: Vec::new(), null_errors: Vec::new(), truncated: false };
+        for (row, fields) in self.view_rows(0..self.total_rows) {
+            for column in &schema {
+                let col = column.col as usize;
+                let value = field_or_empty(&fields, col);
+                if value.trim().is_empty() || self.is_null_token(col, value) {
+                    if !column.nullable {
+                        if report.null_errors.len() < MAX_REPORTED_ERRORS {
+                            report.null_errors.push((row, column.col));
+                        } else {
+                            report.truncated = true;
+                        }
+                    }
+                } else if coerce_cell(value, column.inferred_type).is_err() {
+                    if report.type_errors.len() < MAX_REPORTED_ERRORS {
+                        report.type_errors.push(TypeValidationError {
+                            row,
+                            col: column.col,
+                            expected_type: column.inferred_type,
+                            actual_value: value.to_string(),
+                        });
+                    } else {
+                        report.truncated = true;
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    fn add_derived(&mut self, state: DerivedColumnState) -> Result<i64, String> {
+        let column = self.build_derived(state, &self.derived)?;
+        self.derived.push(column);
+        self.derived_columns_changed();
+        Ok(self.total_cols - 1)
+    }
+
+    // Helper: check `state` against the real columns and the derived columns
+    // it would join, and compile it
+    fn build_derived(&self, state: DerivedColumnState, existing: &[DerivedColumn]) -> Result<DerivedColumn, String> {
+        if state.name.trim().is_empty() {
+            return Err("Derived column name must not be empty".to_string());
+        }
+        if existing.iter().any(|d| d.state.name == state.name) {
+            return Err(format!("A derived column named '{}' already exists", state.name));
+        }
+        let column = DerivedColumn::new(state)?;
+        if let Some(bad) = column.source_cols().into_iter().find(|&c| c < 0 || c >= self.base_cols) {
+            return Err(format!("Source column {} is not a real column", bad));
+        }
+        Ok(column)
+    }
+
+    fn derived_columns_changed(&mut self) {
+        self.total_cols = self.base_cols + self.derived.len() as i64;
+        self.inverted_index = None;
+        self.search_index = None;
+        self.invalidate_analysis();
+    }
+
+    // Helper: only real columns can be edited; derived ones are computed
+    fn check_editable_col(&self, col: i64) -> Result<usize, String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        if col >= self.base_cols {
+            return Err(format!("Column {} is derived and can't be edited", col));
+        }
+        Ok(col as usize)
+    }
+
+    // Helper: write (source_row, col, value) changes into the overlay; returns the count
+    fn commit_edits(&mut self, changes: Vec<(usize, usize, String)>) -> Result<u64, String> {
+        let bytes = changes.iter().map(|(_, _, value)| edit_cost(value)).sum();
+        self.check_budget("edit_overlay", bytes, "save_to_file and reopen the result")?;
+        let changed = changes.len() as u64;
+        for (source_row, col, value) in changes {
+            self.edits.entry(source_row).or_default().insert(col, value);
+        }
+        if changed > 0 {
+            self.inverted_index = None;
+            self.search_index = None;
+            self.invalidate_analysis();
+        }
+        Ok(changed)
+    }
+
+    // 11. RANGE STATS (sum/avg/count for the current selection)
+    // Runs over the session's rows as seen in the grid (row order and edits
+    // applied). Cheap enough for every selection change up to RANGE_STATS_SYNC_LIMIT cells.
+    //
+    // Deadlines: range_stats_full, count_matches and column_unique_values
+    // take an optional deadline_ms. The clock is checked every
+    // DEADLINE_CHECK_ROWS rows; once it has run out the scan stops and the
+    // result says is_partial with rows_scanned. A partial result is exactly
+    // the full computation over the first rows_scanned rows of the scan, in
+    // view order, nothing more: how far a given deadline gets depends on the
+    // machine and the page cache, so two calls may cover different prefixes.
+    // For the exact answer call again without a deadline (async from Dart).
+    #[frb(sync)]
+    pub fn range_stats(&self, row_start: i64, row_count: i64, col_start: i64, col_count: i64) -> RangeStats {
+        let rows = row_count.clamp(0, (self.total_rows - row_start.max(0)).max(0));
+        let cols = col_count.clamp(0, (self.total_cols - col_start.max(0)).max(0));
+        if rows.saturating_mul(cols) > RANGE_STATS_SYNC_LIMIT {
+            return RangeStats { exceeds_sync_limit: true, ..RangeStats::empty() };
+        }
+        self.range_stats_full(row_start, row_count, col_start, col_count, None)
+    }
+
+    // Same as range_stats without the size cap (call it async from Dart, or
+    // with a deadline)
+    pub fn range_stats_full(
+        &self,
+        row_start: i64,
+        row_count: i64,
+        col_start: i64,
+        col_count: i64,
+        deadline_ms: Option<i64>,
+    ) -> RangeStats {
+        let row_start = row_start.max(0);
+        let row_end = row_start.saturating_add(row_count.max(0)).min(self.total_rows);
+        let col_start = col_start.max(0);
+        let col_end = col_start.saturating_add(col_count.max(0)).min(self.total_cols);
+
+        let deadline = Deadline::after_ms(deadline_ms);
+        let mut stats = RangeStats::empty();
+        for (_, fields) in self.view_rows(row_start..row_end) {
+            if deadline.passed(stats.rows_scanned) {
+                stats.is_partial = true;
+                break;
+            }
+            for col in col_start..col_end {
+                stats.add(field_or_empty(&fields, col as usize));
+            }
+            stats.rows_scanned += 1;
+        }
+        stats.mean = (stats.numeric_count > 0).then(|| stats.sum / stats.numeric_count as f64);
+        stats
+    }
+
+    // 11b. RANGE STATS OVER A MULTI-RANGE SELECTION
+    // Same numbers as range_stats over the union of the ranges: overlapping
+    // cells count once. Same size cap (on distinct cells); rows_scanned
+    // counts the distinct rows read.
+    #[frb(sync)]
+    pub fn multi_range_stats(&self, ranges: Vec<CellRange>) -> RangeStats {
+        let bands = range_union(&self.clamp_ranges(&ranges));
+        if union_cell_count(&bands) > RANGE_STATS_SYNC_LIMIT {
+            return RangeStats { exceeds_sync_limit: true, ..RangeStats::empty() };
+        }
+
+        let mut stats = RangeStats::empty();
+        for band in &bands {
+            for (_, fields) in self.view_rows(band.start..band.end) {
+                for &(col_start, col_end) in &band.spans {
+                    for col in col_start..col_end {
+                        stats.add(field_or_empty(&fields, col as usize));
+                    }
+                }
+                stats.rows_scanned += 1;
+            }
+        }
+        stats.mean = (stats.numeric_count > 0).then(|| stats.sum / stats.numeric_count as f64);
+        stats
+    }
+
+    // Helper: ranges clamped to the session as (row_start, row_end, col_start,
+    // col_end), empty ones dropped, sorted top-to-bottom then left-to-right
+    fn clamp_ranges(&self, ranges: &[CellRange]) -> Vec<(i64, i64, i64, i64)> {
+        let mut rects: Vec<(i64, i64, i64, i64)> = ranges
+            .iter()
+            .map(|range| {
+                let row_start = range.row_start.max(0);
+                let col_start = range.col_start.max(0);
+                (
+                    row_start,
+                    row_start.saturating_add(range.row_count.max(0)).min(self.total_rows),
+                    col_start,
+                    col_start.saturating_add(range.col_count.max(0)).min(self.total_cols),
+                )
+            })
+            .filter(|&(r0, r1, c0, c1)| r0 < r1 && c0 < c1)
+            .collect();
+        rects.sort_unstable();
+        rects
+    }
+
+    // 12. FILE FINGERPRINT
+    // MD5 of the whole mapped file: fast, not cryptographic. Two sessions with
+    // the same fingerprint are byte-identical, so it works as a cache key and
+    // lets the UI notice that the file changed since it was last opened.
+    pub fn compute_md5_fingerprint(&self) -> String {
+        self.fingerprint
+            .get_or_init(|| format!("{:x}", md5::compute(&self.content[..])))
+            .clone()
+    }
+
+    // 13. NAMED VIEWS
+    // A view captures the row order, normalization and the UI's column layout
+    // and scroll anchor (passed in, since only Dart knows them).
+    pub fn save_view(
+        &mut self,
+        name: String,
+        scroll_anchor_row: i64,
+        col_start: i64,
+        visible_columns: Vec<i64>,
+    ) -> Result<(), String> {
+        if name.trim().is_empty() {
+            return Err("View name must not be empty".to_string());
+        }
+        let order_bytes = self.row_order.as_ref().map_or(0, |o| o.len() * std::mem::size_of::<i64>());
+        self.check_budget("views", order_bytes, "delete views you no longer need")?;
+        let view = SavedView {
+            scroll_anchor_row,
+            col_start,
+            visible_columns,
+            row_order: self.row_order.clone(),
+            reverse_order: self.reverse_order,
+            normalize: self.normalize.clone(),
+        };
+        self.views.insert(name, view);
+        Ok(())
+    }
+
+    pub fn list_views(&self) -> Vec<String> {
+        self.views.keys().cloned().collect()
+    }
+
+    pub fn delete_view(&mut self, name: String) -> Result<(), String> {
+        self.views
+            .remove(&name)
+            .map(|_| ())
+            .ok_or_else(|| format!("No view named '{}'", name))
+    }
+
+    // Restores what still makes sense for the current file and reports the rest
+    // (e.g. a view saved before the file shrank).
+    pub fn apply_view(&mut self, name: String) -> Result<ViewRestoreReport, String> {
+        let view = self
+            .views
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| format!("No view named '{}'", name))?;
+        let mut problems = Vec::new();
+
+        let indexed_rows = self.natural_rows() as i64;
+        let (first, end) = (self.row_base as i64, self.row_end as i64);
+        match view.row_order {
+            Some(order) if order.iter().any(|&r| r < first || r >= end) => {
+                problems.push("Row order refers to rows that no longer exist; kept file order".to_string());
+                self.row_order = None;
+                self.total_rows = indexed_rows;
+            }
+            Some(order) => {
+                self.total_rows = order.len() as i64;
+                self.row_order = Some(order);
+            }
+            None => {
+                self.row_order = None;
+                self.total_rows = indexed_rows;
+            }
+        }
+        self.filter_base = None; // The view's row order is the new unfiltered base
+        self.reverse_order = view.reverse_order;
+        self.normalize = view.normalize;
+        self.invalidate_analysis();
+
+        let (visible_columns, dropped): (Vec<i64>, Vec<i64>) = view
+            .visible_columns
+            .into_iter()
+            .partition(|&c| c >= 0 && c < self.total_cols);
+        if !dropped.is_empty() {
+            problems.push(format!("Columns {:?} are out of range and were dropped", dropped));
+        }
+
+        let max_row = (self.total_rows - 1).max(0);
+        let scroll_anchor_row = view.scroll_anchor_row.clamp(0, max_row);
+        if scroll_anchor_row != view.scroll_anchor_row {
+            problems.push(format!("Scroll position {} is past the end; moved to {}", view.scroll_anchor_row, scroll_anchor_row));
+        }
+
+        let max_col = (self.total_cols - 1).max(0);
+        let col_start = view.col_start.clamp(0, max_col);
+        if col_start != view.col_start {
+            problems.push(format!("Column position {} is out of range; moved to {}", view.col_start, col_start));
+        }
+
+        Ok(ViewRestoreReport { scroll_anchor_row, col_start, visible_columns, problems })
+    }
+
+    // 14. SESSION STATE (JSON, persisted by the app per file fingerprint)
+    pub fn export_state(&self) -> Result<String, String> {
+        let state = SessionState {
+            version: SESSION_STATE_VERSION,
+            views: self.views.clone(),
+            derived_columns: self.derived.iter().map(|d| d.state.clone()).collect(),
+            query_history: self.query_history.iter().cloned().collect(),
+            row_tags: self.row_tags.iter().map(|(tag, rows)| (tag.clone(), rows.clone())).collect(),
+        };
+        serde_json::to_string(&state).map_err(|e| format!("Failed to export state: {}", e))
+    }
+
+    // All or nothing: if any part is invalid the session is left as it was
+    pub fn import_state(&mut self, json: String) -> Result<(), String> {
+        let state: SessionState =
+            serde_json::from_str(&json).map_err(|e| format!("Invalid session state: {}", e))?;
+        self.apply_state(state)
+    }
+
+    // Helper: import_state once the JSON is parsed
+    fn apply_state(&mut self, state: SessionState) -> Result<(), String> {
+        if state.version > SESSION_STATE_VERSION {
+            return Err(format!("Session state version {} is newer than supported ({})", state.version, SESSION_STATE_VERSION));
+        }
+        let mut derived = Vec::with_capacity(state.derived_columns.len());
+        for column in state.derived_columns {
+            derived.push(self.build_derived(column, &derived)?);
+        }
+
+        self.views = state.views;
+        self.derived = derived;
+        self.derived_columns_changed();
+        let skip = state.query_history.len().saturating_sub(QUERY_HISTORY_CAP);
+        self.query_history = state.query_history.into_iter().skip(skip).collect();
+        self.row_tags = state
+            .row_tags
+            .into_iter()
+            .map(|(tag, mut rows)| {
+                rows.sort_unstable();
+                rows.dedup();
+                (tag, rows)
+            })
+            .collect();
+        self.refresh_tag_digests();
+        Ok(())
+    }
+
+    // 15. EXISTENCE CHECKS ("would filtering to this value find anything?")
+    // Contract: a single top-to-bottom pass that returns on the first match.
+    // Rows are never sorted on disk (a sorted view is only a row order), so
+    // there is no binary search; a value near the top answers instantly, a
+    // missing value costs a full column scan.
+    pub fn column_contains(&self, col: i64, value: String, options: NormalizeOptions) -> bool {
+        if col < 0 || col >= self.total_cols { return false; }
+        let col = col as usize;
+        let needle = normalize_with(&options, &value).into_owned();
+        let mut trace = OpTrace::start("column_contains", || format!("col {}", col));
+
+        let mut scanned = 0;
+        let found = self.column_cells(col, 0..self.total_rows).any(|(_, cell)| {
+            scanned += 1;
+            normalize_with(&options, &cell) == needle.as_str()
+        });
+        trace.touched(scanned, || self.view_bytes(0..scanned));
+        found
+    }
+
+    // Batch version: one pass for all values (session normalization), stopping
+    // as soon as every value has been seen. Same no-binary-search contract.
+    pub fn column_contains_many(&self, col: i64, values: Vec<String>) -> Vec<bool> {
+        let mut found = vec![false; values.len()];
+        if col < 0 || col >= self.total_cols { return found; }
+        let col = col as usize;
+
+        // normalized value -> positions in `values` (duplicates allowed)
+        let mut pending: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, value) in values.iter().enumerate() {
+            pending.entry(self.normalize_cell(value).into_owned()).or_default().push(i);
+        }
+
+        let mut trace = OpTrace::start("column_contains_many", || format!("col {}, {} values", col, values.len()));
+        let mut scanned = 0;
+        for (_, cell) in self.column_cells(col, 0..self.total_rows) {
+            if pending.is_empty() { break; }
+            scanned += 1;
+            let cell = self.normalize_cell(&cell);
+            if let Some(positions) = pending.remove(cell.as_ref()) {
+                for i in positions {
+                    found[i] = true;
+                }
+            }
+        }
+        trace.touched(scanned, || self.view_bytes(0..scanned));
+        found
+    }
+
+    // 16. ROW SET OPERATIONS (keyed on key_cols, exact values)
+    // Results are new in-memory sessions; neither input is modified.
+
+    // All rows of self, plus rows of other whose key isn't in self (each new key once)
+    pub fn union(&self, other: &SheetSession, key_cols: Vec<i64>) -> Result<SheetSession, String> {
+        self.check_same_cols(other)?;
+        let key_cols = self.check_key_cols(other, &key_cols)?;
+        let mut seen = self.collect_keys(&key_cols);
+
+        let mut rows = Vec::new();
+        for row in 0..other.total_rows {
+            if seen.insert(other.row_key(row, &key_cols)) {
+                rows.push(row);
+            }
+        }
+
+        let mut out = Vec::new();
+        self.append_records(&mut out, 0..self.total_rows)?;
+        other.append_records(&mut out, rows)?;
+        Self::from_records(out)
+    }
+
+    // Rows of self whose key also appears in other
+    pub fn intersection(&self, other: &SheetSession, key_cols: Vec<i64>) -> Result<SheetSession, String> {
+        self.check_same_cols(other)?;
+        let key_cols = self.check_key_cols(other, &key_cols)?;
+        let other_keys = other.collect_keys(&key_cols);
+
+        let rows = (0..self.total_rows).filter(|&row| other_keys.contains(&self.row_key(row, &key_cols)));
+        let mut out = Vec::new();
+        self.append_records(&mut out, rows)?;
+        Self::from_records(out)
+    }
+
+    // Rows of self whose key does NOT appear in other, i.e. SQL
+    // LEFT JOIN ... WHERE other.key IS NULL ("rows new since yesterday's file").
+    // Column counts may differ; only the key columns must exist in both.
+    pub fn anti_join(&self, other: &SheetSession, key_cols: Vec<i64>) -> Result<SheetSession, String> {
+        let key_cols = self.check_key_cols(other, &key_cols)?;
+        let other_keys = other.collect_keys(&key_cols);
+
+        let rows = (0..self.total_rows).filter(|&row| !other_keys.contains(&self.row_key(row, &key_cols)));
+        let mut out = Vec::new();
+        self.append_records(&mut out, rows)?;
+        Self::from_records(out)
+    }
+
+    // Joins: each output row is all of self's columns followed by other's
+    // columns minus its key columns. Keys compare exactly, like the set ops.
+
+    // One row per matching (self, other) pair; duplicate keys in other fan out.
+    pub fn inner_join(
+        &self,
+        other: &SheetSession,
+        left_key: Vec<i64>,
+        right_key: Vec<i64>,
+    ) -> Result<SheetSession, String> {
+        self.join(other, &left_key, &right_key, false)
+    }
+
+    // Like inner_join, but rows of self without a match are kept too, with
+    // other's columns left empty.
+    pub fn left_join(
+        &self,
+        other: &SheetSession,
+        left_key: Vec<i64>,
+        right_key: Vec<i64>,
+    ) -> Result<SheetSession, String> {
+        self.join(other, &left_key, &right_key, true)
+    }
+
+    // 17. DERIVED COLUMNS
+    // Virtual columns after the real ones, computed lazily for whatever rows are
+    // read (grid, stats, exports). They're part of export_state, read-only, and
+    // saving the session writes their values out like any other column.
+
+    // Regex extraction: capture group `group_index` of `pattern` over the source
+    // cell; non-matching cells are empty. Returns the new column's index.
+    pub fn add_derived_column(
+        &mut self,
+        name: String,
+        source_col: i64,
+        pattern: String,
+        group_index: i64,
+    ) -> Result<i64, String> {
+        self.add_derived(DerivedColumnState {
+            name,
+            spec: DerivedSpec::RegexExtract { source_col, pattern, group_index },
+        })
+    }
+
+    // Concatenation of real columns and literal text. Returns the new column's index.
+    pub fn add_concat_column(&mut self, name: String, parts: Vec<ConcatPart>) -> Result<i64, String> {
+        self.add_derived(DerivedColumnState { name, spec: DerivedSpec::Concat { parts } })
+    }
+
+    // Hash of `cols` per row (values as the grid shows them, NUL-separated so
+    // "a","bc" and "ab","c" differ), for dedup keys and provenance checks.
+    // Returns the new column's index.
+    pub fn generate_row_hash_column(
+        &mut self,
+        cols: Vec<i64>,
+        algorithm: HashAlgorithm,
+        new_col_name: String,
+    ) -> Result<i64, String> {
+        self.add_derived(DerivedColumnState { name: new_col_name, spec: DerivedSpec::RowHash { cols, algorithm } })
+    }
+
+    // Only the name changes: the column keeps its index and values
+    pub fn rename_derived_column(&mut self, name: String, new_name: String) -> Result<(), String> {
+        if new_name.trim().is_empty() {
+            return Err("Derived column name must not be empty".to_string());
+        }
+        if new_name != name && self.derived.iter().any(|d| d.state.name == new_name) {
+            return Err(format!("A derived column named '{}' already exists", new_name));
+        }
+        let column = self
+            .derived
+            .iter_mut()
+            .find(|d| d.state.name == name)
+            .ok_or_else(|| format!("No derived column named '{}'", name))?;
+        column.state.name = new_name;
+        Ok(())
+    }
+
+    pub fn remove_derived_column(&mut self, name: String) -> Result<(), String> {
+        let pos = self
+            .derived
+            .iter()
+            .position(|d| d.state.name == name)
+            .ok_or_else(|| format!("No derived column named '{}'", name))?;
+        self.derived.remove(pos);
+        self.derived_columns_changed();
+        Ok(())
+    }
+
+    pub fn list_derived_columns(&self) -> Vec<String> {
+        self.derived.iter().map(|d| d.state.name.clone()).collect()
+    }
+
+    // Turns derived column `col` into a real one, so reads stop evaluating
+    // it: its value for every row of the file (filtered-out rows too) goes
+    // into the edit overlay, and it becomes the last real column (named in
+    // header_names if the session has them; derived columns before it move
+    // one to the right). The wider column count is pinned as the override.
+    // Like any edit it counts against the memory budget and is lost on
+    // reopen unless saved. Refused if a row has fields past the real
+    // columns, which it would overwrite. Returns the rows written.
+    pub fn materialise_column(&mut self, col: i64) -> Result<u64, String> {
+        if col < self.base_cols || col >= self.total_cols {
+            return Err(format!("Column {} is not a derived column", col));
+        }
+        if self.col_window.is_some() {
+            return Err("Columns can't be materialised in a sub view".to_string());
+        }
+        let base = self.base_cols as usize;
+        let pos = col as usize - base;
+
+        let mut changes = Vec::with_capacity(self.row_end - self.row_base);
+        for row in self.row_base..self.row_end {
+            let fields = self.source_fields(row);
+            if fields.len() > base {
+                return Err(format!(
+                    "Row {} has fields past the {} real columns, which the column would overwrite",
+                    row, base
+                ));
+            }
+            changes.push((row, base, self.derived[pos].evaluate(&fields)));
+        }
+        let written = self.commit_edits(changes)?;
+
+        let column = self.derived.remove(pos);
+        if !self.header_names.is_empty() {
+            self.header_names.resize(base, String::new());
+            self.header_names.push(column.state.name);
+        }
+        if let Some(order) = &mut self.col_order {
+            order.to_file.push(base);
+            order.to_session.push(base);
+        }
+        self.cols_override = Some(self.base_cols + 1);
+        self.resolve_total_cols();
+        self.derived_columns_changed();
+        Ok(written)
+    }
+
+    // 18. FILTERS
+    // Conditions are ANDed. A filter is just a row order over the rows that
+    // matched, so scrolling, stats, saving etc. all see the filtered rows.
+
+    // Matching logical rows of the current view, without changing the session
+    pub fn filter_rows(&self, conditions: Vec<FilterCondition>) -> Result<Vec<i64>, String> {
+        let mut trace = OpTrace::start("filter_rows", || format!("{} conditions", conditions.len()));
+        let filter = self.compile_filter(conditions)?;
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        Ok((0..self.total_rows)
+            .filter(|&row| self.row_passes(self.source_row(row), &filter))
+            .collect())
+    }
+
+    // How many rows filter_rows would return, optionally time-boxed (see
+    // RANGE STATS for how deadlines and partial results work)
+    pub fn count_matches(
+        &self,
+        conditions: Vec<FilterCondition>,
+        deadline_ms: Option<i64>,
+    ) -> Result<MatchCount, String> {
+        let filter = self.compile_filter(conditions)?;
+        let deadline = Deadline::after_ms(deadline_ms);
+        let mut result = MatchCount { count: 0, rows_scanned: 0, total_rows: self.total_rows, is_partial: false };
+        for row in 0..self.total_rows {
+            if deadline.passed(result.rows_scanned) {
+                result.is_partial = true;
+                break;
+            }
+            if self.row_passes(self.source_row(row), &filter) {
+                result.count += 1;
+            }
+            result.rows_scanned += 1;
+        }
+        Ok(result)
+    }
+
+    // Show only the matching rows. Replaces any earlier filter rather than
+    // narrowing it; returns how many rows are left.
+    pub fn apply_filter(&mut self, conditions: Vec<FilterCondition>) -> Result<i64, String> {
+        let mut trace = OpTrace::start("apply_filter", || format!("{} conditions", conditions.len()));
+        let recorded: Vec<RecordedCondition> = conditions.iter().map(|c| self.record_condition(c)).collect();
+        let filter = self.compile_filter(conditions)?;
+        self.clear_filter();
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+
+        // The match count isn't known up front, so the budget is checked as the order grows
+        let mut order: Vec<i64> = Vec::new();
+        for pos in 0..self.total_rows as usize {
+            let row = self.ordered_row(pos);
+            if !self.row_passes(row, &filter) { continue; }
+            if order.len() == order.capacity() {
+                let grow = order.capacity().max(1024) * std::mem::size_of::<i64>();
+                self.check_budget("row_order", grow, "narrow the filter or truncate_to_rows first")?;
+                order.reserve(order.capacity().max(1024));
+            }
+            order.push(row as i64);
+        }
+        self.filter_base = Some(self.row_order.take());
+        self.total_rows = order.len() as i64;
+        self.row_order = Some(order);
+        self.invalidate_analysis();
+        self.record_query(recorded);
+        Ok(self.total_rows)
+    }
+
+    // Back to the rows (and order) from before apply_filter
+    pub fn clear_filter(&mut self) {
+        if let Some(order) = self.filter_base.take() {
+            self.total_rows = order.as_ref().map_or(self.natural_rows(), Vec::len) as i64;
+            self.row_order = order;
+            self.invalidate_analysis();
+        }
+    }
+
+    // 19. MANIFEST (machine-readable description of the sheet, as JSON)
+    // Types always come from the TYPE_SAMPLE_ROWS sample. Completeness counts
+    // and sample values cover the same sample unless full_scan is set; the
+    // manifest's *_exact flags say which numbers are estimates.
+    pub fn export_manifest(&self, sample_values: i64, full_scan: bool) -> Result<String, String> {
+        let sample_values = sample_values.max(0) as usize;
+        let scan_rows = if full_scan { self.total_rows } else { self.total_rows.min(TYPE_SAMPLE_ROWS) };
+        let all_cols = self.total_cols.min(i32::MAX as i64) as i32;
+        let names = self.get_header_chunk(0, all_cols);
+        let types = self.infer_column_types(0, all_cols);
+
+        let mut columns: Vec<ManifestColumn> = names
+            .into_iter()
+            .zip(types)
+            .enumerate()
+            .map(|(col, (name, inferred_type))| ManifestColumn {
+                index: col as i64,
+                name,
+                inferred_type,
+                non_empty: 0,
+                empty: 0,
+                null_like: 0,
+                sample_values: Vec::new(),
+            })
+            .collect();
+
+        let task = self.begin_task(scan_rows);
+        for (row, fields) in self.view_rows(0..scan_rows) {
+            for (col, column) in columns.iter_mut().enumerate() {
+                let value = field_or_empty(&fields, col);
+                if value.trim().is_empty() {
+                    column.empty += 1;
+                    continue;
+                }
+                if self.is_null_token(col, value) {
+                    column.null_like += 1;
+                    continue;
+                }
+                column.non_empty += 1;
+                if column.sample_values.len() < sample_values && !column.sample_values.iter().any(|v| v == value) {
+                    column.sample_values.push(value.to_string());
+                }
+            }
+            task.report(row + 1);
+        }
+
+        let manifest = Manifest {
+            path: (!self.path.as_os_str().is_empty()).then(|| self.path.to_string_lossy().into_owned()),
+            size_bytes: self.content.len() as u64,
+            fingerprint: self.compute_md5_fingerprint(),
+            delimiter: ",",
+            line_terminator: str::from_utf8(self.terminator.as_bytes()).unwrap_or("\n"),
+            encoding: "utf-8", // Lines that aren't valid UTF-8 are read as Windows-1252
+            row_count: self.total_rows,
+            column_count: self.total_cols,
+            rows_scanned: scan_rows,
+            types_exact: self.total_rows <= TYPE_SAMPLE_ROWS,
+            counts_exact: scan_rows == self.total_rows,
+            columns,
+        };
+        serde_json::to_string_pretty(&manifest).map_err(|e| format!("Failed to encode manifest: {}", e))
+    }
+
+    // 20. SAMPLE SUMMARY
+    // Everything comes from one seeded random sample of sample_size rows (all
+    // rows if the view is smaller), so the dialog is stable between opens.
+    // Past SUMMARY_UNIQUE_CAP distinct values new values aren't tracked, which
+    // makes top_values approximate for very high-cardinality columns.
+    pub fn sample_and_describe(&self, sample_size: usize) -> SummaryReport {
+        let rows = self.sample_rows(sample_size);
+        let all_cols = self.total_cols.min(i32::MAX as i64) as i32;
+        let names = self.get_header_chunk(0, all_cols);
+        let mut builders = vec![SummaryBuilder::default(); names.len()];
+
+        let task = self.begin_task(rows.len() as i64);
+        for (done, (_, fields)) in self.view_rows(rows.iter().copied()).enumerate() {
+            for (col, builder) in builders.iter_mut().enumerate() {
+                let value = field_or_empty(&fields, col);
+                if self.is_null_token(col, value) {
+                    builder.null_like += 1;
+                } else {
+                    builder.observe(value);
+                }
+            }
+            task.report(done as i64 + 1);
+        }
+
+        let sampled_rows = rows.len() as i64;
+        SummaryReport {
+            total_rows: self.total_rows,
+            sampled_rows,
+            columns: names
+                .into_iter()
+                .zip(builders)
+                .enumerate()
+                .map(|(col, (name, builder))| builder.finish(col as i64, name, sampled_rows))
+                .collect(),
+        }
+    }
+
+    // 21. HEADER ROW
+    // For files without a header: names (one per real column) replace the
+    // letters in get_header_chunk, and save_to_file writes them as the first
+    // row. Data rows are untouched, so row 0 is still the file's first line.
+    // Files that have one are opened with OpenOptions.first_row_header.
+    pub fn write_csv_header(&mut self, names: Vec<String>) -> Result<(), String> {
+        if !self.header_names.is_empty() {
+            return Err("The sheet already has a header row".to_string());
+        }
+        if names.len() as i64 != self.base_cols {
+            return Err(format!(
+                "Expected {} header names (one per column), got {}", self.base_cols, names.len()
+            ));
+        }
+        self.header_names = names;
+        self.synthetic_header = true;
+        Ok(())
+    }
+
+    // Raw and canonical (unique) names for a range of columns, derived ones
+    // included. Name-based APIs (resolve_column, query replay, JSON keys) use
+    // the canonical names; CSV, HTML and Markdown headers keep the raw ones.
+    pub fn get_header_metadata(&self, col_start: i64, col_count: i32) -> Vec<HeaderName> {
+        let raw = self.get_header_chunk(0, self.total_cols.min(i32::MAX as i64) as i32);
+        let canonical = unique_names(&raw);
+        let first = col_start.clamp(0, raw.len() as i64) as usize;
+        raw.into_iter()
+            .zip(canonical)
+            .skip(first)
+            .take(col_count.max(0) as usize)
+            .map(|(raw, canonical)| HeaderName { raw, canonical })
+            .collect()
+    }
+
+    // Column for a name: an exact canonical name wins, otherwise names are
+    // compared ignoring case and surrounding whitespace, which can match
+    // several columns ("Amount" and "amount").
+    pub fn resolve_column(&self, name: String) -> Result<i64, ColumnLookupError> {
+        let names = self.canonical_names();
+        if let Some(col) = names.iter().position(|n| *n == name) {
+            return Ok(col as i64);
+        }
+        let wanted = name.trim().to_lowercase();
+        let candidates: Vec<i64> = names
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.trim().to_lowercase() == wanted)
+            .map(|(col, _)| col as i64)
+            .collect();
+        match candidates.as_slice() {
+            [] => Err(ColumnLookupError::NotFound),
+            [col] => Ok(*col),
+            _ => Err(ColumnLookupError::Ambiguous { candidates }),
+        }
+    }
+
+    // Helper: whether exports start with a header line (write_csv_header or
+    // OpenOptions.first_row_header)
+    fn has_header_row(&self) -> bool {
+        !self.header_names.is_empty()
+    }
+
+    // Helper: canonical name of every column
+    fn canonical_names(&self) -> Vec<String> {
+        unique_names(&self.get_header_chunk(0, self.total_cols.min(i32::MAX as i64) as i32))
+    }
+
+    // Undo write_csv_header: back to letters, and saving writes no header
+    pub fn remove_header_row(&mut self) -> Result<(), String> {
+        if !self.synthetic_header {
+            return Err("The sheet has no added header row to remove".to_string());
+        }
+        self.header_names.clear();
+        self.synthetic_header = false;
+        Ok(())
+    }
+
+    // 22. MEMORY BUDGET
+    // A soft ceiling on what the session allocates itself (row index, row
+    // orders, edit overlay, saved views, caches); mapped file pages are the
+    // OS's business and aren't counted. Operations that would cross it first
+    // evict caches, then fail with "Memory budget exceeded: <component> ...".
+    // Opening degrades instead where it can: a row index over the budget is
+    // kept sparse (see RowIndex), and only what can't shrink fails, with
+    // SheetErrorKind::MemoryBudgetExceeded naming it.
+    // Lowering the budget below current use only evicts caches; nothing
+    // already built is dropped.
+    pub fn set_memory_budget(&mut self, bytes: Option<i64>) -> Result<(), String> {
+        self.memory_budget = bytes.map(check_memory_budget).transpose()?;
+        if self.budget_left(&self.analysis.lock().unwrap()).is_some_and(|left| left == 0) {
+            self.invalidate_analysis();
+        }
+        Ok(())
+    }
+
+    pub fn get_session_info(&self) -> SessionInfo {
+        let usage = self.memory_usage(&self.analysis.lock().unwrap());
+        SessionInfo {
+            path: self.path.to_string_lossy().into_owned(),
+            path_bytes: path_bytes(&self.path),
+            total_rows: self.total_rows,
+            total_cols: self.total_cols,
+            line_terminator: self.terminator,
+            memory_budget: self.memory_budget.map(|b| b as i64),
+            memory_used: usage.iter().map(|(_, bytes)| *bytes as i64).sum(),
+            memory: usage
+                .into_iter()
+                .map(|(component, bytes)| MemoryUsage { component: component.to_string(), bytes: bytes as i64 })
+                .collect(),
+            responses_truncated: self.responses_truncated.load(Ordering::Relaxed) as i64,
+            padded_cells_exported: self.padded_cells_exported.load(Ordering::Relaxed) as i64,
+            merge_delimiter_rows: *self.merge_delimiter_rows.get_or_init(|| {
+                let rows = self.row_base..self.row_end.min(self.row_base + WIDTH_SCAN_ROWS);
+                rows.map(|row| self.row_bytes(row))
+                    .filter(|line| !line.is_empty() && count_fields(line, false) != count_fields(line, true))
+                    .count() as i64
+            }),
+            backend: match &*self.content {
+                FileContent::Mapped(_) => StorageBackend::Mapped,
+                FileContent::Buffered(_) => StorageBackend::Buffered,
+            },
+            backend_reason: self.backend_reason.clone(),
+            detached: self.detached.clone(),
+            cols_estimate: self.actual_total_cols(),
+            cols_exact: self.exact_cols.get().is_some(),
+            generation: self.changes.lock().unwrap().len() as i64,
+        }
+    }
+
+    // 23. ENCODING REPAIR
+    // Rewrites invalid UTF-8 in a buffered copy of the file (the file on disk
+    // is untouched; save_to_file writes the repaired bytes). Row boundaries
+    // stay put, so the row order, edits and views still line up. Returns how
+    // many invalid bytes were fixed anywhere in the file (rows dropped by
+    // truncate_to_rows included); with none found nothing is copied.
+    pub fn detect_and_fix_encoding(&mut self, strategy: EncodingFixStrategy) -> Result<u64, String> {
+        if let EncodingFixStrategy::Replace(text) = &strategy {
+            if text.contains(['\n', '\r', ',', '"']) {
+                return Err("Replacement must not contain newlines, commas or quotes".to_string());
+            }
+        }
+        if str::from_utf8(&self.content).is_ok() {
+            return Ok(0);
+        }
+        self.check_budget("buffer", self.content.len(), "fix the file with an external tool")?;
+
+        let mut fixed_bytes = 0u64;
+        let mut out = Vec::with_capacity(self.content.len());
+        for chunk in self.content.utf8_chunks() {
+            out.extend_from_slice(chunk.valid().as_bytes());
+            let invalid = chunk.invalid();
+            if invalid.is_empty() { continue; }
+            fixed_bytes += invalid.len() as u64;
+            match &strategy {
+                EncodingFixStrategy::Remove => {}
+                EncodingFixStrategy::Replace(text) => out.extend_from_slice(text.as_bytes()),
+                EncodingFixStrategy::Latin1Decode => {
+                    for &byte in invalid {
+                        let mut buf = [0u8; 2];
+                        out.extend_from_slice(char::from(byte).encode_utf8(&mut buf).as_bytes());
+                    }
+                }
+            }
+        }
+
+        // Same rows, new byte offsets (row_base/row_end stay valid)
+        let (rows, stride) = (self.row_offsets.len(), self.row_offsets.stride);
+        self.row_offsets = Arc::new(RowIndex::build(&out, self.terminator.index_byte(), Some(rows), stride));
+        self.content = Arc::new(FileContent::Buffered(out));
+        self.backend_reason = "buffered copy with repaired encoding".to_string();
+        self.fingerprint = OnceLock::new();
+        self.widest_row = OnceLock::new();
+        self.exact_cols = OnceLock::new();
+        self.merge_delimiter_rows = OnceLock::new();
+        self.inverted_index = None;
+        self.search_index = None;
+        self.invalidate_analysis();
+        self.refresh_tag_digests();
+        Ok(fixed_bytes)
+    }
+
+    // 24. ROW DIAGNOSTICS
+    // Runs the grid's splitter over the raw line and reports what it saw.
+    // Edited cells are listed, but ranges and counts describe the file's bytes
+    // (the whole line, even in a sub view).
+    pub fn get_row_diagnostics(&self, row: i64) -> Result<RowDiagnostics, String> {
+        if row < 0 || row >= self.total_rows {
+            return Err(format!("Row {} is out of range", row));
+        }
+        let source_row = self.source_row(row);
+        let raw = self.row_bytes(source_row);
+        let had_cr = self.raw_line(source_row).last() == Some(&b'\r');
+        let line = String::from_utf8_lossy(raw);
+        let had_invalid_utf8 = matches!(line, Cow::Owned(_));
+
+        let ranges = if raw.is_empty() { Vec::new() } else { fields_with_raw_ranges(raw, self.merge_delimiters).1 };
+        let field_count = ranges.len();
+
+        let mut warnings = Vec::new();
+        if self.col_window.is_none() && field_count as i64 != self.base_cols {
+            let fix = if (field_count as i64) < self.base_cols { "padded with empty cells" } else { "extra fields are hidden" };
+            warnings.push(format!("Row has {} fields but the sheet has {} columns; {}", field_count, self.base_cols, fix));
+        }
+        for (i, &(start, end)) in ranges.iter().enumerate() {
+            let field = &raw[start..end];
+            if field.first() == Some(&b'"') {
+                if let Some(problem) = quote_problem(field) {
+                    warnings.push(format!("Field {}: {}", i, problem));
+                }
+            } else if field.contains(&b'"') {
+                warnings.push(format!("Field {}: quote in an unquoted field is kept as is", i));
+            }
+        }
+        if had_invalid_utf8 {
+            warnings.push("Invalid UTF-8 is shown as \u{FFFD}".to_string());
+        }
+
+        let mut edited_cols: Vec<i64> = self
+            .edits
+            .get(&source_row)
+            .map(|cols| cols.keys().map(|&c| c as i64).collect())
+            .unwrap_or_default();
+        edited_cols.sort_unstable();
+
+        Ok(RowDiagnostics {
+            row,
+            source_row: source_row as i64,
+            raw_byte_len: raw.len() as i64,
+            field_count: field_count as i64,
+            field_byte_ranges: ranges.into_iter().map(|(s, e)| (s as i64, e as i64)).collect(),
+            had_quotes: raw.contains(&b'"'),
+            had_cr,
+            had_invalid_utf8,
+            is_edited: !edited_cols.is_empty(),
+            edited_cols,
+            warnings,
+        })
+    }
+
+    // 25. SORT
+    // Keys are applied in order, each breaking ties left by the previous ones,
+    // and the sort is stable: rows still tied keep their current order. The
+    // result is a row order like shuffle_rows (a filter stays in place).
+    pub fn sort_rows(&mut self, keys: Vec<SortKey>) -> Result<(), String> {
+        let rules = self.sort_rules(keys)?;
+        let mut trace = OpTrace::start("sort_rows", || format!("{} keys", rules.len()));
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        let n = self.total_rows as usize;
+        let key_bytes = n * sort_row_bytes(rules.len());
+        self.check_budget("row_order", key_bytes, "filter or truncate_to_rows before sorting, or use sort_to_file")?;
+
+        let task = self.begin_task(n as i64);
+        let mut decorated: Vec<(i64, Vec<SortValue>)> = Vec::with_capacity(n);
+        for pos in 0..n {
+            let row = self.ordered_row(pos);
+            decorated.push((row as i64, self.sort_values(&rules, row)));
+            task.report(pos as i64 + 1);
+        }
+        drop(task);
+
+        decorated.sort_by(|a, b| compare_sort_values(&rules, &a.1, &b.1));
+        self.row_order = Some(decorated.into_iter().map(|(row, _)| row).collect());
+        self.invalidate_analysis();
+        Ok(())
+    }
+
+    // The view sorted into a new CSV at `path` (written like save_to_file),
+    // for when sort_rows' row order won't fit the memory budget: rows are
+    // sorted in runs that do fit, spilled next to `path` (temp_path of
+    // "<path>.run<N>") and merged. Same keys and tie rule as sort_rows; the
+    // session itself is unchanged, and a failed sort leaves `path` as it was.
+    pub fn sort_to_file(&self, keys: Vec<SortKey>, path: String) -> Result<(), String> {
+        let rules = self.sort_rules(keys)?;
+        let mut trace = OpTrace::start("sort_to_file", || path.clone());
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        let run_rows = match self.budget_left(&self.analysis.lock().unwrap()) {
+            Some(left) => (left as usize / sort_row_bytes(rules.len())).max(SORT_RUN_MIN_ROWS),
+            None => usize::MAX,
+        };
+        let total = self.total_rows as usize;
+        let runs: Vec<std::path::PathBuf> = (0..total.div_ceil(run_rows).max(1))
+            .map(|run| temp_path(std::path::Path::new(&format!("{}.run{}", path, run))))
+            .collect();
+
+        let result = write_staged(&[path.into()], |staged| {
+            let task = self.begin_task(2 * total as i64);
+            let write_err = |e: std::io::Error| format!("Failed to write sort run: {}", e);
+            for (run, run_path) in runs.iter().enumerate() {
+                let start = run * run_rows;
+                let end = start.saturating_add(run_rows).min(total);
+                let mut decorated: Vec<(i64, Vec<SortValue>)> = (start..end)
+                    .map(|row| (row as i64, self.sort_values(&rules, self.source_row(row as i64))))
+                    .collect();
+                decorated.sort_by(|a, b| compare_sort_values(&rules, &a.1, &b.1));
+                let file = File::create(run_path).map_err(|e| format!("Failed to create sort run: {}", e))?;
+                let mut writer = BufWriter::new(file);
+                for (row, _) in decorated {
+                    writer.write_all(&row.to_le_bytes()).map_err(write_err)?;
+                }
+                writer.flush().map_err(write_err)?;
+                task.report(end as i64);
+            }
+
+            let file = File::create(&staged[0]).map_err(|e| format!("Failed to create file: {}", e))?;
+            let mut writer = BufWriter::new(file);
+            let write_err = |e: std::io::Error| format!("Failed to write file: {}", e);
+            let terminator = self.terminator.as_bytes();
+            if self.has_header_row() {
+                let all_cols = self.total_cols.min(i32::MAX as i64) as i32;
+                write_csv_row(&mut writer, &self.get_header_chunk(0, all_cols))?;
+                if total > 0 {
+                    writer.write_all(terminator).map_err(write_err)?;
+                }
+            }
+            let mut readers = Vec::with_capacity(runs.len());
+            for run_path in &runs {
+                let file = File::open(run_path).map_err(|e| format!("Failed to read sort run: {}", e))?;
+                readers.push(BufReader::new(file));
+            }
+            let mut heads = Vec::with_capacity(readers.len());
+            for reader in &mut readers {
+                heads.push(self.next_sorted_row(reader, &rules)?);
+            }
+            for written in 0..total {
+                // Ties go to the earlier run, which holds the earlier rows
+                let Some(run) = heads
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(run, head)| head.as_ref().map(|head| (run, head)))
+                    .min_by(|a, b| compare_sort_values(&rules, &a.1 .1, &b.1 .1))
+                    .map(|(run, _)| run)
+                else {
+                    return Err("Sort runs ended early".to_string());
+                };
+                let next = self.next_sorted_row(&mut readers[run], &rules)?;
+                let Some((row, _)) = std::mem::replace(&mut heads[run], next) else { unreachable!() };
+                if written > 0 {
+                    writer.write_all(terminator).map_err(write_err)?;
+                }
+                self.write_record(&mut writer, row, self.sanitize_formulas)?;
+                task.report((total + written) as i64 + 1);
+            }
+            writer.flush().map_err(write_err)
+        });
+        for run_path in &runs {
+            let _ = std::fs::remove_file(run_path);
+        }
+        result
+    }
+
+    // Helper: the next row of a sort run with its sort values (None at the end)
+    fn next_sorted_row(
+        &self,
+        reader: &mut impl Read,
+        rules: &[(usize, SortRule)],
+    ) -> Result<Option<(i64, Vec<SortValue>)>, String> {
+        let mut bytes = [0u8; 8];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(format!("Failed to read sort run: {}", e)),
+        }
+        let row = i64::from_le_bytes(bytes);
+        Ok(Some((row, self.sort_values(rules, self.source_row(row)))))
+    }
+
+    // Helper: sort keys checked against the columns
+    fn sort_rules(&self, keys: Vec<SortKey>) -> Result<Vec<(usize, SortRule)>, String> {
+        if keys.is_empty() {
+            return Err("No sort keys given".to_string());
+        }
+        keys.into_iter()
+            .map(|key| {
+                let (col, rule) = match key {
+                    SortKey::Column { col, descending } => (col, SortRule::Natural { descending }),
+                    SortKey::Custom { col, comparator } => (col, SortRule::from(comparator)),
+                };
+                if col < 0 || col >= self.total_cols {
+                    return Err(format!("Sort column {} is out of range", col));
+                }
+                Ok((col as usize, rule))
+            })
+            .collect()
+    }
+
+    // Helper: a source row's sort value under each rule
+    fn sort_values(&self, rules: &[(usize, SortRule)], row: usize) -> Vec<SortValue> {
+        let fields = self.row_fields(row);
+        rules
+            .iter()
+            .map(|(col, rule)| rule.value(&self.normalize_cell(field_or_empty(&fields, *col))))
+            .collect()
+    }
+
+    // 26. SUB VIEWS
+    // A window of the current view as a session of its own: row 0 is the
+    // window's first row, column 0 its first column, and every read, search,
+    // stat and export stays inside it. The mapped bytes and row index are
+    // shared, so in file order this is O(1); under a row order (sort, filter,
+    // shuffle) the window's slice of the order is copied. Edits inside the
+    // window, header names, normalization and the memory budget come along;
+    // derived columns and views don't, so the window must lie within the real
+    // columns. Sub views nest, and neither side sees the other's later changes.
+    pub fn sub_view(
+        &self,
+        row_start: i64,
+        row_count: i64,
+        col_start: i64,
+        col_count: i64,
+    ) -> Result<SheetSession, String> {
+        let (row_end, col_end) = (row_start.saturating_add(row_count), col_start.saturating_add(col_count));
+        if row_start < 0 || row_count < 0 || row_end > self.total_rows {
+            return Err(format!("Rows {}..{} are outside the session (0..{})", row_start, row_end, self.total_rows));
+        }
+        if col_start < 0 || col_count < 0 || col_end > self.base_cols {
+            return Err(format!(
+                "Columns {}..{} are outside the real columns (0..{})", col_start, col_end, self.base_cols
+            ));
+        }
+        if self.col_order.is_some() {
+            return Err("Sub views need the file's column order; call reset_column_order first".to_string());
+        }
+
+        // Reversed: the window is a run of positions counted from the end
+        let pos_start = if self.reverse_order { self.total_rows - row_start - row_count } else { row_start } as usize;
+        let pos_end = pos_start + row_count as usize;
+        let (row_base, row_end, row_order) = match &self.row_order {
+            Some(order) => (self.row_base, self.row_end, Some(order[pos_start..pos_end].to_vec())),
+            None => (self.row_base + pos_start, self.row_base + pos_end, None),
+        };
+
+        let (col_start, col_count) = (col_start as usize, col_count as usize);
+        let in_window: Option<HashSet<i64>> = match (&row_order, self.edits.is_empty()) {
+            (Some(order), false) => Some(order.iter().copied().collect()),
+            _ => None,
+        };
+        let mut edits: HashMap<usize, HashMap<usize, String>> = HashMap::new();
+        for (&row, cols) in &self.edits {
+            let row_inside = match &in_window {
+                Some(rows) => rows.contains(&(row as i64)),
+                None => (row_base..row_end).contains(&row),
+            };
+            if !row_inside { continue; }
+            let cols: HashMap<usize, String> = cols
+                .iter()
+                .filter(|(&c, _)| (col_start..col_start + col_count).contains(&c))
+                .map(|(&c, value)| (c - col_start, value.clone()))
+                .collect();
+            if !cols.is_empty() {
+                edits.insert(row, cols);
+            }
+        }
+
+        let mut session = SheetSession {
+            total_rows: row_count,
+            total_cols: 0,
+            path: self.path.clone(),
+            content: self.content.clone(),
+            row_offsets: self.row_offsets.clone(),
+            row_base,
+            row_end,
+            col_window: Some((self.file_col(col_start).unwrap_or(col_start), col_count)),
+            col_order: None,
+            terminator: self.terminator,
+            detected_cols: col_count as i64,
+            base_cols: 0,
+            cols_override: None,
+            max_cols: self.max_cols.max(col_count as i64),
+            warnings: Vec::new(),
+            memory_budget: self.memory_budget,
+            header_names: self.header_names.iter().skip(col_start).take(col_count).cloned().collect(),
+            synthetic_header: self.synthetic_header,
+            first_row_header: self.first_row_header,
+            normalize: self.normalize.clone(),
+            row_order,
+            reverse_order: self.reverse_order,
+            filter_base: None,
+            edits,
+            repaired_widths: self
+                .repaired_widths
+                .iter()
+                .filter(|(row, _)| match &in_window {
+                    Some(rows) => rows.contains(&(**row as i64)),
+                    None => (row_base..row_end).contains(row),
+                })
+                .map(|(&row, &width)| (row, width.saturating_sub(col_start).min(col_count)))
+                .collect(),
+            repair_cols: None,
+            analysis: Mutex::new(AnalysisCache::default()),
+            task: TaskState::default(),
+            fingerprint: OnceLock::new(),
+            widest_row: OnceLock::new(),
+            exact_cols: OnceLock::new(),
+            refining_cols: AtomicBool::new(false),
+            changes: Mutex::new(Vec::new()),
+            merge_delimiters: self.merge_delimiters,
+            write_merged_delimiters: self.write_merged_delimiters,
+            merge_delimiter_rows: OnceLock::new(),
+            backend_choice: self.backend_choice,
+            io_retry: self.io_retry,
+            backend_reason: self.backend_reason.clone(),
+            detached: self.detached.clone(),
+            responses_truncated: AtomicU64::new(0),
+            strict_padding: self.strict_padding,
+            padding_export: self.padding_export,
+            padded_cells_exported: AtomicU64::new(0),
+            views: BTreeMap::new(),
+            derived: Vec::new(),
+            null_tokens: self.null_tokens.window(col_start, col_count),
+            query_history: VecDeque::new(),
+            inverted_index: None,
+            search_index: None,
+            sanitize_formulas: self.sanitize_formulas,
+            decimal_separator: self.decimal_separator,
+            anchors: HashMap::new(),
+            next_anchor: 1,
+            row_tags: HashMap::new(),
+            tag_digests: HashMap::new(),
+        };
+        session.resolve_total_cols();
+        Ok(session)
+    }
+
+    // 26b. SNAPSHOTS (long exports without holding up the grid)
+    // A frozen copy of the session as it is now: same rows in the same
+    // order, filter, sort, edits, column order, derived columns and export
+    // settings. The mapped bytes and row index are shared; the row order and
+    // edits are copied (O(rows) while sorted or filtered). Run a long export
+    // on the snapshot from another isolate and the session stays free for
+    // viewport reads, edits and filter changes, none of which reach the
+    // snapshot: its output always reflects the moment snapshot was called.
+    // Views, query history, search indexes and anchors stay behind;
+    // progress and cancel_task work on the snapshot itself.
+    pub fn snapshot(&self) -> SheetSession {
+        SheetSession {
+            total_rows: self.total_rows,
+            total_cols: self.total_cols,
+            path: self.path.clone(),
+            content: self.content.clone(),
+            row_offsets: self.row_offsets.clone(),
+            row_base: self.row_base,
+            row_end: self.row_end,
+            col_window: self.col_window,
+            col_order: self.col_order.clone(),
+            terminator: self.terminator,
+            detected_cols: self.detected_cols,
+            base_cols: self.base_cols,
+            cols_override: self.cols_override,
+            max_cols: self.max_cols,
+            warnings: self.warnings.clone(),
+            memory_budget: self.memory_budget,
+            header_names: self.header_names.clone(),
+            synthetic_header: self.synthetic_header,
+            first_row_header: self.first_row_header,
+            normalize: self.normalize.clone(),
+            row_order: self.row_order.clone(),
+            reverse_order: self.reverse_order,
+            filter_base: None,
+            edits: self.edits.clone(),
+            repaired_widths: self.repaired_widths.clone(),
+            repair_cols: self.repair_cols,
+            analysis: Mutex::new(AnalysisCache::default()),
+            task: TaskState::default(),
+            fingerprint: OnceLock::new(),
+            widest_row: OnceLock::new(),
+            exact_cols: OnceLock::new(),
+            refining_cols: AtomicBool::new(false),
+            changes: Mutex::new(Vec::new()),
+            merge_delimiters: self.merge_delimiters,
+            write_merged_delimiters: self.write_merged_delimiters,
+            merge_delimiter_rows: OnceLock::new(),
+            backend_choice: self.backend_choice,
+            io_retry: self.io_retry,
+            backend_reason: self.backend_reason.clone(),
+            detached: self.detached.clone(),
+            responses_truncated: AtomicU64::new(0),
+            strict_padding: self.strict_padding,
+            padding_export: self.padding_export,
+            padded_cells_exported: AtomicU64::new(0),
+            views: BTreeMap::new(),
+            derived: self.derived.clone(),
+            null_tokens: self.null_tokens.clone(),
+            query_history: VecDeque::new(),
+            inverted_index: None,
+            search_index: None,
+            sanitize_formulas: self.sanitize_formulas,
+            decimal_separator: self.decimal_separator,
+            anchors: HashMap::new(),
+            next_anchor: 1,
+            row_tags: HashMap::new(),
+            tag_digests: HashMap::new(),
+        }
+    }
+
+    // The rows matching one column test as a session of their own (row 0 is
+    // the first match, in view order), e.g. "open matches in a new tab". Built
+    // like a full-width sub_view: the mapped bytes and row index are shared,
+    // only the matching row numbers are stored, and the same things come
+    // along (no derived columns or views).
+    pub fn apply_row_filter_and_new_session(&self, col: i64, predicate: FilterPredicate) -> Result<SheetSession, String> {
+        let matches = self.filter_rows(vec![FilterCondition::Column { col, predicate }])?;
+        let order: Vec<i64> = matches.iter().map(|&row| self.source_row(row) as i64).collect();
+        self.check_budget("row_order", order.len() * std::mem::size_of::<i64>(), "narrow the filter or truncate_to_rows first")?;
+
+        let mut session = self.sub_view(0, self.total_rows, 0, self.base_cols)?;
+        session.total_rows = order.len() as i64;
+        session.row_order = Some(order);
+        session.reverse_order = false;
+        Ok(session)
+    }
+
+    // 27. ROLLING WINDOWS (chart trend lines)
+    // One output per row in the range: the aggregate of that row and the
+    // window_size - 1 rows before it, within the range. Positions without a
+    // full window yet are NaN; non-numeric cells count as 0. O(n): a running
+    // sum, and a monotonic deque of candidates for min/max.
+    pub fn window_aggregate(&self, col: i64, window_size: usize, op: AggOp, row_start: i64, row_count: i32) -> Vec<f64> {
+        let start = row_start.max(0);
+        let end = row_start.saturating_add(row_count.max(0) as i64).min(self.total_rows);
+        if col < 0 || col >= self.total_cols || start >= end {
+            return Vec::new();
+        }
+        let values: Vec<f64> = self
+            .column_cells(col as usize, start..end)
+            .map(|(_, cell)| parse_number(cell.trim()).unwrap_or(0.0))
+            .collect();
+        if window_size == 0 {
+            return vec![f64::NAN; values.len()];
+        }
+
+        let mut out = Vec::with_capacity(values.len());
+        let mut sum = 0.0;
+        let mut candidates: VecDeque<usize> = VecDeque::new(); // Indices, best value at the front
+        for (i, &value) in values.iter().enumerate() {
+            sum += value;
+            if i >= window_size {
+                sum -= values[i - window_size];
+            }
+
+            let beats = |kept: f64| match op {
+                AggOp::Min => value <= kept,
+                AggOp::Max => value >= kept,
+                AggOp::Sum | AggOp::Mean => false,
+            };
+            if matches!(op, AggOp::Min | AggOp::Max) {
+                while candidates.back().is_some_and(|&j| beats(values[j])) {
+                    candidates.pop_back();
+                }
+                candidates.push_back(i);
+                if candidates.front().is_some_and(|&j| j + window_size <= i) {
+                    candidates.pop_front();
+                }
+            }
+
+            if i + 1 < window_size {
+                out.push(f64::NAN);
+                continue;
+            }
+            out.push(match op {
+                AggOp::Sum => sum,
+                AggOp::Mean => sum / window_size as f64,
+                AggOp::Min | AggOp::Max => candidates.front().map_or(f64::NAN, |&j| values[j]),
+            });
+        }
+        out
+    }
+
+    // 28. ROW AS JSON ("copy row as JSON")
+    // One object, keys in column order: header names (derived columns by
+    // name), "col_N" without a header. Each cell is typed on its own (see
+    // CellValue): integers and floats become numbers, true/false booleans,
+    // empty cells and null tokens null, the rest strings.
+    pub fn row_to_json(&self, row: i64) -> Result<String, String> {
+        if row < 0 || row >= self.total_rows {
+            return Err(format!("Row {} is out of range", row));
+        }
+        let fields = self.row_fields(self.source_row(row));
+        let encode_err = |e: serde_json::Error| format!("Failed to encode row: {}", e);
+
+        // Written by hand so keys keep column order (serde_json's Map sorts them).
+        // Keys must be unique, so repeated names get their canonical suffix.
+        let raw_keys: Vec<String> = (0..self.total_cols as usize)
+            .map(|col| {
+                if col >= self.base_cols as usize {
+                    self.derived[col - self.base_cols as usize].state.name.clone()
+                } else {
+                    self.header_names.get(col).cloned().unwrap_or_else(|| format!("col_{}", col))
+                }
+            })
+            .collect();
+        let mut json = String::from("{");
+        for (col, key) in unique_names(&raw_keys).into_iter().enumerate() {
+            if col > 0 {
+                json.push(',');
+            }
+            json.push_str(&serde_json::to_string(&key).map_err(encode_err)?);
+            json.push(':');
+            let value = field_or_empty(&fields, col);
+            let value = if self.is_null_token(col, value) { serde_json::Value::Null } else { json_cell(value) };
+            json.push_str(&serde_json::to_string(&value).map_err(encode_err)?);
+        }
+        json.push('}');
+        Ok(json)
+    }
+
+    // 29. WHOLE COLUMNS (sparklines, charts)
+    // Every cell of one column in view order (edits applied; short rows give
+    // ""). The result is fully materialized: budget roughly 24 bytes plus the
+    // text per row (a 10M-row column is 240 MB before any text), so for big
+    // files prefer get_grid_chunk pages, sample_and_describe or range_stats.
+    // An out-of-range col gives an empty Vec.
+    pub fn get_column_data(&self, col: i64) -> Vec<String> {
+        if col < 0 || col >= self.total_cols {
+            return Vec::new();
+        }
+        self.column_cells(col as usize, 0..self.total_rows).map(|(_, cell)| cell).collect()
+    }
+
+    // get_column_data with each cell typed on its own (CellValue); the same
+    // memory caveat applies
+    pub fn get_column_data_typed(&self, col: i64) -> Vec<CellValue> {
+        if col < 0 || col >= self.total_cols {
+            return Vec::new();
+        }
+        self.column_cells(col as usize, 0..self.total_rows).map(|(_, cell)| typed_cell(&cell)).collect()
+    }
+
+    // Smallest and largest value of a column in one pass, compared the way
+    // the column's inferred type suggests: numerically for Integer/Float
+    // (cells that aren't numbers are skipped), false < true for Boolean,
+    // byte order otherwise. Empty and null-token cells don't count. Numeric
+    // and boolean bounds come back typed as in get_column_data_typed, text
+    // bounds as CellValue::Text.
+    pub fn get_column_value_range(&self, col: i64) -> Result<(CellValue, CellValue), String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        let column_type = self.infer_column_types(col, 1).first().copied().unwrap_or(ColumnType::Text);
+        let sort_key = |value: &str| match column_type {
+            ColumnType::Integer | ColumnType::Float => parse_number(value).filter(|n| !n.is_nan()),
+            ColumnType::Boolean => parse_bool(value.trim()).map(|b| b as u8 as f64),
+            _ => Some(0.0), // Text: the string itself decides
+        };
+
+        let mut bounds: Option<((f64, String), (f64, String))> = None;
+        for (_, value) in self.column_cells(col as usize, 0..self.total_rows) {
+            if value.trim().is_empty() || self.is_null_token(col as usize, &value) {
+                continue;
+            }
+            let Some(key) = sort_key(&value) else { continue };
+            match &mut bounds {
+                None => bounds = Some(((key, value.clone()), (key, value))),
+                Some((min, max)) => {
+                    let cmp = |bound: &(f64, String)| key.total_cmp(&bound.0).then_with(|| value.cmp(&bound.1));
+                    if cmp(min).is_lt() {
+                        *min = (key, value);
+                    } else if cmp(max).is_gt() {
+                        *max = (key, value);
+                    }
+                }
+            }
+        }
+
+        let ((_, min), (_, max)) = bounds.ok_or_else(|| format!("Column {} has no values", col))?;
+        Ok(match column_type {
+            ColumnType::Integer | ColumnType::Float | ColumnType::Boolean => (typed_cell(&min), typed_cell(&max)),
+            _ => (CellValue::Text(min), CellValue::Text(max)),
+        })
+    }
+
+    // 30. NULL TOKENS ("\\N", "NULL", "-" meaning missing)
+    // A cell is null-like when its trimmed text is exactly one of its column's
+    // tokens: per_col entries replace the sheet-wide list for that column.
+    // The grid flags such cells (CellData::is_null), IsNull/IsNotNull filters
+    // and type inference treat them as missing, the manifest and summary count
+    // them as null_like. Each call replaces all tokens.
+    pub fn set_null_tokens(&mut self, tokens: Vec<String>, per_col: Vec<ColumnNullTokens>) -> Result<(), String> {
+        let to_set = |tokens: Vec<String>| -> HashSet<String> {
+            tokens.into_iter().map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect()
+        };
+        let mut overrides = HashMap::new();
+        for entry in per_col {
+            if entry.col < 0 || entry.col >= self.total_cols {
+                return Err(format!("Column {} is out of range", entry.col));
+            }
+            overrides.insert(entry.col as usize, to_set(entry.tokens));
+        }
+        self.null_tokens.default = to_set(tokens);
+        self.null_tokens.per_col = overrides;
+        self.invalidate_analysis();
+        Ok(())
+    }
+
+    // How exporters (save_to_file, partition_by_hash, the HTML/Markdown
+    // tables, explode_column_to_file) write null-like cells: None keeps them
+    // as in the file, Some("") writes true empties, anything else replaces them.
+    pub fn set_null_export(&mut self, replacement: Option<String>) {
+        self.null_tokens.export_as = replacement;
+    }
+
+    // CSV injection guard for the file exporters (save_to_file,
+    // partition_by_hash, explode_column_to_file): cells starting with = + - @
+    // (or tab / CR) that aren't plain numbers get a leading ' so spreadsheets
+    // show them as text. Off by default because it changes the data;
+    // save_in_place and export_sample_to_file never sanitize, and the
+    // clipboard copies (copy_range_as_csv, export_ranges) sanitize unless
+    // told not to. Numbers are read with the session's decimal separator.
+    pub fn set_sanitize_formulas(&mut self, enabled: bool) {
+        self.sanitize_formulas = enabled;
+    }
+
+    // Decimal mark the formula guard reads numbers with: under Comma "-1,5"
+    // stays a number (a point still works, "-1.5"). Point by default.
+    pub fn set_decimal_separator(&mut self, separator: DecimalSeparator) {
+        self.decimal_separator = separator;
+    }
+
+    // With merge_consecutive_delimiters on, whether save_to_file and
+    // save_in_place write the merged fields (the default: what the grid
+    // shows) or copy untouched rows with their original runs of commas.
+    // Edited rows are always written merged, since the edits are keyed by
+    // the merged columns.
+    pub fn set_write_merged_delimiters(&mut self, enabled: bool) {
+        self.write_merged_delimiters = enabled;
+    }
+
+    // Tell padding apart from real empty fields: grid cells past a short
+    // row's last field get CellData.is_padding, filters never match them (not
+    // even IsEmpty or IsNull), find_text skips them, and file exports follow
+    // set_padding_export. Off by default.
+    pub fn set_strict_padding(&mut self, enabled: bool) {
+        self.strict_padding = enabled;
+    }
+
+    pub fn set_padding_export(&mut self, policy: PaddingExport) {
+        self.padding_export = policy;
+    }
+
+    // Helper: apply the padding export policy to the `written` session
+    // columns of view row `row`
+    fn check_padding_export(&self, row: i64, written: impl Iterator<Item = usize>) -> Result<(), String> {
+        if self.padding_export == PaddingExport::Write {
+            return Ok(());
+        }
+        let padding = self.padding_mask(self.source_row(row));
+        let padded = written.filter(|&col| is_padded(&padding, col)).count();
+        if padded == 0 {
+            return Ok(());
+        }
+        match self.padding_export {
+            PaddingExport::Fail => Err(format!(
+                "Row {} is missing {} fields that would be written as empty values",
+                row, padded
+            )),
+            _ => {
+                self.padded_cells_exported.fetch_add(padded as u64, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    // Helper: whether exports must re-encode every row to drop merged delimiters
+    fn rewrites_merged_rows(&self) -> bool {
+        self.merge_delimiters && self.write_merged_delimiters
+    }
+
+    // 31. STRUCTURE MINIMAP (strip next to the scrollbar)
+    // Splits the view into up to `buckets` runs of consecutive rows and
+    // reports each run's field-count range and longest line, so column count
+    // jumps and giant rows stand out. Looks at the file's bytes (edits don't
+    // count; a sub view still sees whole lines) in one pass with progress.
+    pub fn structure_profile(&self, buckets: i32) -> Vec<StructureBucket> {
+        let n = self.total_rows;
+        if n == 0 || buckets < 1 {
+            return Vec::new();
+        }
+        let buckets = (buckets as i64).min(n);
+        let task = self.begin_task(n);
+        (0..buckets)
+            .map(|b| {
+                let (start, end) = (b * n / buckets, (b + 1) * n / buckets);
+                let mut bucket = StructureBucket {
+                    row_start: start,
+                    row_count: end - start,
+                    min_cols: i64::MAX,
+                    max_cols: 0,
+                    max_line_bytes: 0,
+                };
+                for row in start..end {
+                    let line = self.row_bytes(self.source_row(row));
+                    let fields = if line.is_empty() { 0 } else { count_fields(line, self.merge_delimiters) };
+                    bucket.min_cols = bucket.min_cols.min(fields);
+                    bucket.max_cols = bucket.max_cols.max(fields);
+                    bucket.max_line_bytes = bucket.max_line_bytes.max(line.len() as i64);
+                }
+                task.report(end);
+                bucket
+            })
+            .collect()
+    }
+
+    // 32. OUTLIERS (IQR rule)
+    // View rows whose value lies below Q1 - multiplier * IQR or above
+    // Q3 + multiplier * IQR (1.5 is the usual multiplier), ascending.
+    // Quartiles use linear interpolation over all numeric cells of the
+    // column; other cells are never flagged. Needs at least 4 numbers.
+    pub fn detect_outliers(&self, col: i64, multiplier: f64) -> Result<Vec<i64>, String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        if !multiplier.is_finite() || multiplier < 0.0 {
+            return Err(format!("Multiplier must be a non-negative number, got {}", multiplier));
+        }
+
+        let task = self.begin_task(self.total_rows);
+        let mut values = Vec::new();
+        for (row, cell) in self.column_cells(col as usize, 0..self.total_rows) {
+            if let Some(n) = parse_number(&cell) {
+                values.push((row, n));
+            }
+            task.report(row + 1);
+        }
+        if values.len() < 4 {
+            return Err(format!("Column {} has {} numeric values; at least 4 are needed", col, values.len()));
+        }
+
+        let mut sorted: Vec<f64> = values.iter().map(|&(_, n)| n).collect();
+        sorted.sort_unstable_by(f64::total_cmp);
+        let (q1, q3) = (percentile(&sorted, 0.25), percentile(&sorted, 0.75));
+        let (low, high) = (q1 - multiplier * (q3 - q1), q3 + multiplier * (q3 - q1));
+        Ok(values.into_iter().filter(|&(_, n)| n < low || n > high).map(|(row, _)| row).collect())
+    }
+
+    // Histogram bucket tooltips: view rows whose numeric value in `col` lies
+    // in [low, high], in one pass (nothing is collected). Cells that aren't
+    // numbers never count.
+    pub fn count_column_values_in_range(&self, col: i64, low: f64, high: f64) -> Result<i64, String> {
+        self.range_counts(col, low, high).map(|(in_range, _)| in_range)
+    }
+
+    // count_column_values_in_range / number of numeric cells in `col` (0.0
+    // when the column has none)
+    pub fn fraction_in_range(&self, col: i64, low: f64, high: f64) -> Result<f64, String> {
+        let (in_range, numeric) = self.range_counts(col, low, high)?;
+        Ok(if numeric == 0 { 0.0 } else { in_range as f64 / numeric as f64 })
+    }
+
+    // Length distribution of the cells in `col` (empty cells count as 0), for
+    // truncation limits and spotting suspiciously long values. Percentiles
+    // interpolate like detect_outliers' quartiles, rounded to whole lengths.
+    pub fn compute_cell_lengths(&self, col: i64) -> LengthStats {
+        let mut stats = LengthStats { min_len: 0, max_len: 0, mean_len: 0.0, p50_len: 0, p95_len: 0, p99_len: 0 };
+        if col < 0 || col >= self.total_cols || self.total_rows <= 0 { return stats; }
+
+        let task = self.begin_task(self.total_rows);
+        let mut lengths = Vec::with_capacity(self.total_rows as usize);
+        for (row, cell) in self.column_cells(col as usize, 0..self.total_rows) {
+            lengths.push(cell.chars().count() as f64);
+            task.report(row + 1);
+        }
+        lengths.sort_unstable_by(f64::total_cmp);
+        let at = |p: f64| percentile(&lengths, p).round() as usize;
+        stats.min_len = lengths[0] as usize;
+        stats.max_len = lengths[lengths.len() - 1] as usize;
+        stats.mean_len = lengths.iter().sum::<f64>() / lengths.len() as f64;
+        (stats.p50_len, stats.p95_len, stats.p99_len) = (at(0.5), at(0.95), at(0.99));
+        stats
+    }
+
+    // Checks that every cell of `col` is a date in `format` (chrono strftime
+    // syntax, e.g. "%Y-%m-%d"; surrounding whitespace is ignored) and, with
+    // require_ascending, that each date is no earlier than the previous
+    // valid one, as time series usually assume. Errors on a bad column or
+    // format.
+    pub fn validate_date_column(
+        &self,
+        col: i64,
+        format: &str,
+        require_ascending: bool,
+    ) -> Result<DateValidationReport, String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        let bad_format = chrono::format::StrftimeItems::new(format)
+            .any(|item| item == chrono::format::Item::Error);
+        if format.trim().is_empty() || bad_format {
+            return Err(format!("Invalid date format: {:?}", format));
+        }
+        let col = col as usize;
+
+        let mut report = DateValidationReport {
+            invalid_format_rows: Vec::new(),
+            out_of_order_rows: Vec::new(),
+            null_rows: Vec::new(),
+            truncated: false,
+        };
+        let mut previous: Option<chrono::NaiveDate> = None;
+        for (row, cell) in self.column_cells(col, 0..self.total_rows) {
+            let value = cell.trim();
+            let list = if value.is_empty() || self.is_null_token(col, value) {
+                &mut report.null_rows
+            } else {
+                match chrono::NaiveDate::parse_from_str(value, format) {
+                    Err(_) => &mut report.invalid_format_rows,
+                    Ok(date) => {
+                        let earlier = previous.is_some_and(|p| date < p);
+                        previous = Some(date);
+                        if !(require_ascending && earlier) { continue; }
+                        &mut report.out_of_order_rows
+                    }
+                }
+            };
+            if list.len() < MAX_REPORTED_ERRORS {
+                list.push(row);
+            } else {
+                report.truncated = true;
+            }
+        }
+        Ok(report)
+    }
+
+    // (first, last) view rows, inclusive, of every run of two or more
+    // consecutive rows with the same value in `col` (session normalization
+    // applies; empty cells form runs too), e.g. stuck sensors or pasted-down
+    // values. One pass keeping only the previous value; an out-of-range
+    // column gives no runs.
+    pub fn get_consecutive_duplicate_rows(&self, col: i64) -> Vec<(i64, i64)> {
+        let mut runs = Vec::new();
+        if col < 0 || col >= self.total_cols { return runs; }
+        let col = col as usize;
+
+        let mut previous: Option<String> = None;
+        let mut run_start = 0;
+        for (row, cell) in self.column_cells(col, 0..self.total_rows) {
+            let cell = self.normalize_cell(&cell).into_owned();
+            if previous.as_ref() != Some(&cell) {
+                if row - run_start > 1 {
+                    runs.push((run_start, row - 1));
+                }
+                run_start = row;
+                previous = Some(cell);
+            }
+        }
+        if self.total_rows - run_start > 1 {
+            runs.push((run_start, self.total_rows - 1));
+        }
+        runs
+    }
+
+    // (first, last) view rows, inclusive, of every run of non-empty cells in
+    // `col`, for a minimap of where a column has data. Blank (whitespace
+    // only) cells and null tokens count as empty. An out-of-range column
+    // gives no runs.
+    pub fn get_sparse_column_ranges(&self, col: i64) -> Vec<(i64, i64)> {
+        self.emptiness_runs(col, false)
+    }
+
+    // The gaps between get_sparse_column_ranges: runs of empty cells
+    pub fn get_empty_column_ranges(&self, col: i64) -> Vec<(i64, i64)> {
+        self.emptiness_runs(col, true)
+    }
+
+    // Helper: inclusive runs of rows whose cell in `col` is (or isn't) empty
+    fn emptiness_runs(&self, col: i64, empty: bool) -> Vec<(i64, i64)> {
+        let mut runs = Vec::new();
+        if col < 0 || col >= self.total_cols { return runs; }
+        let col = col as usize;
+
+        let mut run_start = None;
+        for (row, cell) in self.column_cells(col, 0..self.total_rows) {
+            let is_empty = cell.trim().is_empty() || self.is_null_token(col, &cell);
+            match (is_empty == empty, run_start) {
+                (true, None) => run_start = Some(row),
+                (false, Some(start)) => {
+                    runs.push((start, row - 1));
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            runs.push((start, self.total_rows - 1));
+        }
+        runs
+    }
+
+    // Helper: (cells in [low, high], numeric cells) of one column
+    fn range_counts(&self, col: i64, low: f64, high: f64) -> Result<(i64, i64), String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        if low.is_nan() || high.is_nan() || low > high {
+            return Err(format!("Invalid range [{}, {}]", low, high));
+        }
+        let (mut in_range, mut numeric) = (0, 0);
+        for (_, cell) in self.column_cells(col as usize, 0..self.total_rows) {
+            if let Some(n) = parse_number(&cell) {
+                numeric += 1;
+                if (low..=high).contains(&n) {
+                    in_range += 1;
+                }
+            }
+        }
+        Ok((in_range, numeric))
+    }
+
+    // 33. QUERY HISTORY
+    // Every successful apply_filter is recorded (newest last, the oldest
+    // dropped past QUERY_HISTORY_CAP) and saved with export_state. Newest
+    // first; limit <= 0 means all.
+    pub fn list_query_history(&self, limit: i64) -> Vec<QueryHistoryEntry> {
+        let limit = if limit <= 0 { usize::MAX } else { limit as usize };
+        self.query_history
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|record| QueryHistoryEntry {
+                id: record.id,
+                timestamp_ms: record.timestamp_ms,
+                description: record.conditions.iter().map(describe_condition).collect::<Vec<_>>().join(" AND "),
+            })
+            .collect()
+    }
+
+    // Apply a recorded filter to the current view (it's recorded again, as
+    // the newest entry). Columns are found by recorded name first, so history
+    // imported from a file with the same headers in another order still
+    // works, then by index. Returns the rows left, like apply_filter.
+    pub fn replay_query(&mut self, id: i64) -> Result<i64, String> {
+        let record = self
+            .query_history
+            .iter()
+            .find(|record| record.id == id)
+            .cloned()
+            .ok_or_else(|| format!("No query with id {} in the history", id))?;
+        let conditions = record
+            .conditions
+            .into_iter()
+            .map(|condition| match condition {
+                RecordedCondition::Column { col, col_name, predicate } => {
+                    let col = col_name.and_then(|name| self.resolve_column(name).ok()).unwrap_or(col);
+                    FilterCondition::Column { col, predicate: predicate.into() }
+                }
+                RecordedCondition::AnyColumnContains(text) => FilterCondition::AnyColumnContains(text),
+            })
+            .collect();
+        self.apply_filter(conditions)
+    }
+
+    pub fn clear_query_history(&mut self) {
+        self.query_history.clear();
+    }
+
+    // 34. ROW REPAIR SUGGESTIONS
+    // For every row whose field count isn't expected_cols (usually one stray
+    // comma or a missing value), propose fixes: merge runs of adjacent fields
+    // back into one, or insert empty fields, at each possible position. Each
+    // proposal is scored by how many cells then match their column's type
+    // (inferred from well-formed rows only), best first (at most MAX_REPAIR_PROPOSALS). Nothing changes until
+    // apply_repair. At most MAX_REPORTED_ERRORS rows are listed.
+    pub fn suggest_row_repairs(&mut self, expected_cols: i64) -> Result<Vec<RowRepair>, String> {
+        if expected_cols < 1 || expected_cols > self.base_cols {
+            return Err(format!("Expected column count must be between 1 and {}, got {}", self.base_cols, expected_cols));
+        }
+        let expected = expected_cols as usize;
+        let types = self.repair_types(expected);
+        self.repair_cols = Some(expected);
+
+        let task = self.begin_task(self.total_rows);
+        let mut repairs = Vec::new();
+        for row in 0..self.total_rows {
+            task.report(row + 1);
+            let source_row = self.source_row(row);
+            let fields = self.source_fields(source_row);
+            if fields.len() == expected || fields.is_empty() { continue; }
+            repairs.push(RowRepair {
+                row,
+                source_row: source_row as i64,
+                proposals: repair_proposals(&fields, &types),
+                original: fields,
+            });
+            if repairs.len() >= MAX_REPORTED_ERRORS { break; }
+        }
+        Ok(repairs)
+    }
+
+    // Write proposal `proposal_index` of suggest_row_repairs for one view row
+    // into the edit overlay (save_to_file and discard_edits work as for any
+    // edit). The proposals are recomputed, so they must still be current.
+    pub fn apply_repair(&mut self, row: i64, proposal_index: i64) -> Result<(), String> {
+        let expected = self.repair_cols.ok_or("Call suggest_row_repairs first")?;
+        if row < 0 || row >= self.total_rows {
+            return Err(format!("Row {} is out of range", row));
+        }
+        let source_row = self.source_row(row);
+        let fields = self.source_fields(source_row);
+        if fields.len() == expected {
+            return Err(format!("Row {} already has {} fields", row, expected));
+        }
+        let types = self.repair_types(expected);
+        let proposal = usize::try_from(proposal_index)
+            .ok()
+            .and_then(|i| repair_proposals(&fields, &types).into_iter().nth(i))
+            .ok_or_else(|| format!("Row {} has no repair proposal {}", row, proposal_index))?;
+
+        let changes = proposal.cells.into_iter().enumerate().map(|(col, value)| (source_row, col, value)).collect();
+        self.commit_edits(changes)?;
+        self.repaired_widths.insert(source_row, expected);
+        Ok(())
+    }
+
+    // 35. INVERTED INDEX (repeated searches without rescanning)
+    // Maps every token of every cell (all columns, derived included, after
+    // trim/case normalization) to the cells containing it. Positions are
+    // (source row, col) so they stay valid under sort and filter; compare with
+    // RowData::source_row. Editing cells, changing derived columns or the
+    // normalization drops the index. Budget-checked as it grows; replaces any
+    // earlier index.
+    pub fn build_inverted_index(&mut self, tokenizer: TokenizerMode) -> Result<(), String> {
+        if tokenizer == TokenizerMode::CharacterNgram(0) {
+            return Err("N-gram length must be at least 1".to_string());
+        }
+        self.inverted_index = None;
+
+        let mut postings: HashMap<String, Vec<(i64, i64)>> = HashMap::new();
+        let mut bytes = 0usize;
+        let mut checked = 0usize;
+        let task = self.begin_task(self.total_rows);
+        for (done, source_row) in (0..self.total_rows).map(|row| self.source_row(row)).enumerate() {
+            let fields = self.row_fields(source_row);
+            for (col, field) in fields.iter().take(self.total_cols as usize).enumerate() {
+                let cell = self.normalize_cell(field);
+                let at = (source_row as i64, col as i64);
+                for token in cell_tokens(&cell, tokenizer) {
+                    match postings.get_mut(token) {
+                        Some(cells) if cells.last() == Some(&at) => continue, // Repeated in this cell
+                        Some(cells) => cells.push(at),
+                        None => {
+                            bytes += token.len() + INDEX_TOKEN_OVERHEAD;
+                            postings.insert(token.to_string(), vec![at]);
+                        }
+                    }
+                    bytes += std::mem::size_of::<(i64, i64)>();
+                }
+            }
+            if bytes >= checked + INDEX_BUDGET_STEP {
+                checked = bytes;
+                self.check_budget("inverted_index", bytes, "index fewer rows (truncate_to_rows) or use n-grams less")?;
+            }
+            task.report(done as i64 + 1);
+        }
+        drop(task);
+        self.inverted_index = Some(InvertedIndex { mode: tokenizer, postings, bytes });
+        Ok(())
+    }
+
+    // Cells containing `token` (normalized like the index), in file order.
+    // A hash lookup; empty without an index or for an unknown token.
+    pub fn inverted_lookup(&self, token: &str) -> Vec<(i64, i64)> {
+        let Some(index) = &self.inverted_index else { return Vec::new() };
+        let token = self.normalize_cell(token);
+        let mut cells = index.postings.get(token.as_ref()).cloned().unwrap_or_default();
+        cells.sort_unstable();
+        cells
+    }
+
+    // Which tokenizer the current index was built with, if there is one
+    pub fn inverted_index_mode(&self) -> Option<TokenizerMode> {
+        self.inverted_index.as_ref().map(|index| index.mode)
+    }
+
+    // 36. CANDIDATE KEYS (defaults for join / duplicate detection)
+    // Distinctness of every column over sample_rows random rows (None:
+    // KEY_SAMPLE_ROWS). Sampled counts come from a HyperLogLog sketch, so
+    // memory stays fixed per column and "unique" means within the sketch's
+    // error; a sample covering the whole view is counted exactly instead
+    // (pass Some(total_rows) for an exact answer). Cells are normalized;
+    // empty and null-token cells count as nulls. If no column is unique,
+    // pairs of the KEY_PAIR_COLS most distinct columns are tried and the
+    // unique-looking ones are added. Best candidates first.
+    pub fn detect_key_columns(&self, sample_rows: Option<i64>) -> Vec<KeyCandidate> {
+        let limit = sample_rows.unwrap_or(KEY_SAMPLE_ROWS).max(1);
+        let exact = limit >= self.total_rows;
+        let rows = self.sample_rows(limit.min(self.total_rows) as usize);
+        let cols = self.total_cols as usize;
+        if rows.is_empty() || cols == 0 {
+            return Vec::new();
+        }
+
+        let mut counters: Vec<DistinctCounter> = (0..cols).map(|_| DistinctCounter::new(exact)).collect();
+        let mut null_counts = vec![0i64; cols];
+        for &row in &rows {
+            for (col, hash) in self.key_hashes(row).into_iter().enumerate() {
+                match hash {
+                    Some(hash) => counters[col].insert(hash),
+                    None => null_counts[col] += 1,
+                }
+            }
+        }
+        let candidate = |col: i64, paired_with: Option<i64>, counter: &DistinctCounter, nulls: i64| {
+            let distinct = counter.count(rows.len() - nulls as usize);
+            KeyCandidate {
+                col,
+                paired_with,
+                distinct_ratio: distinct / rows.len() as f64,
+                null_count: nulls,
+                is_unique_in_sample: nulls == 0 && counter.looks_unique(distinct, rows.len()),
+            }
+        };
+        let mut candidates: Vec<KeyCandidate> = (0..cols)
+            .map(|col| candidate(col as i64, None, &counters[col], null_counts[col]))
+            .collect();
+
+        if cols > 1 && !candidates.iter().any(|c| c.is_unique_in_sample) {
+            let mut best: Vec<usize> = (0..cols).collect();
+            best.sort_by(|&a, &b| candidates[b].distinct_ratio.total_cmp(&candidates[a].distinct_ratio));
+            best.truncate(KEY_PAIR_COLS);
+            let pairs: Vec<(usize, usize)> = best
+                .iter()
+                .enumerate()
+                .flat_map(|(i, &a)| best[i + 1..].iter().map(move |&b| (a.min(b), a.max(b))))
+                .collect();
+            let mut pair_counters: Vec<DistinctCounter> =
+                pairs.iter().map(|_| DistinctCounter::new(exact)).collect();
+            let mut pair_nulls = vec![0i64; pairs.len()];
+            for &row in &rows {
+                let hashes = self.key_hashes(row);
+                for (i, &(a, b)) in pairs.iter().enumerate() {
+                    match (hashes[a], hashes[b]) {
+                        (Some(ha), Some(hb)) => {
+                            pair_counters[i].insert(SplitMix64(ha ^ hb.rotate_left(1)).next_u64())
+                        }
+                        _ => pair_nulls[i] += 1,
+                    }
+                }
+            }
+            for (i, &(a, b)) in pairs.iter().enumerate() {
+                let pair = candidate(a as i64, Some(b as i64), &pair_counters[i], pair_nulls[i]);
+                if pair.is_unique_in_sample {
+                    candidates.push(pair);
+                }
+            }
+        }
+
+        candidates.sort_by(|a, b| {
+            b.is_unique_in_sample
+                .cmp(&a.is_unique_in_sample)
+                .then(b.distinct_ratio.total_cmp(&a.distinct_ratio))
+                .then(a.paired_with.is_some().cmp(&b.paired_with.is_some()))
+        });
+        candidates
+    }
+
+    // Helper: per-column hash of the normalized cell for key detection; None
+    // for empty and null-token cells
+    fn key_hashes(&self, row: i64) -> Vec<Option<u64>> {
+        let fields = self.row_fields(self.source_row(row));
+        (0..self.total_cols as usize)
+            .map(|col| {
+                let value = self.normalize_cell(field_or_empty(&fields, col));
+                if value.is_empty() || self.is_null_token(col, &value) {
+                    None
+                } else {
+                    Some(SplitMix64(fnv1a_64(value.as_bytes())).next_u64())
+                }
+            })
+            .collect()
+    }
+
+    // 37. SEARCH INDEX (opt-in; whole-token find_text without a scan)
+    // One pass over `cols` (None: all columns) recording which rows contain
+    // each lowercase token. Costs memory, so it's built only on request and
+    // is budget-checked as it grows; progress goes through get_task_progress
+    // and the size through search_index_info / get_session_info. Editing or
+    // changing the columns drops it. Replaces any earlier index.
+    pub fn build_search_index(&mut self, cols: Option<Vec<i64>>) -> Result<SearchIndexInfo, String> {
+        let cols = self.search_cols(cols)?;
+        if self.row_offsets.len() > u32::MAX as usize {
+            return Err("Too many rows for a search index".to_string());
+        }
+        self.search_index = None;
+
+        let mut postings: HashMap<String, Vec<u32>> = HashMap::new();
+        let mut bytes = 0usize;
+        let mut checked = 0usize;
+        let task = self.begin_task(self.total_rows);
+        for (done, source_row) in (0..self.total_rows).map(|row| self.source_row(row)).enumerate() {
+            let fields = self.row_fields(source_row);
+            for &col in &cols {
+                for token in search_tokens(field_or_empty(&fields, col)) {
+                    match postings.get_mut(&token) {
+                        Some(rows) if rows.last() == Some(&(source_row as u32)) => continue,
+                        Some(rows) => rows.push(source_row as u32),
+                        None => {
+                            bytes += token.len() + INDEX_TOKEN_OVERHEAD;
+                            postings.insert(token, vec![source_row as u32]);
+                        }
+                    }
+                    bytes += std::mem::size_of::<u32>();
+                }
+            }
+            if bytes >= checked + INDEX_BUDGET_STEP {
+                checked = bytes;
+                self.check_budget("search_index", bytes, "index fewer columns")?;
+            }
+            task.report(done as i64 + 1);
+        }
+        drop(task);
+
+        let total = self.row_offsets.len();
+        let postings: HashMap<String, RowSet> =
+            postings.into_iter().map(|(token, rows)| (token, RowSet::compact(rows, total))).collect();
+        let bytes = postings.iter().map(|(token, rows)| token.len() + INDEX_TOKEN_OVERHEAD + rows.bytes()).sum();
+        self.search_index = Some(SearchIndex { cols, postings, bytes });
+        self.search_index_info().ok_or_else(|| "Search index was not built".to_string())
+    }
+
+    pub fn search_index_info(&self) -> Option<SearchIndexInfo> {
+        self.search_index.as_ref().map(|index| SearchIndexInfo {
+            cols: index.cols.iter().map(|&c| c as i64).collect(),
+            token_count: index.postings.len() as i64,
+            bytes: index.bytes as i64,
+        })
+    }
+
+    pub fn drop_search_index(&mut self) {
+        self.search_index = None;
+    }
+
+    // What the search index holds, written to `path` as JSON, to judge
+    // whether it's worth its memory. Needs an index over `col` alone
+    // (build_search_index(Some(vec![col]))).
+    pub fn write_column_index_stats(&self, col: i64, path: String) -> Result<ColumnIndexStats, String> {
+        let cols = self.search_cols(Some(vec![col]))?;
+        let Some(index) = self.search_index.as_ref().filter(|index| index.cols == cols) else {
+            return Err(format!("No search index over column {} alone; build one first", col));
+        };
+
+        let mut covered = vec![0u64; self.row_offsets.len().div_ceil(64)];
+        let mut counts: Vec<(&String, usize)> = index
+            .postings
+            .iter()
+            .map(|(token, rows)| {
+                rows.mark(&mut covered);
+                (token, rows.len())
+            })
+            .collect();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let stats = ColumnIndexStats {
+            col,
+            distinct_value_count: index.postings.len(),
+            total_indexed_rows: covered.iter().map(|word| word.count_ones() as i64).sum(),
+            index_memory_bytes: index.bytes,
+            top_20_values: counts.into_iter().take(20).map(|(token, rows)| (token.clone(), rows)).collect(),
+        };
+
+        let json = serde_json::to_string_pretty(&stats).map_err(|e| format!("Failed to encode index stats: {}", e))?;
+        std::fs::write(&path, json).map_err(|e| format!("Failed to write index stats: {}", e))?;
+        Ok(stats)
+    }
+
+    // View rows (in view order) with a match in `cols` (None: all columns).
+    // WholeToken queries over exactly the indexed columns are answered from
+    // the search index without reading any rows; everything else scans.
+    pub fn find_text(&self, query: String, mode: FindMode, cols: Option<Vec<i64>>) -> Result<Vec<i64>, String> {
+        let mut trace = OpTrace::start("find_text", || {
+            let mode = match mode {
+                FindMode::WholeToken => "whole token",
+                FindMode::Substring => "substring",
+                FindMode::Regex => "regex",
+            };
+            let cols = cols.as_ref().map_or("all".to_string(), |cols| cols.len().to_string());
+            format!("{}, {} chars, {} cols", mode, query.chars().count(), cols)
+        });
+        let cols = self.search_cols(cols)?;
+        let cols = &cols;
+        // Gets the row's fields and padding_mask (padding never matches)
+        type RowTest<'a> = Box<dyn Fn(&[String], &Option<Vec<bool>>) -> bool + 'a>;
+        let row_matches: RowTest = match mode {
+            FindMode::WholeToken => {
+                let wanted = search_tokens(&query);
+                if wanted.is_empty() {
+                    return Err("The query has no letters or digits to search for".to_string());
+                }
+                if let Some(index) = self.search_index.as_ref().filter(|index| index.cols == *cols) {
+                    trace.cache_hit();
+                    let sets: Option<Vec<&RowSet>> = wanted.iter().map(|token| index.postings.get(token)).collect();
+                    let Some(sets) = sets else { return Ok(Vec::new()) };
+                    return Ok((0..self.total_rows)
+                        .filter(|&row| sets.iter().all(|set| set.contains(self.source_row(row))))
+                        .collect());
+                }
+                Box::new(move |fields, _| {
+                    let tokens: HashSet<String> =
+                        cols.iter().flat_map(|&col| search_tokens(field_or_empty(fields, col))).collect();
+                    wanted.iter().all(|token| tokens.contains(token))
+                })
+            }
+            FindMode::Substring => {
+                let needle = query.to_lowercase();
+                Box::new(move |fields, padding| {
+                    cols.iter().any(|&col| {
+                        !is_padded(padding, col) && field_or_empty(fields, col).to_lowercase().contains(&needle)
+                    })
+                })
+            }
+            FindMode::Regex => {
+                let re = Regex::new(&query).map_err(|e| format!("Invalid regex: {}", e))?;
+                Box::new(move |fields, padding| {
+                    cols.iter().any(|&col| !is_padded(padding, col) && re.is_match(field_or_empty(fields, col)))
+                })
+            }
+        };
+        trace.touched(self.total_rows, || self.view_bytes(0..self.total_rows));
+        Ok((0..self.total_rows)
+            .filter(|&row| {
+                let source_row = self.source_row(row);
+                row_matches(&self.row_fields(source_row), &self.padding_mask(source_row))
+            })
+            .collect())
+    }
+
+    // Helper: validated, sorted, deduplicated column list (None: all columns)
+    fn search_cols(&self, cols: Option<Vec<i64>>) -> Result<Vec<usize>, String> {
+        let Some(cols) = cols else { return Ok((0..self.total_cols as usize).collect()) };
+        if cols.is_empty() {
+            return Err("No columns to search".to_string());
+        }
+        let mut checked = Vec::with_capacity(cols.len());
+        for col in cols {
+            if col < 0 || col >= self.total_cols {
+                return Err(format!("Column {} is out of range", col));
+            }
+            checked.push(col as usize);
+        }
+        checked.sort_unstable();
+        checked.dedup();
+        Ok(checked)
+    }
+
+    // 38. UNIQUE VALUES (filter dropdowns, "group by" previews)
+    // Distinct non-empty values of a column after trim/case normalization,
+    // with counts, most frequent first (ties by value), capped at
+    // max_values; distinct_count counts them all. Budget-checked as the set
+    // grows. Optionally time-boxed (see RANGE STATS).
+    pub fn column_unique_values(
+        &self,
+        col: i64,
+        max_values: i64,
+        deadline_ms: Option<i64>,
+    ) -> Result<UniqueValues, String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        let deadline = Deadline::after_ms(deadline_ms);
+        let mut counts: HashMap<String, i64> = HashMap::new();
+        let (mut bytes, mut checked) = (0usize, 0usize);
+        let mut rows_scanned = 0i64;
+        let mut is_partial = false;
+        for (_, cell) in self.column_cells(col as usize, 0..self.total_rows) {
+            if deadline.passed(rows_scanned) {
+                is_partial = true;
+                break;
+            }
+            let value = self.normalize_cell(&cell);
+            if !value.is_empty() {
+                match counts.get_mut(value.as_ref()) {
+                    Some(count) => *count += 1,
+                    None => {
+                        bytes += value.len() + INDEX_TOKEN_OVERHEAD;
+                        counts.insert(value.into_owned(), 1);
+                    }
+                }
+            }
+            if bytes >= checked + INDEX_BUDGET_STEP {
+                checked = bytes;
+                self.check_budget("unique_values", bytes, "use a deadline or a smaller view")?;
+            }
+            rows_scanned += 1;
+        }
+
+        let distinct_count = counts.len() as i64;
+        let mut values: Vec<(String, i64)> = counts.into_iter().collect();
+        values.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        values.truncate(max_values.max(0) as usize);
+        Ok(UniqueValues { values, distinct_count, rows_scanned, total_rows: self.total_rows, is_partial })
+    }
+
+    // 39. COLUMN ORDER (normalize files whose columns come in different orders)
+    // Reorders the real columns everywhere: grid, headers, exports and
+    // save_in_place write the new order. Edits, header names and per-column
+    // null tokens move with their columns; column-based caches and indexes
+    // are dropped, and saved views keep their old column numbers. Not
+    // available with derived columns (they refer to columns by position)
+    // or in sub views. Names are the canonical ones (see get_header_metadata).
+    pub fn reorder_columns_alphabetically(&mut self, ascending: bool) -> Result<(), String> {
+        let names = self.canonical_names();
+        let mut order: Vec<usize> = (0..self.base_cols as usize).collect();
+        order.sort_by(|&a, &b| {
+            names[a].to_lowercase().cmp(&names[b].to_lowercase()).then_with(|| names[a].cmp(&names[b]))
+        });
+        if !ascending {
+            order.reverse();
+        }
+        self.reorder_columns(order)
+    }
+
+    // `names` must list every real column exactly once
+    pub fn reorder_columns_by_list(&mut self, names: Vec<String>) -> Result<(), String> {
+        if names.len() as i64 != self.base_cols {
+            return Err(format!("Expected {} column names (one per column), got {}", self.base_cols, names.len()));
+        }
+        let current = self.canonical_names();
+        let mut order = Vec::with_capacity(names.len());
+        let mut seen = HashSet::with_capacity(names.len());
+        for name in names {
+            let col = current[..self.base_cols as usize]
+                .iter()
+                .position(|n| *n == name)
+                .ok_or_else(|| format!("No column named '{}'", name))?;
+            if !seen.insert(col) {
+                return Err(format!("Column '{}' is listed twice", name));
+            }
+            order.push(col);
+        }
+        self.reorder_columns(order)
+    }
+
+    // Back to the file's column order
+    pub fn reset_column_order(&mut self) -> Result<(), String> {
+        let Some(order) = &self.col_order else { return Ok(()) };
+        let back = order.to_session.clone();
+        self.reorder_columns(back)
+    }
+
+    // Helper: new session col i shows current col order[i]
+    fn reorder_columns(&mut self, order: Vec<usize>) -> Result<(), String> {
+        if !self.derived.is_empty() {
+            return Err("Remove the derived columns before reordering columns".to_string());
+        }
+        if self.col_window.is_some() {
+            return Err("Columns of a sub view can't be reordered".to_string());
+        }
+        let mut moved_to = vec![0; order.len()]; // current col -> new col
+        for (new, &old) in order.iter().enumerate() {
+            moved_to[old] = new;
+        }
+
+        let to_file: Vec<usize> = order.iter().map(|&old| self.file_col(old).unwrap_or(old)).collect();
+        for row_edits in self.edits.values_mut() {
+            *row_edits = row_edits
+                .drain()
+                .map(|(col, value)| (moved_to.get(col).copied().unwrap_or(col), value))
+                .collect();
+        }
+        if !self.header_names.is_empty() {
+            // Names can be fewer than the columns (a column override or a
+            // reopen onto a wider file); the extra columns keep their letter
+            self.header_names = order
+                .iter()
+                .map(|&old| {
+                    self.header_names.get(old).cloned().unwrap_or_else(|| Self::number_to_col_name(old as i64))
+                })
+                .collect();
+        }
+        self.null_tokens.per_col = std::mem::take(&mut self.null_tokens.per_col)
+            .into_iter()
+            .map(|(col, tokens)| (moved_to.get(col).copied().unwrap_or(col), tokens))
+            .collect();
+
+        self.col_order = if to_file.iter().enumerate().all(|(col, &file)| col == file) {
+            None
+        } else {
+            let mut to_session = vec![0; to_file.len()];
+            for (col, &file) in to_file.iter().enumerate() {
+                to_session[file] = col;
+            }
+            Some(ColumnOrder { to_file, to_session })
+        };
+        self.inverted_index = None;
+        self.search_index = None;
+        self.invalidate_analysis();
+        Ok(())
+    }
+
+    // 40. ENCODING CHECK (how many lines aren't UTF-8)
+    // Lines that aren't valid UTF-8 are read as Windows-1252 everywhere
+    // (grid, search, export of edited rows; see decode_line), and
+    // RowData.decoded_with_fallback flags them. This counts such lines over
+    // `sample` random rows (None: all rows, exact) so the UI can warn about
+    // them. Only the file's bytes are looked at, not edits.
+    pub fn analyze_encoding(&self, sample: Option<i64>) -> EncodingReport {
+        let limit = sample.unwrap_or(self.total_rows).max(0);
+        let rows = self.sample_rows(limit.min(self.total_rows) as usize);
+
+        let (mut non_ascii, mut utf8, mut fallback, mut plausible_1252) = (0i64, 0i64, 0i64, 0i64);
+        for &row in &rows {
+            let line = self.row_bytes(self.source_row(row));
+            if line.is_ascii() {
+                continue;
+            }
+            non_ascii += 1;
+            if str::from_utf8(line).is_ok() {
+                utf8 += 1;
+            } else {
+                fallback += 1;
+                // Bytes Windows-1252 leaves undefined make it a poor guess
+                if !line.iter().any(|b| matches!(b, 0x81 | 0x8D | 0x8F | 0x90 | 0x9D)) {
+                    plausible_1252 += 1;
+                }
+            }
+        }
+
+        let share = |lines: i64| if non_ascii == 0 { 1.0 } else { lines as f64 / non_ascii as f64 };
+        let mut scores = vec![
+            EncodingScore { encoding: "utf-8".to_string(), confidence: share(utf8) },
+            EncodingScore { encoding: "windows-1252".to_string(), confidence: share(plausible_1252) },
+        ];
+        scores.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        EncodingReport {
+            scores,
+            fallback_lines: fallback,
+            lines_scanned: rows.len() as i64,
+            is_exact: rows.len() as i64 == self.total_rows,
+        }
+    }
+
+    // 41. COMPARE SUMMARY (worth running a full diff or join?)
+    // Compares the shape of two sessions from up to sample_rows random rows
+    // of each: columns are matched by canonical name when both have header
+    // names (first_row_header or write_csv_header) and by position
+    // otherwise, and each matched pair gets a similarity score for its value
+    // distribution (blank and null-token cells aside; see ColumnComparison).
+    pub fn compare_summary(&self, other: &SheetSession, sample_rows: usize) -> CompareSummary {
+        let names = self.canonical_names();
+        let other_names = other.canonical_names();
+        let by_name = !self.header_names.is_empty() && !other.header_names.is_empty();
+        let pairs: Vec<(usize, usize)> = if by_name {
+            let positions: HashMap<&str, usize> =
+                other_names.iter().enumerate().map(|(col, name)| (name.as_str(), col)).collect();
+            names.iter().enumerate().filter_map(|(col, name)| Some((col, *positions.get(name.as_str())?))).collect()
+        } else {
+            (0..names.len().min(other_names.len())).map(|col| (col, col)).collect()
+        };
+        let matched_self: HashSet<usize> = pairs.iter().map(|&(col, _)| col).collect();
+        let matched_other: HashSet<usize> = pairs.iter().map(|&(_, col)| col).collect();
+
+        let sample = self.sample_values(sample_rows);
+        let other_sample = other.sample_values(sample_rows);
+        let columns: Vec<ColumnComparison> = pairs
+            .iter()
+            .map(|&(col, other_col)| {
+                let (values, other_values) = (&sample.1[col], &other_sample.1[other_col]);
+                let (similarity, numeric) = distribution_similarity(values, other_values);
+                let null_ratio = |values: &[String], rows: usize| {
+                    if rows == 0 { 0.0 } else { 1.0 - values.len() as f64 / rows as f64 }
+                };
+                ColumnComparison {
+                    name: names[col].clone(),
+                    col_self: col as i64,
+                    col_other: other_col as i64,
+                    similarity,
+                    numeric,
+                    null_ratio_self: null_ratio(values, sample.0),
+                    null_ratio_other: null_ratio(other_values, other_sample.0),
+                }
+            })
+            .collect();
+
+        CompareSummary {
+            rows_self: self.total_rows,
+            rows_other: other.total_rows,
+            row_count_delta: other.total_rows - self.total_rows,
+            sampled_rows_self: sample.0 as i64,
+            sampled_rows_other: other_sample.0 as i64,
+            matched_by_name: by_name,
+            only_in_self: (0..names.len()).filter(|c| !matched_self.contains(c)).map(|c| names[c].clone()).collect(),
+            only_in_other: (0..other_names.len())
+                .filter(|c| !matched_other.contains(c))
+                .map(|c| other_names[c].clone())
+                .collect(),
+            moved: pairs
+                .iter()
+                .filter(|(col, other_col)| col != other_col)
+                .map(|&(col, _)| names[col].clone())
+                .collect(),
+            different_distributions: columns
+                .iter()
+                .filter(|c| c.similarity < COMPARE_DIFFERENT_BELOW)
+                .map(|c| c.name.clone())
+                .collect(),
+            columns,
+        }
+    }
+
+    // Helper: (rows sampled, per column its non-blank, non-null-token cells)
+    fn sample_values(&self, sample_rows: usize) -> (usize, Vec<Vec<String>>) {
+        let rows = self.sample_rows(sample_rows);
+        let mut values = vec![Vec::new(); self.total_cols.max(0) as usize];
+        for (_, fields) in self.view_rows(rows.iter().copied()) {
+            for (col, column) in values.iter_mut().enumerate() {
+                let value = field_or_empty(&fields, col).trim();
+                if !value.is_empty() && !self.is_null_token(col, value) {
+                    column.push(value.to_string());
+                }
+            }
+        }
+        (rows.len(), values)
+    }
+
+    // 42. SCROLL ANCHORS (keep the viewport still across reopen and filters)
+    // An anchor remembers the file row under a view row and a hash of its
+    // bytes. resolve_anchor finds it again after sorting, filtering or
+    // reopen: at the same file row if the bytes still match, else at the
+    // nearest matching row within ANCHOR_SEARCH_ROWS (rows inserted or
+    // removed above it). Nothing is tracked per row; each anchor costs a
+    // few bytes until drop_anchor. Edits don't move anchors; repairing the
+    // encoding (which rewrites bytes) loses them.
+    pub fn create_anchor(&mut self, view_row: i64) -> Result<i64, String> {
+        if view_row < 0 || view_row >= self.total_rows {
+            return Err(format!("Row {} is out of range", view_row));
+        }
+        let source_row = self.source_row(view_row);
+        let digest = md5::compute(self.row_bytes(source_row)).0;
+        let id = self.next_anchor;
+        self.next_anchor += 1;
+        self.anchors.insert(id, Anchor { source_row, digest });
+        Ok(id)
+    }
+
+    // Where the anchored row is in the view now. A row found at another
+    // file row stays anchored there from now on.
+    pub fn resolve_anchor(&mut self, id: i64) -> Result<AnchorPosition, String> {
+        let anchor = self.anchors.get(&id).ok_or_else(|| format!("No anchor with id {}", id))?;
+        let (source_row, digest) = (anchor.source_row, anchor.digest);
+        let Some(found) = self.find_anchored_row(source_row, &digest) else {
+            return Ok(AnchorPosition { row: None, nearest: self.nearest_view_row(source_row) });
+        };
+        if let Some(anchor) = self.anchors.get_mut(&id) {
+            anchor.source_row = found;
+        }
+        Ok(match self.view_row_of(found) {
+            Some(row) => AnchorPosition { row: Some(row), nearest: Some(row) },
+            None => AnchorPosition { row: None, nearest: self.nearest_view_row(found) },
+        })
+    }
+
+    pub fn drop_anchor(&mut self, id: i64) -> bool {
+        self.anchors.remove(&id).is_some()
+    }
+
+    // Helper: file row holding the anchored bytes, nearest to where they were
+    fn find_anchored_row(&self, source_row: usize, digest: &[u8; 16]) -> Option<usize> {
+        let matches = |row: usize| {
+            (self.row_base..self.row_end).contains(&row) && md5::compute(self.row_bytes(row)).0 == *digest
+        };
+        (0..=ANCHOR_SEARCH_ROWS).find_map(|distance| {
+            let above = source_row.checked_sub(distance).filter(|&row| matches(row));
+            above.or_else(|| Some(source_row + distance).filter(|&row| distance > 0 && matches(row)))
+        })
+    }
+
+    // Helper: file row -> view row, if the view shows it
+    fn view_row_of(&self, source_row: usize) -> Option<i64> {
+        let pos = match &self.row_order {
+            Some(order) => order.iter().position(|&row| row as usize == source_row)?,
+            None => source_row.checked_sub(self.row_base).filter(|_| source_row < self.row_end)?,
+        };
+        Some(if self.reverse_order { self.total_rows - 1 - pos as i64 } else { pos as i64 })
+    }
+
+    // Helper: view row whose file row is closest to `source_row`
+    fn nearest_view_row(&self, source_row: usize) -> Option<i64> {
+        if self.total_rows <= 0 { return None; }
+        let pos = match &self.row_order {
+            Some(order) => order.iter().enumerate().min_by_key(|(_, &row)| (row as usize).abs_diff(source_row))?.0,
+            None => source_row.clamp(self.row_base, self.row_end - 1) - self.row_base,
+        };
+        Some(if self.reverse_order { self.total_rows - 1 - pos as i64 } else { pos as i64 })
+    }
+
+    // 43. ROW TAGS (bookmarks, flagged rows)
+    // Named sets of rows the user marked, without touching cell data. Tags
+    // hold file rows, so they follow rows through sorting and filtering;
+    // they are part of export_state. On reopen each tagged row is looked
+    // for the way resolve_anchor does (same bytes, at most
+    // ANCHOR_SEARCH_ROWS away), so rows inserted above it don't shift its
+    // tags onto other rows; a row that isn't found loses its tags. There is
+    // no row-level session diff to report them in (compare_summary works on
+    // columns), so get_rows_by_tag is the way to see them.
+    pub fn tag_row(&mut self, row: i64, tag: String) -> Result<(), String> {
+        if row < 0 || row >= self.total_rows {
+            return Err(format!("Row {} is out of range", row));
+        }
+        if tag.trim().is_empty() {
+            return Err("Tag must not be empty".to_string());
+        }
+        let source_row = self.source_row(row);
+        let digest = md5::compute(self.row_bytes(source_row)).0;
+        let source_row = source_row as i64;
+        self.tag_digests.insert(source_row, digest);
+        let rows = self.row_tags.entry(tag).or_default();
+        if let Err(pos) = rows.binary_search(&source_row) {
+            rows.insert(pos, source_row);
+        }
+        Ok(())
+    }
+
+    // Returns whether the row had the tag
+    pub fn untag_row(&mut self, row: i64, tag: &str) -> Result<bool, String> {
+        if row < 0 || row >= self.total_rows {
+            return Err(format!("Row {} is out of range", row));
+        }
+        let source_row = self.source_row(row) as i64;
+        let Some(rows) = self.row_tags.get_mut(tag) else { return Ok(false) };
+        let Ok(pos) = rows.binary_search(&source_row) else { return Ok(false) };
+        rows.remove(pos);
+        if rows.is_empty() {
+            self.row_tags.remove(tag);
+        }
+        if !self.row_tags.values().any(|rows| rows.binary_search(&source_row).is_ok()) {
+            self.tag_digests.remove(&source_row);
+        }
+        Ok(true)
+    }
+
+    // View rows with `tag`, ascending; tagged rows the view doesn't show
+    // (filtered out, or gone after reopen) are left out
+    pub fn get_rows_by_tag(&self, tag: &str) -> Vec<i64> {
+        let Some(tagged) = self.row_tags.get(tag) else { return Vec::new() };
+        let mut rows: Vec<i64> = match &self.row_order {
+            None => tagged.iter().filter_map(|&row| self.view_row_of(row as usize)).collect(),
+            Some(_) => (0..self.total_rows)
+                .filter(|&row| tagged.binary_search(&(self.source_row(row) as i64)).is_ok())
+                .collect(),
+        };
+        rows.sort_unstable();
+        rows
+    }
+
+    // Every tag in use, sorted
+    pub fn all_tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self.row_tags.keys().cloned().collect();
+        tags.sort_unstable();
+        tags
+    }
+
+    pub fn clear_tag(&mut self, tag: &str) {
+        self.row_tags.remove(tag);
+        let tagged: HashSet<i64> = self.row_tags.values().flatten().copied().collect();
+        self.tag_digests.retain(|row, _| tagged.contains(row));
+    }
+
+    // Helper: hash every tagged row as it is now (rows kept their places but
+    // their bytes may have changed: save_in_place, encoding repair, import)
+    fn refresh_tag_digests(&mut self) {
+        let tagged: HashSet<i64> = self.row_tags.values().flatten().copied().collect();
+        self.tag_digests = tagged
+            .into_iter()
+            .filter(|&row| (self.row_base..self.row_end).contains(&(row as usize)))
+            .map(|row| (row, md5::compute(self.row_bytes(row as usize)).0))
+            .collect();
+    }
+
+    // Helper: reopen's share of ROW TAGS: move the tags onto the rows of
+    // `fresh` (the file as it is now) that hold the tagged bytes
+    fn reanchor_tags(&mut self, fresh: &mut SheetSession) {
+        let moved: HashMap<i64, i64> = self
+            .tag_digests
+            .iter()
+            .filter_map(|(&row, digest)| Some((row, fresh.find_anchored_row(row as usize, digest)? as i64)))
+            .collect();
+        for (tag, rows) in std::mem::take(&mut self.row_tags) {
+            let mut rows: Vec<i64> = rows.iter().filter_map(|row| moved.get(row).copied()).collect();
+            rows.sort_unstable();
+            rows.dedup();
+            if !rows.is_empty() {
+                fresh.row_tags.insert(tag, rows);
+            }
+        }
+        fresh.tag_digests = moved.iter().map(|(old, &new)| (new, self.tag_digests[old])).collect();
+    }
+
+    pub fn get_task_progress(&self) -> TaskProgress {
+        TaskProgress {
+            processed_rows: self.task.processed.load(Ordering::Relaxed),
+            total_rows: self.task.total.load(Ordering::Relaxed),
+            is_running: self.task.running.load(Ordering::Relaxed),
+        }
+    }
+
+    // Ask the running operation to stop. Only operations that say they can be
+    // cancelled (partition_by_hash) look at this; they clean up and fail.
+    pub fn cancel_task(&self) {
+        if self.task.running.load(Ordering::Relaxed) {
+            self.task.cancel_requested.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_normalize_options(&mut self, options: NormalizeOptions) {
+        self.normalize = options;
+        self.inverted_index = None; // Built from normalized text
+    }
+
+    // Helper: apply the session's trim/case settings to a cell value
+    fn normalize_cell<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        normalize_with(&self.normalize, value)
+    }
+
+    // Helper: the in-range columns of a requested window (same clamping as headers)
+    fn visible_cols(&self, col_start: i64, col_count: i32) -> Vec<usize> {
+        let start = col_start.max(0);
+        let end = col_start.saturating_add(col_count.max(0) as i64).min(self.total_cols);
+        (start..end).map(|c| c as usize).collect()
+    }
+
+    // Helper: cached column profiles; uncached columns are computed in one shared pass
+    fn column_profiles(&self, cols: &[usize]) -> Vec<ColumnProfile> {
+        let mut cache = self.analysis.lock().unwrap();
+
+        let missing: Vec<usize> = cols
+            .iter()
+            .copied()
+            .filter(|c| !cache.profiles.contains_key(c))
+            .collect();
+        if !missing.is_empty() {
+            let mut builders = vec![ProfileBuilder::default(); missing.len()];
+            for (_, fields) in self.view_rows(0..self.total_rows.min(TYPE_SAMPLE_ROWS)) {
+                for (builder, &col) in builders.iter_mut().zip(&missing) {
+                    let value = field_or_empty(&fields, col);
+                    // A "\\N" shouldn't make a number column Text
+                    builder.observe(if self.is_null_token(col, value) { "" } else { value });
+                }
+            }
+            for (builder, col) in builders.into_iter().zip(missing) {
+                cache.profiles.insert(col, builder.finish());
+            }
+        }
+
+        cols.iter().map(|c| cache.profiles[c]).collect()
+    }
+
+    // Call whenever the rows (or how they're parsed) change
+    fn invalidate_analysis(&mut self) {
+        *self.analysis.get_mut().unwrap() = AnalysisCache::default();
+    }
+
+    fn check_explode_args(&self, col: i64, secondary_delimiter: &str) -> Result<(), String> {
+        if col < 0 || col >= self.total_cols {
+            return Err(format!("Column {} is out of range", col));
+        }
+        if secondary_delimiter.is_empty() {
+            return Err("Secondary delimiter must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    // Helper: one source row -> its exploded copies. The file is split first
+    // (quote-aware), so a sub-delimiter equal to ',' only splits quoted cells.
+    fn explode_row(&self, row: i64, col: usize, delimiter: &str) -> Vec<Vec<String>> {
+        let mut fields = self.row_fields(self.source_row(row));
+        if fields.len() <= col {
+            fields.resize(col + 1, String::new());
+        }
+
+        let value = std::mem::take(&mut fields[col]);
+        if value.is_empty() {
+            return vec![fields];
+        }
+        value
+            .split(delimiter)
+            .map(|part| {
+                let mut copy = fields.clone();
+                copy[col] = part.to_string();
+                copy
+            })
+            .collect()
+    }
+
+    // Helper: start progress reporting for a long operation
+    fn begin_task(&self, total_rows: i64) -> TaskGuard<'_> {
+        self.task.processed.store(0, Ordering::Relaxed);
+        self.task.total.store(total_rows, Ordering::Relaxed);
+        self.task.running.store(true, Ordering::Relaxed);
+        self.task.cancel_requested.store(false, Ordering::Relaxed);
+        TaskGuard(&self.task)
+    }
+
+    // Helper: set operations that stack rows need the same shape
+    fn check_same_cols(&self, other: &SheetSession) -> Result<(), String> {
+        if self.total_cols != other.total_cols {
+            return Err(format!(
+                "Column counts differ ({} vs {})", self.total_cols, other.total_cols
+            ));
+        }
+        Ok(())
+    }
+
+    // Helper: key columns must exist in both sessions
+    fn check_key_cols(&self, other: &SheetSession, key_cols: &[i64]) -> Result<Vec<usize>, String> {
+        if key_cols.is_empty() {
+            return Err("No key columns selected".to_string());
+        }
+        let limit = self.total_cols.min(other.total_cols);
+        if let Some(&bad) = key_cols.iter().find(|&&c| c < 0 || c >= limit) {
+            return Err(format!("Key column {} is out of range", bad));
+        }
+        Ok(key_cols.iter().map(|&c| c as usize).collect())
+    }
+
+    fn check_join_keys(
+        &self,
+        other: &SheetSession,
+        left_key: &[i64],
+        right_key: &[i64],
+    ) -> Result<(Vec<usize>, Vec<usize>), String> {
+        if left_key.len() != right_key.len() {
+            return Err(format!(
+                "Key lengths differ ({} vs {})", left_key.len(), right_key.len()
+            ));
+        }
+        if left_key.is_empty() {
+            return Err("No key columns selected".to_string());
+        }
+        if let Some(&bad) = left_key.iter().find(|&&c| c < 0 || c >= self.total_cols) {
+            return Err(format!("Left key column {} is out of range", bad));
+        }
+        if let Some(&bad) = right_key.iter().find(|&&c| c < 0 || c >= other.total_cols) {
+            return Err(format!("Right key column {} is out of range", bad));
+        }
+        Ok((
+            left_key.iter().map(|&c| c as usize).collect(),
+            right_key.iter().map(|&c| c as usize).collect(),
+        ))
+    }
+
+    // Helper: key -> rows with that key, in row order
+    fn key_index(&self, key_cols: &[usize]) -> HashMap<String, Vec<i64>> {
+        let mut index: HashMap<String, Vec<i64>> = HashMap::new();
+        for row in 0..self.total_rows {
+            index.entry(self.row_key(row, key_cols)).or_default().push(row);
+        }
+        index
+    }
+
+    // Helper: shared by inner_join/left_join; keep_unmatched pads self's rows
+    // that have no partner instead of dropping them
+    fn join(
+        &self,
+        other: &SheetSession,
+        left_key: &[i64],
+        right_key: &[i64],
+        keep_unmatched: bool,
+    ) -> Result<SheetSession, String> {
+        let (left_key, right_key) = self.check_join_keys(other, left_key, right_key)?;
+        let right_index = other.key_index(&right_key);
+        let other_width = (0..other.total_cols as usize).filter(|c| !right_key.contains(c)).count();
+
+        let mut out = Vec::new();
+        for row in 0..self.total_rows {
+            let matches = right_index.get(&self.row_key(row, &left_key));
+            if matches.is_none() && !keep_unmatched { continue; }
+            let mut left = self.row_fields(self.source_row(row));
+            left.resize(self.total_cols as usize, String::new());
+
+            match matches {
+                Some(matches) => {
+                    for &other_row in matches {
+                        let mut joined = left.clone();
+                        joined.extend(other.non_key_fields(other_row, &right_key));
+                        write_csv_row(&mut out, &joined)?;
+                        out.push(b'\n');
+                    }
+                }
+                None => {
+                    left.resize(left.len() + other_width, String::new());
+                    write_csv_row(&mut out, &left)?;
+                    out.push(b'\n');
+                }
+            }
+        }
+        Self::from_records(out)
+    }
+
+    // Helper: a row's cells (padded to total_cols) without the given key columns
+    fn non_key_fields(&self, row: i64, key_cols: &[usize]) -> Vec<String> {
+        let mut fields = self.row_fields(self.source_row(row));
+        fields.resize(self.total_cols as usize, String::new());
+        fields
+            .into_iter()
+            .enumerate()
+            .filter(|(c, _)| !key_cols.contains(c))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    // Helper: a row's key cells joined with \0 (so "a,b"+"c" != "a"+"b,c")
+    fn row_key(&self, row: i64, key_cols: &[usize]) -> String {
+        let fields = self.row_fields(self.source_row(row));
+        key_cols
+            .iter()
+            .map(|&c| field_or_empty(&fields, c))
+            .collect::<Vec<_>>()
+            .join("\0")
+    }
+
+    fn collect_keys(&self, key_cols: &[usize]) -> HashSet<String> {
+        (0..self.total_rows).map(|row| self.row_key(row, key_cols)).collect()
+    }
+
+    // Helper: append logical rows to an in-memory CSV buffer, each \n terminated
+    // (see from_records)
+    fn append_records(&self, out: &mut Vec<u8>, rows: impl IntoIterator<Item = i64>) -> Result<(), String> {
+        for row in rows {
+            self.write_record(out, row, false)?;
+            out.push(b'\n');
+        }
+        Ok(())
+    }
+
+    // Helper: k distinct logical rows chosen at random (Floyd's algorithm), in
+    // ascending order for read locality
+    fn sample_rows(&self, k: usize) -> Vec<i64> {
+        self.sample_rows_seeded(k, SUMMARY_SEED)
+    }
+
+    fn sample_rows_seeded(&self, k: usize, seed: u64) -> Vec<i64> {
+        let n = self.total_rows;
+        if k as i64 >= n {
+            return (0..n).collect();
+        }
+        let mut rng = SplitMix64(seed);
+        let mut chosen = HashSet::with_capacity(k);
+        for j in (n - k as i64)..n {
+            let t = rng.next_below(j as u64 + 1) as i64;
+            if !chosen.insert(t) {
+                chosen.insert(j);
+            }
+        }
+        let mut rows: Vec<i64> = chosen.into_iter().collect();
+        rows.sort_unstable();
+        rows
+    }
+
+    // Helper: approximate heap bytes per component. Takes the locked cache so
+    // callers already holding the analysis lock can use it.
+    fn memory_usage(&self, cache: &AnalysisCache) -> Vec<(&'static str, u64)> {
+        let word = std::mem::size_of::<usize>();
+        let order_bytes = |order: &Option<Vec<i64>>| order.as_ref().map_or(0, |o| o.capacity() * word);
+        let buffer = match &*self.content {
+            FileContent::Buffered(bytes) => bytes.capacity(),
+            FileContent::Mapped(_) => 0,
+        };
+        let edits: usize = self
+            .edits
+            .values()
+            .map(|row| EDIT_ROW_OVERHEAD + row.values().map(|v| EDIT_CELL_OVERHEAD + v.capacity()).sum::<usize>())
+            .sum();
+        let views: usize = self.views.values().map(|v| order_bytes(&v.row_order)).sum();
+        let read_order = cache.read_order.as_ref().map_or(0, |(key, order)| (key.len() + order.len()) * word);
+        let profiles = cache.profiles.len() * std::mem::size_of::<(usize, ColumnProfile)>();
+
+        vec![
+            ("row_index", self.row_offsets.bytes() as u64),
+            ("buffer", buffer as u64),
+            ("row_order", (order_bytes(&self.row_order) + self.filter_base.as_ref().map_or(0, order_bytes)) as u64),
+            ("edit_overlay", edits as u64),
+            ("views", views as u64),
+            ("inverted_index", self.inverted_index.as_ref().map_or(0, |index| index.bytes) as u64),
+            ("search_index", self.search_index.as_ref().map_or(0, |index| index.bytes) as u64),
+            ("caches", (read_order + profiles) as u64),
+        ]
+    }
+
+    // Helper: bytes left under the budget (None when there is no budget)
+    fn budget_left(&self, cache: &AnalysisCache) -> Option<u64> {
+        let budget = self.memory_budget?;
+        let used: u64 = self.memory_usage(cache).iter().map(|(_, bytes)| bytes).sum();
+        Some(budget.saturating_sub(used))
+    }
+
+    // Helper: make sure `bytes` more fit under the budget, evicting caches if
+    // that's what it takes. `hint` tells the user what to do instead.
+    fn check_budget(&self, component: &str, bytes: usize, hint: &str) -> Result<(), String> {
+        let mut cache = self.analysis.lock().unwrap();
+        let Some(left) = self.budget_left(&cache) else { return Ok(()) };
+        if bytes as u64 <= left { return Ok(()); }
+
+        *cache = AnalysisCache::default();
+        let left = self.budget_left(&cache).unwrap_or(u64::MAX);
+        if bytes as u64 <= left { return Ok(()); }
+        Err(format!(
+            "Memory budget exceeded: {} needs {} more bytes but only {} of {} are left; {}",
+            component, bytes, left, self.memory_budget.unwrap_or(0), hint
+        ))
+    }
+
+    // Helper: 1-based line of a logical row in the source file (any header
+    // added with write_csv_header isn't in the file, so doesn't count)
+    fn file_line_number(&self, row: i64) -> usize {
+        self.source_row(row) + 1
+    }
+
+    // Helper: logical (view) row -> row in the file
+
+    fn source_row(&self, row: i64) -> usize {
+        let row = if self.reverse_order { self.total_rows - 1 - row } else { row };
+        self.ordered_row(row as usize)
+    }
+
+    // Helper: position in the row order -> row in the file (ignores reverse_order)
+    fn ordered_row(&self, pos: usize) -> usize {
+        match &self.row_order {
+            Some(order) => order[pos] as usize,
+            None => self.row_base + pos,
+        }
+    }
+
+    // Helper: one cell of a source row as the grid shows it, splitting only
+    // what's needed (derived columns still decode the whole row)
+    fn cell_text(&self, row: usize, col: usize) -> String {
+        if col >= self.base_cols as usize {
+            return self.derived[col - self.base_cols as usize].evaluate(&self.source_fields(row));
+        }
+        if let Some(edited) = self.edits.get(&row).and_then(|e| e.get(&col)) {
+            return edited.clone();
+        }
+        if self.repaired_widths.get(&row).is_some_and(|&w| col >= w) {
+            return String::new();
+        }
+        let line = self.row_bytes(row);
+        match self.file_col(col) {
+            Some(file_col) if !line.is_empty() => self.split_line(&decode_line(line))
+                .into_iter()
+                .nth(file_col)
+                .map(Cow::into_owned)
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    // Helper: column types for repair scoring, from the first TYPE_SAMPLE_ROWS
+    // rows that have exactly `expected` fields (bad rows would skew them)
+    fn repair_types(&self, expected: usize) -> Vec<ColumnType> {
+        let mut builders = vec![ProfileBuilder::default(); expected];
+        let well_formed = (0..self.total_rows)
+            .map(|row| self.source_fields(self.source_row(row)))
+            .filter(|fields| fields.len() == expected)
+            .take(TYPE_SAMPLE_ROWS as usize);
+        for fields in well_formed {
+            for (builder, field) in builders.iter_mut().zip(&fields) {
+                builder.observe(field);
+            }
+        }
+        builders.into_iter().map(|b| b.finish().column_type).collect()
+    }
+
+    // Helper: a filter condition as history stores it
+    fn record_condition(&self, condition: &FilterCondition) -> RecordedCondition {
+        match condition {
+            FilterCondition::Column { col, predicate } => {
+                let named = *col >= self.base_cols || !self.header_names.is_empty();
+                let col_name = named.then(|| self.get_header_metadata(*col, 1).pop()).flatten().map(|h| h.canonical);
+                let predicate = match predicate {
+                    FilterPredicate::Equals(v) => RecordedPredicate::Equals(v.clone()),
+                    FilterPredicate::NotEquals(v) => RecordedPredicate::NotEquals(v.clone()),
+                    FilterPredicate::Contains(v) => RecordedPredicate::Contains(v.clone()),
+                    FilterPredicate::IsEmpty => RecordedPredicate::IsEmpty,
+                    FilterPredicate::IsNotEmpty => RecordedPredicate::IsNotEmpty,
+                    FilterPredicate::IsNull => RecordedPredicate::IsNull,
+                    FilterPredicate::IsNotNull => RecordedPredicate::IsNotNull,
+                };
+                RecordedCondition::Column { col: *col, col_name, predicate }
+            }
+            FilterCondition::AnyColumnContains(text) => RecordedCondition::AnyColumnContains(text.clone()),
+        }
+    }
+
+    // Helper: append to the query history, dropping the oldest past the cap
+    fn record_query(&mut self, conditions: Vec<RecordedCondition>) {
+        let id = self.query_history.back().map_or(1, |last| last.id + 1);
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        if self.query_history.len() >= QUERY_HISTORY_CAP {
+            self.query_history.pop_front();
+        }
+        self.query_history.push_back(QueryRecord { id, timestamp_ms, conditions });
+    }
+
+    // Helper: is this cell one of its column's null tokens? (a set lookup)
+    fn is_null_token(&self, col: usize, value: &str) -> bool {
+        let tokens = self.null_tokens.per_col.get(&col).unwrap_or(&self.null_tokens.default);
+        !tokens.is_empty() && tokens.contains(value.trim())
+    }
+
+    // Helper: apply set_null_export to a row about to be written
+    fn export_nulls(&self, fields: &mut [String]) {
+        let Some(replacement) = &self.null_tokens.export_as else { return };
+        for (col, field) in fields.iter_mut().enumerate() {
+            if self.is_null_token(col, field) {
+                field.clone_from(replacement);
+            }
+        }
+    }
+
+    // Helper: column in the raw line -> session column (None outside a sub view's window)
+    fn session_col(&self, file_col: usize) -> Option<usize> {
+        if let Some(order) = &self.col_order {
+            return Some(order.to_session.get(file_col).copied().unwrap_or(file_col));
+        }
+        match self.col_window {
+            Some((start, count)) => file_col.checked_sub(start).filter(|&c| c < count),
+            None => Some(file_col),
+        }
+    }
+
+    // Helper: session column -> column in the raw line (None past a sub view's window)
+    fn file_col(&self, col: usize) -> Option<usize> {
+        if let Some(order) = &self.col_order {
+            return Some(order.to_file.get(col).copied().unwrap_or(col));
+        }
+        match self.col_window {
+            Some((start, count)) => (col < count).then_some(start + col),
+            None => Some(col),
+        }
+    }
+
+    // Helper: rows in file order (what an empty row order stands for)
+    fn natural_rows(&self) -> usize {
+        self.row_end - self.row_base
+    }
+
+    // Helper: column count as detection would see it (a sub view's window is fixed)
+    fn detect_cols(&self) -> i64 {
+        match self.col_window {
+            Some((_, count)) => count as i64,
+            None => detect_total_cols(&self.content, &self.row_offsets, self.merge_delimiters),
+        }
+    }
+
+    // Helper: validate the conditions and normalize their values once up front
+    fn compile_filter(&self, conditions: Vec<FilterCondition>) -> Result<CompiledFilter, String> {
+        let norm = |value: String| self.normalize_cell(&value).into_owned();
+        let mut compiled = CompiledFilter { conditions: Vec::with_capacity(conditions.len()), raw_needles: Vec::new() };
+
+        for condition in conditions {
+            let condition = match condition {
+                FilterCondition::Column { col, predicate } => {
+                    if col < 0 || col >= self.total_cols {
+                        return Err(format!("Filter column {} is out of range (0..{})", col, self.total_cols));
+                    }
+                    let predicate = match predicate {
+                        FilterPredicate::Equals(v) => FilterPredicate::Equals(norm(v)),
+                        FilterPredicate::NotEquals(v) => FilterPredicate::NotEquals(norm(v)),
+                        FilterPredicate::Contains(v) => FilterPredicate::Contains(norm(v)),
+                        other => other,
+                    };
+                    FilterCondition::Column { col, predicate }
+                }
+                FilterCondition::AnyColumnContains(v) => {
+                    let needle = norm(v);
+                    // The raw line only contains the needle verbatim when no quote
+                    // unescaping or derived value could create it (fallback-decoded
+                    // lines are checked after decoding, see row_passes)
+                    if !needle.is_empty() && self.derived.is_empty() && !needle.contains('"') {
+                        compiled.raw_needles.push(needle.as_bytes().to_vec());
+                    }
+                    FilterCondition::AnyColumnContains(needle)
+                }
+            };
+            compiled.conditions.push(condition);
+        }
+        Ok(compiled)
+    }
+
+    // Helper: does one source row pass every condition? AnyColumnContains first
+    // rejects rows whose raw bytes can't contain the text, then the row is split
+    // once and every condition is checked against the fields (so a match that
+    // spans a delimiter doesn't count).
+    fn row_passes(&self, row: usize, filter: &CompiledFilter) -> bool {
+        if !filter.raw_needles.is_empty() && !self.edits.contains_key(&row) {
+            let line = self.row_bytes(row);
+            // ASCII folding is only exact for ASCII lines, and the raw bytes
+            // only match UTF-8 needles when the line is read as UTF-8
+            let fold = !self.normalize.case_sensitive;
+            if (if fold { line.is_ascii() } else { str::from_utf8(line).is_ok() })
+                && !filter.raw_needles.iter().all(|needle| contains_bytes(line, needle, fold))
+            {
+                return false;
+            }
+        }
+
+        let fields = self.row_fields(row);
+        let visible = &fields[..fields.len().min(self.total_cols as usize)];
+        let padding = self.padding_mask(row);
+        filter.conditions.iter().all(|condition| match condition {
+            // Strict padding: a cell the row doesn't have matches nothing
+            FilterCondition::Column { col, .. } if is_padded(&padding, *col as usize) => false,
+            FilterCondition::Column { col, predicate } => {
+                let raw = field_or_empty(&fields, *col as usize);
+                let is_null = || raw.trim().is_empty() || self.is_null_token(*col as usize, raw);
+                let cell = self.normalize_cell(raw);
+                match predicate {
+                    FilterPredicate::Equals(v) => cell == v.as_str(),
+                    FilterPredicate::NotEquals(v) => cell != v.as_str(),
+                    FilterPredicate::Contains(v) => cell.contains(v.as_str()),
+                    FilterPredicate::IsEmpty => cell.is_empty(),
+                    FilterPredicate::IsNotEmpty => !cell.is_empty(),
+                    FilterPredicate::IsNull => is_null(),
+                    FilterPredicate::IsNotNull => !is_null(),
+                }
+            }
+            FilterCondition::AnyColumnContains(needle) => {
+                needle.is_empty() || visible.iter().any(|f| self.normalize_cell(f).contains(needle.as_str()))
+            }
+        })
+    }
+
+    // Helper: `rows` (view rows, in the order given) as (row, row_fields). Analysis
+    // and export scans read through this or column_cells rather than the mmap,
+    // so they see what the grid shows: edits, repaired widths, derived columns.
+    fn view_rows<'a>(
+        &'a self,
+        rows: impl IntoIterator<Item = i64> + 'a,
+    ) -> impl Iterator<Item = (i64, Vec<String>)> + 'a {
+        rows.into_iter().map(move |row| (row, self.row_fields(self.source_row(row))))
+    }
+
+    // Helper: view_rows for one column, as (row, cell_text); splits only as far
+    // as the column
+    fn column_cells<'a>(
+        &'a self,
+        col: usize,
+        rows: impl IntoIterator<Item = i64> + 'a,
+    ) -> impl Iterator<Item = (i64, String)> + 'a {
+        rows.into_iter().map(move |row| (row, self.cell_text(self.source_row(row), col)))
+    }
+
+    // Helper: every cell of one source row as seen in the grid: real cells (with
+    // edits), then derived columns. With derived columns present, ragged extra
+    // fields past base_cols are dropped so derived values land at their index.
+    fn row_fields(&self, row: usize) -> Vec<String> {
+        let mut fields = self.source_fields(row);
+        if !self.derived.is_empty() {
+            let derived: Vec<String> = self.derived.iter().map(|d| d.evaluate(&fields)).collect();
+            fields.resize(self.base_cols as usize, String::new());
+            fields.extend(derived);
+        }
+        fields
+    }
+
+    // Helper: with strict padding, which session columns of one source row
+    // are padding (see build_row: real columns past the row's fields or its
+    // repaired width, unless edited); None when strict padding is off
+    fn padding_mask(&self, row: usize) -> Option<Vec<bool>> {
+        if !self.strict_padding {
+            return None;
+        }
+        let line = self.row_bytes(row);
+        let fields = if line.is_empty() { 0 } else { self.split_line(&decode_line(line)).len() };
+        let width = self.repaired_widths.get(&row);
+        let edits = self.edits.get(&row);
+        Some(
+            (0..self.base_cols as usize)
+                .map(|col| {
+                    !edits.is_some_and(|e| e.contains_key(&col))
+                        && (width.is_some_and(|&w| col >= w) || self.file_col(col).is_none_or(|c| c >= fields))
+                })
+                .collect(),
+        )
+    }
+
+    // Helper: raw bytes of a range of view rows (for traces)
+    fn view_bytes(&self, rows: std::ops::Range<i64>) -> i64 {
+        rows.map(|row| self.row_bytes(self.source_row(row)).len() as i64).sum()
+    }
+
+    // Helper: split_fields, with runs of commas merged when the session does that
+    fn split_line<'a>(&self, line: &'a str) -> Vec<Cow<'a, str>> {
+        if !self.merge_delimiters {
+            return split_fields(line);
+        }
+        let mut ranges = Vec::new();
+        let fields = split_fields_with_ranges(line, Some(&mut ranges));
+        merge_delimiter_runs(fields, &ranges)
+    }
+
+    // Helper: decoded real fields of one source row (see decode_line; quote-aware),
+    // with the edit overlay applied
+    fn source_fields(&self, row: usize) -> Vec<String> {
+        let line_bytes = self.row_bytes(row);
+        let mut fields: Vec<String> = if line_bytes.is_empty() {
+            Vec::new()
+        } else {
+            let line = decode_line(line_bytes);
+            let all = self.split_line(&line);
+            match &self.col_order {
+                // Reordered: every real column (short rows padded), then any extra fields as they were
+                Some(order) => order
+                    .to_file
+                    .iter()
+                    .map(|&c| all.get(c).map_or_else(String::new, |cell| cell.to_string()))
+                    .chain(all.iter().skip(order.to_file.len()).map(|cell| cell.to_string()))
+                    .collect(),
+                None => {
+                    let (skip, take) = self.col_window.unwrap_or((0, usize::MAX));
+                    all.into_iter().skip(skip).take(take).map(Cow::into_owned).collect()
+                }
+            }
+        };
+
+        if let Some(&width) = self.repaired_widths.get(&row) {
+            fields.resize(width, String::new());
+        }
+        if let Some(row_edits) = self.edits.get(&row) {
+            for (&col, value) in row_edits {
+                if fields.len() <= col {
+                    fields.resize(col + 1, String::new());
+                }
+                fields[col] = value.clone();
+            }
+        }
+        fields
+    }
+
+    // Helper: raw bytes of one row, without its terminator
+    fn row_bytes(&self, row: usize) -> &[u8] {
+        let line = self.raw_line(row);
+        match self.terminator {
+            LineTerminator::Crlf => line.strip_suffix(b"\r").unwrap_or(line),
+            _ => line,
+        }
+    }
+
+    // Helper: one row up to its index byte (\n, or \r in CR files); a CRLF
+    // row still has its \r here
+    fn raw_line(&self, row: usize) -> &[u8] {
+        // The end byte is the start of the NEXT row, minus 1 (for the \n)
+        let (start_byte, end_byte) = self.row_offsets.span(&self.content, row);
+
+        if start_byte >= end_byte { return &[]; }
+        &self.content[start_byte..end_byte]
+    }
+
+    // Helper: 0 -> A, 26 -> AA
+    fn number_to_col_name(mut n: i64) -> String {
+        let mut result = String::new();
+        loop {
+            let remainder = (n % 26) as u8;
+            result.insert(0, (b'A' + remainder) as char);
+            n = n / 26 - 1;
+            if n < 0 { break; }
+        }
+        result
+    }
+}
+
+// Row count of a file without building the index (picker badges, "this is
+// big, open anyway?" prompts): one pass over the mapped file, nothing stored.
+// Rows are counted exactly like the session's index does (a quoted newline
+// also ends a row there), so the number matches what opening the file shows;
+// only line_terminator is used from the options. cancel_row_counts stops it.
+pub fn count_rows(path: String, options: OpenOptions) -> Result<RowCount, String> {
+    let generation = ROW_COUNT_GENERATION.load(Ordering::Relaxed);
+    let file = File::open(&path).map_err(|e| format!("Failed to open file: {}", e))?;
+    // UNSAFE: same contract as new_from_file_with_options
+    let mmap = unsafe { Mmap::map(&file).map_err(|e| format!("Failed to map file: {}", e))? };
+    let data: &[u8] = &mmap;
+
+    let terminator = match options.line_terminator.unwrap_or(LineTerminator::Auto) {
+        LineTerminator::Auto => detect_line_terminator(data, &mut Vec::new()),
+        explicit => explicit,
+    };
+    let index_byte = terminator.index_byte();
+
+    let mut total_rows = 1i64;
+    let mut max_line_length = 0usize;
+    let mut line_start = 0usize;
+    for (chunk_index, chunk) in data.chunks(COUNT_ROWS_CHUNK).enumerate() {
+        if ROW_COUNT_GENERATION.load(Ordering::Relaxed) != generation {
+            return Err("Row count was cancelled".to_string());
+        }
+        let chunk_start = chunk_index * COUNT_ROWS_CHUNK;
+        for (i, &byte) in chunk.iter().enumerate() {
+            if byte == index_byte {
+                let end = chunk_start + i;
+                let cr = terminator == LineTerminator::Crlf && end > line_start && data[end - 1] == b'\r';
+                max_line_length = max_line_length.max(end - line_start - cr as usize);
+                line_start = end + 1;
+                total_rows += 1;
+            }
+        }
+    }
+    max_line_length = max_line_length.max(data.len() - line_start);
+
+    Ok(RowCount {
+        total_rows,
+        max_line_length: max_line_length as i64,
+        byte_size: data.len() as i64,
+    })
+}
+
+// Make every count_rows call in flight fail with "cancelled"
+pub fn cancel_row_counts() {
+    ROW_COUNT_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+// Bumped by cancel_row_counts; each count_rows remembers its starting value
+static ROW_COUNT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// count_rows checks for cancellation between chunks of this many bytes
+const COUNT_ROWS_CHUNK: usize = 1 << 20;
+
+// Folder picker: summaries of the sheets in a directory (recursive: in its
+// subdirectories too, not following symlinked ones), at most max_files, in
+// path order. Files with a sheet extension (SCAN_EXTENSIONS) are always
+// listed, with error set if they can't be read; files without an extension
+// are listed if their first bytes look like delimited text. Only the first
+// SCAN_SNIFF_BYTES of each file are read. cancel_directory_scans stops it.
+pub fn scan_directory(path: String, recursive: bool, max_files: i64) -> Result<Vec<FileSummary>, String> {
+    let mut found = Vec::new();
+    walk_directory(std::path::Path::new(&path), recursive, max_files, &mut |summary| {
+        found.push(summary);
+        true
+    })?;
+    Ok(found)
+}
+
+// scan_directory, sending each summary as soon as it's ready so a big folder
+// fills the list progressively. Returns how many were sent; stops early if
+// the stream is closed.
+#[cfg(feature = "frb")]
+pub fn scan_directory_stream(
+    path: String,
+    recursive: bool,
+    max_files: i64,
+    sink: StreamSink<FileSummary>,
+) -> Result<i64, String> {
+    walk_directory(std::path::Path::new(&path), recursive, max_files, &mut |summary| sink.add(summary).is_ok())
+}
+
+// Make every scan_directory call in flight fail with "cancelled"
+pub fn cancel_directory_scans() {
+    SCAN_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+// Bumped by cancel_directory_scans; each scan remembers its starting value
+static SCAN_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// scan_directory reads this much of each file, and always lists these
+const SCAN_SNIFF_BYTES: usize = 16 * 1024;
+const SCAN_EXTENSIONS: [&str; 4] = ["csv", "tsv", "txt", "psv"];
+
+// Delimiters scan_directory tells apart, most likely first on ties
+const SCAN_DELIMITERS: [u8; 4] = [b',', b'\t', b';', b'|'];
+
+// Helper: the directory walk behind scan_directory; `emit` returns false to
+// stop. Returns how many summaries were emitted.
+fn walk_directory(
+    root: &std::path::Path,
+    recursive: bool,
+    max_files: i64,
+    emit: &mut dyn FnMut(FileSummary) -> bool,
+) -> Result<i64, String> {
+    let generation = SCAN_GENERATION.load(Ordering::Relaxed);
+    let mut pending = vec![root.to_path_buf()];
+    let mut emitted = 0i64;
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir == root => return Err(format!("Failed to read directory: {}", e)),
+            Err(e) => {
+                // An unreadable subdirectory is listed like an unreadable file
+                if emitted >= max_files || !emit(failed_summary(&dir, format!("Failed to read directory: {}", e))) {
+                    break;
+                }
+                emitted += 1;
+                continue;
+            }
+        };
+        let mut paths: Vec<(std::path::PathBuf, bool)> = entries
+            .filter_map(Result::ok)
+            .map(|entry| (entry.path(), entry.file_type().is_ok_and(|t| t.is_dir())))
+            .collect();
+        paths.sort();
+
+        let mut subdirs = Vec::new();
+        for (path, is_dir) in paths {
+            if SCAN_GENERATION.load(Ordering::Relaxed) != generation {
+                return Err("Directory scan was cancelled".to_string());
+            }
+            if emitted >= max_files {
+                return Ok(emitted);
+            }
+            if is_dir {
+                if recursive {
+                    subdirs.push(path);
+                }
+                continue;
+            }
+            let Some(summary) = summarize_file(&path) else { continue };
+            if !emit(summary) {
+                return Ok(emitted);
+            }
+            emitted += 1;
+        }
+        // Popped from the end, so push in reverse to visit in path order
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok(emitted)
+}
+
+// Helper: sniff one file; None if it isn't a sheet candidate
+fn summarize_file(path: &std::path::Path) -> Option<FileSummary> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+    let listed = extension.as_deref().is_some_and(|e| SCAN_EXTENSIONS.contains(&e));
+    if !listed && extension.is_some() {
+        return None;
+    }
+
+    let read = || -> std::io::Result<(std::fs::Metadata, Vec<u8>)> {
+        use std::io::Read;
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let mut head = Vec::with_capacity(SCAN_SNIFF_BYTES);
+        file.take(SCAN_SNIFF_BYTES as u64).read_to_end(&mut head)?;
+        Ok((metadata, head))
+    };
+    let (metadata, head) = match read() {
+        Ok(read) => read,
+        Err(e) if listed => return Some(failed_summary(path, format!("Failed to read file: {}", e))),
+        Err(_) => return None,
+    };
+    let size = metadata.len();
+    let whole = head.len() as u64 >= size;
+
+    // Complete lines only, unless the sniff holds the whole file
+    let text = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&head);
+    let mut lines: Vec<&[u8]> = text.split(|&b| b == b'\n').collect();
+    if !whole && lines.len() > 1 {
+        lines.pop();
+    }
+    let lines: Vec<&[u8]> = lines.into_iter().map(|l| l.strip_suffix(b"\r").unwrap_or(l)).collect();
+    let delimiter = sniff_delimiter(&lines);
+    if !listed && (delimiter.is_none() || head.contains(&0)) {
+        return None;
+    }
+
+    let quick_row_estimate = if whole {
+        1 + head.iter().filter(|&&b| b == b'\n').count() as i64
+    } else {
+        let sniffed: usize = lines.iter().map(|l| l.len() + 1).sum();
+        (size as f64 / (sniffed as f64 / lines.len() as f64)).round() as i64
+    };
+    let first_headers = match (lines.first(), delimiter) {
+        (Some(line), Some(b',')) => split_fields(&decode_line(line)).into_iter().map(Cow::into_owned).collect(),
+        (Some(line), Some(d)) => {
+            decode_line(line).split(d as char).map(|f| f.trim_matches('"').to_string()).collect()
+        }
+        (Some(line), None) if !line.is_empty() => vec![decode_line(line).into_owned()],
+        _ => Vec::new(),
+    };
+
+    Some(FileSummary {
+        path: path.to_string_lossy().into_owned(),
+        path_bytes: path_bytes(path),
+        size: size as i64,
+        modified: metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as i64),
+        quick_row_estimate,
+        detected_delimiter: delimiter.map(|d| (d as char).to_string()),
+        first_headers,
+        error: None,
+    })
+}
+
+// Helper: what scan_directory lists for a file or directory it couldn't read
+fn failed_summary(path: &std::path::Path, error: String) -> FileSummary {
+    FileSummary {
+        path: path.to_string_lossy().into_owned(),
+        path_bytes: path_bytes(path),
+        size: 0,
+        modified: None,
+        quick_row_estimate: 0,
+        detected_delimiter: None,
+        first_headers: Vec::new(),
+        error: Some(error),
+    }
+}
+
+// The delimiter that splits the sniffed lines most consistently: the first
+// line must have 2+ fields and most lines the same count. Quotes are
+// honoured, so a delimiter inside "..." doesn't count.
+fn sniff_delimiter(lines: &[&[u8]]) -> Option<u8> {
+    let lines: Vec<&[u8]> = lines.iter().copied().filter(|l| !l.is_empty()).collect();
+    let fields = |line: &[u8], delimiter: u8| {
+        let mut in_quotes = false;
+        1 + line
+            .iter()
+            .filter(|&&b| {
+                if b == b'"' {
+                    in_quotes = !in_quotes;
+                }
+                b == delimiter && !in_quotes
+            })
+            .count()
+    };
+    SCAN_DELIMITERS
+        .iter()
+        .filter_map(|&d| {
+            let expected = fields(lines.first()?, d);
+            let consistent = lines.iter().filter(|l| fields(l, d) == expected).count();
+            (expected > 1 && consistent * 2 > lines.len()).then_some((consistent, expected, d))
+        })
+        .max_by_key(|&(consistent, expected, d)| {
+            (consistent, expected, std::cmp::Reverse(SCAN_DELIMITERS.iter().position(|&x| x == d)))
+        })
+        .map(|(_, _, d)| d)
+}
+
+// ------------------------------------
+// Type Inference
+// ------------------------------------
+
+// How many rows actual_total_cols looks at for ragged widths
+const WIDTH_SCAN_ROWS: usize = 100;
+
+// How many rows type inference looks at
+const TYPE_SAMPLE_ROWS: i64 = 1000;
+
+// All-digit, fixed-length values at least this long are treated as IDs
+const ID_MIN_DIGITS: usize = 5;
+
+#[derive(Clone, Copy)]
+struct ColumnProfile {
+    column_type: ColumnType,
+    looks_like_id: bool,
+}
+
+#[derive(Clone, Default)]
+struct ProfileBuilder {
+    integers: usize,
+    floats: usize,
+    booleans: usize,
+    texts: usize,
+    digit_len: Option<usize>, // Length shared by every all-digit value so far
+    not_id: bool,
+    leading_zero: bool,
+}
+
+impl ProfileBuilder {
+    fn observe(&mut self, value: &str) {
+        let value = value.trim();
+        if value.is_empty() { return; }
+
+        match classify_cell(value) {
+            ColumnType::Integer => self.integers += 1,
+            ColumnType::Float => self.floats += 1,
+            ColumnType::Boolean => self.booleans += 1,
+            _ => self.texts += 1,
+        }
+
+        if self.not_id { return; }
+        let all_digits = value.bytes().all(|b| b.is_ascii_digit());
+        if all_digits && self.digit_len.is_none_or(|len| len == value.len()) {
+            self.digit_len = Some(value.len());
+            self.leading_zero |= value.len() > 1 && value.starts_with('0');
+        } else {
+            self.not_id = true;
+        }
+    }
+
+    fn finish(self) -> ColumnProfile {
+        let numeric = self.integers + self.floats;
+        let column_type = if numeric + self.booleans + self.texts == 0 {
+            ColumnType::Empty
+        } else if self.texts > 0 || (self.booleans > 0 && numeric > 0) {
+            ColumnType::Text
+        } else if self.booleans > 0 {
+            ColumnType::Boolean
+        } else if self.floats > 0 {
+            ColumnType::Float
+        } else {
+            ColumnType::Integer
+        };
+
+        let looks_like_id = !self.not_id
+            && self.digit_len.is_some_and(|len| self.leading_zero || len >= ID_MIN_DIGITS);
+
+        ColumnProfile { column_type, looks_like_id }
+    }
+}
+
+// Distinct values sample_and_describe tracks per column
+const SUMMARY_UNIQUE_CAP: usize = 1000;
+const SUMMARY_TOP_VALUES: usize = 5;
+const SUMMARY_SEED: u64 = 0x5EED;
+
+// compare_summary: similarity under which a column counts as different,
+// and the histogram resolution for numeric columns
+const COMPARE_DIFFERENT_BELOW: f64 = 0.5;
+const COMPARE_BINS: usize = 20;
+
+// Overlap (sum of per-bucket minimum shares, 0..1) of two samples, and
+// whether they were compared as numbers: both all numeric gives COMPARE_BINS
+// equal bins over the combined range, anything else exact value
+// frequencies. Two empty samples are the same; one empty one isn't.
+fn distribution_similarity(a: &[String], b: &[String]) -> (f64, bool) {
+    fn counts<K: std::hash::Hash + Eq>(keys: impl Iterator<Item = K>) -> HashMap<K, usize> {
+        let mut counts = HashMap::new();
+        for key in keys {
+            *counts.entry(key).or_insert(0) += 1;
+        }
+        counts
+    }
+    fn overlap<K: std::hash::Hash + Eq>(x: &HashMap<K, usize>, y: &HashMap<K, usize>, nx: usize, ny: usize) -> f64 {
+        x.iter()
+            .map(|(key, &n)| (n as f64 / nx as f64).min(y.get(key).copied().unwrap_or(0) as f64 / ny as f64))
+            .sum()
+    }
+
+    if a.is_empty() || b.is_empty() {
+        return (if a.is_empty() && b.is_empty() { 1.0 } else { 0.0 }, false);
+    }
+    let numbers = |values: &[String]| values.iter().map(|v| parse_number(v)).collect::<Option<Vec<f64>>>();
+    if let (Some(xs), Some(ys)) = (numbers(a), numbers(b)) {
+        let min = xs.iter().chain(&ys).copied().fold(f64::INFINITY, f64::min);
+        let max = xs.iter().chain(&ys).copied().fold(f64::NEG_INFINITY, f64::max);
+        let bins = COMPARE_BINS as f64;
+        let bin = |n: &f64| if max > min { ((n - min) / (max - min) * bins).min(bins - 1.0) as usize } else { 0 };
+        let (x, y) = (counts(xs.iter().map(bin)), counts(ys.iter().map(bin)));
+        return (overlap(&x, &y, a.len(), b.len()), true);
+    }
+    let (x, y) = (counts(a.iter().map(String::as_str)), counts(b.iter().map(String::as_str)));
+    (overlap(&x, &y, a.len(), b.len()), false)
+}
+
+// Per-column accumulator for sample_and_describe
+#[derive(Clone, Default)]
+struct SummaryBuilder {
+    type_counts: BTreeMap<u8, (ColumnType, i64)>, // Keyed by type order for stable output
+    empty: i64,
+    null_like: i64,
+    value_counts: HashMap<String, i64>,
+    capped: bool,
+    urls: i64,
+    emails: i64,
+    all_numeric: bool, // Meaningful once a non-empty cell was seen
+    seen_value: bool,
+    // Welford running mean/variance over numeric cells
+    count: i64,
+    mean: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+}
+
+impl SummaryBuilder {
+    fn observe(&mut self, value: &str) {
+        let value = value.trim();
+        if value.is_empty() {
+            self.empty += 1;
+            return;
+        }
+
+        let cell_type = classify_cell(value);
+        self.type_counts.entry(cell_type as u8).or_insert((cell_type, 0)).1 += 1;
+        match content_kind_of(value) {
+            ContentKind::Url => self.urls += 1,
+            ContentKind::Email => self.emails += 1,
+            ContentKind::Plain => {}
+        }
+
+        if let Some(count) = self.value_counts.get_mut(value) {
+            *count += 1;
+        } else if self.value_counts.len() < SUMMARY_UNIQUE_CAP {
+            self.value_counts.insert(value.to_string(), 1);
+        } else {
+            self.capped = true;
+        }
+
+        let number = match cell_type {
+            ColumnType::Integer | ColumnType::Float => parse_number(value),
+            _ => None,
+        };
+        self.all_numeric = number.is_some() && (self.all_numeric || !self.seen_value);
+        self.seen_value = true;
+        if let Some(n) = number {
+            if self.count == 0 {
+                self.min = n;
+                self.max = n;
+            }
+            self.count += 1;
+            let delta = n - self.mean;
+            self.mean += delta / self.count as f64;
+            self.m2 += delta * (n - self.mean);
+            self.min = self.min.min(n);
+            self.max = self.max.max(n);
+        }
+    }
+
+    fn finish(self, col: i64, name: String, sampled_rows: i64) -> ColumnSummary {
+        let numeric = self.seen_value && self.all_numeric;
+        let mut top_values: Vec<(String, i64)> = self.value_counts.iter().map(|(v, &c)| (v.clone(), c)).collect();
+        top_values.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_values.truncate(SUMMARY_TOP_VALUES);
+        let non_empty: i64 = self.type_counts.values().map(|&(_, count)| count).sum();
+        let ratio = |count: i64| if non_empty == 0 { 0.0 } else { count as f64 / non_empty as f64 };
+
+        ColumnSummary {
+            col,
+            name,
+            type_counts: self.type_counts.into_values().collect(),
+            null_ratio: if sampled_rows == 0 { 0.0 } else { (self.empty + self.null_like) as f64 / sampled_rows as f64 },
+            null_like: self.null_like,
+            unique_count: self.value_counts.len() as i64,
+            unique_count_capped: self.capped,
+            min: numeric.then_some(self.min),
+            max: numeric.then_some(self.max),
+            mean: numeric.then_some(self.mean),
+            std_dev: (numeric && self.count > 1).then(|| (self.m2 / (self.count - 1) as f64).sqrt()),
+            top_values,
+            url_ratio: ratio(self.urls),
+            email_ratio: ratio(self.emails),
+        }
+    }
+}
+
+// A sort key reduced to what comparing needs
+#[derive(Clone, Copy)]
+enum SortRule {
+    Natural { descending: bool },
+    Numeric { descending: bool },
+    Lexicographic { descending: bool },
+    Length { descending: bool },
+    Nulls { first: bool },
+}
+
+// Rows per sort_to_file run however tight the memory budget is
+const SORT_RUN_MIN_ROWS: usize = 4096;
+
+// What sorting holds per row: its number and one SortValue per key
+fn sort_row_bytes(keys: usize) -> usize {
+    std::mem::size_of::<i64>() + keys * std::mem::size_of::<SortValue>()
+}
+
+// Keys in order, each breaking the previous one's ties
+fn compare_sort_values(rules: &[(usize, SortRule)], a: &[SortValue], b: &[SortValue]) -> std::cmp::Ordering {
+    rules
+        .iter()
+        .zip(a.iter().zip(b))
+        .map(|((_, rule), (x, y))| rule.compare(x, y))
+        .find(|ord| ord.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
+// One cell's sort key, extracted once per row
+enum SortValue {
+    Null,
+    Number(f64),
+    Text(String),
+    Length(usize),
+    Present, // Non-null, for the Nulls rule
+}
+
+impl From<SortComparator> for SortRule {
+    fn from(comparator: SortComparator) -> Self {
+        match comparator {
+            SortComparator::NumericAscending => SortRule::Numeric { descending: false },
+            SortComparator::NumericDescending => SortRule::Numeric { descending: true },
+            SortComparator::LexicographicAscending => SortRule::Lexicographic { descending: false },
+            SortComparator::LexicographicDescending => SortRule::Lexicographic { descending: true },
+            SortComparator::LengthAscending => SortRule::Length { descending: false },
+            SortComparator::LengthDescending => SortRule::Length { descending: true },
+            SortComparator::NullsFirst => SortRule::Nulls { first: true },
+            SortComparator::NullsLast => SortRule::Nulls { first: false },
+        }
+    }
+}
+
+impl SortRule {
+    fn value(self, cell: &str) -> SortValue {
+        if cell.trim().is_empty() {
+            return SortValue::Null;
+        }
+        match self {
+            SortRule::Natural { .. } => match parse_number(cell.trim()) {
+                Some(n) => SortValue::Number(n),
+                None => SortValue::Text(cell.to_string()),
+            },
+            SortRule::Numeric { .. } => parse_number(cell.trim()).map_or(SortValue::Null, SortValue::Number),
+            SortRule::Lexicographic { .. } => SortValue::Text(cell.to_string()),
+            SortRule::Length { .. } => SortValue::Length(cell.chars().count()),
+            SortRule::Nulls { .. } => SortValue::Present,
+        }
+    }
+
+    fn compare(self, a: &SortValue, b: &SortValue) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
+        let descending = match self {
+            SortRule::Nulls { first } => {
+                let null_rank = |v: &SortValue| matches!(v, SortValue::Null) != first;
+                return null_rank(a).cmp(&null_rank(b));
+            }
+            SortRule::Natural { descending }
+            | SortRule::Numeric { descending }
+            | SortRule::Lexicographic { descending }
+            | SortRule::Length { descending } => descending,
+        };
+
+        let ordering = match (a, b) {
+            (SortValue::Null, SortValue::Null) => return Ordering::Equal,
+            (SortValue::Null, _) => return Ordering::Greater,
+            (_, SortValue::Null) => return Ordering::Less,
+            (SortValue::Number(x), SortValue::Number(y)) => x.total_cmp(y),
+            (SortValue::Number(_), _) => Ordering::Less,
+            (_, SortValue::Number(_)) => Ordering::Greater,
+            (SortValue::Text(x), SortValue::Text(y)) => x.cmp(y),
+            (SortValue::Length(x), SortValue::Length(y)) => x.cmp(y),
+            _ => Ordering::Equal,
+        };
+        if descending { ordering.reverse() } else { ordering }
+    }
+}
+
+// Typed form of one cell, for get_column_data_typed and row_to_json
+fn typed_cell(value: &str) -> CellValue {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return CellValue::Empty;
+    }
+    let digits = trimmed.strip_prefix('-').unwrap_or(trimmed);
+    let padded = trimmed.starts_with('+') || (digits.len() > 1 && digits.starts_with('0') && !digits[1..].starts_with('.'));
+    let text = || CellValue::Text(value.to_string());
+    match classify_cell(trimmed) {
+        ColumnType::Integer if !padded => trimmed.parse::<i64>().map_or_else(|_| text(), CellValue::Integer),
+        // Integers too big for i64 also land here; only real decimals become f64
+        ColumnType::Float if !padded && trimmed.contains(['.', 'e', 'E']) => {
+            parse_number(trimmed).filter(|n| n.is_finite()).map_or_else(text, CellValue::Float)
+        }
+        ColumnType::Boolean => parse_bool(trimmed).map_or_else(text, CellValue::Boolean),
+        _ => text(),
+    }
+}
+
+// One cell as a JSON value for row_to_json
+fn json_cell(value: &str) -> serde_json::Value {
+    match typed_cell(value) {
+        CellValue::Empty => serde_json::Value::Null,
+        CellValue::Integer(n) => n.into(),
+        CellValue::Float(n) => n.into(),
+        CellValue::Boolean(b) => b.into(),
+        CellValue::Text(text) => text.into(),
+    }
+}
+
+// Url for http(s)://, ftp:// and www. links to a plausible host, Email for
+// name@domain.tld (optionally mailto:), else Plain. Prefix and character
+// checks only, no RFC parsing; surrounding whitespace is ignored, inner
+// whitespace makes it Plain.
+fn content_kind_of(value: &str) -> ContentKind {
+    let value = value.trim();
+    if value.len() < 4 || value.bytes().any(|b| b.is_ascii_whitespace()) {
+        return ContentKind::Plain;
+    }
+    let lower_prefix = |prefix: &str| {
+        value.len() > prefix.len() && value.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+    };
+    for scheme in ["https://", "http://", "ftp://", "www."] {
+        if lower_prefix(scheme) {
+            let rest = if scheme == "www." { value } else { &value[scheme.len()..] };
+            let host = rest.split(['/', '?', '#']).next().unwrap_or("");
+            let host = host.rsplit_once('@').map_or(host, |(_, host)| host); // user:pass@host
+            let host = host.split(':').next().unwrap_or(""); // :port
+            let plausible = is_domain(host) || host.eq_ignore_ascii_case("localhost");
+            return if plausible { ContentKind::Url } else { ContentKind::Plain };
+        }
+    }
+    let address = if lower_prefix("mailto:") { &value[7..] } else { value };
+    match address.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && local.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+/=?^_`{|}~.-".contains(&b))
+                && is_domain(domain) =>
+        {
+            ContentKind::Email
+        }
+        _ => ContentKind::Plain,
+    }
+}
+
+// Helper: dot-separated labels of letters, digits and hyphens, ending in a
+// top-level label of 2+ letters
+fn is_domain(host: &str) -> bool {
+    let Some((_, tld)) = host.rsplit_once('.') else { return false };
+    tld.len() >= 2
+        && tld.chars().all(char::is_alphabetic)
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b >= 0x80)
+        })
+}
+
+// Type of a single (trimmed, non-empty) cell
+fn classify_cell(value: &str) -> ColumnType {
+    if value.parse::<i64>().is_ok() {
+        ColumnType::Integer
+    } else if parse_number(value).is_some() {
+        ColumnType::Float
+    } else if parse_bool(value).is_some() {
+        ColumnType::Boolean
+    } else {
+        ColumnType::Text
+    }
+}
+
+impl RangeStats {
+    fn empty() -> Self {
+        RangeStats {
+            count: 0,
+            numeric_count: 0,
+            sum: 0.0,
+            mean: None,
+            min: None,
+            max: None,
+            exceeds_sync_limit: false,
+            is_partial: false,
+            rows_scanned: 0,
+        }
+    }
+
+    fn add(&mut self, value: &str) {
+        let value = value.trim();
+        if value.is_empty() { return; }
+        self.count += 1;
+
+        if let Some(n) = parse_number(value) {
+            self.numeric_count += 1;
+            self.sum += n;
+            self.min = Some(self.min.map_or(n, |m| m.min(n)));
+            self.max = Some(self.max.map_or(n, |m| m.max(n)));
+        }
+    }
+}
+
+// ------------------------------------
+// Setup & Utils
+// ------------------------------------
+
+// Shared numeric parser for stats/inference. The digit check keeps
+// "inf" / "NaN" (which f64 happily parses) out of the numeric bucket.
+fn parse_number(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if !value.bytes().any(|b| b.is_ascii_digit()) { return None; }
+    value.parse::<f64>().ok()
+}
+
+// p-th quantile (0..=1) of sorted values, interpolating between neighbours
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let pos = p * (sorted.len() - 1) as f64;
+    let (lower, upper) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (pos - lower as f64)
+}
+
+// Cap for per-cell problem lists (coercion/validation errors)
+const MAX_REPORTED_ERRORS: usize = 10_000;
+
+// Chunk responses (get_grid_chunk, get_viewport, get_rows_by_index) stop
+// once their estimated encoded size would pass this; u64::MAX is no limit
+static RESPONSE_SIZE_BUDGET: AtomicU64 = AtomicU64::new(DEFAULT_RESPONSE_SIZE_BUDGET);
+const DEFAULT_RESPONSE_SIZE_BUDGET: u64 = 64 << 20;
+
+// Estimated bridge encoding cost beyond the text itself (length prefixes,
+// flags, indices)
+const ROW_WIRE_OVERHEAD: usize = 32;
+const CELL_WIRE_OVERHEAD: usize = 16;
+
+// Cap for one chunk response, for every session (None: no cap). Call once
+// at startup; the default is DEFAULT_RESPONSE_SIZE_BUDGET.
+#[frb(sync)]
+pub fn set_response_size_budget(bytes: Option<i64>) -> Result<(), String> {
+    let bytes = match bytes {
+        Some(bytes) if bytes < 1 => return Err(format!("Response size budget must be positive, got {}", bytes)),
+        Some(bytes) => bytes as u64,
+        None => u64::MAX,
+    };
+    RESPONSE_SIZE_BUDGET.store(bytes, Ordering::Relaxed);
+    Ok(())
+}
+
+// Operation tracing for field reports of slow spots. Off by default; while
+// off every instrumented site costs one relaxed load. While on, the last
+// `capacity` operations (open, get_grid_chunk, find_text, existence checks,
+// filter and sort rebuilds, file exports) are kept, oldest dropped first.
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE_BUFFER: Mutex<TraceBuffer> = Mutex::new(TraceBuffer { records: VecDeque::new(), capacity: 0 });
+const MAX_TRACE_CAPACITY: i64 = 100_000;
+
+struct TraceBuffer {
+    records: VecDeque<TraceRecord>,
+    capacity: usize,
+}
+
+#[frb(sync)]
+pub fn enable_tracing(capacity: i64) -> Result<(), String> {
+    if !(1..=MAX_TRACE_CAPACITY).contains(&capacity) {
+        return Err(format!("Trace capacity must be between 1 and {}, got {}", MAX_TRACE_CAPACITY, capacity));
+    }
+    let mut buffer = TRACE_BUFFER.lock().unwrap();
+    buffer.capacity = capacity as usize;
+    while buffer.records.len() > buffer.capacity {
+        buffer.records.pop_front();
+    }
+    TRACE_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Stops recording; what was recorded stays until get_trace
+#[frb(sync)]
+pub fn disable_tracing() {
+    TRACE_ENABLED.store(false, Ordering::Relaxed);
+}
+
+// The recorded operations, oldest first; the buffer is emptied
+#[frb(sync)]
+pub fn get_trace() -> Vec<TraceRecord> {
+    TRACE_BUFFER.lock().unwrap().records.drain(..).collect()
+}
+
+// One instrumented operation, recorded when dropped (early returns and
+// errors included). Inert when tracing was off at the start.
+struct OpTrace(Option<OpTraceState>);
+
+struct OpTraceState {
+    operation: &'static str,
+    args: String,
+    started_at_ms: i64,
+    started: Instant,
+    rows: i64,
+    bytes: i64,
+    cache_hits: i64,
+}
+
+impl OpTrace {
+    fn start(operation: &'static str, args: impl FnOnce() -> String) -> OpTrace {
+        if !TRACE_ENABLED.load(Ordering::Relaxed) {
+            return OpTrace(None);
+        }
+        let started_at_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        OpTrace(Some(OpTraceState {
+            operation,
+            args: args(),
+            started_at_ms,
+            started: Instant::now(),
+            rows: 0,
+            bytes: 0,
+            cache_hits: 0,
+        }))
+    }
+
+    // `bytes` is only evaluated while tracing
+    fn touched(&mut self, rows: i64, bytes: impl FnOnce() -> i64) {
+        if let Some(state) = &mut self.0 {
+            state.rows += rows;
+            state.bytes += bytes();
+        }
+    }
+
+    fn cache_hit(&mut self) {
+        if let Some(state) = &mut self.0 {
+            state.cache_hits += 1;
+        }
+    }
+}
+
+impl Drop for OpTrace {
+    fn drop(&mut self) {
+        let Some(state) = self.0.take() else { return };
+        let record = TraceRecord {
+            operation: state.operation.to_string(),
+            args: state.args,
+            started_at_ms: state.started_at_ms,
+            duration_micros: state.started.elapsed().as_micros() as i64,
+            rows_touched: state.rows,
+            bytes_read: state.bytes,
+            cache_hits: state.cache_hits,
+        };
+        let mut buffer = TRACE_BUFFER.lock().unwrap();
+        if buffer.capacity == 0 {
+            return;
+        }
+        if buffer.records.len() >= buffer.capacity {
+            buffer.records.pop_front();
+        }
+        buffer.records.push_back(record);
+    }
+}
+
+// Bytes left for one chunk response. The first take always succeeds so a
+// response is never empty.
+struct ResponseBudget {
+    left: u64,
+    started: bool,
+}
+
+impl ResponseBudget {
+    fn current() -> Self {
+        ResponseBudget { left: RESPONSE_SIZE_BUDGET.load(Ordering::Relaxed), started: false }
+    }
+
+    fn unlimited() -> Self {
+        ResponseBudget { left: u64::MAX, started: false }
+    }
+
+    fn take(&mut self, bytes: usize) -> bool {
+        if self.started && bytes as u64 > self.left {
+            self.left = 0;
+            return false;
+        }
+        self.started = true;
+        self.left = self.left.saturating_sub(bytes as u64);
+        true
+    }
+
+    fn is_spent(&self) -> bool {
+        self.started && self.left == 0
+    }
+}
+
+// Rows between clock checks in deadline-bounded scans
+const DEADLINE_CHECK_ROWS: i64 = 1024;
+
+// Soft time limit for interactive scans (see RANGE STATS); None never expires
+struct Deadline(Option<Instant>);
+
+impl Deadline {
+    fn after_ms(ms: Option<i64>) -> Self {
+        Deadline(ms.map(|ms| Instant::now() + Duration::from_millis(ms.max(0) as u64)))
+    }
+
+    // Checked only every DEADLINE_CHECK_ROWS rows, so each call scans at least that many
+    fn passed(&self, rows_scanned: i64) -> bool {
+        rows_scanned > 0
+            && rows_scanned % DEADLINE_CHECK_ROWS == 0
+            && self.0.is_some_and(|end| Instant::now() >= end)
+    }
+}
+
+// Rows start..end of a range union, with sorted, disjoint column spans
+struct RowBand {
+    start: i64,
+    end: i64,
+    spans: Vec<(i64, i64)>,
+}
+
+// The union of (row_start, row_end, col_start, col_end) rectangles as row
+// bands, so every cell appears once
+fn range_union(rects: &[(i64, i64, i64, i64)]) -> Vec<RowBand> {
+    let mut edges: Vec<i64> = rects.iter().flat_map(|&(r0, r1, _, _)| [r0, r1]).collect();
+    edges.sort_unstable();
+    edges.dedup();
+
+    let mut bands = Vec::new();
+    for band in edges.windows(2) {
+        let (start, end) = (band[0], band[1]);
+        let mut spans: Vec<(i64, i64)> = rects
+            .iter()
+            .filter(|&&(r0, r1, _, _)| r0 <= start && end <= r1)
+            .map(|&(_, _, c0, c1)| (c0, c1))
+            .collect();
+        if spans.is_empty() {
+            continue;
+        }
+        spans.sort_unstable();
+        let mut merged: Vec<(i64, i64)> = Vec::with_capacity(spans.len());
+        for (c0, c1) in spans {
+            match merged.last_mut() {
+                Some(last) if c0 <= last.1 => last.1 = last.1.max(c1),
+                _ => merged.push((c0, c1)),
+            }
+        }
+        bands.push(RowBand { start, end, spans: merged });
+    }
+    bands
+}
+
+fn union_cell_count(bands: &[RowBand]) -> i64 {
+    bands
+        .iter()
+        .map(|band| (band.end - band.start).saturating_mul(band.spans.iter().map(|(c0, c1)| c1 - c0).sum()))
+        .fold(0i64, i64::saturating_add)
+}
+
+// Selections bigger than this go through range_stats_full
+const RANGE_STATS_SYNC_LIMIT: i64 = 1_000_000;
+
+// Above any sane real-world CSV; wider "first rows" are usually corrupt.
+const DEFAULT_MAX_COLS: i64 = 4096;
+
+impl NullTokens {
+    // The tokens as seen by a sub view starting at col_start
+    fn window(&self, col_start: usize, col_count: usize) -> NullTokens {
+        NullTokens {
+            default: self.default.clone(),
+            per_col: self
+                .per_col
+                .iter()
+                .filter(|(&c, _)| (col_start..col_start + col_count).contains(&c))
+                .map(|(&c, tokens)| (c - col_start, tokens.clone()))
+                .collect(),
+            export_as: self.export_as.clone(),
+        }
+    }
+}
+
+impl LineTerminator {
+    // The byte rows are indexed on (CRLF rows drop their \r when read)
+    fn index_byte(self) -> u8 {
+        match self {
+            LineTerminator::Cr => b'\r',
+            _ => b'\n',
+        }
+    }
+
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineTerminator::Crlf => b"\r\n",
+            LineTerminator::Cr => b"\r",
+            _ => b"\n",
+        }
+    }
+}
+
+// Auto mode: see LineTerminator. Mixed endings get a warning saying which
+// terminator won, since the other one ends up inside cells.
+fn detect_line_terminator(data: &[u8], warnings: &mut Vec<String>) -> LineTerminator {
+    let newlines = data.iter().filter(|&&b| b == b'\n').count();
+    let crs = data.iter().filter(|&&b| b == b'\r').count();
+    if newlines == 0 {
+        return if crs > 0 { LineTerminator::Cr } else { LineTerminator::Lf };
+    }
+
+    let first_nl = data.iter().position(|&b| b == b'\n').unwrap_or(0);
+    let terminator = if first_nl > 0 && data[first_nl - 1] == b'\r' { LineTerminator::Crlf } else { LineTerminator::Lf };
+    let crlfs = data.windows(2).filter(|w| w == b"\r\n").count();
+    let bare_crs = crs - crlfs;
+    if bare_crs > 0 || (crlfs > 0 && crlfs < newlines) {
+        warnings.push(format!(
+            "Mixed line endings ({} \\n, {} \\r\\n, {} bare \\r); rows split on {}",
+            newlines - crlfs,
+            crlfs,
+            bare_crs,
+            if terminator == LineTerminator::Crlf { "\\r\\n" } else { "\\n" }
+        ));
+    }
+    terminator
+}
+
+// Load the file as `backend` says (Auto: mapped unless it is on a network
+// filesystem), retrying temporary errors. Also returns the reason shown in
+// SessionInfo.backend_reason.
+fn load_file(
+    path: &std::path::Path,
+    backend: StorageBackend,
+    retry: IoRetryPolicy,
+) -> Result<(FileContent, String), SheetError> {
+    let (buffered, reason) = match backend {
+        StorageBackend::Mapped => (false, "memory-mapped (OpenOptions.backend)".to_string()),
+        StorageBackend::Buffered => (true, "read into memory (OpenOptions.backend)".to_string()),
+        StorageBackend::Auto => match network_filesystem(path) {
+            Some(fs) => (true, format!("read into memory: on a network filesystem ({})", fs)),
+            None => (false, "memory-mapped".to_string()),
+        },
+    };
+    if buffered {
+        let bytes = with_io_retry(retry, "read file", || std::fs::read(path))?;
+        return Ok((FileContent::Buffered(bytes), reason));
+    }
+
+    // Memory Map the file (treat disk like RAM)
+    let file = with_io_retry(retry, "open file", || File::open(path))?;
+
+    // UNSAFE: Standard requirement for mmap. We promise not to modify the file underneath.
+    let mmap = with_io_retry(retry, "map file", || unsafe { Mmap::map(&file) })?;
+    Ok((FileContent::Mapped(mmap), reason))
+}
+
+// Helper: run `op`, retrying temporary errors as `policy` says. Giving up on
+// one says so (SheetErrorKind::Transient), so the user knows trying again
+// later may work.
+fn with_io_retry<T>(
+    policy: IoRetryPolicy,
+    what: &str,
+    mut op: impl FnMut() -> std::io::Result<T>,
+) -> Result<T, SheetError> {
+    let attempts = policy.attempts.max(1);
+    let mut delay = policy.backoff_ms;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) if !is_transient_io(&e) => return Err(format!("Failed to {}: {}", what, e).into()),
+            Err(e) if attempt >= attempts => {
+                return Err(SheetError {
+                    kind: SheetErrorKind::Transient,
+                    message: format!(
+                        "Failed to {} (temporary I/O error, gave up after {} attempts; \
+                         a network share may be unreachable): {}",
+                        what, attempts, e
+                    ),
+                })
+            }
+            Err(_) => {
+                std::thread::sleep(std::time::Duration::from_millis(delay));
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// OS error codes that mean "try again" on a flaky mount: EIO, ENETDOWN,
+// ENETUNREACH, EHOSTDOWN, EHOSTUNREACH and ESTALE on Unix; on Windows an
+// unexpected network error, network name deleted, semaphore timeout and
+// network unreachable, plus sharing and lock violations (a virus scanner or
+// indexer briefly holding a file we just wrote)
+#[cfg(target_os = "linux")]
+const TRANSIENT_OS_ERRORS: &[i32] = &[5, 100, 101, 112, 113, 116];
+#[cfg(target_os = "macos")]
+const TRANSIENT_OS_ERRORS: &[i32] = &[5, 50, 51, 64, 65, 70];
+#[cfg(windows)]
+const TRANSIENT_OS_ERRORS: &[i32] = &[32, 33, 59, 64, 121, 1231];
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const TRANSIENT_OS_ERRORS: &[i32] = &[5]; // EIO
+
+// Helper: whether retrying the call might succeed
+fn is_transient_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+    ) || e.raw_os_error().is_some_and(|code| TRANSIENT_OS_ERRORS.contains(&code))
+}
+
+// Filesystem type when `path` is on a network mount (best effort; None when
+// unknown). Linux reads /proc/self/mounts; Windows goes by UNC paths, which
+// misses mapped drive letters; elsewhere nothing is detected.
+#[cfg(target_os = "linux")]
+fn network_filesystem(path: &std::path::Path) -> Option<String> {
+    let path = std::fs::canonicalize(path).ok()?;
+    let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+    let (_, fs_type) = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (_, point, fs_type) = (fields.next()?, fields.next()?, fields.next()?);
+            // Spaces etc. in mount points are written as octal escapes
+            let point = point.replace("\\040", " ").replace("\\011", "\t").replace("\\134", "\\");
+            path.starts_with(&point).then_some((point.len(), fs_type))
+        })
+        .max_by_key(|(len, _)| *len)?;
+    let network = matches!(
+        fs_type,
+        "nfs" | "nfs4" | "cifs" | "smb3" | "smbfs" | "9p" | "afs" | "davfs" | "ceph" | "glusterfs"
+            | "fuse.sshfs" | "fuse.rclone" | "fuse.s3fs" | "fuse.gcsfuse"
+    );
+    network.then(|| fs_type.to_string())
+}
+
+#[cfg(windows)]
+fn network_filesystem(path: &std::path::Path) -> Option<String> {
+    let path = path.to_string_lossy();
+    let unc = path.starts_with("\\\\?\\UNC\\") || (path.starts_with("\\\\") && !path.starts_with("\\\\?\\"));
+    unc.then(|| "network share".to_string())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn network_filesystem(_path: &std::path::Path) -> Option<String> {
+    None
+}
+
+// Row index file (build_index_file / open_with_index), all integers little-endian:
+//
+//   magic       8 bytes   INDEX_MAGIC
+//   major       u16       format version; readers refuse any major but their own
+//   minor       u16       newer minors only append header fields
+//   header_len  u32       bytes of header fields that follow
+//   file_size   u64       length of the CSV in bytes
+//   sample_md5  16 bytes  MD5 of the CSV's first and last INDEX_HASH_SPAN
+//                         bytes (hashing all of it would cost the scan we skip)
+//   terminator  u8        1 = \n, 2 = \r\n, 3 = \r (never Auto)
+//   delimiter   u8        b','
+//   quote       u8        b'"'
+//   merge       u8        1 if merge_consecutive_delimiters was on, else 0
+//   row_count   u64
+//   ...                   fields of later minor versions (skipped by header_len)
+//   offsets     row_count x u64: where each row starts
+//
+// Forward compatibility: a reader accepts a newer minor of its major and
+// skips header bytes past the fields it knows; anything that changes the
+// meaning of existing fields or the offsets bumps the major.
+const INDEX_MAGIC: &[u8; 8] = b"TSROWIDX";
+const INDEX_VERSION_MAJOR: u16 = 1;
+const INDEX_VERSION_MINOR: u16 = 0;
+const INDEX_HEADER_V1_LEN: usize = 36; // file_size through row_count
+const INDEX_HASH_SPAN: usize = 64 * 1024;
+// Evenly spread offsets checked to follow a terminator byte on load
+const INDEX_SPOT_CHECKS: usize = 1024;
+
+// What decode_row_index found in a usable index file
+struct RowIndexFile {
+    row_offsets: Vec<usize>,
+    terminator: LineTerminator,
+    merge: bool,
+}
+
+// MD5 of the first and last INDEX_HASH_SPAN bytes (the whole file when shorter)
+fn index_sample_md5(content: &[u8]) -> [u8; 16] {
+    let head = &content[..content.len().min(INDEX_HASH_SPAN)];
+    let tail = &content[content.len().saturating_sub(INDEX_HASH_SPAN).max(head.len())..];
+    md5::compute([head, tail].concat()).0
+}
+
+fn encode_row_index(content: &[u8], row_offsets: &RowIndex, terminator: LineTerminator, merge: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + INDEX_HEADER_V1_LEN + row_offsets.len() * 8);
+    out.extend_from_slice(INDEX_MAGIC);
+    out.extend_from_slice(&INDEX_VERSION_MAJOR.to_le_bytes());
+    out.extend_from_slice(&INDEX_VERSION_MINOR.to_le_bytes());
+    out.extend_from_slice(&(INDEX_HEADER_V1_LEN as u32).to_le_bytes());
+    out.extend_from_slice(&(content.len() as u64).to_le_bytes());
+    out.extend_from_slice(&index_sample_md5(content));
+    let terminator_code: u8 = match terminator {
+        LineTerminator::Crlf => 2,
+        LineTerminator::Cr => 3,
+        _ => 1,
+    };
+    out.extend_from_slice(&[terminator_code, b',', b'"', merge as u8]);
+    out.extend_from_slice(&(row_offsets.len() as u64).to_le_bytes());
+    for offset in row_offsets.starts(content) {
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+    }
+    out
+}
+
+// Parse an index file and check it against the CSV bytes; the error says why not
+fn decode_row_index(bytes: &[u8], content: &[u8]) -> Result<RowIndexFile, String> {
+    let u16_at = |at: usize| bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    let u64_at = |at: usize| bytes.get(at..at + 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+
+    if bytes.get(..8) != Some(&INDEX_MAGIC[..]) {
+        return Err("not a row index file".to_string());
+    }
+    let (Some(major), Some(header_len)) = (u16_at(8), u32_at(12)) else {
+        return Err("truncated header".to_string());
+    };
+    if major != INDEX_VERSION_MAJOR {
+        return Err(format!("format version {} is not supported", major));
+    }
+    let header_len = header_len as usize;
+    if header_len < INDEX_HEADER_V1_LEN || bytes.len() < 16 + header_len {
+        return Err("truncated header".to_string());
+    }
+    if u64_at(16) != Some(content.len() as u64) {
+        return Err("built for a file of another size".to_string());
+    }
+    if bytes[24..40] != index_sample_md5(content) {
+        return Err("built for different file contents".to_string());
+    }
+    let terminator = match bytes[40] {
+        1 => LineTerminator::Lf,
+        2 => LineTerminator::Crlf,
+        3 => LineTerminator::Cr,
+        other => return Err(format!("unknown line terminator code {}", other)),
+    };
+    if bytes[41] != b',' || bytes[42] != b'"' {
+        return Err("built with another delimiter or quote character".to_string());
+    }
+    let merge = match bytes[43] {
+        0 => false,
+        1 => true,
+        _ => return Err("corrupt header".to_string()),
+    };
+
+    let offsets = &bytes[16 + header_len..];
+    let rows = u64_at(44).unwrap_or(0);
+    if rows == 0 || offsets.len() as u64 != rows.saturating_mul(8) {
+        return Err("row offsets are truncated or corrupt".to_string());
+    }
+    let row_offsets: Vec<usize> = offsets
+        .chunks_exact(8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()) as usize)
+        .collect();
+    let ordered = row_offsets[0] == 0 && row_offsets.windows(2).all(|pair| pair[0] < pair[1]);
+    if !ordered || row_offsets[row_offsets.len() - 1] > content.len() {
+        return Err("row offsets are corrupt".to_string());
+    }
+    let step = (row_offsets.len() / INDEX_SPOT_CHECKS).max(1);
+    let aligned = row_offsets[1..]
+        .iter()
+        .step_by(step)
+        .all(|&offset| content[offset - 1] == terminator.index_byte());
+    if !aligned {
+        return Err("row offsets don't match the file's line breaks".to_string());
+    }
+    Ok(RowIndexFile { row_offsets, terminator, merge })
+}
+
+// Rows per kept offset in a sparse row index (see RowIndex)
+const SPARSE_INDEX_STRIDE: usize = 64;
+
+// Estimate Columns from the first row
+// We look at the first line and count commas.
+fn detect_total_cols(data: &[u8], row_offsets: &RowIndex, merge: bool) -> i64 {
+    // If the file is empty or weird, default to 0 cols
+    if row_offsets.len() == 0 { return 0; }
+    let first_line_end = if row_offsets.len() > 1 { row_offsets.start(data, 1) } else { data.len() };
+    count_fields(&data[0..first_line_end], merge)
+}
+
+// Trim/case normalization shared by every matching API
+fn normalize_with<'a>(options: &NormalizeOptions, value: &'a str) -> Cow<'a, str> {
+    let value = if options.trim_whitespace { value.trim() } else { value };
+    if options.case_sensitive {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(value.to_lowercase())
+    }
+}
+
+// Rough HashMap bookkeeping per edited row / cell, for the memory budget
+const EDIT_ROW_OVERHEAD: usize = 64;
+const EDIT_CELL_OVERHEAD: usize = 32;
+
+// Budgeted size of one edit (assumes a new row entry, so it errs high)
+fn edit_cost(value: &str) -> usize {
+    EDIT_ROW_OVERHEAD + EDIT_CELL_OVERHEAD + value.len()
+}
+
+fn check_memory_budget(bytes: i64) -> Result<u64, String> {
+    if bytes < 0 {
+        return Err(format!("Memory budget must not be negative, got {}", bytes));
+    }
+    Ok(bytes as u64)
+}
+
+// Byte substring search for filter pre-checks; `fold` ignores ASCII case
+// (the needle is expected to be lowercase already)
+fn contains_bytes(haystack: &[u8], needle: &[u8], fold: bool) -> bool {
+    if needle.len() > haystack.len() { return false; }
+    haystack
+        .windows(needle.len())
+        .any(|w| if fold { w.eq_ignore_ascii_case(needle) } else { w == needle })
+}
+
+// Canonical header names: repeats get _2, _3, ... skipping any suffixed
+// name that is already a header, so the result has no duplicates
+fn unique_names(raw: &[String]) -> Vec<String> {
+    let existing: HashSet<&str> = raw.iter().map(String::as_str).collect();
+    let mut used: HashSet<String> = HashSet::with_capacity(raw.len());
+    raw.iter()
+        .map(|name| {
+            let unique = if used.contains(name) {
+                (2..)
+                    .map(|n| format!("{}_{}", name, n))
+                    .find(|candidate| !existing.contains(candidate.as_str()) && !used.contains(candidate))
+                    .unwrap_or_default()
+            } else {
+                name.clone()
+            };
+            used.insert(unique.clone());
+            unique
+        })
+        .collect()
+}
+
+// Short rows are padded with empty cells, same as get_grid_chunk
+fn field_or_empty(fields: &[String], col: usize) -> &str {
+    fields.get(col).map(String::as_str).unwrap_or("")
+}
+
+// Canonical form of a cell for a type: Ok(None) means leave it as is
+// (empty cells, Text/Empty columns), Err explains why it doesn't fit.
+fn coerce_cell(value: &str, column_type: ColumnType) -> Result<Option<String>, String> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() { return Ok(None); }
+
+    match column_type {
+        ColumnType::Empty | ColumnType::Text => Ok(None),
+        ColumnType::Integer => match parse_number(trimmed) {
+            Some(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Ok(Some((n as i64).to_string())),
+            _ => Err(format!("'{}' is not an integer", value)),
+        },
+        ColumnType::Float => parse_number(trimmed)
+            .map(|n| Some(n.to_string()))
+            .ok_or_else(|| format!("'{}' is not a number", value)),
+        ColumnType::Boolean => parse_bool(trimmed)
+            .map(|b| Some(b.to_string()))
+            .ok_or_else(|| format!("'{}' is not a boolean", value)),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    if value.eq_ignore_ascii_case("true") {
+        Some(true)
+    } else if value.eq_ignore_ascii_case("false") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// Small seeded PRNG (SplitMix64): same sequence on every platform, no extra crate.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // 0..bound (the modulo bias is negligible for row shuffling)
+    fn next_below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+// Distinct-value counter for detect_key_columns: exact hashes when the whole
+// view is read, otherwise a fixed-size HyperLogLog sketch
+enum DistinctCounter {
+    Exact(HashSet<u64>),
+    Sketch(Vec<u8>),
+}
+
+impl DistinctCounter {
+    fn new(exact: bool) -> Self {
+        if exact {
+            DistinctCounter::Exact(HashSet::new())
+        } else {
+            DistinctCounter::Sketch(vec![0; 1 << HLL_PRECISION])
+        }
+    }
+
+    // `hash` must already be well mixed (the sketch reads its top and low bits)
+    fn insert(&mut self, hash: u64) {
+        match self {
+            DistinctCounter::Exact(seen) => {
+                seen.insert(hash);
+            }
+            DistinctCounter::Sketch(registers) => {
+                let index = (hash >> (64 - HLL_PRECISION)) as usize;
+                let rank = ((hash << HLL_PRECISION).leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+                registers[index] = registers[index].max(rank);
+            }
+        }
+    }
+
+    // Distinct values seen, capped at the `inserted` non-null values
+    fn count(&self, inserted: usize) -> f64 {
+        let registers = match self {
+            DistinctCounter::Exact(seen) => return seen.len() as f64,
+            DistinctCounter::Sketch(registers) => registers,
+        };
+        let m = registers.len() as f64;
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / registers.iter().map(|&r| 0.5f64.powi(r as i32)).sum::<f64>();
+        let zeros = registers.iter().filter(|&&r| r == 0).count();
+        let estimate = if raw <= 2.5 * m && zeros > 0 { m * (m / zeros as f64).ln() } else { raw };
+        estimate.min(inserted as f64)
+    }
+
+    // Sketches can't prove uniqueness; within three standard errors counts
+    fn looks_unique(&self, distinct: f64, rows: usize) -> bool {
+        match self {
+            DistinctCounter::Exact(_) => distinct as usize == rows,
+            DistinctCounter::Sketch(registers) => {
+                distinct >= rows as f64 * (1.0 - 3.0 * 1.04 / (registers.len() as f64).sqrt())
+            }
+        }
+    }
+}
+
+// Quote-aware field count of a raw line: commas inside "..." don't count.
+// With `merge`, a run of commas counts once (see merge_delimiter_runs).
+fn count_fields(line: &[u8], merge: bool) -> i64 {
+    let mut in_quotes = false;
+    let mut fields = 1;
+    let mut after_comma = false;
+    for &byte in line {
+        match byte {
+            b'"' => in_quotes = !in_quotes, // "" toggles twice, so escapes are fine
+            b',' if !in_quotes => {
+                if !(merge && after_comma) {
+                    fields += 1;
+                }
+                after_comma = true;
+                continue;
+            }
+            _ => {}
+        }
+        after_comma = false;
+    }
+    fields
+}
+
+// Windows-1252 for 0x80..=0x9F; the five undefined bytes map to the C1
+// controls of the same value, as browsers do
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+// One line as text: borrowed when it's valid UTF-8, otherwise the whole line
+// is read as Windows-1252 (mixed files: legacy rows pasted into UTF-8). Per
+// line and byte for byte, so the same bytes always give the same text.
+fn decode_line(line: &[u8]) -> Cow<'_, str> {
+    match str::from_utf8(line) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(
+            line.iter()
+                .map(|&b| match b {
+                    0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+                    _ => char::from(b),
+                })
+                .collect(),
+        ),
+    }
+}
+
+// Quote-aware field splitter (RFC 4180 style): "a,b" is one field and "" is an
+// escaped quote. Rows are still newline-indexed, so quoted newlines aren't supported.
+fn split_fields(line: &str) -> Vec<Cow<'_, str>> {
+    split_fields_with_ranges(line, None)
+}
+
+// Same splitter, optionally recording each field's [start, end) byte range in
+// `line` (quotes and any junk after them included, the comma excluded).
+//
+// Splitting searches bytes, which is safe for UTF-8: every byte of a
+// multi-byte sequence is >= 0x80, so ',' (0x2C) and '"' (0x22) only ever
+// occur as themselves and each cut lands on a character boundary. The
+// byte-level paths (count_fields, the raw filter pre-check) rely on the same
+// property; decoding upstream guarantees `line` is valid UTF-8.
+fn split_fields_with_ranges<'a>(
+    line: &'a str,
+    mut ranges: Option<&mut Vec<(usize, usize)>>,
+) -> Vec<Cow<'a, str>> {
+    let bytes = line.as_bytes();
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    let mut record = |start: usize, end: usize| {
+        if let Some(ranges) = ranges.as_deref_mut() {
+            ranges.push((start, end));
+        }
+    };
+
+    loop {
+        if bytes.get(pos) != Some(&b'"') {
+            // Plain field: borrow straight from the line
+            match line[pos..].find(',') {
+                Some(off) => {
+                    debug_assert!(line.is_char_boundary(pos + off) && line.is_char_boundary(pos + off + 1));
+                    fields.push(Cow::Borrowed(&line[pos..pos + off]));
+                    record(pos, pos + off);
+                    pos += off + 1;
+                }
+                None => {
+                    fields.push(Cow::Borrowed(&line[pos..]));
+                    record(pos, line.len());
+                    return fields;
+                }
+            }
+            continue;
+        }
+
+        // Quoted field: unescape until the closing quote
+        let mut value = String::new();
+        let mut i = pos + 1;
+        let mut closed = false;
+        while let Some(off) = line[i..].find('"') {
+            let q = i + off;
+            value.push_str(&line[i..q]);
+            if bytes.get(q + 1) == Some(&b'"') {
+                value.push('"');
+                i = q + 2;
+            } else {
+                i = q + 1;
+                closed = true;
+                break;
+            }
+        }
+        if !closed {
+            // Unterminated quote: take the rest of the line as the value
+            value.push_str(&line[i..]);
+            fields.push(Cow::Owned(value));
+            record(pos, line.len());
+            return fields;
+        }
+
+        // Junk between the closing quote and the next comma is kept verbatim
+        match line[i..].find(',') {
+            Some(off) => {
+                value.push_str(&line[i..i + off]);
+                fields.push(Cow::Owned(value));
+                record(pos, i + off);
+                pos = i + off + 1;
+            }
+            None => {
+                value.push_str(&line[i..]);
+                fields.push(Cow::Owned(value));
+                record(pos, line.len());
+                return fields;
+            }
+        }
+    }
+}
+
+// Is `col` padding in a padding_mask? (derived columns never are)
+fn is_padded(mask: &Option<Vec<bool>>, col: usize) -> bool {
+    mask.as_ref().is_some_and(|mask| mask.get(col).copied().unwrap_or(false))
+}
+
+// Fields of a split line (with their ranges) as if each run of commas were
+// one: the empty fields between two commas of a run go. A quoted "" has a
+// non-empty range and stays, so commas inside or around quotes never merge;
+// the first and last field stay too (",,a" is still ["", "a"]).
+fn merge_delimiter_runs<T>(fields: Vec<T>, ranges: &[(usize, usize)]) -> Vec<T> {
+    let last = fields.len().saturating_sub(1);
+    fields
+        .into_iter()
+        .zip(ranges)
+        .enumerate()
+        .filter(|&(i, (_, &(start, end)))| i == 0 || i == last || start < end)
+        .map(|(_, (field, _))| field)
+        .collect()
+}
+
+// What's odd about one raw quoted field, if anything
+fn quote_problem(field: &[u8]) -> Option<&'static str> {
+    let mut i = 1;
+    while i < field.len() {
+        if field[i] == b'"' {
+            if field.get(i + 1) == Some(&b'"') {
+                i += 2;
+                continue;
+            }
+            return (i + 1 < field.len()).then_some("text after the closing quote is kept as is");
+        }
+        i += 1;
+    }
+    Some("unterminated quote runs to the end of the line")
+}
+
+// Decoded fields of a raw line plus each field's [start, end) range in the
+// raw bytes (see split_fields_with_ranges). With `merge` the fields a run of
+// commas leaves out are dropped from both (the commas stay between ranges).
+fn fields_with_raw_ranges(raw: &[u8], merge: bool) -> (Vec<String>, Vec<(usize, usize)>) {
+    let line = String::from_utf8_lossy(raw);
+    let mut ranges = Vec::new();
+    let mut fields: Vec<String> =
+        split_fields_with_ranges(&line, Some(&mut ranges)).into_iter().map(Cow::into_owned).collect();
+    if merge {
+        fields = merge_delimiter_runs(fields, &ranges);
+        ranges = merge_delimiter_runs(ranges.clone(), &ranges);
+    }
+    if matches!(line, Cow::Owned(_)) {
+        for range in &mut ranges {
+            *range = (lossy_to_raw_offset(raw, range.0), lossy_to_raw_offset(raw, range.1));
+        }
+    }
+    (fields, ranges)
+}
+
+// Anonymized stand-in for one cell (see AnonymizeSpec). Integers stay
+// integers and decimals keep their number of decimal places.
+fn scramble_cell(value: &str, seed: u64) -> String {
+    let mut rng = SplitMix64(seed ^ fnv1a_64(value.as_bytes()));
+    let trimmed = value.trim();
+    if let Some(n) = parse_number(trimmed) {
+        let factor = 0.9 + (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64 * 0.2;
+        if let Ok(i) = trimmed.parse::<i64>() {
+            return ((i as f64 * factor).round() as i64).to_string();
+        }
+        if !trimmed.contains(['e', 'E']) {
+            let decimals = trimmed.split_once('.').map_or(0, |(_, frac)| frac.len());
+            return format!("{:.*}", decimals, n * factor);
+        }
+        return (n * factor).to_string();
+    }
+    let digest = format!("{:x}", md5::compute(format!("{}:{}", seed, value)));
+    digest.chars().cycle().take(value.chars().count()).collect()
+}
+
+// Map a byte offset in String::from_utf8_lossy(raw) back to raw. Each invalid
+// sequence became one U+FFFD (3 bytes), so offsets shift by segment.
+fn lossy_to_raw_offset(raw: &[u8], decoded_offset: usize) -> usize {
+    let (mut decoded, mut raw_pos) = (0, 0);
+    for chunk in raw.utf8_chunks() {
+        let valid = chunk.valid().len();
+        if decoded_offset <= decoded + valid {
+            return raw_pos + (decoded_offset - decoded);
+        }
+        decoded += valid;
+        raw_pos += valid;
+        if !chunk.invalid().is_empty() {
+            if decoded_offset < decoded + 3 {
+                return raw_pos;
+            }
+            decoded += 3;
+            raw_pos += chunk.invalid().len();
+        }
+    }
+    raw_pos
+}
+
+// Embedded by export_to_html when inline_css is set
+const HTML_TABLE_CSS: &str = "<style>\n\
+table { border-collapse: collapse; font-family: sans-serif; font-size: 14px; }\n\
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }\n\
+th { background: #eee; }\n\
+tr.even td { background: #f6f6f6; }\n\
+</style>\n";
+
+// save_in_place, partition_by_hash and split_into_files write
+// <file>.<this> next to each output first (temp_path)
+const SAVE_TEMP_SUFFIX: &str = "turbo-sheet-tmp";
+
+fn temp_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}", SAVE_TEMP_SUFFIX));
+    temp.into()
+}
+
+// The exact OS form of a path, for handing back across the bridge
+#[cfg(unix)]
+fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(windows)]
+fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str().encode_wide().flat_map(u16::to_le_bytes).collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+// Move `from` over `to` so that `to` is always complete: a plain rename, or
+// where that's refused (e.g. Windows while the file is open elsewhere), move
+// `to` aside, rename, and put it back if the rename still fails.
+fn replace_file(from: &std::path::Path, to: &std::path::Path) -> Result<(), String> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let mut backup = to.as_os_str().to_owned();
+    backup.push(".turbo-sheet-bak");
+    std::fs::rename(to, &backup).map_err(|e| format!("Failed to replace file: {}", e))?;
+    if let Err(e) = std::fs::rename(from, to) {
+        let _ = std::fs::rename(&backup, to);
+        return Err(format!("Failed to replace file: {}", e));
+    }
+    let _ = std::fs::remove_file(&backup);
+    Ok(())
+}
+
+// Write a set of output files: `write` fills the temp_path of each path, and
+// only once it has finished them all are they moved into place (one by one,
+// with replace_file). On error the temporaries are removed and whatever was
+// at `paths` before stays as it was.
+fn write_staged<T>(
+    paths: &[std::path::PathBuf],
+    write: impl FnOnce(&[std::path::PathBuf]) -> Result<T, String>,
+) -> Result<T, String> {
+    let staged: Vec<std::path::PathBuf> = paths.iter().map(|path| temp_path(path)).collect();
+    let result = write(&staged).and_then(|value| {
+        for (from, to) in staged.iter().zip(paths) {
+            replace_file(from, to)?;
+        }
+        Ok(value)
+    });
+    if result.is_err() {
+        for path in &staged {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    result
+}
+
+// build_inverted_index (also build_search_index, column_unique_values):
+// HashMap entry + Vec header per distinct key, and how much growth passes
+// between memory budget checks (column edits use the same step)
+const INDEX_TOKEN_OVERHEAD: usize = 64;
+const INDEX_BUDGET_STEP: usize = 1 << 20;
+
+// Tokens of one (normalized) cell for the inverted index
+fn cell_tokens(cell: &str, mode: TokenizerMode) -> Vec<&str> {
+    match mode {
+        TokenizerMode::WhitespaceSplit => cell.split_whitespace().collect(),
+        TokenizerMode::CharacterNgram(n) => {
+            let starts: Vec<usize> = cell.char_indices().map(|(i, _)| i).chain([cell.len()]).collect();
+            starts.windows(n + 1).map(|w| &cell[w[0]..w[n]]).collect()
+        }
+    }
+}
+
+// Whole-word tokens for the search index and find_text: runs of letters and
+// digits, lowercased
+fn search_tokens(cell: &str) -> Vec<String> {
+    cell.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// detect_key_columns: default sample size, how many of the most distinct
+// columns are paired up when no single column is unique, and the
+// HyperLogLog precision (2^12 one-byte registers, about 1.6% error)
+const KEY_SAMPLE_ROWS: i64 = 10_000;
+const KEY_PAIR_COLS: usize = 8;
+const HLL_PRECISION: u32 = 12;
+
+// Best-first cap on suggest_row_repairs proposals per row
+const MAX_REPAIR_PROPOSALS: usize = 3;
+
+// Candidate fixes for a row that has fields.len() != types.len()
+fn repair_proposals(fields: &[String], types: &[ColumnType]) -> Vec<RepairProposal> {
+    let expected = types.len();
+    let mut proposals = Vec::new();
+    if fields.len() > expected {
+        // k + 1 fields were one (an unquoted comma inside a value)
+        let k = fields.len() - expected;
+        for at in 0..expected {
+            let mut cells = fields[..at].to_vec();
+            cells.push(fields[at..=at + k].join(","));
+            cells.extend_from_slice(&fields[at + k + 1..]);
+            proposals.push((format!("Merge fields {}-{}", at, at + k), cells));
+        }
+    } else {
+        // k values are missing
+        let k = expected - fields.len();
+        for at in 0..=fields.len() {
+            let mut cells = fields[..at].to_vec();
+            cells.extend(std::iter::repeat_n(String::new(), k));
+            cells.extend_from_slice(&fields[at..]);
+            let what = if k == 1 { "1 empty field".to_string() } else { format!("{} empty fields", k) };
+            proposals.push((format!("Insert {} at {}", what, at), cells));
+        }
+    }
+
+    let mut scored: Vec<RepairProposal> = proposals
+        .into_iter()
+        .map(|(description, cells)| {
+            let score = cells.iter().zip(types).filter(|(cell, &column_type)| cell_fits(cell, column_type)).count() as i64;
+            RepairProposal { description, cells, score }
+        })
+        .collect();
+    scored.sort_by_key(|p| std::cmp::Reverse(p.score)); // Stable: earlier positions win ties
+    scored.truncate(MAX_REPAIR_PROPOSALS);
+    scored
+}
+
+// Does a cell look like it belongs in a column of this type? (empty always does)
+fn cell_fits(cell: &str, column_type: ColumnType) -> bool {
+    let cell = cell.trim();
+    if cell.is_empty() {
+        return true;
+    }
+    match (classify_cell(cell), column_type) {
+        (_, ColumnType::Empty) => false,
+        (ColumnType::Integer, ColumnType::Float) => true,
+        (cell_type, column_type) => cell_type == column_type,
+    }
+}
+
+// Entries list_query_history keeps per session
+const QUERY_HISTORY_CAP: usize = 100;
+
+// How far (in file rows, either way) resolve_anchor looks for a row that moved
+const ANCHOR_SEARCH_ROWS: usize = 10_000;
+
+impl From<RecordedPredicate> for FilterPredicate {
+    fn from(predicate: RecordedPredicate) -> Self {
+        match predicate {
+            RecordedPredicate::Equals(v) => FilterPredicate::Equals(v),
+            RecordedPredicate::NotEquals(v) => FilterPredicate::NotEquals(v),
+            RecordedPredicate::Contains(v) => FilterPredicate::Contains(v),
+            RecordedPredicate::IsEmpty => FilterPredicate::IsEmpty,
+            RecordedPredicate::IsNotEmpty => FilterPredicate::IsNotEmpty,
+            RecordedPredicate::IsNull => FilterPredicate::IsNull,
+            RecordedPredicate::IsNotNull => FilterPredicate::IsNotNull,
+        }
+    }
+}
+
+// Readable one-liner for a recorded condition (list_query_history)
+fn describe_condition(condition: &RecordedCondition) -> String {
+    match condition {
+        RecordedCondition::Column { col, col_name, predicate } => {
+            let column = col_name.clone().unwrap_or_else(|| format!("column {}", col));
+            match predicate {
+                RecordedPredicate::Equals(v) => format!("{} = {:?}", column, v),
+                RecordedPredicate::NotEquals(v) => format!("{} != {:?}", column, v),
+                RecordedPredicate::Contains(v) => format!("{} contains {:?}", column, v),
+                RecordedPredicate::IsEmpty => format!("{} is empty", column),
+                RecordedPredicate::IsNotEmpty => format!("{} is not empty", column),
+                RecordedPredicate::IsNull => format!("{} is null", column),
+                RecordedPredicate::IsNotNull => format!("{} is not null", column),
+            }
+        }
+        RecordedCondition::AnyColumnContains(text) => format!("any column contains {:?}", text),
+    }
+}
+
+// partition_by_hash keeps one open file per shard
+const MAX_SHARDS: i64 = 1024;
+
+// export_to_parquet_partitioned: directory for empty partition values (what
+// Hive uses), the cap on distinct values, and rows buffered per row group
+const HIVE_DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+const MAX_PARTITIONS: usize = 10_000;
+const PARQUET_ROW_GROUP_ROWS: usize = 64 * 1024;
+
+// Hive's path escaping for partition names and values: %XX for controls and
+// the characters it reserves (plus those Windows refuses in file names)
+fn hive_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_control() || "\"#%'*/:=?\\{[]^<>|".contains(c) {
+            out.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn parquet_err(e: parquet::errors::ParquetError) -> String {
+    format!("Failed to write Parquet: {}", e)
+}
+
+// 64-bit FNV-1a: tiny, and stable across runs/platforms (unlike std's hasher)
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+// Default row cap for export_to_markdown
+const MARKDOWN_MAX_ROWS: usize = 1000;
+
+// CSV injection guard: prefix ' to cells a spreadsheet would read as a
+// formula, except clean numbers like -5 or +1.5 (-1,5 with a decimal
+// comma), which must stay numbers
+fn sanitize_formulas(fields: &mut [String], decimal: DecimalSeparator) {
+    for field in fields {
+        let risky = field.starts_with(['=', '+', '-', '@', '\t', '\r']);
+        let number = match decimal {
+            DecimalSeparator::Comma if !field.contains('.') => parse_number(&field.replacen(',', ".", 1)),
+            _ => parse_number(field),
+        };
+        if risky && (field.trim() != field.as_str() || number.is_none()) {
+            field.insert(0, '\'');
+        }
+    }
+}
+
+// Cell text for a Markdown table: pipes are escaped and line breaks (which
+// would end the row) become spaces
+fn markdown_escape(value: &str) -> String {
+    value.replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+// Text -> HTML: the four characters that can break out of an element or attribute
+fn html_escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['<', '>', '&', '"']) {
+        return Cow::Borrowed(value);
+    }
+    let mut out = String::with_capacity(value.len() + 8);
+    for c in value.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+// Write one CSV record, quoting fields that need it
+pub(crate) fn write_csv_row<W: Write>(writer: &mut W, fields: &[String]) -> Result<(), String> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",").map_err(|e| format!("Failed to write file: {}", e))?;
+        }
+        writer
+            .write_all(quote_csv_field(field).as_bytes())
+            .map_err(|e| format!("Failed to write file: {}", e))?;
+    }
+    Ok(())
+}
+
+// Clipboard TSV: quote only what would break the grid (tabs, line breaks, quotes)
+fn quote_tsv_field(field: &str) -> Cow<'_, str> {
+    if field.contains(['\t', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+fn quote_csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[frb(init)]
+pub fn init_app() {
+    flutter_rust_bridge::setup_default_user_utils();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper: a session over in-memory CSV text
+    fn session(text: &str) -> SheetSession {
+        SheetSession::from_buffer(text.as_bytes().to_vec()).unwrap()
+    }
+
+    // Helper: an in-memory session whose first row is the header
+    fn session_with_header(text: &str) -> SheetSession {
+        let options = OpenOptions { first_row_header: true, ..Default::default() };
+        let content = FileContent::Buffered(text.as_bytes().to_vec());
+        SheetSession::from_content(std::path::PathBuf::new(), content, options, None).unwrap()
+    }
+
+    // Helper: (raw, canonical) name of every column
+    fn header_pairs(sheet: &SheetSession) -> Vec<(String, String)> {
+        let metadata = sheet.get_header_metadata(0, sheet.total_cols as i32);
+        metadata.into_iter().map(|name| (name.raw, name.canonical)).collect()
+    }
+
+    // The response size budget is process-wide: tests that change it hold
+    // this lock, and the guard puts the default back
+    static RESPONSE_BUDGET_LOCK: Mutex<()> = Mutex::new(());
+
+    struct BudgetGuard {
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Drop for BudgetGuard {
+        fn drop(&mut self) {
+            RESPONSE_SIZE_BUDGET.store(DEFAULT_RESPONSE_SIZE_BUDGET, Ordering::Relaxed);
+        }
+    }
+
+    fn response_budget(bytes: i64) -> BudgetGuard {
+        let guard = RESPONSE_BUDGET_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_response_size_budget(Some(bytes)).unwrap();
+        BudgetGuard { _lock: guard }
+    }
+
+    // Helper: one oversized cell in the middle of the first row
+    fn oversized(cell_len: usize) -> SheetSession {
+        session(&format!("a,{},c\nd,e,f\ng,h,i\nj,k,l", "x".repeat(cell_len)))
+    }
+
+    #[test]
+    fn oversized_cell_ends_the_chunk_with_a_continuation() {
+        let _budget = response_budget(64 << 10);
+        let sheet = oversized(1 << 20);
+
+        let rows = sheet.get_grid_chunk(0, 4, 0, 3);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].cells.len(), 1);
+        assert_eq!(rows[0].continuation, Some(ChunkContinuation { next_row: 0, next_col: 1 }));
+
+        // Fetching from the continuation always sends its first cell, however big
+        let rows = sheet.get_grid_chunk(0, 4, 1, 2);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].cells[0].content.len(), 1 << 20);
+        assert_eq!(rows[0].continuation, Some(ChunkContinuation { next_row: 0, next_col: 2 }));
+
+        let rows = sheet.get_grid_chunk(0, 4, 2, 1);
+        let cells: Vec<&str> = rows.iter().map(|row| row.cells[0].content.as_str()).collect();
+        assert_eq!(cells, ["c", "f", "i", "l"]);
+        assert!(rows.iter().all(|row| row.continuation.is_none()));
+        assert_eq!(sheet.get_session_info().responses_truncated, 2);
+    }
+
+    #[test]
+    fn budget_applies_to_viewport_and_rows_by_index() {
+        let _budget = response_budget(64 << 10);
+        let sheet = oversized(1 << 20);
+
+        let viewport = sheet.get_viewport(0, 4, 0, 3);
+        assert_eq!(viewport.rows.len(), 1);
+        assert_eq!(viewport.continuation, Some(ChunkContinuation { next_row: 0, next_col: 1 }));
+
+        // The first index asked for goes first; the rest stop at the oversized row
+        let rows = sheet.get_rows_by_index(vec![3, 0, 1], 0, 3);
+        let indices: Vec<i64> = rows.iter().map(|row| row.index).collect();
+        assert_eq!(indices, [3, 0]);
+        assert_eq!(rows[1].continuation, Some(ChunkContinuation { next_row: 0, next_col: 1 }));
+    }
+
+    #[test]
+    fn rows_past_the_budget_continue_at_the_next_row() {
+        let _budget = response_budget(4 << 10);
+        let text: Vec<String> = (0..200).map(|i| format!("{},{}", i, "y".repeat(100))).collect();
+        let sheet = session(&text.join("\n"));
+
+        // Re-fetching from each continuation row yields every row exactly once
+        let (mut next, mut fetched) = (0, Vec::new());
+        while next < sheet.total_rows {
+            let rows = sheet.get_grid_chunk(next, 200, 0, 2);
+            assert!(!rows.is_empty() && rows.len() < 200);
+            next = match rows.last().unwrap().continuation {
+                Some(continuation) => continuation.next_row,
+                None => sheet.total_rows,
+            };
+            let whole = rows.iter().filter(|row| row.index < next);
+            fetched.extend(whole.map(|row| row.cells[0].content.parse::<i64>().unwrap()));
+        }
+        assert_eq!(fetched, (0..200).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn detached_session_shows_no_rows_until_reopened() {
+        let path = std::env::temp_dir().join(format!("ts_detach_{}.csv", std::process::id()));
+        std::fs::write(&path, "a,b\nc,d\n").unwrap();
+        let mut sheet = SheetSession::new_from_file(path.to_string_lossy().into_owned()).unwrap();
+        sheet.set_cell(1, 0, "edited".to_string()).unwrap();
+
+        sheet.detach("test".to_string());
+        assert_eq!(sheet.total_rows, 0);
+        assert!(sheet.get_grid_chunk(0, 10, 0, 2).is_empty());
+        assert_eq!(sheet.get_session_info().detached.as_deref(), Some("test"));
+        assert!(sheet.save_in_place().unwrap_err().message.contains("detached"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a,b\nc,d\n");
+
+        sheet.reopen().unwrap();
+        assert_eq!(sheet.get_session_info().detached, None);
+        assert_eq!(sheet.get_grid_chunk(1, 1, 0, 2)[0].cells[0].content, "c");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn io_errors_say_whether_retrying_may_help() {
+        let policy = IoRetryPolicy { attempts: 3, backoff_ms: 0 };
+        let mut calls = 0;
+        let e = with_io_retry::<()>(policy, "read file", || {
+            calls += 1;
+            Err(std::io::ErrorKind::TimedOut.into())
+        })
+        .unwrap_err();
+        assert_eq!((e.kind, calls), (SheetErrorKind::Transient, 3));
+        assert!(e.message.contains("gave up after 3 attempts"));
+
+        let e = with_io_retry::<()>(policy, "read file", || Err(std::io::ErrorKind::NotFound.into())).unwrap_err();
+        assert_eq!(e.kind, SheetErrorKind::Permanent);
+
+        let missing = std::env::temp_dir().join("ts_no_such_file.csv");
+        let e = SheetSession::new_from_file(missing.to_string_lossy().into_owned()).err().unwrap();
+        assert_eq!(e.kind, SheetErrorKind::Permanent);
+        assert!(e.message.starts_with("Failed to open file"));
+    }
+
+    // Helper: a fresh empty directory under the system temp dir
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ts_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn failed_partition_leaves_existing_files_alone() {
+        let dir = temp_dir("partition");
+        let sheet = session("a,1\nb,2\nc,3\nd,4");
+        std::fs::write(dir.join("part-00000.csv"), "old").unwrap();
+        std::fs::write(dir.join("notes.txt"), "keep").unwrap();
+        // A directory where the second shard's temporary file would go
+        std::fs::create_dir(temp_path(&dir.join("part-00001.csv"))).unwrap();
+
+        let out_dir = dir.to_string_lossy().into_owned();
+        assert!(sheet.partition_by_hash(0, 2, out_dir.clone(), false).is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("part-00000.csv")).unwrap(), "old");
+        assert_eq!(std::fs::read_to_string(dir.join("notes.txt")).unwrap(), "keep");
+        assert!(!temp_path(&dir.join("part-00000.csv")).exists());
+        assert!(!dir.join("part-00001.csv").exists());
+
+        std::fs::remove_dir(temp_path(&dir.join("part-00001.csv"))).unwrap();
+        let counts = sheet.partition_by_hash(0, 2, out_dir, false).unwrap();
+        assert_eq!(counts.iter().sum::<i64>(), 4);
+        assert_ne!(std::fs::read_to_string(dir.join("part-00000.csv")).unwrap(), "old");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_parquet_partitioning_leaves_existing_files_alone() {
+        let dir = temp_dir("parquet_partition");
+        let sheet = session("a,1\nb,2\nc,3");
+        std::fs::create_dir(dir.join("A=a")).unwrap();
+        std::fs::write(dir.join("A=a/part-0.parquet"), "old").unwrap();
+        // A directory where the second partition's temporary file would go
+        std::fs::create_dir_all(temp_path(&dir.join("A=b/part-0.parquet"))).unwrap();
+
+        let out_dir = dir.to_string_lossy().into_owned();
+        assert!(sheet.export_to_parquet_partitioned(out_dir.clone(), 0).is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("A=a/part-0.parquet")).unwrap(), "old");
+        assert!(!temp_path(&dir.join("A=a/part-0.parquet")).exists());
+        assert!(!dir.join("A=c").exists()); // Created by the export, so removed again
+
+        std::fs::remove_dir(temp_path(&dir.join("A=b/part-0.parquet"))).unwrap();
+        let report = sheet.export_to_parquet_partitioned(out_dir, 0).unwrap();
+        assert_eq!(report.partitions_written, 3);
+        assert_ne!(std::fs::read(dir.join("A=a/part-0.parquet")).unwrap(), b"old");
+        assert!(dir.join("A=c/part-0.parquet").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn every_shard_starts_with_the_files_header() {
+        let dir = temp_dir("partition_header");
+        let sheet = session_with_header("id,v\n1,a\n2,b\n3,c\n4,d");
+        let counts = sheet.partition_by_hash(0, 2, dir.to_string_lossy().into_owned(), true).unwrap();
+
+        let mut rows = Vec::new();
+        for (shard, &count) in counts.iter().enumerate() {
+            let text = std::fs::read_to_string(dir.join(format!("part-{:05}.csv", shard))).unwrap();
+            let mut lines = text.lines();
+            assert_eq!(lines.next(), Some("id,v"));
+            let shard_rows: Vec<String> = lines.map(str::to_string).collect();
+            assert_eq!(shard_rows.len() as i64, count);
+            rows.extend(shard_rows);
+        }
+        rows.sort();
+        assert_eq!(rows, ["1,a", "2,b", "3,c", "4,d"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn every_part_starts_with_the_files_header() {
+        let dir = temp_dir("split_header");
+        let out_dir = dir.to_string_lossy().into_owned();
+        let paths = session_with_header("id\n1\n2\n3").split_into_files(out_dir.clone(), 2, true).unwrap();
+        let parts: Vec<String> = paths.iter().map(|path| std::fs::read_to_string(path).unwrap()).collect();
+        assert_eq!(parts, ["id\n1\n2", "id\n3"]);
+
+        // Without a header row include_header has nothing to copy
+        let paths = session("1\n2\n3").split_into_files(out_dir, 2, true).unwrap();
+        assert_eq!(std::fs::read_to_string(&paths[0]).unwrap(), "1\n2");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_split_leaves_existing_files_alone() {
+        let dir = temp_dir("split");
+        let sheet = session("a\nb\nc");
+        std::fs::write(dir.join("part-001.csv"), "old").unwrap();
+        std::fs::create_dir(temp_path(&dir.join("part-002.csv"))).unwrap();
+
+        let out_dir = dir.to_string_lossy().into_owned();
+        assert!(sheet.split_into_files(out_dir.clone(), 2, false).is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("part-001.csv")).unwrap(), "old");
+        assert!(!temp_path(&dir.join("part-001.csv")).exists());
+
+        std::fs::remove_dir(temp_path(&dir.join("part-002.csv"))).unwrap();
+        let paths = sheet.split_into_files(out_dir, 2, false).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(std::fs::read_to_string(&paths[0]).unwrap(), "a\nb");
+        assert_eq!(std::fs::read_to_string(&paths[1]).unwrap(), "c");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_row_header_names_the_columns_and_is_not_a_row() {
+        let sheet = session_with_header("id,name,\n1,Ann,x\n2,Bo,y");
+        assert_eq!(sheet.get_header_chunk(0, 3), ["id", "name", "C"]);
+        assert_eq!(sheet.total_rows, 2);
+        assert_eq!(sheet.get_grid_chunk(0, 1, 0, 3)[0].cells[1].content, "Ann");
+        assert_eq!(sheet.get_grid_chunk(0, 1, 0, 3)[0].source_row, 1);
+        assert_eq!(session_with_header("").total_rows, 0);
+    }
+
+    #[test]
+    fn two_duplicate_header_names() {
+        let sheet = session_with_header("amount,amount,note\n1,2,x");
+        let expected = [("amount", "amount"), ("amount", "amount_2"), ("note", "note")];
+        assert_eq!(header_pairs(&sheet), expected.map(|(r, c)| (r.to_string(), c.to_string())));
+        assert_eq!(sheet.resolve_column("amount_2".to_string()), Ok(1));
+        assert_eq!(sheet.resolve_column("amount".to_string()), Ok(0));
+    }
+
+    #[test]
+    fn three_duplicate_header_names() {
+        let sheet = session_with_header("amount,amount,amount\n1,2,3");
+        let canonical: Vec<String> = header_pairs(&sheet).into_iter().map(|(_, c)| c).collect();
+        assert_eq!(canonical, ["amount", "amount_2", "amount_3"]);
+        assert_eq!(sheet.resolve_column("amount_3".to_string()), Ok(2));
+    }
+
+    #[test]
+    fn duplicate_skips_a_suffix_that_is_already_a_header() {
+        let sheet = session_with_header("amount,amount,amount_2\n1,2,3");
+        let canonical: Vec<String> = header_pairs(&sheet).into_iter().map(|(_, c)| c).collect();
+        assert_eq!(canonical, ["amount", "amount_3", "amount_2"]);
+        assert_eq!(sheet.resolve_column("amount_2".to_string()), Ok(2));
+        assert_eq!(sheet.resolve_column("amount_3".to_string()), Ok(1));
+    }
+
+    #[test]
+    fn names_differing_only_in_case_are_ambiguous() {
+        let sheet = session_with_header("Amount,amount\n1,2");
+        assert_eq!(sheet.resolve_column("amount".to_string()), Ok(1));
+        let candidates = vec![0, 1];
+        assert_eq!(sheet.resolve_column(" AMOUNT ".to_string()), Err(ColumnLookupError::Ambiguous { candidates }));
+        assert_eq!(sheet.resolve_column("total".to_string()), Err(ColumnLookupError::NotFound));
+    }
+
+    #[test]
+    fn exports_write_the_raw_header_back() {
+        let dir = temp_dir("header_export");
+        let sheet = session_with_header("amount,amount\n1,2\n3,4");
+        let out = dir.join("out.csv");
+        sheet.save_to_file(out.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "amount,amount\n1,2\n3,4");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_row_header_survives_save_in_place_and_reopen() {
+        let dir = temp_dir("header_reopen");
+        let path = dir.join("in.csv");
+        std::fs::write(&path, "a,b\n1,2\n3,4").unwrap();
+        let options = OpenOptions { first_row_header: true, ..Default::default() };
+        let path_str = path.to_string_lossy().into_owned();
+        let mut sheet = SheetSession::new_from_file_with_options(path_str, options).unwrap();
+        sheet.set_cell(0, 1, "20".to_string()).unwrap();
+        sheet.save_in_place().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a,b\n1,20\n3,4");
+        assert_eq!((sheet.total_rows, sheet.get_header_chunk(0, 2)), (2, vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(sheet.get_grid_chunk(0, 1, 0, 2)[0].cells[1].content, "20");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ranges_ending_past_i64_max_are_out_of_range() {
+        let sheet = session("1\n2\n3");
+        let err = sheet.sub_view(1, i64::MAX, 0, 1).err().unwrap();
+        assert_eq!(err, format!("Rows 1..{} are outside the session (0..3)", i64::MAX));
+        assert!(sheet.sub_view(0, 1, 1, i64::MAX).is_err());
+        assert_eq!(sheet.window_aggregate(0, 2, AggOp::Sum, i64::MAX - 1, 10), Vec::<f64>::new());
+        let sums = sheet.window_aggregate(0, 2, AggOp::Sum, 1, i32::MAX);
+        assert_eq!((sums.len(), sums[1]), (2, 5.0));
+        assert!(sheet.get_grid_chunk(0, 1, i64::MAX - 1, 10)[0].cells.is_empty());
+    }
+
+    #[test]
+    fn the_column_clamp_warning_is_given_once() {
+        let options = OpenOptions { max_cols: Some(2), ..Default::default() };
+        let content = FileContent::Buffered(b"a,b,c,d\n1,2,3,4".to_vec());
+        let mut sheet = SheetSession::from_content(std::path::PathBuf::new(), content, options, None).unwrap();
+        sheet.set_total_cols_override(Some(3)).unwrap();
+        sheet.set_total_cols_override(None).unwrap();
+        let clamped = sheet.get_warnings().iter().filter(|w| w.contains("clamped")).count();
+        assert_eq!((sheet.total_cols, clamped), (2, 1));
+    }
+
+    #[test]
+    fn reordering_columns_past_the_named_ones_keeps_their_letters() {
+        let mut sheet = session_with_header("b,a\n1,2\n");
+        sheet.set_total_cols_override(Some(4)).unwrap();
+        sheet.reorder_columns_alphabetically(true).unwrap();
+        assert_eq!(sheet.get_header_chunk(0, 4), ["a", "b", "C", "D"]);
+
+        let dir = temp_dir("reorder_wider");
+        let (mut sheet, path) = file_session(&dir, "1,2\n3,4");
+        sheet.write_csv_header(vec!["y".to_string(), "x".to_string()]).unwrap();
+        std::fs::write(&path, "1,2,3\n4,5,6").unwrap();
+        sheet.reopen().unwrap();
+        sheet.reorder_columns_alphabetically(true).unwrap();
+        assert_eq!(sheet.get_header_chunk(0, 3), ["C", "x", "y"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_rereads_a_first_row_header() {
+        let dir = temp_dir("header_reread");
+        let path = dir.join("in.csv");
+        std::fs::write(&path, "a,b,c\n1,2,3").unwrap();
+        let options = OpenOptions { first_row_header: true, ..Default::default() };
+        let path_str = path.to_string_lossy().into_owned();
+        let mut sheet = SheetSession::new_from_file_with_options(path_str, options).unwrap();
+        std::fs::write(&path, "z,y,x\n1,2,3").unwrap();
+        sheet.reopen().unwrap();
+        assert_eq!(sheet.get_header_chunk(0, 3), ["z", "y", "x"]);
+
+        sheet.reorder_columns_alphabetically(true).unwrap();
+        std::fs::write(&path, "r,q,p\n1,2,3").unwrap();
+        sheet.reopen().unwrap();
+        assert_eq!(sheet.get_header_chunk(0, 3), ["p", "q", "r"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Helper: the newest query in `from`'s history, replayed on `to` (as if
+    // imported with import_state); returns the first cell of each row left
+    fn replay_newest(from: &SheetSession, to: &mut SheetSession) -> Vec<String> {
+        to.query_history = from.query_history.clone();
+        let id = from.query_history.back().unwrap().id;
+        to.replay_query(id).unwrap();
+        let rows = to.get_grid_chunk(0, to.total_rows as i32, 0, 1);
+        rows.into_iter().map(|row| row.cells[0].content.clone()).collect()
+    }
+
+    #[test]
+    fn replay_finds_columns_by_name_in_another_order() {
+        let mut first = session_with_header("id,city\n1,Oslo\n2,Rome");
+        let predicate = FilterPredicate::Equals("Rome".to_string());
+        first.apply_filter(vec![FilterCondition::Column { col: 1, predicate }]).unwrap();
+
+        let mut second = session_with_header("city,id\nRome,7\nOslo,8\nRome,9");
+        assert_eq!(replay_newest(&first, &mut second), ["Rome", "Rome"]);
+
+        // Without a header the recorded index is all there is
+        let mut plain = session("7,Rome\n8,Oslo");
+        assert_eq!(replay_newest(&first, &mut plain), ["7"]);
+    }
+
+    #[test]
+    fn replay_tells_duplicate_names_apart() {
+        let mut first = session_with_header("x,x\n1,a\n2,b");
+        let predicate = FilterPredicate::Equals("b".to_string());
+        first.apply_filter(vec![FilterCondition::Column { col: 1, predicate }]).unwrap();
+
+        // x_2 is the third column here
+        let mut second = session_with_header("x,y,x\n1,b,a\n2,a,b");
+        assert_eq!(replay_newest(&first, &mut second), ["2"]);
+    }
+
+    #[test]
+    fn compare_summary_matches_canonical_names() {
+        let a = session_with_header("id,amount,amount\n1,10,x\n2,20,y");
+        let b = session_with_header("amount,id,amount,extra\n10,1,x,q\n20,2,y,r");
+        let summary = a.compare_summary(&b, 100);
+        assert!(summary.matched_by_name);
+        let pairs: Vec<(String, i64, i64)> =
+            summary.columns.iter().map(|c| (c.name.clone(), c.col_self, c.col_other)).collect();
+        let expected = [("id", 0, 1), ("amount", 1, 0), ("amount_2", 2, 2)];
+        assert_eq!(pairs, expected.map(|(name, a, b)| (name.to_string(), a, b)));
+        assert_eq!(summary.only_in_other, ["extra"]);
+        assert!(summary.only_in_self.is_empty());
+        assert_eq!(summary.moved, ["id", "amount"]);
+
+        // One side without names: by position
+        let plain = session("1,10,x\n2,20,y");
+        let summary = a.compare_summary(&plain, 100);
+        assert!(!summary.matched_by_name);
+        assert!(summary.columns.iter().all(|c| c.col_self == c.col_other));
+        assert_eq!(summary.columns.len(), 3);
+    }
+
+    #[test]
+    fn cr_only_files_split_on_cr_and_save_with_it() {
+        let sheet = session("a,1\rb,2\rc,3");
+        assert_eq!(sheet.get_session_info().line_terminator, LineTerminator::Cr);
+        assert!(sheet.get_warnings().is_empty());
+        assert_eq!(sheet.get_column_data(1), ["1", "2", "3"]);
+
+        let dir = temp_dir("cr_only");
+        let out = dir.join("out.csv");
+        sheet.save_to_file(out.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), b"a,1\rb,2\rc,3");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mixed_line_endings_split_on_the_first_lines_terminator_and_warn() {
+        // CRLF first: a bare \n still ends a row, a bare \r stays in its cell
+        let sheet = session("a,1\r\nb,2\nc,3\rd,4");
+        assert_eq!(sheet.get_session_info().line_terminator, LineTerminator::Crlf);
+        assert_eq!(sheet.get_column_data(1), ["1", "2", "3\rd"]);
+        assert_eq!(sheet.get_warnings(), ["Mixed line endings (1 \\n, 1 \\r\\n, 1 bare \\r); rows split on \\r\\n"]);
+
+        // LF first: a CRLF row keeps its \r in the last cell
+        let sheet = session("a,1\nb,2\r\nc,3");
+        assert_eq!(sheet.get_session_info().line_terminator, LineTerminator::Lf);
+        assert_eq!(sheet.get_column_data(1), ["1", "2\r", "3"]);
+        assert_eq!(sheet.get_warnings(), ["Mixed line endings (1 \\n, 1 \\r\\n, 0 bare \\r); rows split on \\n"]);
+    }
+
+    #[test]
+    fn formula_prefixes_are_neutralized_but_numbers_are_not() {
+        let cases = [
+            ("=SUM(A1:A9)", "'=SUM(A1:A9)"),
+            ("+cmd|' /C calc'!A0", "'+cmd|' /C calc'!A0"),
+            ("-2+3", "'-2+3"),
+            ("@SUM(1)", "'@SUM(1)"),
+            ("\tdata", "'\tdata"),
+            ("\rdata", "'\rdata"),
+            // Clean numbers stay numbers; padded ones are not clean
+            ("-5", "-5"),
+            ("-1.25e3", "-1.25e3"),
+            ("+1.5", "+1.5"),
+            ("-5 ", "'-5 "),
+            ("plain", "plain"),
+            ("a=b", "a=b"),
+        ];
+        let mut fields: Vec<String> = cases.iter().map(|(cell, _)| cell.to_string()).collect();
+        sanitize_formulas(&mut fields, DecimalSeparator::Point);
+        for (field, (cell, expected)) in fields.iter().zip(cases) {
+            assert_eq!(field, expected, "{:?}", cell);
+        }
+
+        // With a decimal comma "-1,5" is a number; "-1,5,6" and "-1.5,6" are not
+        let cases = [("-1,5", "'-1,5", "-1,5"), ("-1.5", "-1.5", "-1.5"), ("-1,5,6", "'-1,5,6", "'-1,5,6")];
+        for separator in [DecimalSeparator::Point, DecimalSeparator::Comma] {
+            let mut fields: Vec<String> = cases.iter().map(|(cell, _, _)| cell.to_string()).collect();
+            fields.push("-1.5,6".to_string());
+            sanitize_formulas(&mut fields, separator);
+            let expected: Vec<&str> = cases
+                .iter()
+                .map(|&(_, point, comma)| if separator == DecimalSeparator::Comma { comma } else { point })
+                .chain(["'-1.5,6"])
+                .collect();
+            assert_eq!(fields, expected, "{:?}", separator);
+        }
+
+        let mut sheet = session("=1+1,-7\n@x,+2");
+        assert_eq!(sheet.copy_range_as_csv(0, 2, 0, 2, None).unwrap(), "'=1+1,-7\n'@x,+2");
+        assert_eq!(sheet.copy_range_as_csv(0, 2, 0, 2, Some(false)).unwrap(), "=1+1,-7\n@x,+2");
+
+        let dir = temp_dir("sanitize");
+        let out = dir.join("out.csv");
+        sheet.save_to_file(out.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "=1+1,-7\n@x,+2");
+        sheet.set_sanitize_formulas(true);
+        sheet.save_to_file(out.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "'=1+1,-7\n'@x,+2");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Tracing is process-wide as well: tests that read traces hold this
+    // lock, and only look at the operation they ran
+    static TRACE_LOCK: Mutex<()> = Mutex::new(());
+
+    // Helper: `f`'s result and the trace records of `operation` it made
+    fn traced<T>(operation: &str, f: impl FnOnce() -> T) -> (T, Vec<TraceRecord>) {
+        let _guard = TRACE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        get_trace();
+        enable_tracing(MAX_TRACE_CAPACITY).unwrap();
+        let value = f();
+        disable_tracing();
+        (value, get_trace().into_iter().filter(|t| t.operation == operation).collect())
+    }
+
+    #[test]
+    fn indexed_token_queries_read_no_rows() {
+        let mut text = String::new();
+        for i in 0..4000 {
+            text.push_str(&format!("{},order {} shipped\n", i, if i % 1000 == 7 { "Kiwi-7" } else { "pear" }));
+        }
+        let mut sheet = session(&text);
+        sheet.build_search_index(Some(vec![1])).unwrap();
+        let kiwi = |sheet: &SheetSession| sheet.find_text("kiwi 7".to_string(), FindMode::WholeToken, Some(vec![1]));
+
+        let ((indexed, scanned), traces) = traced("find_text", || {
+            let indexed = kiwi(&sheet).unwrap();
+            (indexed, sheet.find_text("kiwi-7".to_string(), FindMode::Substring, Some(vec![1])).unwrap())
+        });
+        assert_eq!(indexed, [7, 1007, 2007, 3007]);
+        assert_eq!(scanned, indexed);
+        let touched: Vec<(i64, i64, i64)> =
+            traces.iter().map(|t| (t.rows_touched, t.bytes_read, t.cache_hits)).collect();
+        assert_eq!(touched, [(0, 0, 1), (sheet.total_rows, sheet.view_bytes(0..sheet.total_rows), 0)]);
+
+        // Edits drop the index, so the query scans again and sees the edit
+        sheet.set_cell(7, 1, "order pear shipped".to_string()).unwrap();
+        assert_eq!(kiwi(&sheet).unwrap(), [1007, 2007, 3007]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_with_non_utf8_names_open_by_their_bytes() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let dir = temp_dir("latin1_name");
+        let mut name = dir.as_os_str().as_bytes().to_vec();
+        name.extend_from_slice(b"/caf\xe9.csv"); // Latin-1 "café"
+        let path = std::path::PathBuf::from(std::ffi::OsString::from_vec(name.clone()));
+        std::fs::write(&path, "a,1\nb,2").unwrap();
+
+        let lossy = path.to_string_lossy().into_owned();
+        assert!(SheetSession::new_from_file(lossy.clone()).is_err());
+        let mut sheet = SheetSession::new_from_file_bytes(name.clone()).unwrap();
+        let info = sheet.get_session_info();
+        assert_eq!((info.path_bytes, info.path), (name.clone(), lossy));
+        assert_eq!(sheet.get_column_data(0), ["a", "b"]);
+
+        // Writes go back to the same file, not to its lossy name
+        sheet.set_cell(1, 1, "20".to_string()).unwrap();
+        sheet.save_in_place().unwrap();
+        sheet.reopen().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a,1\nb,20");
+        assert_eq!(sheet.get_session_info().path_bytes, name);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Helper: open `csv` with the index file holding `index`, returning
+    // the session and the warning explaining why the index was ignored
+    fn open_with_index_bytes(csv: &std::path::Path, index: &[u8]) -> (SheetSession, Option<String>) {
+        let idx = csv.with_extension("idx");
+        std::fs::write(&idx, index).unwrap();
+        let path = |p: &std::path::Path| p.to_string_lossy().into_owned();
+        let sheet = SheetSession::open_with_index(path(csv), path(&idx)).unwrap();
+        let warning = sheet.get_warnings().into_iter().find(|w| w.starts_with("Index file not used"));
+        (sheet, warning)
+    }
+
+    #[test]
+    fn index_files_round_trip() {
+        let dir = temp_dir("index_round_trip");
+        let csv = dir.join("data.csv");
+        std::fs::write(&csv, "a,1\rb,2\rc,3").unwrap();
+        let idx = dir.join("data.idx");
+        let path = |p: &std::path::Path| p.to_string_lossy().into_owned();
+        SheetSession::build_index_file(path(&csv), path(&idx), OpenOptions::default()).unwrap();
+        let index = std::fs::read(&idx).unwrap();
+        assert_eq!(&index[..8], INDEX_MAGIC);
+
+        let (sheet, warning) = open_with_index_bytes(&csv, &index);
+        assert_eq!(warning, None);
+        assert_eq!(sheet.get_session_info().line_terminator, LineTerminator::Cr);
+        assert_eq!(sheet.row_offsets.offsets, [0, 4, 8]);
+        assert_eq!(sheet.get_column_data(1), ["1", "2", "3"]);
+
+        // A newer minor with an extra header field still loads
+        let mut newer = index.clone();
+        newer[10..12].copy_from_slice(&(INDEX_VERSION_MINOR + 1).to_le_bytes());
+        newer[12..16].copy_from_slice(&(INDEX_HEADER_V1_LEN as u32 + 4).to_le_bytes());
+        newer.splice(16 + INDEX_HEADER_V1_LEN..16 + INDEX_HEADER_V1_LEN, [7; 4]);
+        let (sheet, warning) = open_with_index_bytes(&csv, &newer);
+        assert_eq!(warning, None);
+        assert_eq!(sheet.get_column_data(0), ["a", "b", "c"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_index_files_fall_back_to_a_scan() {
+        let dir = temp_dir("index_corrupt");
+        let csv = dir.join("data.csv");
+        std::fs::write(&csv, "a,1\nb,2\nc,3").unwrap();
+        let content = std::fs::read(&csv).unwrap();
+        let rows = RowIndex::from_offsets(vec![0, 4, 8], b'\n', 1);
+        let index = encode_row_index(&content, &rows, LineTerminator::Lf, false);
+        let offsets = 16 + INDEX_HEADER_V1_LEN;
+        let corrupted = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = index.clone();
+            edit(&mut bytes);
+            bytes
+        };
+
+        let cases: Vec<(Vec<u8>, &str)> = vec![
+            (corrupted(&|b| b[0] = b'X'), "not a row index file"),
+            (corrupted(&|b| b[8] = 2), "format version 2 is not supported"),
+            (corrupted(&|b| b.truncate(20)), "truncated header"),
+            (corrupted(&|b| b[24] ^= 1), "built for different file contents"),
+            (corrupted(&|b| b[41] = b';'), "built with another delimiter or quote character"),
+            (corrupted(&|b| b.truncate(b.len() - 3)), "row offsets are truncated or corrupt"),
+            (corrupted(&|b| b[offsets + 8] = 9), "row offsets are corrupt"),
+            (corrupted(&|b| b[offsets + 8] = 5), "row offsets don't match the file's line breaks"),
+        ];
+        for (bytes, why) in cases {
+            let (sheet, warning) = open_with_index_bytes(&csv, &bytes);
+            assert_eq!(warning.unwrap(), format!("Index file not used ({}); the file was scanned instead", why));
+            assert_eq!(sheet.row_offsets.offsets, [0, 4, 8]);
+        }
+
+        // Same size, other contents: stale for this file
+        let (sheet, warning) = open_with_index_bytes(&csv, &index);
+        assert_eq!((warning, sheet.total_rows), (None, 3));
+        std::fs::write(&csv, "a,1\nb,2\nc,4").unwrap();
+        let (sheet, warning) = open_with_index_bytes(&csv, &index);
+        assert!(warning.unwrap().contains("built for different file contents"));
+        assert_eq!(sheet.get_column_data(1), ["1", "2", "4"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn strict_padding_flags_made_up_cells_only() {
+        // Full row, short row, real empties, blank line, short row
+        let text = "a,b,c\nd\ne,,\n\nf,g";
+        let mut sheet = session(text);
+        let flags = |sheet: &SheetSession| -> Vec<Vec<bool>> {
+            let rows = sheet.get_grid_chunk(0, 5, 0, 3);
+            rows.iter().map(|row| row.cells.iter().map(|c| c.is_padding).collect()).collect()
+        };
+        let is_empty = |col| vec![FilterCondition::Column { col, predicate: FilterPredicate::IsEmpty }];
+        assert!(flags(&sheet).iter().flatten().all(|&padding| !padding));
+        assert_eq!(sheet.filter_rows(is_empty(1)).unwrap(), [1, 2, 3]);
+
+        sheet.set_strict_padding(true);
+        let expected: [&[bool]; 5] =
+            [&[false; 3], &[false, true, true], &[false; 3], &[], &[false, false, true]];
+        assert_eq!(flags(&sheet), expected);
+        assert_eq!(sheet.filter_rows(is_empty(1)).unwrap(), [2]);
+        assert_eq!(sheet.filter_rows(is_empty(2)).unwrap(), [2]);
+
+        // An edited cell is real data, even when edited to empty
+        sheet.set_cell(1, 2, String::new()).unwrap();
+        assert_eq!(flags(&sheet)[1], [false, true, false]);
+        assert_eq!(sheet.filter_rows(is_empty(2)).unwrap(), [1, 2]);
+
+        let dir = temp_dir("strict_padding");
+        let out = dir.join("out.csv").to_string_lossy().into_owned();
+        let mode = || ExportColumnMode::VisibleOrder;
+        let export = |sheet: &SheetSession| sheet.save_to_file_with_columns(out.clone(), mode(), false);
+        sheet.set_padding_export(PaddingExport::Fail);
+        assert_eq!(export(&sheet).unwrap_err(), "Row 1 is missing 1 fields that would be written as empty values");
+        sheet.set_padding_export(PaddingExport::Warn);
+        export(&sheet).unwrap();
+        assert_eq!(sheet.get_session_info().padded_cells_exported, 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "a,b,c\nd,,\ne,,\n\nf,g,");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn exports_from_a_snapshot_ignore_changes_made_meanwhile() {
+        let parity = |i: i64| if i % 2 == 0 { "even" } else { "odd" };
+        let text: Vec<String> = (0..20_000).map(|i| format!("{},{}", i, parity(i))).collect();
+        let mut sheet = session(&text.join("\n"));
+        let only = |value: &str| {
+            vec![FilterCondition::Column { col: 1, predicate: FilterPredicate::Equals(value.to_string()) }]
+        };
+        sheet.apply_filter(only("even")).unwrap();
+        sheet.set_cell(0, 0, "first".to_string()).unwrap();
+        let (exported, reference) = (sheet.snapshot(), sheet.snapshot());
+
+        let dir = temp_dir("snapshot_export");
+        let (out, expected) = (dir.join("out.csv"), dir.join("expected.csv"));
+        let path = |p: &std::path::Path| p.to_string_lossy().into_owned();
+        let change = |sheet: &mut SheetSession, n: i64| {
+            sheet.set_cell(n % 50, 1, format!("changed {}", n)).unwrap();
+            if n % 2 == 0 {
+                sheet.clear_filter();
+            } else {
+                sheet.apply_filter(only("odd")).unwrap();
+            }
+        };
+        // Some changes land before the export starts, the rest while it runs;
+        // either way the file holds the snapshot's rows
+        for n in 0..10 {
+            change(&mut sheet, n);
+        }
+        let start = std::sync::Barrier::new(2);
+        std::thread::scope(|scope| {
+            let export = scope.spawn(|| {
+                start.wait();
+                exported.save_to_file(path(&out))
+            });
+            start.wait();
+            for n in 10..20 {
+                assert_eq!(sheet.get_grid_chunk(0, 50, 0, 2).len(), 50);
+                change(&mut sheet, n);
+            }
+            export.join().unwrap().unwrap();
+        });
+
+        reference.save_to_file(path(&expected)).unwrap();
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(written, std::fs::read_to_string(&expected).unwrap());
+        assert!(written.starts_with("first,even\n2,even\n"));
+        assert!(!written.contains("odd") && !written.contains("changed"));
+        assert_eq!(written.lines().count(), 10_000);
+        assert_eq!(sheet.get_grid_chunk(0, 1, 0, 2)[0].cells[1].content, "odd"); // The live session moved on
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_sort_comparator_orders_nulls_as_documented() {
+        // Blank cells are null; "abc" counts as null for the numeric comparators
+        let text = "a,10,pear\nb,,\nc,9,Apple\nd,abc,fig\ne,  ,  \nf,100,kiwi";
+        let sorted = |keys: Vec<SortKey>| {
+            let mut sheet = session(text);
+            sheet.sort_rows(keys).unwrap();
+            sheet.get_column_data(0).concat()
+        };
+        let custom = |col, comparator| SortKey::Custom { col, comparator };
+        let cases = [
+            (custom(1, SortComparator::NumericAscending), "cafbde"),
+            (custom(1, SortComparator::NumericDescending), "facbde"),
+            (custom(2, SortComparator::LexicographicAscending), "cdfabe"),
+            (custom(2, SortComparator::LexicographicDescending), "afdcbe"),
+            (custom(2, SortComparator::LengthAscending), "dafcbe"),
+            (custom(2, SortComparator::LengthDescending), "cafdbe"),
+            (custom(2, SortComparator::NullsFirst), "beacdf"),
+            (custom(2, SortComparator::NullsLast), "acdfbe"),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(sorted(vec![key]), expected);
+        }
+
+        // Later keys only order rows the earlier ones tie
+        let keys = vec![custom(2, SortComparator::NullsFirst), custom(1, SortComparator::NumericDescending)];
+        assert_eq!(sorted(keys), "befacd");
+    }
+
+    // Helper: bytes get_session_info reports for one component
+    fn memory_of(sheet: &SheetSession, component: &str) -> i64 {
+        let info = sheet.get_session_info();
+        info.memory.iter().find(|m| m.component == component).map_or(0, |m| m.bytes)
+    }
+
+    // Helper: open `text` in memory under a memory budget
+    fn open_with_budget(text: &str, budget: i64) -> Result<SheetSession, SheetError> {
+        let options = OpenOptions { memory_budget: Some(budget), ..Default::default() };
+        let content = FileContent::Buffered(text.as_bytes().to_vec());
+        SheetSession::from_content(std::path::PathBuf::new(), content, options, None)
+    }
+
+    #[test]
+    fn a_row_index_over_the_budget_becomes_sparse() {
+        // 12,000 buffered bytes and 3,001 rows: a dense index needs 24,008 more
+        let text = "b,2\na,1\nc,3\n".repeat(1000);
+        let dense = session(&text);
+        let sparse = open_with_budget(&text, 20_000).unwrap();
+        assert_eq!(sparse.row_offsets.stride, SPARSE_INDEX_STRIDE);
+        assert!(sparse.get_warnings().iter().any(|w| w.contains("every 64th row is indexed")));
+        assert!(memory_of(&sparse, "row_index") < 1000);
+        assert_eq!(sparse.total_rows, dense.total_rows);
+        let cells = |sheet: &SheetSession| -> Vec<Vec<String>> {
+            let rows = sheet.get_grid_chunk(0, sheet.total_rows as i32, 0, 2);
+            rows.into_iter().map(|row| row.cells.into_iter().map(|cell| cell.content).collect()).collect()
+        };
+        assert_eq!(cells(&sparse), cells(&dense));
+        let encode = |sheet: &SheetSession| {
+            encode_row_index(&sheet.content, &sheet.row_offsets, sheet.terminator, false)
+        };
+        assert_eq!(encode(&sparse), encode(&dense));
+
+        // What can't shrink is refused, naming the part that doesn't fit
+        for (budget, component) in [(12_100, "row_index"), (1024, "buffer")] {
+            let Err(err) = open_with_budget(&text, budget) else { panic!("opened over the budget") };
+            let kind = SheetErrorKind::MemoryBudgetExceeded { component: component.to_string() };
+            assert_eq!(err.kind, kind);
+            assert!(err.message.starts_with(&format!("Memory budget exceeded: {}", component)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn sort_to_file_merges_runs_like_sort_rows() {
+        let text: Vec<String> = (0..10_000).map(|n| format!("k{},{}", n * 37 % 101, n)).collect();
+        let text = text.join("\n");
+        let keys = || vec![SortKey::Column { col: 0, descending: true }];
+        let dir = temp_dir("sort_to_file");
+        let (expected, actual) = (dir.join("expected.csv"), dir.join("actual.csv"));
+
+        let mut sorted = session(&text);
+        sorted.sort_rows(keys()).unwrap();
+        sorted.save_to_file(expected.to_string_lossy().into_owned()).unwrap();
+
+        // Too tight for an in-memory sort: runs of SORT_RUN_MIN_ROWS rows
+        let mut sheet = session(&text);
+        sheet.set_memory_budget(Some(sheet.get_session_info().memory_used + 1024)).unwrap();
+        assert!(sheet.sort_rows(keys()).is_err());
+        sheet.sort_to_file(keys(), actual.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(std::fs::read(&actual).unwrap(), std::fs::read(&expected).unwrap());
+        assert_eq!(sheet.get_grid_chunk(0, 1, 0, 2)[0].cells[1].content, "0");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2); // No runs left behind
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_small_memory_budget_refuses_instead_of_allocating() {
+        let text = "b,2\na,1\nc,3\n".repeat(1000);
+        let mut sheet = session(&text);
+        sheet.infer_column_types(0, 2);
+        let caches = memory_of(&sheet, "caches");
+        assert!(caches > 0);
+        let used = sheet.get_session_info().memory_used;
+        sheet.set_memory_budget(Some(used + 16)).unwrap();
+        assert_eq!(memory_of(&sheet, "caches"), caches);
+
+        // Caches go first when they are what stands in the way
+        sheet.check_budget("test", caches as usize + 16, "").unwrap();
+        assert_eq!(memory_of(&sheet, "caches"), 0);
+
+        let err = sheet.sort_rows(vec![SortKey::Column { col: 0, descending: false }]).unwrap_err();
+        let hinted = err.starts_with("Memory budget exceeded: row_order") && err.ends_with("use sort_to_file");
+        assert!(hinted, "{}", err);
+        assert_eq!(sheet.get_grid_chunk(0, 1, 0, 1)[0].cells[0].content, "b");
+
+        let err = sheet.set_cell(0, 0, "x".repeat(1000)).unwrap_err();
+        assert!(err.starts_with("Memory budget exceeded: edit_overlay"), "{}", err);
+        assert_eq!(memory_of(&sheet, "edit_overlay"), 0);
+        assert_eq!(sheet.get_grid_chunk(0, 1, 0, 1)[0].cells[0].content, "b");
+
+        // Lowering the budget below what is in use evicts the caches
+        sheet.infer_column_types(0, 2);
+        sheet.set_memory_budget(Some(1)).unwrap();
+        assert_eq!(memory_of(&sheet, "caches"), 0);
+    }
+
+    #[test]
+    fn column_contains_stops_at_the_first_match() {
+        let mut text = String::from("Rome,1\nOslo,2\n");
+        text.push_str(&"Paris,3\n".repeat(1000));
+        let sheet = session(&text);
+        let rows_read = |traces: Vec<TraceRecord>| traces.iter().map(|t| t.rows_touched).collect::<Vec<_>>();
+
+        let contains = |value: &str| sheet.column_contains(0, value.to_string(), NormalizeOptions::default());
+        let (found, traces) = traced("column_contains", || [contains("Rome"), contains("Lima")]);
+        assert_eq!(found, [true, false]);
+        assert_eq!(rows_read(traces), [1, sheet.total_rows]);
+
+        let names = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let (found, traces) = traced("column_contains_many", || {
+            let contains_all = |values: &[&str]| sheet.column_contains_many(0, names(values));
+            [contains_all(&["Oslo", "Rome"]), contains_all(&["Lima"])]
+        });
+        assert_eq!(found, [vec![true, true], vec![false]]);
+        assert_eq!(rows_read(traces), [2, sheet.total_rows]);
+
+        let loose = NormalizeOptions { trim_whitespace: true, case_sensitive: false };
+        assert!(sheet.column_contains(0, " rome ".to_string(), loose));
+        assert!(!sheet.column_contains(0, "rome".to_string(), NormalizeOptions::default()));
+    }
+
+    #[test]
+    fn multi_byte_cells_split_on_character_boundaries() {
+        // 3-byte CJK and €, 4-byte emoji and 𝄞, some inside quotes with commas
+        let rows = [["日本語", "😀,🎉", "中文"], ["€uro", "𝄞", "\"naïve\" 🙂"]];
+        let sheet = session("日本語,\"😀,🎉\",中文\n€uro,𝄞,\"\"\"naïve\"\" 🙂\"");
+        for (row, expected) in sheet.get_grid_chunk(0, 2, 0, 3).iter().zip(rows) {
+            let cells: Vec<&str> = row.cells.iter().map(|c| c.content.as_str()).collect();
+            assert_eq!(cells, expected);
+        }
+
+        let line = "字,😀😀,\"🎉,🎉\",a€";
+        let mut ranges = Vec::new();
+        let fields = split_fields_with_ranges(line, Some(&mut ranges));
+        assert_eq!(fields, ["字", "😀😀", "🎉,🎉", "a€"]);
+        for (start, end) in ranges {
+            assert!(line.is_char_boundary(start) && line.is_char_boundary(end));
+        }
+        assert_eq!(count_fields(line.as_bytes(), false), 4);
+    }
+
+    #[test]
+    fn analysis_reads_edited_cells() {
+        let mut sheet = session("5,x\n9,y\n7,x");
+        assert_eq!(sheet.range_stats(0, 3, 0, 1).max, Some(9.0));
+        sheet.set_cell(2, 0, "12".to_string()).unwrap();
+        sheet.set_cell(1, 1, "x".to_string()).unwrap();
+
+        let stats = sheet.range_stats(0, 3, 0, 1);
+        assert_eq!((stats.max, stats.sum), (Some(12.0), 26.0));
+        let range = CellRange { row_start: 1, row_count: 2, col_start: 0, col_count: 1 };
+        assert_eq!(sheet.multi_range_stats(vec![range]).max, Some(12.0));
+        assert_eq!(sheet.get_column_value_range(0).unwrap().1, CellValue::Integer(12));
+        let unique = sheet.column_unique_values(1, 10, None).unwrap();
+        assert_eq!(unique.values, [("x".to_string(), 3)]);
+        assert_eq!(sheet.get_column_data(0), ["5", "9", "12"]);
+    }
+
+    #[test]
+    fn import_state_with_a_bad_derived_column_changes_nothing() {
+        let mut sheet = session("a,b\nc,d");
+        sheet.save_view("mine".to_string(), 0, 0, vec![0, 1]).unwrap();
+        sheet.add_concat_column("ab".to_string(), vec![ConcatPart::Column(0), ConcatPart::Column(1)]).unwrap();
+        sheet.tag_row(0, "star".to_string()).unwrap();
+
+        let column = |name: &str, col: i64| DerivedColumnState {
+            name: name.to_string(),
+            spec: DerivedSpec::Concat { parts: vec![ConcatPart::Column(col)] },
+        };
+        let state = SessionState {
+            version: SESSION_STATE_VERSION,
+            views: BTreeMap::new(),
+            derived_columns: vec![column("ok", 0), column("bad", 7)],
+            query_history: Vec::new(),
+            row_tags: BTreeMap::new(),
+        };
+        assert_eq!(sheet.apply_state(state).unwrap_err(), "Source column 7 is not a real column");
+        assert_eq!(sheet.list_views(), ["mine"]);
+        assert_eq!(sheet.get_header_chunk(0, 3), ["A", "B", "ab"]);
+        assert_eq!(sheet.all_tags(), ["star"]);
+
+        let state = SessionState {
+            version: SESSION_STATE_VERSION,
+            views: BTreeMap::new(),
+            derived_columns: vec![column("ok", 0), column("ok", 1)],
+            query_history: Vec::new(),
+            row_tags: BTreeMap::new(),
+        };
+        assert!(sheet.apply_state(state).unwrap_err().contains("already exists"));
+        assert_eq!(sheet.total_cols, 3);
+    }
+
+    // Helper: a session over a temp file holding `text`
+    fn file_session(dir: &std::path::Path, text: &str) -> (SheetSession, std::path::PathBuf) {
+        let path = dir.join("data.csv");
+        std::fs::write(&path, text).unwrap();
+        (SheetSession::new_from_file(path.to_string_lossy().into_owned()).unwrap(), path)
+    }
+
+    #[test]
+    fn tags_follow_their_rows_when_rows_are_inserted_above() {
+        let dir = temp_dir("tags_insert");
+        let (mut sheet, path) = file_session(&dir, "a\nb\nc");
+        sheet.tag_row(1, "star".to_string()).unwrap();
+        sheet.tag_row(2, "flag".to_string()).unwrap();
+
+        std::fs::write(&path, "x\ny\na\nb\nc").unwrap();
+        sheet.reopen().unwrap();
+        assert_eq!(sheet.get_rows_by_tag("star"), [3]);
+        assert_eq!(sheet.get_grid_chunk(3, 1, 0, 1)[0].cells[0].content, "b");
+        assert_eq!(sheet.get_rows_by_tag("flag"), [4]);
+
+        // A tagged row that's gone takes its tags with it
+        std::fs::write(&path, "x\na\nc").unwrap();
+        sheet.reopen().unwrap();
+        assert_eq!(sheet.all_tags(), ["flag"]);
+        assert_eq!(sheet.get_rows_by_tag("flag"), [2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tags_on_edited_rows_survive_save_in_place() {
+        let dir = temp_dir("tags_save");
+        let (mut sheet, path) = file_session(&dir, "a\nb\nc");
+        sheet.tag_row(1, "star".to_string()).unwrap();
+        sheet.set_cell(1, 0, "B".to_string()).unwrap();
+        sheet.save_in_place().unwrap();
+
+        std::fs::write(&path, "new\na\nB\nc").unwrap();
+        sheet.reopen().unwrap();
+        assert_eq!(sheet.get_rows_by_tag("star"), [2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Helper: (first cell, source row) of every view row
+    fn column_and_source(sheet: &SheetSession) -> Vec<(String, i64)> {
+        let rows = sheet.get_grid_chunk(0, sheet.total_rows as i32, 0, 1);
+        rows.into_iter().map(|row| (row.cells[0].content.clone(), row.source_row)).collect()
+    }
+
+    #[test]
+    fn reversal_leaves_the_header_row_out() {
+        let mut sheet = session_with_header("n,v\n1,a\n2,b\n3,a\n4,b");
+        sheet.set_reverse_order(true);
+        let expected = [("4", 4), ("3", 3), ("2", 2), ("1", 1)];
+        assert_eq!(column_and_source(&sheet), expected.map(|(n, row)| (n.to_string(), row)));
+        assert_eq!(sheet.get_header_chunk(0, 2), ["n", "v"]);
+
+        sheet.apply_filter(vec![FilterCondition::AnyColumnContains("a".to_string())]).unwrap();
+        let expected = [("3", 3), ("1", 1)];
+        assert_eq!(column_and_source(&sheet), expected.map(|(n, row)| (n.to_string(), row)));
+    }
+
+    #[test]
+    fn reversed_rows_appended_to_the_file_show_first() {
+        let dir = temp_dir("reverse_tail");
+        let path = dir.join("log.csv");
+        std::fs::write(&path, "n\n1\n2").unwrap();
+        let options = OpenOptions { first_row_header: true, ..Default::default() };
+        let path_str = path.to_string_lossy().into_owned();
+        let mut sheet = SheetSession::new_from_file_with_options(path_str, options).unwrap();
+        sheet.set_reverse_order(true);
+
+        std::fs::write(&path, "n\n1\n2\n3").unwrap();
+        sheet.reopen().unwrap();
+        let cells: Vec<String> = column_and_source(&sheet).into_iter().map(|(n, _)| n).collect();
+        assert_eq!(cells, ["3", "2", "1"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn links_are_flagged_and_counted_per_column() {
+        let text = "https://example.com/a,ann@example.org,plain\nwww.example.com,mailto:bo@example.net,x\n\
+                    http://bad host,not@mail,www.\nftp://files.example.com,,@example.com\n";
+        let sheet = session(text);
+        let kinds = |detect_links: bool| -> Vec<Vec<ContentKind>> {
+            let opts = GridChunkOptions { detect_links, ..Default::default() };
+            let rows = sheet.get_grid_chunk_with_options(0, 4, 0, 3, opts);
+            rows.iter().map(|row| row.cells.iter().map(|cell| cell.content_kind).collect()).collect()
+        };
+        use ContentKind::{Email, Plain, Url};
+        let expected = [[Url, Email, Plain], [Url, Email, Plain], [Plain, Plain, Plain], [Url, Plain, Plain]];
+        assert_eq!(kinds(true), expected.map(Vec::from));
+        assert!(kinds(false).iter().flatten().all(|&kind| kind == Plain));
+
+        let report = sheet.sample_and_describe(100);
+        let ratios: Vec<(f64, f64)> = report.columns.iter().map(|c| (c.url_ratio, c.email_ratio)).collect();
+        assert_eq!(ratios, [(0.75, 0.0), (0.0, 2.0 / 3.0), (0.0, 0.0)]);
+    }
+
+    #[cfg(feature = "c-ffi")]
+    #[test]
+    fn unbounded_chunk_ignores_the_budget() {
+        let _budget = response_budget(64 << 10);
+        let rows = oversized(1 << 20).get_grid_chunk_unbounded(0, 4, 0, 3);
+        assert_eq!(rows.len(), 4);
+        assert!(rows.iter().all(|row| row.cells.len() == 3 && row.continuation.is_none()));
+    }
 }
@@ -0,0 +1,40 @@
+/* C API for the turbo-sheet engine (Rust feature "c-ffi"). See ffi.rs. */
+#ifndef TURBO_SHEET_H
+#define TURBO_SHEET_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define TS_OK 0
+#define TS_ERR_INVALID_HANDLE (-1)
+#define TS_ERR_INVALID_ARGUMENT (-2)
+#define TS_ERR_OPERATION_FAILED (-3) /* details in ts_last_error; also returned for internal panics */
+#define TS_ERR_BUFFER_TOO_SMALL (-4) /* *out_len holds the size needed */
+
+int32_t ts_open(const char *path, uint64_t *out_handle);
+int32_t ts_close(uint64_t handle);
+int32_t ts_totals(uint64_t handle, int64_t *out_rows, int64_t *out_cols);
+
+/* CSV records (one \n-terminated line per row). Pass buf = NULL to get the size. */
+int32_t ts_get_grid_chunk(uint64_t handle, int64_t row_start, int32_t row_count,
+                          int64_t col_start, int32_t col_count,
+                          uint8_t *buf, size_t buf_len, size_t *out_len);
+
+/* Rows where any cell contains needle; *out_count is the total match count. */
+int32_t ts_search(uint64_t handle, const char *needle,
+                  int64_t *out_rows, size_t max_rows, size_t *out_count);
+
+int32_t ts_export_csv(uint64_t handle, const char *path);
+
+/* Last error on this thread, UTF-8 without a terminating NUL. */
+int32_t ts_last_error(uint8_t *buf, size_t buf_len, size_t *out_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TURBO_SHEET_H */